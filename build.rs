@@ -0,0 +1,26 @@
+//! Embeds build information, which is reported by `version` subcommand and the monitoring server.
+
+use std::env;
+use std::process::Command;
+
+/// Runs the command, returning its trimmed output on success.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok().map(|v| v.trim().to_owned()).filter(|v| !v.is_empty())
+}
+
+fn main() {
+    // Source packages are built without the repository, in which case the commit is unknown.
+    let commit = output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
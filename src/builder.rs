@@ -0,0 +1,80 @@
+//! Programmatic construction of the proxy for embedders.
+//!
+//! The configuration file describes everything the standalone binary needs, while embedders
+//! often want to plug in their own pieces: additional routes served next to applications, access
+//! records shipped somewhere else, metrics exported through their own registry, or listeners
+//! decided at runtime. The builder starts from the config and overrides or extends it with typed
+//! options, running the proxy exactly the same way `run` does otherwise.
+
+use std::error;
+use std::sync::Arc;
+
+use crate::{Config, Lifecycle, Metrics};
+use crate::logging::AccessSink;
+use crate::net::Endpoint;
+use crate::route::HyperRoute;
+
+pub struct ProxyBuilder {
+    pub(crate) config: Config,
+    pub(crate) lifecycle: Lifecycle,
+    pub(crate) addr: Option<Endpoint>,
+    pub(crate) routes: Vec<HyperRoute>,
+    pub(crate) sinks: Vec<Arc<dyn AccessSink>>,
+    pub(crate) metrics: Arc<Metrics>,
+}
+
+impl ProxyBuilder {
+    /// Constructs a builder, which runs the proxy with the given config as is.
+    pub fn new(config: Config) -> Self {
+        let metrics = Arc::new(Metrics::new(&config));
+
+        Self {
+            config: config,
+            lifecycle: Lifecycle::new(),
+            addr: None,
+            routes: Vec::new(),
+            sinks: Vec::new(),
+            metrics: metrics,
+        }
+    }
+
+    /// Sets the lifecycle, whose hooks are run along with the configured ones and through which
+    /// the shutdown may be requested.
+    pub fn lifecycle(mut self, lifecycle: Lifecycle) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Overrides the address the proxy listens on, either TCP or Unix socket one.
+    pub fn listen<A: Into<Endpoint>>(mut self, addr: A) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Adds a route, which is tried before applications in the order routes are added.
+    ///
+    /// Requests are still matched by standby, WebSocket and Server-Sent Events routes first, when
+    /// they are enabled. In multi-tenant mode the tenant the request belongs to is passed in
+    /// `X-Cocaine-Tenant` header, see [`Tenants`](crate::route::Tenants).
+    pub fn route(mut self, route: HyperRoute) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Adds a destination for access records, apart from the configured ones.
+    pub fn access_sink(mut self, sink: Arc<dyn AccessSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Returns the metrics registry the proxy is going to update, which may be exported by the
+    /// embedder through its own means.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Runs the proxy until the shutdown is requested through the lifecycle.
+    pub fn run(self) -> Result<(), Box<dyn error::Error>> {
+        crate::serve(self)
+    }
+}
@@ -1,9 +1,63 @@
-use std::fmt;
+//! Typed HTTP headers understood by the proxy.
+//!
+//! Each header implements hyper's `Header` trait, so it can be set on and extracted from requests
+//! and responses directly. Apart from that, headers implement `FromStr` and `Display`, parsing
+//! from and formatting into the exact wire representation, which is useful for clients that do
+//! not use hyper.
+//!
+//! With the `serde-headers` feature enabled, headers are also serialized into and deserialized
+//! from their wire representation as strings.
+//!
+//! ```ignore
+//! let mut headers = Headers::new();
+//! headers.set(XCocaineService("echo".into()));
+//! headers.set(XCocaineEvent("ping".into()));
+//! headers.set(XRequestId(42));
+//! headers.set(XTracingPolicy(TracingPolicy::Manual(1.0)));
+//! ```
+
+use std::fmt::{self, Display, Formatter};
 use std::str::{self, FromStr};
 
 use hyper;
 use hyper::header::{self, Header, Raw};
 
+/// Parses the header from its wire representation.
+fn parse<H: Header>(s: &str) -> Result<H, hyper::Error> {
+    H::parse_header(&Raw::from(s.to_owned()))
+}
+
+/// Implements string-based serialization for headers, using their wire representation.
+#[cfg(feature = "serde-headers")]
+macro_rules! impl_serde_header {
+    ($($name:ident),*) => {
+        $(
+            impl ::serde::Serialize for $name {
+                fn serialize<S: ::serde::Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+                    se.collect_str(self)
+                }
+            }
+
+            impl<'de> ::serde::Deserialize<'de> for $name {
+                fn deserialize<D: ::serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+                    let s: String = ::serde::Deserialize::deserialize(de)?;
+                    FromStr::from_str(&s).map_err(|_| {
+                        ::serde::de::Error::custom(format!("invalid `{}` header value", $name::header_name()))
+                    })
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "serde-headers")]
+impl_serde_header!(XCocaineService, XCocaineEvent, XPoweredBy, XRequestId, XTracingPolicy, XCocaineApp,
+                   XErrorGeneratedBy);
+
+/// Name of the service to invoke, which takes precedence over the one from the request path.
+///
+/// Must be specified together with `X-Cocaine-Event`, unless the service has a default event
+/// configured.
 #[derive(Clone, Debug, PartialEq)]
 pub struct XCocaineService(pub String);
 
@@ -27,14 +81,23 @@ impl Header for XCocaineService {
     }
 }
 
-impl XCocaineService {
-    pub fn to_string(&self) -> String {
-        match *self {
-            XCocaineService(ref v) => v.clone(),
-        }
+impl Display for XCocaineService {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&self.0)
     }
 }
 
+impl FromStr for XCocaineService {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Name of the event to invoke, which takes precedence over the one from the request path.
+///
+/// Must be specified together with `X-Cocaine-Service`.
 #[derive(Clone, Debug, PartialEq)]
 pub struct XCocaineEvent(pub String);
 
@@ -58,14 +121,21 @@ impl Header for XCocaineEvent {
     }
 }
 
-impl XCocaineEvent {
-    pub fn to_string(&self) -> String {
-        match *self {
-            XCocaineEvent(ref v) => v.clone(),
-        }
+impl Display for XCocaineEvent {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&self.0)
     }
 }
 
+impl FromStr for XCocaineEvent {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Name and version of the proxy, which is set on responses from applications.
 #[derive(Clone, Debug, PartialEq)]
 pub struct XPoweredBy(pub String);
 
@@ -95,6 +165,24 @@ impl Default for XPoweredBy {
     }
 }
 
+impl Display for XPoweredBy {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&self.0)
+    }
+}
+
+impl FromStr for XPoweredBy {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Trace id of the request, transmitted as a hexadecimal number.
+///
+/// The proxy generates a random one when absent. Note that the header name is configurable in the
+/// proxy, this one is the default.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct XRequestId(pub u64);
 
@@ -124,7 +212,21 @@ impl Header for XRequestId {
     }
 
     fn fmt_header(&self, fmt: &mut header::Formatter) -> Result<(), fmt::Error> {
-        fmt.fmt_line(&format!("{:016x}", self.0))
+        fmt.fmt_line(self)
+    }
+}
+
+impl Display for XRequestId {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for XRequestId {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
     }
 }
 
@@ -137,6 +239,7 @@ pub enum TracingPolicy {
     Manual(f64),
 }
 
+/// Tracing policy of the request, either `Auto` or a tracing chance in [0.0; 1.0] range.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct XTracingPolicy(pub TracingPolicy);
 
@@ -172,13 +275,28 @@ impl Header for XTracingPolicy {
     }
 
     fn fmt_header(&self, fmt: &mut header::Formatter) -> Result<(), fmt::Error> {
+        fmt.fmt_line(self)
+    }
+}
+
+impl Display for XTracingPolicy {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
         match *self {
-            XTracingPolicy(TracingPolicy::Auto) => fmt.fmt_line(&"Auto"),
-            XTracingPolicy(TracingPolicy::Manual(v)) => fmt.fmt_line(&format!("{:.3}", v)),
+            XTracingPolicy(TracingPolicy::Auto) => fmt.write_str("Auto"),
+            XTracingPolicy(TracingPolicy::Manual(v)) => write!(fmt, "{:.3}", v),
         }
     }
 }
 
+impl FromStr for XTracingPolicy {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Name of the application that generated the response.
 // TODO: Almost the same header already exists for service, but it's here for compatibility with tornado.
 #[derive(Clone, Debug, PartialEq)]
 pub struct XCocaineApp(pub String);
@@ -203,9 +321,32 @@ impl Header for XCocaineApp {
     }
 }
 
+impl Display for XCocaineApp {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&self.0)
+    }
+}
+
+impl FromStr for XCocaineApp {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
+/// Name of the subsystem that generated the error response, like `proxy` for errors generated by
+/// the proxy itself.
 #[derive(Clone, Debug, PartialEq)]
 pub struct XErrorGeneratedBy(pub String);
 
+impl XErrorGeneratedBy {
+    /// Constructs the header for errors generated by the proxy itself.
+    pub fn proxy() -> Self {
+        XErrorGeneratedBy("proxy".into())
+    }
+}
+
 impl Header for XErrorGeneratedBy {
     fn header_name() -> &'static str {
         "X-Error-Generated-By"
@@ -226,6 +367,20 @@ impl Header for XErrorGeneratedBy {
     }
 }
 
+impl Display for XErrorGeneratedBy {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&self.0)
+    }
+}
+
+impl FromStr for XErrorGeneratedBy {
+    type Err = hyper::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use hyper::header::Raw;
@@ -281,4 +436,29 @@ mod test {
         assert!(XTracingPolicy::parse_header(&Raw::from("-1")).is_err());
         assert!(XTracingPolicy::parse_header(&Raw::from("1.01")).is_err());
     }
+
+    #[test]
+    fn test_headers_wire_roundtrip() {
+        assert_eq!("000000000000002a", XRequestId(42).to_string());
+        assert_eq!(XRequestId(42), "2a".parse().unwrap());
+
+        assert_eq!("Auto", XTracingPolicy(TracingPolicy::Auto).to_string());
+        assert_eq!("0.500", XTracingPolicy(TracingPolicy::Manual(0.5)).to_string());
+        assert_eq!(XTracingPolicy(TracingPolicy::Manual(0.5)), "0.500".parse().unwrap());
+
+        assert_eq!("echo", XCocaineService("echo".into()).to_string());
+        assert_eq!(XCocaineEvent("ping".into()), "ping".parse().unwrap());
+        assert!("".parse::<XRequestId>().is_err());
+    }
+
+    #[cfg(feature = "serde-headers")]
+    #[test]
+    fn test_headers_serde() {
+        use serde_json;
+
+        assert_eq!(r#""000000000000002a""#, serde_json::to_string(&XRequestId(42)).unwrap());
+        assert_eq!(XRequestId(42), serde_json::from_str(r#""2a""#).unwrap());
+        assert_eq!(XTracingPolicy(TracingPolicy::Auto), serde_json::from_str(r#""Auto""#).unwrap());
+        assert!(serde_json::from_str::<XRequestId>(r#""damn""#).is_err());
+    }
 }
@@ -4,18 +4,24 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{self, Debug, Formatter};
 use std::fs::File;
+use std::iter;
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
 use num_cpus;
-use serde::Serializer;
+use regex::Regex;
+use serde::{Serialize, Serializer};
 use serde::de::{self, Deserialize, Deserializer};
-use serde_yaml;
+use serde_yaml::{self, Mapping, Value};
+use uuid::Uuid;
 
 use cocaine::logging::Severity;
 
+use crate::lifecycle::Phase;
+use crate::net::{Endpoint, Network};
+
 fn serialize_into_str<S>(severity: &Severity, se: S) -> Result<S::Ok, S::Error>
     where S: Serializer
 {
@@ -38,11 +44,31 @@ fn deserialize_addr<'de, D>(de: D) -> Result<SocketAddr, D::Error>
     Ok(addr)
 }
 
+/// Attribute of a TLS connection, which may be forwarded to applications in a request header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsAttribute {
+    /// Negotiated protocol version, like `TLSv1_3`.
+    Protocol,
+    /// Negotiated cipher suite.
+    Cipher,
+    /// Server name requested by the client with SNI.
+    Sni,
+    /// SHA-256 fingerprint of the client certificate.
+    ClientCert,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NetworkConfig {
     #[serde(deserialize_with = "deserialize_addr")]
     addr: SocketAddr,
     backlog: i32,
+    #[serde(default)]
+    forward: Vec<TlsAttribute>,
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    proxy_protocol: bool,
+    disconnect_probe: Option<u64>,
 }
 
 impl NetworkConfig {
@@ -53,6 +79,64 @@ impl NetworkConfig {
     pub fn backlog(&self) -> i32 {
         self.backlog
     }
+
+    /// Returns connection attributes forwarded to applications.
+    pub fn forward(&self) -> &[TlsAttribute] {
+        &self.forward
+    }
+
+    /// Returns TLS settings, if the listener terminates HTTPS.
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Returns whether TCP connections are expected to start with a PROXY protocol header.
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Returns the interval of checking whether clients with requests in flight have gone, if
+    /// enabled.
+    pub fn disconnect_probe(&self) -> Option<Duration> {
+        self.disconnect_probe.map(Duration::from_millis)
+    }
+}
+
+fn default_tls_reload_interval() -> u64 {
+    10
+}
+
+/// TLS termination settings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    #[serde(default = "default_tls_reload_interval")]
+    reload_interval: u64,
+}
+
+impl TlsConfig {
+    /// Returns the path to the PEM-encoded certificate chain.
+    pub fn cert(&self) -> &Path {
+        &self.cert
+    }
+
+    /// Returns the path to the PEM-encoded private key.
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+
+    /// Returns the path to PEM-encoded certificates of authorities, which client certificates are
+    /// verified against, if clients are asked for certificates.
+    pub fn client_ca(&self) -> Option<&Path> {
+        self.client_ca.as_ref().map(PathBuf::as_path)
+    }
+
+    /// Returns how often certificate files are checked for changes.
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -77,248 +161,2836 @@ impl LoggingBaseConfig {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct LoggingConfig {
-    common: LoggingBaseConfig,
-    access: LoggingBaseConfig,
+/// Compression codec used for Kafka message sets.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    None,
+    Gzip,
+    Snappy,
 }
 
-impl LoggingConfig {
-    pub fn common(&self) -> &LoggingBaseConfig {
-        &self.common
+impl Default for KafkaCompression {
+    fn default() -> Self {
+        KafkaCompression::None
     }
+}
 
-    pub fn access(&self) -> &LoggingBaseConfig {
-        &self.access
-    }
+fn default_access_queue_limit() -> usize {
+    16384
+}
+
+fn default_access_queue_batch() -> usize {
+    256
+}
+
+fn default_access_queue_probe_interval() -> u64 {
+    5
 }
 
+fn default_access_queue_max_backoff() -> u64 {
+    30
+}
+
+fn default_kafka_batch() -> usize {
+    512
+}
+
+fn default_kafka_queue_limit() -> usize {
+    65536
+}
+
+/// Settings of the Kafka sink for access logs.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct MonitoringConfig {
-    addr: (IpAddr, u16),
+pub struct KafkaConfig {
+    brokers: Vec<String>,
+    topic: String,
+    #[serde(default)]
+    compression: KafkaCompression,
+    #[serde(default = "default_kafka_batch")]
+    batch: usize,
+    #[serde(default = "default_kafka_queue_limit")]
+    queue_limit: usize,
 }
 
-impl MonitoringConfig {
-    pub fn addr(&self) -> SocketAddr {
-        let (addr, port) = self.addr;
-        SocketAddr::new(addr, port)
+impl KafkaConfig {
+    /// Returns the list of bootstrap brokers in `host:port` format.
+    pub fn brokers(&self) -> &[String] {
+        &self.brokers
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn compression(&self) -> KafkaCompression {
+        self.compression
+    }
+
+    /// Returns the maximum number of records produced in a single request.
+    pub fn batch(&self) -> usize {
+        self.batch
+    }
+
+    /// Returns the maximum number of records waiting to be produced. Records that do not fit are
+    /// dropped.
+    pub fn queue_limit(&self) -> usize {
+        self.queue_limit
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct ServicePoolConfig {
+/// Settings of the queue between request completion paths and the access logger.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct AccessQueueConfig {
+    #[serde(default = "default_access_queue_limit")]
     limit: usize,
-    lifespan: u64,
-    reconnection_ratio: f64,
+    #[serde(default = "default_access_queue_batch")]
+    batch: usize,
+    #[serde(default = "default_access_queue_probe_interval")]
+    probe_interval: u64,
+    #[serde(default = "default_access_queue_max_backoff")]
+    max_backoff: u64,
 }
 
-impl ServicePoolConfig {
+impl AccessQueueConfig {
+    /// Returns the maximum number of records waiting to be logged. Records that do not fit are
+    /// dropped.
     pub fn limit(&self) -> usize {
         self.limit
     }
 
-    pub fn lifespan(&self) -> u64 {
-        self.lifespan
+    /// Returns the maximum number of records flushed at once.
+    pub fn batch(&self) -> usize {
+        self.batch
     }
 
-    pub fn reconnection_ratio(&self) -> f64 {
-        self.reconnection_ratio
+    /// Returns how often the logging service is checked to be reachable before flushing.
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_secs(self.probe_interval)
+    }
+
+    /// Returns the maximum delay between reconnection attempts while the logging service is
+    /// unreachable.
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff)
     }
 }
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
-pub struct DetailPoolConfig {
-    limit: Option<usize>,
-    lifespan: Option<u64>,
-    reconnection_ratio: Option<f64>,
+impl Default for AccessQueueConfig {
+    fn default() -> Self {
+        Self {
+            limit: default_access_queue_limit(),
+            batch: default_access_queue_batch(),
+            probe_interval: default_access_queue_probe_interval(),
+            max_backoff: default_access_queue_max_backoff(),
+        }
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct PoolConfig {
-    limit: usize,
-    lifespan: u64,
-    reconnection_ratio: f64,
-    services: HashMap<String, DetailPoolConfig>,
+/// A status code or a whole class of them, like `404` or `5xx`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatusMatch {
+    Code(u16),
+    /// The first digit of matching codes.
+    Class(u16),
 }
 
-impl PoolConfig {
-    pub fn config(&self, name: &str) -> ServicePoolConfig {
-        match self.services.get(name) {
-            Some(cfg) => {
-                ServicePoolConfig {
-                    limit: cfg.limit.unwrap_or(self.limit),
-                    lifespan: cfg.lifespan.unwrap_or(self.lifespan),
-                    reconnection_ratio: cfg.reconnection_ratio.unwrap_or(self.reconnection_ratio),
-                }
-            }
-            None => {
-                ServicePoolConfig {
-                    limit: self.limit,
-                    lifespan: self.lifespan,
-                    reconnection_ratio: self.reconnection_ratio,
-                }
-            }
+impl StatusMatch {
+    pub fn matches(&self, code: u16) -> bool {
+        match *self {
+            StatusMatch::Code(expected) => code == expected,
+            StatusMatch::Class(class) => code / 100 == class,
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct TracingConfig {
-    path: String,
-    header: String,
-    probability: f64,
-}
+impl FromStr for StatusMatch {
+    type Err = String;
 
-impl TracingConfig {
-    pub fn path(&self) -> &str {
-        &self.path
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let result = if value.len() == 3 && value.ends_with("xx") {
+            value[..1].parse().ok().map(StatusMatch::Class)
+        } else {
+            value.parse().ok().map(StatusMatch::Code)
+        };
+
+        match result {
+            Some(StatusMatch::Code(code)) if code >= 100 && code <= 599 => Ok(StatusMatch::Code(code)),
+            Some(StatusMatch::Class(class)) if class >= 1 && class <= 5 => Ok(StatusMatch::Class(class)),
+            Some(..) | None => Err(format!("invalid status `{}`, expected either a code or a class like `5xx`", value)),
+        }
     }
+}
 
-    pub fn header(&self) -> &str {
-        &self.header
+fn default_access_sampling_rate() -> f64 {
+    1.0
+}
+
+/// Sampling of access records, which are written with the rate of their status.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AccessSamplingConfig {
+    #[serde(default = "default_access_sampling_rate")]
+    rate: f64,
+    #[serde(default)]
+    statuses: HashMap<String, f64>,
+}
+
+impl AccessSamplingConfig {
+    /// Returns the fraction of records written for statuses without their own rate.
+    pub fn rate(&self) -> f64 {
+        self.rate
     }
 
-    pub fn probability(&self) -> f64 {
-        self.probability
+    /// Returns per-status rates, where codes take precedence over classes.
+    pub fn statuses(&self) -> Vec<(StatusMatch, f64)> {
+        let mut statuses: Vec<_> = self.statuses.iter()
+            .filter_map(|(status, &rate)| status.parse().ok().map(|status| (status, rate)))
+            .collect();
+        statuses.sort_by_key(|&(status, ..)| match status {
+            StatusMatch::Code(..) => 0,
+            StatusMatch::Class(..) => 1,
+        });
+        statuses
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct TimeoutsConfig {
-    path: String,
+pub struct LoggingConfig {
+    common: LoggingBaseConfig,
+    access: LoggingBaseConfig,
+    #[serde(default)]
+    access_queue: AccessQueueConfig,
+    #[serde(default)]
+    access_format: AccessFormatConfig,
+    access_sampling: Option<AccessSamplingConfig>,
+    kafka: Option<KafkaConfig>,
+    #[serde(default)]
+    connections: ConnectionLogConfig,
 }
 
-impl TimeoutsConfig {
-    pub fn path(&self) -> &str {
-        &self.path
+impl LoggingConfig {
+    pub fn common(&self) -> &LoggingBaseConfig {
+        &self.common
+    }
+
+    pub fn access(&self) -> &LoggingBaseConfig {
+        &self.access
+    }
+
+    pub fn access_queue(&self) -> &AccessQueueConfig {
+        &self.access_queue
+    }
+
+    /// Returns the format of access log records.
+    pub fn access_format(&self) -> &AccessFormatConfig {
+        &self.access_format
+    }
+
+    /// Returns access records sampling settings, if only a fraction of them is written.
+    pub fn access_sampling(&self) -> Option<&AccessSamplingConfig> {
+        self.access_sampling.as_ref()
+    }
+
+    /// Returns the Kafka access log sink settings, if configured.
+    pub fn kafka(&self) -> Option<&KafkaConfig> {
+        self.kafka.as_ref()
+    }
+
+    /// Returns settings of logging client connections.
+    pub fn connections(&self) -> &ConnectionLogConfig {
+        &self.connections
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct AuthConfig {
-    service: String,
-    client_id: u32,
-    client_secret: String,
+/// Settings of logging client connections.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ConnectionLogConfig {
+    #[serde(default = "default_connection_log_interval")]
+    interval: u64,
+    #[serde(default)]
+    verbose: bool,
 }
 
-impl AuthConfig {
-    pub fn service(&self) -> &str {
-        &self.service
+impl ConnectionLogConfig {
+    /// Returns the interval between summaries of opened and closed connections.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
     }
 
-    pub fn client_id(&self) -> u32 {
-        self.client_id
+    /// Returns `true` if each opened and closed connection is logged as well.
+    pub fn verbose(&self) -> bool {
+        self.verbose
     }
+}
 
-    pub fn client_secret(&self) -> &str {
-        &self.client_secret
+impl Default for ConnectionLogConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_connection_log_interval(),
+            verbose: false,
+        }
     }
 }
 
-impl Debug for AuthConfig {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        fmt.debug_struct("AuthConfig")
-            .field("service", &self.service)
-            .field("client_id", &self.client_id)
-            .field("client_secret", &"<...>")
-            .finish()
+/// How access records are written into the logging service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessOutput {
+    /// Each field is a separate attribute of the log event, while the message is human-readable.
+    Attributes,
+    /// The message is a JSON object of selected fields and headers.
+    Json,
+}
+
+/// A field of access records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessField {
+    Trace,
+    TraceId,
+    /// Span id of the last attempt to invoke the application.
+    SpanId,
+    Duration,
+    Method,
+    Uri,
+    Prefix,
+    Version,
+    Status,
+    BytesSent,
+    Service,
+    Event,
+    Tenant,
+    BodyReadTime,
+    QueueTime,
+    ResolveTime,
+    FirstByteTime,
+    UpstreamTime,
+    /// Time spent waiting for the client to accept the streamed response body.
+    ClientWriteTime,
+    Attempts,
+    Error,
+    /// Kind of the upstream failure, if any.
+    Failure,
+}
+
+impl AccessField {
+    /// Returns all fields in the order they are written by default.
+    pub fn all() -> Vec<AccessField> {
+        vec![
+            AccessField::Trace, AccessField::TraceId, AccessField::SpanId, AccessField::Duration,
+            AccessField::Method, AccessField::Uri, AccessField::Prefix, AccessField::Version,
+            AccessField::Status, AccessField::BytesSent, AccessField::Service, AccessField::Event,
+            AccessField::Tenant, AccessField::BodyReadTime, AccessField::QueueTime, AccessField::ResolveTime,
+            AccessField::FirstByteTime, AccessField::UpstreamTime, AccessField::ClientWriteTime, AccessField::Attempts,
+            AccessField::Error, AccessField::Failure,
+        ]
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct LoadTestingConfig {
-    enabled: bool,
+fn default_access_output() -> AccessOutput {
+    AccessOutput::Attributes
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Config {
-    network: NetworkConfig,
-    threads: Option<usize>,
-    locators: Vec<(IpAddr, u16)>,
-    logging: LoggingConfig,
-    unicorn: String,
-    monitoring: MonitoringConfig,
-    pool: PoolConfig,
-    tracing: TracingConfig,
-    headers: HashMap<String, String>,
-    timeout: u64,
-    timeouts: TimeoutsConfig,
-    auth: AuthConfig,
-    load_testing: Option<LoadTestingConfig>,
+pub struct AccessFormatConfig {
+    #[serde(default = "default_access_output")]
+    output: AccessOutput,
+    #[serde(default = "AccessField::all")]
+    fields: Vec<AccessField>,
+    #[serde(default)]
+    headers: Vec<String>,
 }
 
-impl Config {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
-        let cfg = serde_yaml::from_reader(&File::open(path)?)?;
+impl AccessFormatConfig {
+    pub fn output(&self) -> AccessOutput {
+        self.output
+    }
 
-        Config::sanitize(&cfg)?;
+    /// Returns fields written in `json` output.
+    pub fn fields(&self) -> &[AccessField] {
+        &self.fields
+    }
 
-        Ok(cfg)
+    /// Returns names of request headers written in `json` output.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
     }
+}
 
-    fn sanitize(cfg: &Config) -> Result<(), Box<dyn Error>> {
-        if let Some(0) = cfg.threads {
-            return Err("number of worker threads must be a positive value (or absent)".into());
+impl Default for AccessFormatConfig {
+    fn default() -> Self {
+        Self {
+            output: default_access_output(),
+            fields: AccessField::all(),
+            headers: Vec::new(),
         }
+    }
+}
 
-        if cfg.tracing.probability < 0.0 || cfg.tracing.probability > 1.0 {
-            return Err("tracing probability must fit in [0.0; 1.0]".into());
-        }
+/// Sampled request mirroring settings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MirroringConfig {
+    name: String,
+    source: String,
+    probability: f64,
+    body_limit: usize,
+}
 
-        Ok(())
+impl MirroringConfig {
+    /// Returns the name of the logging service into which mirrored requests are written.
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    pub fn network(&self) -> &NetworkConfig {
-        &self.network
+    /// Returns the source attribute of mirrored records.
+    pub fn source(&self) -> &str {
+        &self.source
     }
 
-    /// Returns the number of worker threads.
-    ///
-    /// Can be omitted in the config, in that case the number of CPUs of the current machine will
-    /// be returned.
-    pub fn threads(&self) -> usize {
-        self.threads.unwrap_or(num_cpus::get())
+    /// Returns the fraction of requests that are mirrored.
+    pub fn probability(&self) -> f64 {
+        self.probability
     }
 
-    /// Returns the Locator endpoints.
-    pub fn locators(&self) -> &[(IpAddr, u16)] {
-        &self.locators
+    /// Returns the maximum number of body bytes mirrored, the rest is truncated.
+    pub fn body_limit(&self) -> usize {
+        self.body_limit
     }
+}
 
-    pub fn logging(&self) -> &LoggingConfig {
-        &self.logging
-    }
+fn default_redacted_headers() -> Vec<String> {
+    vec!["Authorization".into(), "Proxy-Authorization".into(), "Cookie".into()]
+}
 
-    /// Returns the name of the Unicorn service that will be used for dynamic configuration and
-    /// subscriptions.
-    pub fn unicorn(&self) -> &str {
-        &self.unicorn
-    }
+/// Secrets masked in every diagnostic output, like access logs or mirrored requests.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RedactionConfig {
+    #[serde(default = "default_redacted_headers")]
+    headers: Vec<String>,
+    #[serde(default)]
+    params: Vec<String>,
+    #[serde(default)]
+    fields: Vec<String>,
+}
 
-    pub fn monitoring(&self) -> &MonitoringConfig {
-        &self.monitoring
+impl RedactionConfig {
+    /// Returns case-insensitive names of headers, whose values are masked.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
     }
 
-    pub fn pool(&self) -> &PoolConfig {
-        &self.pool
+    /// Returns names of query parameters, whose values are masked.
+    pub fn params(&self) -> &[String] {
+        &self.params
     }
 
-    pub fn tracing(&self) -> &TracingConfig {
-        &self.tracing
+    /// Returns names of JSON body fields, whose values are masked at any depth.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
     }
+}
 
-    /// Returns HTTP headers forward mapping.
-    pub fn headers(&self) -> &HashMap<String, String> {
-        &self.headers
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            headers: default_redacted_headers(),
+            params: Vec::new(),
+            fields: Vec::new(),
+        }
     }
+}
 
-    /// Returns proxy timeout.
-    pub fn timeout(&self) -> Duration {
-        Duration::new(self.timeout, 0)
+/// Role granted to the monitoring server caller.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminRole {
+    /// Allows to inspect the proxy state.
+    Read,
+    /// Allows to inspect the proxy state and to perform operational changes.
+    Operate,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AdminTokenConfig {
+    name: String,
+    /// Never exposed through the monitoring server along with the rest of the config.
+    #[serde(skip_serializing)]
+    token: String,
+    role: AdminRole,
+}
+
+impl AdminTokenConfig {
+    /// Returns the token name, which identifies callers in logs.
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    pub fn timeouts(&self) -> &TimeoutsConfig {
-        &self.timeouts
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn role(&self) -> AdminRole {
+        self.role
+    }
+}
+
+impl Debug for AdminTokenConfig {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("AdminTokenConfig")
+            .field("name", &self.name)
+            .field("token", &"<...>")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MonitoringConfig {
+    addr: Endpoint,
+    #[serde(default = "default_monitoring_backlog")]
+    backlog: i32,
+    #[serde(default = "default_monitoring_threads")]
+    threads: usize,
+    audit: Option<String>,
+    #[serde(default)]
+    auth: Vec<AdminTokenConfig>,
+    #[serde(default)]
+    allow: Vec<Network>,
+    #[serde(default = "default_monitoring_recent_errors")]
+    recent_errors: usize,
+    #[serde(default)]
+    readiness: ReadinessConfig,
+}
+
+impl MonitoringConfig {
+    /// Returns the endpoint of the monitoring listener, which is separate from traffic ones.
+    pub fn addr(&self) -> &Endpoint {
+        &self.addr
+    }
+
+    /// Returns the limit for the queue of incoming monitoring connections.
+    pub fn backlog(&self) -> i32 {
+        self.backlog
+    }
+
+    /// Returns the number of threads serving monitoring requests.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Returns the path to the audit log file, into which all mutations made through the
+    /// monitoring server are written.
+    pub fn audit(&self) -> Option<&str> {
+        self.audit.as_ref().map(|v| v.as_str())
+    }
+
+    /// Returns the list of tokens allowed to access the monitoring server.
+    ///
+    /// An empty list means that the access is not restricted.
+    pub fn auth(&self) -> &[AdminTokenConfig] {
+        &self.auth
+    }
+
+    /// Returns the list of source networks allowed to access the monitoring server.
+    ///
+    /// An empty list means that the access is not restricted.
+    pub fn allow(&self) -> &[Network] {
+        &self.allow
+    }
+
+    /// Returns the number of recent errors generated by the proxy each worker keeps in memory.
+    pub fn recent_errors(&self) -> usize {
+        self.recent_errors
+    }
+
+    /// Returns conditions checked by the readiness probe.
+    pub fn readiness(&self) -> &ReadinessConfig {
+        &self.readiness
+    }
+}
+
+fn default_readiness_timeout() -> u64 {
+    1000
+}
+
+/// Conditions under which the proxy is ready to serve traffic.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadinessConfig {
+    #[serde(default)]
+    services: Vec<String>,
+    #[serde(default = "default_readiness_timeout")]
+    timeout: u64,
+}
+
+impl ReadinessConfig {
+    /// Returns names of services, which are warmed up at start and must be resolved into at least
+    /// one endpoint before the proxy is ready.
+    pub fn services(&self) -> &[String] {
+        &self.services
+    }
+
+    /// Returns the time a locator is given to accept a connection during the probe.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            services: Vec::new(),
+            timeout: default_readiness_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ServicePoolConfig {
+    limit: usize,
+    lifespan: u64,
+    reconnection_ratio: f64,
+    max_channels: Option<usize>,
+    max_overflow: usize,
+}
+
+impl ServicePoolConfig {
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn lifespan(&self) -> u64 {
+        self.lifespan
+    }
+
+    pub fn reconnection_ratio(&self) -> f64 {
+        self.reconnection_ratio
+    }
+
+    /// Returns the maximum number of channels multiplexed over a single connection, if limited.
+    pub fn max_channels(&self) -> Option<usize> {
+        self.max_channels
+    }
+
+    /// Returns the maximum number of connections opened above the pool limit while all others are
+    /// saturated with channels.
+    pub fn max_overflow(&self) -> usize {
+        self.max_overflow
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct DetailPoolConfig {
+    limit: Option<usize>,
+    lifespan: Option<u64>,
+    reconnection_ratio: Option<f64>,
+    max_channels: Option<usize>,
+    max_overflow: Option<usize>,
+}
+
+fn default_resolve_guard_min_ratio() -> f64 {
+    0.5
+}
+
+fn default_resolve_guard_grace_period() -> u64 {
+    30
+}
+
+/// Protection against endpoint sets shrinking drastically on a single resolve, for example during
+/// a discovery flap.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ResolveGuardConfig {
+    #[serde(default = "default_resolve_guard_min_ratio")]
+    min_ratio: f64,
+    #[serde(default = "default_resolve_guard_grace_period")]
+    grace_period: u64,
+}
+
+impl ResolveGuardConfig {
+    /// Returns the fraction of previously known endpoints, below which a resolved set is considered
+    /// suspicious.
+    pub fn min_ratio(&self) -> f64 {
+        self.min_ratio
+    }
+
+    /// Returns the time previous endpoints are kept for after the set has shrunk.
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+impl Default for ResolveGuardConfig {
+    fn default() -> Self {
+        Self {
+            min_ratio: default_resolve_guard_min_ratio(),
+            grace_period: default_resolve_guard_grace_period(),
+        }
+    }
+}
+
+fn default_resolve_cache_ttl() -> u64 {
+    5000
+}
+
+fn default_resolve_cache_negative_ttl() -> u64 {
+    1000
+}
+
+/// Caching of resolve results, shared by all pools of a worker.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ResolveCacheConfig {
+    #[serde(default = "default_resolve_cache_ttl")]
+    ttl: u64,
+    #[serde(default = "default_resolve_cache_negative_ttl")]
+    negative_ttl: u64,
+}
+
+impl ResolveCacheConfig {
+    /// Returns the time resolved endpoints are reused for.
+    pub fn ttl(&self) -> Duration {
+        Duration::from_millis(self.ttl)
+    }
+
+    /// Returns the time a failed resolve is reported without asking the locator again.
+    pub fn negative_ttl(&self) -> Duration {
+        Duration::from_millis(self.negative_ttl)
+    }
+}
+
+fn default_happy_eyeballs_delay() -> u64 {
+    250
+}
+
+fn default_happy_eyeballs_timeout() -> u64 {
+    2000
+}
+
+/// Racing of IPv6 and IPv4 connections for services resolved into endpoints of both families.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct HappyEyeballsConfig {
+    #[serde(default = "default_happy_eyeballs_delay")]
+    delay: u64,
+    #[serde(default = "default_happy_eyeballs_timeout")]
+    timeout: u64,
+}
+
+impl HappyEyeballsConfig {
+    /// Returns the head start of the IPv6 connection attempt.
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay)
+    }
+
+    /// Returns the time both attempts may take, after which endpoints are kept in resolved order.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+}
+
+fn default_priorities_batch() -> usize {
+    64
+}
+
+/// Header predicate of a priority class.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HeaderPredicateConfig {
+    name: String,
+    value: Option<String>,
+}
+
+impl HeaderPredicateConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the expected header value. If absent only the header presence is checked.
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// A class of requests sharing the same priority, matched by any of services, tenants or headers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PriorityClassConfig {
+    name: String,
+    #[serde(default)]
+    services: Vec<String>,
+    #[serde(default)]
+    tenants: Vec<String>,
+    #[serde(default)]
+    headers: Vec<HeaderPredicateConfig>,
+    shed_at: Option<usize>,
+}
+
+impl PriorityClassConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn services(&self) -> &[String] {
+        &self.services
+    }
+
+    pub fn tenants(&self) -> &[String] {
+        &self.tenants
+    }
+
+    pub fn headers(&self) -> &[HeaderPredicateConfig] {
+        &self.headers
+    }
+
+    /// Returns the number of queued events, at which requests of this class are shed.
+    pub fn shed_at(&self) -> Option<usize> {
+        self.shed_at
+    }
+}
+
+/// Scheduling of requests in services pools by their priority classes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PrioritiesConfig {
+    #[serde(default = "default_priorities_batch")]
+    batch: usize,
+    classes: Vec<PriorityClassConfig>,
+    shed_at: Option<usize>,
+}
+
+impl PrioritiesConfig {
+    /// Returns the maximum number of queued events processed at once before yielding to the
+    /// event loop.
+    pub fn batch(&self) -> usize {
+        self.batch
+    }
+
+    /// Returns classes ordered from the highest priority.
+    pub fn classes(&self) -> &[PriorityClassConfig] {
+        &self.classes
+    }
+
+    /// Returns the shedding threshold of requests matching no class, which have the lowest
+    /// priority.
+    pub fn shed_at(&self) -> Option<usize> {
+        self.shed_at
+    }
+
+    /// Returns shedding thresholds of all classes in priority order, including the implicit
+    /// lowest one.
+    pub fn thresholds(&self) -> Vec<Option<usize>> {
+        self.classes.iter()
+            .map(PriorityClassConfig::shed_at)
+            .chain(iter::once(self.shed_at))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PoolConfig {
+    limit: usize,
+    lifespan: u64,
+    reconnection_ratio: f64,
+    services: HashMap<String, DetailPoolConfig>,
+    resolve_guard: Option<ResolveGuardConfig>,
+    resolve_cache: Option<ResolveCacheConfig>,
+    happy_eyeballs: Option<HappyEyeballsConfig>,
+    priorities: Option<PrioritiesConfig>,
+    max_channels: Option<usize>,
+    max_overflow: Option<usize>,
+}
+
+impl PoolConfig {
+    /// Returns the resolve guard settings, if enabled.
+    pub fn resolve_guard(&self) -> Option<ResolveGuardConfig> {
+        self.resolve_guard
+    }
+
+    /// Returns resolve caching settings, if enabled.
+    pub fn resolve_cache(&self) -> Option<ResolveCacheConfig> {
+        self.resolve_cache
+    }
+
+    /// Returns dual-stack connection racing settings, if enabled.
+    pub fn happy_eyeballs(&self) -> Option<HappyEyeballsConfig> {
+        self.happy_eyeballs
+    }
+
+    /// Returns priority scheduling settings, if requests are prioritized.
+    pub fn priorities(&self) -> Option<&PrioritiesConfig> {
+        self.priorities.as_ref()
+    }
+
+    pub fn config(&self, name: &str) -> ServicePoolConfig {
+        match self.services.get(name) {
+            Some(cfg) => {
+                let limit = cfg.limit.unwrap_or(self.limit);
+                ServicePoolConfig {
+                    limit: limit,
+                    lifespan: cfg.lifespan.unwrap_or(self.lifespan),
+                    reconnection_ratio: cfg.reconnection_ratio.unwrap_or(self.reconnection_ratio),
+                    max_channels: cfg.max_channels.or(self.max_channels),
+                    max_overflow: cfg.max_overflow.or(self.max_overflow).unwrap_or(limit),
+                }
+            }
+            None => {
+                ServicePoolConfig {
+                    limit: self.limit,
+                    lifespan: self.lifespan,
+                    reconnection_ratio: self.reconnection_ratio,
+                    max_channels: self.max_channels,
+                    max_overflow: self.max_overflow.unwrap_or(self.limit),
+                }
+            }
+        }
+    }
+}
+
+/// Rule that rewrites an upstream response status for a service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StatusRewrite {
+    from: u16,
+    to: u16,
+    body: Option<String>,
+    content_type: Option<String>,
+}
+
+impl StatusRewrite {
+    /// Returns the upstream status code this rule matches.
+    pub fn from(&self) -> u16 {
+        self.from
+    }
+
+    /// Returns the status code sent to the client instead.
+    pub fn to(&self) -> u16 {
+        self.to
+    }
+
+    /// Returns the body that replaces the upstream one, if specified.
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_ref().map(|v| v.as_str())
+    }
+
+    /// Returns the content type of the replacing body, if specified.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// Filter rewriting buffered response bodies of a service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFilterConfig {
+    /// Replaces all matches of the regular expression, where `$1` or `$name` refer to groups.
+    Replace { pattern: String, replacement: String },
+    /// Inserts the HTML markup just after the opening `<body>` tag of `text/html` responses.
+    Banner(String),
+    /// Masks values of the named fields at any depth of JSON responses.
+    Redact(Vec<String>),
+}
+
+/// Cookie predicate of a routing rule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CookiePredicateConfig {
+    name: String,
+    value: Option<String>,
+}
+
+impl CookiePredicateConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the expected cookie value. If absent only the cookie presence is checked.
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// Time of a day in minutes since midnight, written as `HH:MM` in configs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    pub fn minutes(&self) -> u32 {
+        self.0
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(2, ':').map(|part| part.parse::<u32>().ok());
+
+        match (parts.next(), parts.next()) {
+            (Some(Some(hours)), Some(Some(minutes))) if hours < 24 && minutes < 60 => {
+                Ok(TimeOfDay(hours * 60 + minutes))
+            }
+            _ => Err(format!("invalid time `{}`, expected `HH:MM`", value)),
+        }
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+        se.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let value: String = Deserialize::deserialize(de)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Fixed offset from UTC, written either as `UTC` or as `+HH:MM`/`-HH:MM` in configs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UtcOffset(i32);
+
+impl UtcOffset {
+    pub fn seconds(&self) -> i32 {
+        self.0
+    }
+}
+
+impl FromStr for UtcOffset {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "UTC" {
+            return Ok(UtcOffset(0));
+        }
+
+        let sign = match value.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            Some(..) | None => return Err(format!("invalid UTC offset `{}`, expected `+HH:MM`", value)),
+        };
+
+        match value[1..].parse::<TimeOfDay>() {
+            Ok(time) if time.minutes() <= 14 * 60 => Ok(UtcOffset(sign * time.minutes() as i32 * 60)),
+            Ok(..) | Err(..) => Err(format!("invalid UTC offset `{}`, expected `+HH:MM`", value)),
+        }
+    }
+}
+
+impl Serialize for UtcOffset {
+    fn serialize<S: Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let minutes = self.0.abs() / 60;
+        se.serialize_str(&format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for UtcOffset {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let value: String = Deserialize::deserialize(de)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Day of a week, numbered from Sunday.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+/// Daily time window of a routing rule.
+///
+/// The end is exclusive. Windows ending before they start span midnight, in which case days
+/// restrict the day the window starts at.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimeWindowConfig {
+    from: TimeOfDay,
+    to: TimeOfDay,
+    #[serde(default)]
+    days: Vec<Weekday>,
+}
+
+impl TimeWindowConfig {
+    pub fn from(&self) -> TimeOfDay {
+        self.from
+    }
+
+    pub fn to(&self) -> TimeOfDay {
+        self.to
+    }
+
+    /// Returns days the window starts at. Empty means every day.
+    pub fn days(&self) -> &[Weekday] {
+        &self.days
+    }
+}
+
+/// Routing rule that redirects requests addressed to a service into the target one when all its
+/// predicates match.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RuleConfig {
+    service: String,
+    target: String,
+    #[serde(default)]
+    cookies: Vec<CookiePredicateConfig>,
+    #[serde(default)]
+    windows: Vec<TimeWindowConfig>,
+}
+
+impl RuleConfig {
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn cookies(&self) -> &[CookiePredicateConfig] {
+        &self.cookies
+    }
+
+    /// Returns time windows, in one of which the rule is active. Empty means always.
+    pub fn windows(&self) -> &[TimeWindowConfig] {
+        &self.windows
+    }
+}
+
+/// Version of the configuration file layout this binary understands.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A configuration key that has been moved or renamed across releases.
+struct Rename {
+    /// Path to the key in the old layout.
+    from: &'static [&'static str],
+    /// Path to the key in the current layout.
+    to: &'static [&'static str],
+    /// Release since which the old layout is deprecated.
+    since: &'static str,
+}
+
+/// Keys moved across releases. Configs with the old layout are migrated on load with a
+/// deprecation warning instead of failing, which allows to upgrade the fleet incrementally.
+///
+/// New entries must be appended here whenever a key is renamed or restructured.
+const RENAMES: &[Rename] = &[
+    // Top-level `timeouts` were easily confused with `timeout`.
+    Rename { from: &["timeouts"], to: &["service_timeouts"], since: "0.3.20" },
+];
+
+fn path_to_string(path: &[&str]) -> String {
+    path.join(".")
+}
+
+fn take(value: &mut Value, path: &[&str]) -> Option<Value> {
+    let (last, path) = path.split_last()?;
+
+    let mut node = value;
+    for key in path {
+        node = match *node {
+            Value::Mapping(ref mut map) => map.get_mut(&Value::String(key.to_string()))?,
+            _ => return None,
+        };
+    }
+
+    match *node {
+        Value::Mapping(ref mut map) => map.remove(&Value::String(last.to_string())),
+        _ => None,
+    }
+}
+
+/// Puts the value by the given path, creating intermediate mappings when required.
+///
+/// Returns `false` if the key already exists or the path is blocked by a non-mapping value.
+fn put(value: &mut Value, path: &[&str], v: Value) -> bool {
+    let (last, path) = match path.split_last() {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let mut node = value;
+    for key in path {
+        node = match *node {
+            Value::Mapping(ref mut map) => {
+                let key = Value::String(key.to_string());
+                if !map.contains_key(&key) {
+                    map.insert(key.clone(), Value::Mapping(Mapping::new()));
+                }
+                map.get_mut(&key).unwrap()
+            }
+            _ => return false,
+        };
+    }
+
+    match *node {
+        Value::Mapping(ref mut map) => {
+            let key = Value::String(last.to_string());
+            if map.contains_key(&key) {
+                return false;
+            }
+            map.insert(key, v);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether the given string is a valid HTTP header name, i.e. a non-empty token.
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Migrates the config from old layouts to the current one, returning deprecation warnings.
+fn migrate(value: &mut Value, renames: &[Rename]) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for rename in renames {
+        if let Some(v) = take(value, rename.from) {
+            let from = path_to_string(rename.from);
+            let to = path_to_string(rename.to);
+
+            if put(value, rename.to, v) {
+                warnings.push(format!("`{}` is deprecated since {}, use `{}` instead", from, rename.since, to));
+            } else {
+                warnings.push(format!("`{}` is deprecated since {} and ignored, because `{}` is specified", from, rename.since, to));
+            }
+        }
+    }
+
+    warnings
+}
+
+fn default_error_statuses() -> Vec<ErrorStatusRule> {
+    vec![ErrorStatusRule::unresolved()]
+}
+
+fn default_retriable_errors() -> Vec<RetriableError> {
+    vec![RetriableError::queue_full()]
+}
+
+fn default_error_origins() -> HashMap<u64, String> {
+    let mut origins = HashMap::new();
+    origins.insert(0x54ff, "vicodyn".into());
+    origins
+}
+
+/// Cocaine application HTTP protocol version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppProtocol {
+    /// Request meta, headers and body are packed into a single frame, and so are response meta
+    /// and headers.
+    V1,
+    /// Request meta, headers and body are sent as separate frames, responses likewise.
+    V2,
+}
+
+impl Default for AppProtocol {
+    fn default() -> Self {
+        AppProtocol::V1
+    }
+}
+
+/// Encoding of HTTP bodies exchanged with a Cocaine application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BodyCodec {
+    /// Raw bytes in MessagePack strings.
+    Identity,
+    /// Raw bytes in MessagePack binaries.
+    MsgpackBinary,
+    /// Base64 in MessagePack strings, which legacy frameworks expect.
+    Base64,
+}
+
+impl Default for BodyCodec {
+    fn default() -> Self {
+        BodyCodec::Identity
+    }
+}
+
+/// How to treat request paths with an empty or missing event name, like `/service`,
+/// `/service/` or `/service//foo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationPolicy {
+    /// Respond with 400 Bad Request.
+    Reject,
+    /// Redirect to the path with repeated slashes collapsed, or with the default event appended
+    /// if there is still no event.
+    Redirect,
+    /// Route into the default event.
+    Default,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NormalizationConfig {
+    policy: NormalizationPolicy,
+    event: Option<String>,
+}
+
+impl NormalizationConfig {
+    pub fn new(policy: NormalizationPolicy, event: Option<String>) -> Self {
+        Self {
+            policy: policy,
+            event: event,
+        }
+    }
+
+    pub fn policy(&self) -> NormalizationPolicy {
+        self.policy
+    }
+
+    /// Returns the default event name, if configured.
+    pub fn event(&self) -> Option<&str> {
+        self.event.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// An entry of the routing table, which maps request paths matching either the prefix or the
+/// pattern into the service and the event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PathRouteConfig {
+    prefix: Option<String>,
+    regex: Option<String>,
+    service: String,
+    event: String,
+    rewrite: Option<String>,
+}
+
+impl PathRouteConfig {
+    /// Returns the path prefix, matched at segment boundaries.
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_ref().map(|v| v.as_str())
+    }
+
+    /// Returns the pattern matched against the path without the query.
+    pub fn regex(&self) -> Option<&str> {
+        self.regex.as_ref().map(|v| v.as_str())
+    }
+
+    /// Returns the service name, which may refer to pattern groups like `$1`.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Returns the event name, which may refer to pattern groups like `$1`.
+    pub fn event(&self) -> &str {
+        &self.event
+    }
+
+    /// Returns the path passed to the application instead of the matched one, if configured.
+    ///
+    /// It replaces the prefix, or the whole path matched by the pattern expanding its groups.
+    pub fn rewrite(&self) -> Option<&str> {
+        self.rewrite.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// A virtual host, which maps requests with matching `Host` headers into the service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VirtualHostConfig {
+    hosts: String,
+    service: String,
+    event: String,
+}
+
+impl VirtualHostConfig {
+    /// Returns the pattern matched against lowercase host names without the port.
+    pub fn hosts(&self) -> &str {
+        &self.hosts
+    }
+
+    /// Returns the service name, which may refer to pattern groups like `$1`.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn event(&self) -> &str {
+        &self.event
+    }
+}
+
+/// A lifecycle hook.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookConfig {
+    /// Shell command, which receives the phase name in `COCAINE_PROXY_PHASE` environment variable.
+    Exec(String),
+    /// URL, which receives a POST request with the phase name in the body.
+    Url(String),
+}
+
+/// Hooks run at the beginning of each lifecycle phase.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LifecycleConfig {
+    #[serde(default)]
+    started: Vec<HookConfig>,
+    #[serde(default)]
+    draining: Vec<HookConfig>,
+    #[serde(default)]
+    stopped: Vec<HookConfig>,
+}
+
+impl LifecycleConfig {
+    /// Returns hooks of the given phase.
+    pub fn hooks(&self, phase: Phase) -> &[HookConfig] {
+        match phase {
+            Phase::Started => &self.started,
+            Phase::Draining => &self.draining,
+            Phase::Stopped => &self.stopped,
+        }
+    }
+}
+
+/// Algorithm of body digests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DigestAlgorithm {
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "sha-256")]
+    Sha256,
+}
+
+/// End-to-end integrity checks of message bodies.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct DigestConfig {
+    #[serde(default)]
+    verify: bool,
+    generate: Option<DigestAlgorithm>,
+}
+
+impl DigestConfig {
+    /// Returns `true` if buffered request bodies must match their `Content-MD5` and `Digest`
+    /// headers.
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    /// Returns the algorithm of digests generated for buffered responses, if enabled.
+    pub fn generate(&self) -> Option<DigestAlgorithm> {
+        self.generate
+    }
+}
+
+fn default_websocket_max_message_size() -> usize {
+    1024 * 1024
+}
+
+/// WebSocket proxying into streaming applications.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct WebSocketConfig {
+    #[serde(default = "default_websocket_max_message_size")]
+    max_message_size: usize,
+}
+
+impl WebSocketConfig {
+    /// Returns the maximum size of a message received from clients, which may span several
+    /// fragments.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+}
+
+/// Server-Sent Events bridging from streaming applications.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct SseConfig {
+    retry: Option<u64>,
+}
+
+impl SseConfig {
+    /// Returns the reconnection time advised to clients, if any.
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry.map(Duration::from_millis)
+    }
+}
+
+/// Warm standby, in which the proxy initializes completely, but answers 503 until promoted.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct StandbyConfig {
+    #[serde(default)]
+    warm: Vec<String>,
+    path: Option<String>,
+}
+
+impl StandbyConfig {
+    /// Returns names of services, whose pools are connected in advance.
+    pub fn warm(&self) -> &[String] {
+        &self.warm
+    }
+
+    /// Returns the Unicorn node, which promotes the proxy once its `active` value is raised.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(|v| v.as_str())
+    }
+}
+
+/// Peer proxies, into which requests are forwarded while the local pool of a service is unhealthy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PeersConfig {
+    endpoints: Vec<Endpoint>,
+}
+
+impl PeersConfig {
+    /// Returns HTTP endpoints of peer proxies, which are tried in turn.
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+}
+
+/// Retry safety of an event, overriding the default decision based on the error category.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetrySafety {
+    /// The event is idempotent, so it is retried on any error.
+    Safe,
+    /// The event is never retried, even if the request wasn't delivered to a worker.
+    Forbidden,
+}
+
+/// Cocaine error considered safe to retry, because it guarantees that the request wasn't
+/// delivered to a worker. Without the code it matches any error of the category.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetriableError {
+    category: u64,
+    code: Option<u64>,
+}
+
+impl RetriableError {
+    pub fn new(category: u64, code: Option<u64>) -> Self {
+        Self {
+            category: category,
+            code: code,
+        }
+    }
+
+    /// Returns the "queue is full" error of the runtime.
+    pub fn queue_full() -> Self {
+        RetriableError::new(0x52ff, Some(1))
+    }
+
+    /// Returns `true` if the error of the given category and code matches this one.
+    pub fn matches(&self, category: u64, code: u64) -> bool {
+        self.category == category && self.code.map(|expected| expected == code).unwrap_or(true)
+    }
+}
+
+/// Rule mapping Cocaine errors of the category, optionally limited to the inclusive range of
+/// codes, into the HTTP status with an optional body template.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorStatusRule {
+    category: u64,
+    codes: Option<(u64, u64)>,
+    status: u16,
+    body: Option<String>,
+}
+
+impl ErrorStatusRule {
+    /// Returns the rule answering 503 when the locator has failed to resolve the service.
+    pub fn unresolved() -> Self {
+        Self {
+            category: 10,
+            codes: Some((1, 1)),
+            status: 503,
+            body: None,
+        }
+    }
+
+    /// Returns `true` if the error of the given category and code matches this rule.
+    pub fn matches(&self, category: u64, code: u64) -> bool {
+        self.category == category && self.codes.map(|(from, to)| from <= code && code <= to).unwrap_or(true)
+    }
+
+    /// Returns the HTTP status code of the response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the response body template, where `{category}`, `{code}` and `{message}` are
+    /// replaced with ones of the error.
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_ref().map(|body| body.as_str())
+    }
+}
+
+/// An isolated Cocaine installation served by the proxy, selected by the `Host` header.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TenantConfig {
+    name: String,
+    hosts: String,
+    locators: Vec<(IpAddr, u16)>,
+    headers: Option<HashMap<String, String>>,
+    pool: Option<DetailPoolConfig>,
+    quota: Option<QuotaConfig>,
+}
+
+impl TenantConfig {
+    /// Returns the tenant name, which is used as a label in metrics and logs.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the regular expression the `Host` header must match for requests to be routed into
+    /// this tenant.
+    pub fn hosts(&self) -> &str {
+        &self.hosts
+    }
+
+    /// Returns request-rate and concurrency limits for this tenant, if any.
+    pub fn quota(&self) -> Option<&QuotaConfig> {
+        self.quota.as_ref()
+    }
+}
+
+fn default_streaming_window() -> usize {
+    1024 * 1024
+}
+
+/// Per-service streaming settings.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct StreamingConfig {
+    #[serde(default)]
+    request: bool,
+    #[serde(default)]
+    response: bool,
+    #[serde(default = "default_streaming_window")]
+    window: usize,
+    #[serde(default)]
+    flow_control: bool,
+}
+
+impl StreamingConfig {
+    /// Returns `true` if request bodies are forwarded to the application as they arrive instead
+    /// of being buffered.
+    pub fn request(&self) -> bool {
+        self.request
+    }
+
+    /// Returns `true` if response chunks are flushed to the client as they arrive from the
+    /// application instead of being buffered.
+    pub fn response(&self) -> bool {
+        self.response
+    }
+
+    /// Returns the maximum number of streamed response bytes buffered for a client, which
+    /// doesn't keep up with the application, and with flow control the maximum number of
+    /// unacknowledged body bytes in either direction.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Returns `true` if the application takes part in window-based flow control.
+    pub fn flow_control(&self) -> bool {
+        self.flow_control
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            request: false,
+            response: false,
+            window: default_streaming_window(),
+            flow_control: false,
+        }
+    }
+}
+
+fn default_memory_pause() -> u64 {
+    10
+}
+
+fn default_signing_header() -> String {
+    "X-Cocaine-Signature".into()
+}
+
+/// Settings of signing forwarded headers.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SigningConfig {
+    /// Never exposed through the monitoring server along with the rest of the config.
+    #[serde(skip_serializing)]
+    key: String,
+    #[serde(default = "default_signing_header")]
+    header: String,
+    headers: Vec<String>,
+}
+
+impl SigningConfig {
+    /// Returns the HMAC key shared with applications.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the name of the header carrying the signature.
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Returns names of signed headers in the order they are signed.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+}
+
+impl Debug for SigningConfig {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("SigningConfig")
+            .field("key", &"<...>")
+            .field("header", &self.header)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+/// Memory pressure settings.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct MemoryConfig {
+    soft_limit: usize,
+    #[serde(default = "default_memory_pause")]
+    pause: u64,
+}
+
+impl MemoryConfig {
+    /// Returns the amount of memory in bytes occupied by buffered bodies, after crossing which
+    /// the proxy stops accepting new connections.
+    pub fn soft_limit(&self) -> usize {
+        self.soft_limit
+    }
+
+    /// Returns the time slice in milliseconds for which accepting is paused before checking the
+    /// memory usage again.
+    pub fn pause(&self) -> u64 {
+        self.pause
+    }
+}
+
+fn default_via_pseudonym() -> String {
+    Uuid::new_v4().hyphenated().to_string()
+}
+
+/// Proxy identity in `Via` headers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ViaConfig {
+    #[serde(default = "default_via_pseudonym")]
+    pseudonym: String,
+    #[serde(default)]
+    strip: bool,
+}
+
+impl ViaConfig {
+    /// Returns the name identifying this proxy instance in `Via` chains.
+    pub fn pseudonym(&self) -> &str {
+        &self.pseudonym
+    }
+
+    /// Returns `true` if incoming `Via` chains must be replaced with this instance entry instead of
+    /// being extended.
+    pub fn strip(&self) -> bool {
+        self.strip
+    }
+}
+
+fn default_breaker_window() -> u64 {
+    10
+}
+
+fn default_breaker_min_requests() -> u32 {
+    20
+}
+
+fn default_breaker_threshold() -> f64 {
+    0.5
+}
+
+fn default_breaker_cooldown() -> u64 {
+    5
+}
+
+/// Per-service circuit breaker settings.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_breaker_window")]
+    window: u64,
+    #[serde(default = "default_breaker_min_requests")]
+    min_requests: u32,
+    #[serde(default = "default_breaker_threshold")]
+    threshold: f64,
+    #[serde(default = "default_breaker_cooldown")]
+    cooldown: u64,
+}
+
+impl CircuitBreakerConfig {
+    /// Returns the period over which error rates are calculated.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+
+    /// Returns the minimum number of requests in the window required to trip the breaker.
+    pub fn min_requests(&self) -> u32 {
+        self.min_requests
+    }
+
+    /// Returns the fraction of failed requests in the window, reaching which trips the breaker.
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Returns the time the breaker stays open before letting a probe request through.
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown)
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: default_breaker_window(),
+            min_requests: default_breaker_min_requests(),
+            threshold: default_breaker_threshold(),
+            cooldown: default_breaker_cooldown(),
+        }
+    }
+}
+
+fn default_backoff_base() -> u64 {
+    10
+}
+
+fn default_backoff_max() -> u64 {
+    1000
+}
+
+fn default_backoff_jitter() -> f64 {
+    0.5
+}
+
+fn default_retry_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_retry_budget_min_rate() -> f64 {
+    1.0
+}
+
+fn default_slo_availability() -> f64 {
+    0.999
+}
+
+fn default_slo_latency_target() -> f64 {
+    0.99
+}
+
+fn default_slo_window() -> u64 {
+    3600
+}
+
+fn default_canary_max_error_ratio() -> f64 {
+    0.01
+}
+
+fn default_canary_min_requests() -> u64 {
+    100
+}
+
+fn default_retry_budget_burst() -> f64 {
+    10.0
+}
+
+fn default_monitoring_backlog() -> i32 {
+    128
+}
+
+fn default_monitoring_threads() -> usize {
+    1
+}
+
+fn default_connection_log_interval() -> u64 {
+    60
+}
+
+fn default_monitoring_recent_errors() -> usize {
+    100
+}
+
+fn default_response_headers_count() -> usize {
+    128
+}
+
+fn default_response_headers_size() -> usize {
+    65536
+}
+
+fn default_response_headers_frame() -> usize {
+    131072
+}
+
+fn default_request_headers_count() -> usize {
+    100
+}
+
+fn default_request_headers_size() -> usize {
+    32768
+}
+
+/// Limits of request headers forwarded to applications.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct RequestHeadersConfig {
+    #[serde(default = "default_request_headers_count")]
+    count: usize,
+    #[serde(default = "default_request_headers_size")]
+    size: usize,
+}
+
+impl RequestHeadersConfig {
+    /// Returns the maximum number of headers.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the maximum total size of header names and values in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+fn default_deadline_header() -> String {
+    "X-Request-Deadline".into()
+}
+
+fn default_timeout_header() -> String {
+    "X-Request-Timeout".into()
+}
+
+/// Headers, with which clients limit the time they are going to wait for responses.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RequestDeadlineConfig {
+    #[serde(default = "default_deadline_header")]
+    deadline_header: String,
+    #[serde(default = "default_timeout_header")]
+    timeout_header: String,
+}
+
+impl RequestDeadlineConfig {
+    /// Returns the name of the header with an absolute deadline in milliseconds since UNIX epoch.
+    pub fn deadline_header(&self) -> &str {
+        &self.deadline_header
+    }
+
+    /// Returns the name of the header with a timeout in milliseconds.
+    pub fn timeout_header(&self) -> &str {
+        &self.timeout_header
+    }
+}
+
+/// Limits of headers accepted from application responses.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ResponseHeadersConfig {
+    #[serde(default = "default_response_headers_count")]
+    count: usize,
+    #[serde(default = "default_response_headers_size")]
+    size: usize,
+    #[serde(default = "default_response_headers_frame")]
+    frame: usize,
+}
+
+impl ResponseHeadersConfig {
+    /// Returns the maximum number of headers.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the maximum total size of header names and values in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the maximum size of a frame carrying the response status or headers in bytes,
+    /// which is checked before parsing it.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+}
+
+impl Default for ResponseHeadersConfig {
+    fn default() -> Self {
+        Self {
+            count: default_response_headers_count(),
+            size: default_response_headers_size(),
+            frame: default_response_headers_frame(),
+        }
+    }
+}
+
+/// Delays between safe retries of application invocations.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct BackoffConfig {
+    #[serde(default = "default_backoff_base")]
+    base: u64,
+    #[serde(default = "default_backoff_max")]
+    max: u64,
+    #[serde(default = "default_backoff_jitter")]
+    jitter: f64,
+}
+
+impl BackoffConfig {
+    /// Returns the delay before the first retry, which doubles for each next one.
+    pub fn base(&self) -> Duration {
+        Duration::from_millis(self.base)
+    }
+
+    /// Returns the maximum delay between retries.
+    pub fn max(&self) -> Duration {
+        Duration::from_millis(self.max)
+    }
+
+    /// Returns the fraction by which each delay is randomly shortened.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            base: default_backoff_base(),
+            max: default_backoff_max(),
+            jitter: default_backoff_jitter(),
+        }
+    }
+}
+
+/// Per-service budgets limiting retries to a fraction of recent requests.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct RetryBudgetConfig {
+    #[serde(default = "default_retry_budget_ratio")]
+    ratio: f64,
+    #[serde(default = "default_retry_budget_min_rate")]
+    min_rate: f64,
+    #[serde(default = "default_retry_budget_burst")]
+    burst: f64,
+}
+
+impl RetryBudgetConfig {
+    /// Returns the number of retries each request earns for its service.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Returns the number of retries per second allowed regardless of the request rate.
+    pub fn min_rate(&self) -> f64 {
+        self.min_rate
+    }
+
+    /// Returns the maximum number of retries a service may accumulate.
+    pub fn burst(&self) -> f64 {
+        self.burst
+    }
+}
+
+/// Service level objectives of a service.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct SloConfig {
+    #[serde(default = "default_slo_availability")]
+    availability: f64,
+    latency: Option<u64>,
+    #[serde(default = "default_slo_latency_target")]
+    latency_target: f64,
+    #[serde(default = "default_slo_window")]
+    window: u64,
+}
+
+impl SloConfig {
+    /// Returns the target fraction of requests answered without server errors.
+    pub fn availability(&self) -> f64 {
+        self.availability
+    }
+
+    /// Returns the latency threshold, if the latency objective is set.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency.map(Duration::from_millis)
+    }
+
+    /// Returns the target fraction of requests answered within the latency threshold.
+    pub fn latency_target(&self) -> f64 {
+        self.latency_target
+    }
+
+    /// Returns the rolling window the compliance is computed over.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+}
+
+/// A single step of a canary ramp.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct CanaryStepConfig {
+    weight: f64,
+    bake: u64,
+}
+
+impl CanaryStepConfig {
+    /// Returns the percentage of requests routed into the canary during this step.
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// Returns the time the canary must stay healthy before moving to the next step.
+    pub fn bake(&self) -> Duration {
+        Duration::from_secs(self.bake)
+    }
+}
+
+/// A schedule of gradually shifting requests of a service into its canary version.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CanaryConfig {
+    target: String,
+    steps: Vec<CanaryStepConfig>,
+    #[serde(default = "default_canary_max_error_ratio")]
+    max_error_ratio: f64,
+    #[serde(default = "default_canary_min_requests")]
+    min_requests: u64,
+}
+
+impl CanaryConfig {
+    /// Returns the name of the canary service.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Returns ramp steps in the order they are taken.
+    pub fn steps(&self) -> &[CanaryStepConfig] {
+        &self.steps
+    }
+
+    /// Returns how much the canary error ratio may exceed the baseline one before the ramp is
+    /// rolled back.
+    pub fn max_error_ratio(&self) -> f64 {
+        self.max_error_ratio
+    }
+
+    /// Returns the number of canary requests required to compare error ratios.
+    pub fn min_requests(&self) -> u64 {
+        self.min_requests
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    rate: Option<f64>,
+    concurrency: Option<usize>,
+}
+
+impl QuotaConfig {
+    /// Returns the maximum number of requests per second.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    /// Returns the maximum number of requests being processed simultaneously.
+    pub fn concurrency(&self) -> Option<usize> {
+        self.concurrency
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TracingConfig {
+    path: String,
+    header: String,
+    probability: f64,
+}
+
+impl TracingConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    pub fn probability(&self) -> f64 {
+        self.probability
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TimeoutsConfig {
+    path: String,
+}
+
+impl TimeoutsConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AuthConfig {
+    service: String,
+    client_id: u32,
+    client_secret: String,
+}
+
+impl AuthConfig {
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn client_id(&self) -> u32 {
+        self.client_id
+    }
+
+    pub fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+}
+
+impl Debug for AuthConfig {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("AuthConfig")
+            .field("service", &self.service)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<...>")
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LoadTestingConfig {
+    enabled: bool,
+    /// Limits of the isolated services pool, if load tests must not share one with live traffic.
+    pool: Option<DetailPoolConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Config {
+    network: NetworkConfig,
+    threads: Option<usize>,
+    locators: Vec<(IpAddr, u16)>,
+    logging: LoggingConfig,
+    unicorn: String,
+    monitoring: MonitoringConfig,
+    pool: PoolConfig,
+    tracing: TracingConfig,
+    headers: HashMap<String, String>,
+    prefix: Option<String>,
+    normalization: Option<NormalizationConfig>,
+    #[serde(default)]
+    routes: Vec<PathRouteConfig>,
+    #[serde(default)]
+    virtual_hosts: Vec<VirtualHostConfig>,
+    timeout: u64,
+    queue_budget: Option<f64>,
+    max_body_size: Option<usize>,
+    service_timeouts: TimeoutsConfig,
+    auth: AuthConfig,
+    load_testing: Option<LoadTestingConfig>,
+    mirroring: Option<MirroringConfig>,
+    #[serde(default)]
+    redaction: RedactionConfig,
+    signing: Option<SigningConfig>,
+    memory: Option<MemoryConfig>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    via: Option<ViaConfig>,
+    digest: Option<DigestConfig>,
+    websocket: Option<WebSocketConfig>,
+    sse: Option<SseConfig>,
+    standby: Option<StandbyConfig>,
+    peers: Option<PeersConfig>,
+    #[serde(default)]
+    hooks: LifecycleConfig,
+    #[serde(default)]
+    rewrites: HashMap<String, Vec<StatusRewrite>>,
+    #[serde(default)]
+    filters: HashMap<String, Vec<BodyFilterConfig>>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    #[serde(default)]
+    timezone: UtcOffset,
+    #[serde(default)]
+    tenants: Vec<TenantConfig>,
+    #[serde(default)]
+    protocols: HashMap<String, AppProtocol>,
+    #[serde(default)]
+    streaming: HashMap<String, StreamingConfig>,
+    #[serde(default)]
+    codecs: HashMap<String, BodyCodec>,
+    #[serde(default)]
+    response_limits: HashMap<String, usize>,
+    #[serde(default)]
+    response_slices: HashMap<String, usize>,
+    #[serde(default)]
+    response_timeouts: HashMap<String, u64>,
+    #[serde(default)]
+    early_hints: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    hedging: HashMap<String, u64>,
+    #[serde(default)]
+    canaries: HashMap<String, CanaryConfig>,
+    #[serde(default)]
+    slos: HashMap<String, SloConfig>,
+    request_headers: Option<RequestHeadersConfig>,
+    request_deadline: Option<RequestDeadlineConfig>,
+    #[serde(default)]
+    response_headers: ResponseHeadersConfig,
+    #[serde(default)]
+    retry_overrides: HashMap<String, HashMap<String, RetrySafety>>,
+    #[serde(default = "default_retriable_errors")]
+    retriable_errors: Vec<RetriableError>,
+    #[serde(default = "default_error_statuses")]
+    error_statuses: Vec<ErrorStatusRule>,
+    #[serde(default)]
+    retry_backoff: BackoffConfig,
+    retry_budget: Option<RetryBudgetConfig>,
+    #[serde(default)]
+    default_events: HashMap<String, String>,
+    #[serde(default)]
+    local_upstreams: HashMap<String, Endpoint>,
+    #[serde(default = "default_error_origins")]
+    error_origins: HashMap<u64, String>,
+    /// Deprecation warnings collected while migrating the config from an old layout.
+    #[serde(skip)]
+    warnings: Vec<String>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn Error>> {
+        let mut value: Value = serde_yaml::from_reader(&File::open(path)?)?;
+        let warnings = migrate(&mut value, RENAMES);
+
+        let mut cfg: Config = serde_yaml::from_value(value)?;
+        cfg.warnings = warnings;
+
+        Config::sanitize(&cfg)?;
+
+        Ok(cfg)
+    }
+
+    /// Loads the config like `load` does, but without failing on the first semantic error,
+    /// returning all of them along with the config instead.
+    pub fn check<P: AsRef<Path>>(path: P) -> Result<(Config, Vec<String>), Box<dyn Error>> {
+        let mut value: Value = serde_yaml::from_reader(&File::open(path)?)?;
+        let warnings = migrate(&mut value, RENAMES);
+
+        let mut cfg: Config = serde_yaml::from_value(value)?;
+        cfg.warnings = warnings;
+
+        let errors = Config::validate(&cfg);
+        Ok((cfg, errors))
+    }
+
+    fn sanitize(cfg: &Config) -> Result<(), Box<dyn Error>> {
+        match Config::validate(cfg).into_iter().next() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the config semantically, returning all errors found.
+    fn validate(cfg: &Config) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(0) = cfg.threads {
+            errors.push("number of worker threads must be a positive value (or absent)".into());
+        }
+
+        if let Some(fraction) = cfg.queue_budget {
+            if fraction <= 0.0 || fraction > 1.0 {
+                errors.push("queue wait budget must fit in (0.0; 1.0]".into());
+            }
+        }
+
+        if cfg.logging.connections.interval == 0 {
+            errors.push("connection log summary interval must be positive".into());
+        }
+
+        if let Some(0) = cfg.network.disconnect_probe {
+            errors.push("disconnect probe interval must be a positive value (or absent)".into());
+        }
+
+        if let Some(0) = cfg.max_body_size {
+            errors.push("request body size limit must be a positive value (or absent)".into());
+        }
+
+        let pools = cfg.pool.services.values().map(|pool| pool.max_channels);
+        if iter::once(cfg.pool.max_channels).chain(pools).any(|channels| channels == Some(0)) {
+            errors.push("maximum number of channels per connection must be a positive value (or absent)".into());
+        }
+
+        if let Some(ref priorities) = cfg.pool.priorities {
+            if priorities.batch == 0 {
+                errors.push("priority scheduling batch must be a positive value".into());
+            }
+
+            for (id, class) in priorities.classes.iter().enumerate() {
+                if priorities.classes[..id].iter().any(|other| other.name == class.name) {
+                    errors.push(format!("duplicate `{}` priority class", class.name));
+                }
+            }
+        }
+
+        if let Some(guard) = cfg.pool.resolve_guard {
+            if guard.min_ratio <= 0.0 || guard.min_ratio > 1.0 {
+                errors.push("resolve guard ratio must fit in (0.0; 1.0]".into());
+            }
+        }
+
+        if let Some(eyeballs) = cfg.pool.happy_eyeballs {
+            if eyeballs.delay >= eyeballs.timeout {
+                errors.push("happy eyeballs delay must be less than its timeout".into());
+            }
+        }
+
+        if cfg.tracing.probability < 0.0 || cfg.tracing.probability > 1.0 {
+            errors.push("tracing probability must fit in [0.0; 1.0]".into());
+        }
+
+        if let Some(ref prefix) = cfg.prefix {
+            if !prefix.starts_with('/') || prefix.ends_with('/') {
+                errors.push("URL prefix must start with a slash and must not end with one".into());
+            }
+        }
+
+        if cfg.response_slices.values().any(|&slice| slice == 0) {
+            errors.push("response slice sizes must be positive".into());
+        }
+
+        if let Some(ref normalization) = cfg.normalization {
+            match normalization.event {
+                Some(ref event) if event.is_empty() || event.contains('/') => {
+                    errors.push("default event name must be non-empty and must not contain slashes".into());
+                }
+                None if normalization.policy == NormalizationPolicy::Default => {
+                    errors.push("`default` normalization policy requires the default event name".into());
+                }
+                Some(..) | None => {}
+            }
+        }
+
+        for route in &cfg.routes {
+            match (route.prefix(), route.regex()) {
+                (Some(prefix), None) if !prefix.starts_with('/') => {
+                    errors.push(format!("routing table prefix `{}` must start with a slash", prefix));
+                }
+                (None, Some(pattern)) => {
+                    if let Err(err) = Regex::new(pattern) {
+                        errors.push(format!("invalid routing table pattern `{}`: {}", pattern, err));
+                    }
+                }
+                (Some(..), None) => {}
+                (..) => {
+                    errors.push(format!("routing table entry for `{}` service must have either a prefix or a pattern",
+                        route.service));
+                }
+            }
+
+            if route.service.is_empty() || route.event.is_empty() {
+                errors.push("routing table entries must have non-empty service and event names".into());
+            }
+        }
+
+        for vhost in &cfg.virtual_hosts {
+            if let Err(err) = Regex::new(&vhost.hosts) {
+                errors.push(format!("invalid virtual host pattern `{}`: {}", vhost.hosts, err));
+            }
+
+            if vhost.service.is_empty() || vhost.event.is_empty() {
+                errors.push("virtual hosts must have non-empty service and event names".into());
+            }
+        }
+
+        let backoff = cfg.retry_backoff;
+        if !(backoff.jitter >= 0.0 && backoff.jitter <= 1.0) {
+            errors.push("retry backoff jitter must be in [0; 1] range".into());
+        }
+
+        if backoff.base > backoff.max {
+            errors.push("retry backoff base delay must not exceed the maximum one".into());
+        }
+
+        if let Some(budget) = cfg.retry_budget {
+            if !(budget.ratio >= 0.0 && budget.ratio <= 1.0) {
+                errors.push("retry budget ratio must be in [0; 1] range".into());
+            }
+
+            if !(budget.min_rate >= 0.0) {
+                errors.push("retry budget minimum rate must be non-negative".into());
+            }
+
+            if !(budget.burst >= 1.0) {
+                errors.push("retry budget burst must allow at least a single retry".into());
+            }
+        }
+
+        if let Some(breaker) = cfg.circuit_breaker {
+            if !(breaker.threshold > 0.0 && breaker.threshold <= 1.0) {
+                errors.push("circuit breaker threshold must be in (0; 1] range".into());
+            }
+        }
+
+        if let Some(ref peers) = cfg.peers {
+            if peers.endpoints.is_empty() {
+                errors.push("at least one peer proxy endpoint must be specified".into());
+            }
+
+            if cfg.circuit_breaker.is_none() {
+                errors.push("forwarding into peer proxies requires circuit breakers to be configured".into());
+            }
+        }
+
+        if let Some(websocket) = cfg.websocket {
+            if websocket.max_message_size == 0 {
+                errors.push("WebSocket message size limit must be positive".into());
+            }
+        }
+
+        if let Some(ref tls) = cfg.network.tls {
+            if cfg.network.forward.contains(&TlsAttribute::ClientCert) && tls.client_ca.is_none() {
+                errors.push("forwarding client certificate fingerprints requires `client_ca` to ask clients for certificates".into());
+            }
+        }
+
+        let queue = cfg.logging.access_queue();
+        if queue.limit == 0 || queue.batch == 0 {
+            errors.push("access log queue limit and batch size must be positive values".into());
+        }
+
+        if queue.probe_interval == 0 || queue.max_backoff == 0 {
+            errors.push("access log probe interval and maximum backoff must be positive values".into());
+        }
+
+        if let Some(sampling) = cfg.logging.access_sampling() {
+            if !(sampling.rate >= 0.0 && sampling.rate <= 1.0) {
+                errors.push("access log sampling rate must be in [0; 1] range".into());
+            }
+
+            for (status, &rate) in &sampling.statuses {
+                if let Err(err) = status.parse::<StatusMatch>() {
+                    errors.push(err.to_string());
+                }
+
+                if !(rate >= 0.0 && rate <= 1.0) {
+                    errors.push(format!("access log sampling rate for `{}` status must be in [0; 1] range", status));
+                }
+            }
+        }
+
+        if let Some(kafka) = cfg.logging.kafka() {
+            if !cfg!(feature = "kafka") {
+                errors.push("Kafka access log sink requires the proxy to be built with `kafka` feature".into());
+            }
+
+            if kafka.brokers.is_empty() {
+                errors.push("at least one Kafka broker must be specified".into());
+            }
+
+            if kafka.batch == 0 || kafka.queue_limit == 0 {
+                errors.push("Kafka batch size and queue limit must be positive values".into());
+            }
+        }
+
+        for (id, tenant) in cfg.tenants.iter().enumerate() {
+            if let Err(err) = Regex::new(&tenant.hosts) {
+                errors.push(format!("invalid hosts pattern for `{}` tenant: {}", tenant.name, err));
+            }
+
+            if tenant.locators.is_empty() {
+                errors.push(format!("at least one locator must be specified for `{}` tenant", tenant.name));
+            }
+
+            if let Some(quota) = tenant.quota {
+                if quota.rate.map(|rate| !(rate > 0.0)).unwrap_or(false) {
+                    errors.push(format!("quota rate for `{}` tenant must be positive", tenant.name));
+                }
+
+                if quota.concurrency == Some(0) {
+                    errors.push(format!("quota concurrency for `{}` tenant must be positive", tenant.name));
+                }
+            }
+
+            if cfg.tenants[..id].iter().any(|other| other.name == tenant.name) {
+                errors.push(format!("duplicate `{}` tenant", tenant.name));
+            }
+        }
+
+        for rule in &cfg.rules {
+            if rule.windows.iter().any(|window| window.from == window.to) {
+                errors.push(format!("time windows of the rule for `{}` service must not be empty", rule.service));
+            }
+        }
+
+        for (service, &limit) in &cfg.response_limits {
+            if limit == 0 {
+                errors.push(format!("response limit for `{}` service must be positive", service));
+            }
+        }
+
+        for (service, &timeout) in &cfg.response_timeouts {
+            if timeout == 0 {
+                errors.push(format!("response timeout for `{}` service must be positive", service));
+            }
+        }
+
+        for rule in &cfg.error_statuses {
+            if rule.status < 400 || rule.status > 599 {
+                errors.push(format!("errors of {} category must be mapped into 4xx or 5xx status", rule.category));
+            }
+
+            if let Some((from, to)) = rule.codes {
+                if from > to {
+                    errors.push(format!("codes range of {} category errors must not be empty", rule.category));
+                }
+            }
+        }
+
+        for (service, &delay) in &cfg.hedging {
+            if delay == 0 {
+                errors.push(format!("hedging delay for `{}` service must be positive", service));
+            }
+        }
+
+        for (service, canary) in &cfg.canaries {
+            if canary.target == *service {
+                errors.push(format!("canary of `{}` service must be another service", service));
+            }
+
+            if canary.steps.is_empty() {
+                errors.push(format!("canary ramp of `{}` service must have at least one step", service));
+            }
+
+            let mut prev = 0.0;
+            for step in &canary.steps {
+                if step.weight <= prev || step.weight > 100.0 {
+                    errors.push(format!("canary weights of `{}` service must increase within (0; 100]", service));
+                }
+                prev = step.weight;
+            }
+
+            if canary.max_error_ratio < 0.0 || canary.max_error_ratio > 1.0 {
+                errors.push(format!("canary error ratio of `{}` service must be within [0; 1]", service));
+            }
+        }
+
+        for (service, slo) in &cfg.slos {
+            if slo.availability <= 0.0 || slo.availability >= 1.0 {
+                errors.push(format!("availability objective of `{}` service must be within (0; 1)", service));
+            }
+
+            if slo.latency_target <= 0.0 || slo.latency_target >= 1.0 {
+                errors.push(format!("latency objective of `{}` service must be within (0; 1)", service));
+            }
+
+            if slo.latency == Some(0) {
+                errors.push(format!("latency threshold of `{}` service must be positive", service));
+            }
+
+            if slo.window < 60 {
+                errors.push(format!("objectives window of `{}` service must be at least a minute", service));
+            }
+        }
+
+        for (service, hints) in &cfg.early_hints {
+            if hints.iter().any(|hint| hint.trim().is_empty()) {
+                errors.push(format!("early hints for `{}` service must not be empty", service));
+            }
+        }
+
+        if let Some(ref signing) = cfg.signing {
+            if signing.key.is_empty() {
+                errors.push("header signing key must not be empty".into());
+            }
+
+            if signing.headers.is_empty() {
+                errors.push("at least one header to sign must be specified".into());
+            }
+        }
+
+        if let Some(memory) = cfg.memory {
+            if memory.soft_limit == 0 || memory.pause == 0 {
+                errors.push("memory soft limit and pause duration must be positive values".into());
+            }
+        }
+
+        if let Some(limit) = cfg.request_headers {
+            if limit.count == 0 || limit.size == 0 {
+                errors.push("request headers count and size limits must be positive values".into());
+            }
+        }
+
+        if cfg.response_headers.count == 0 || cfg.response_headers.size == 0 {
+            errors.push("response headers count and size limits must be positive values".into());
+        }
+
+        if cfg.response_headers.frame < cfg.response_headers.size {
+            errors.push("response meta frame size limit must not be less than the headers size limit".into());
+        }
+
+        for (service, rules) in &cfg.rewrites {
+            for rule in rules {
+                if rule.from < 100 || rule.from > 599 || rule.to < 100 || rule.to > 599 {
+                    errors.push(format!("invalid status rewrite rule for `{}` service: {} -> {}",
+                        service, rule.from, rule.to));
+                }
+            }
+        }
+
+        for (service, streaming) in &cfg.streaming {
+            if streaming.window == 0 {
+                errors.push(format!("streaming window for `{}` service must be positive", service));
+            }
+        }
+
+        for (service, filters) in &cfg.filters {
+            for filter in filters {
+                if let BodyFilterConfig::Replace { ref pattern, .. } = *filter {
+                    if let Err(err) = Regex::new(pattern) {
+                        errors.push(format!("invalid body filter pattern for `{}` service: {}", service, err));
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mirroring) = cfg.mirroring {
+            if mirroring.probability < 0.0 || mirroring.probability > 1.0 {
+                errors.push("mirroring probability must fit in [0.0; 1.0]".into());
+            }
+        }
+
+        if cfg.timeout == 0 {
+            errors.push("proxy timeout must be positive".into());
+        }
+
+        match cfg.timeout.checked_mul(1000) {
+            Some(timeout_ms) => {
+                for (service, &timeout) in &cfg.response_timeouts {
+                    if timeout >= timeout_ms {
+                        errors.push(format!("response timeout for `{}` service must be less than the proxy timeout", service));
+                    }
+                }
+
+                for (service, &delay) in &cfg.hedging {
+                    if delay >= timeout_ms {
+                        errors.push(format!("hedging delay for `{}` service must be less than the proxy timeout", service));
+                    }
+                }
+            }
+            None => errors.push("proxy timeout is too large".into()),
+        }
+
+        if cfg.locators.is_empty() {
+            errors.push("at least one locator must be specified".into());
+        }
+
+        let locators = cfg.locators.iter().chain(cfg.tenants.iter().flat_map(|tenant| tenant.locators.iter()));
+        for &(addr, port) in locators {
+            if port == 0 {
+                errors.push(format!("locator port of {} must be positive", addr));
+            }
+        }
+
+        let mut headers = cfg.headers.iter()
+            .flat_map(|(from, to)| vec![from.as_str(), to.as_str()])
+            .collect::<Vec<_>>();
+        if let Some(ref signing) = cfg.signing {
+            headers.push(&signing.header);
+            headers.extend(signing.headers.iter().map(String::as_str));
+        }
+        if let Some(ref deadline) = cfg.request_deadline {
+            headers.push(&deadline.deadline_header);
+            headers.push(&deadline.timeout_header);
+        }
+        for name in headers {
+            if !is_header_name(name) {
+                errors.push(format!("`{}` is not a valid header name", name));
+            }
+        }
+
+        errors
+    }
+
+    pub fn network(&self) -> &NetworkConfig {
+        &self.network
+    }
+
+    /// Returns the number of worker threads.
+    ///
+    /// Can be omitted in the config, in that case the number of CPUs of the current machine will
+    /// be returned.
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(num_cpus::get())
+    }
+
+    /// Returns the Locator endpoints.
+    pub fn locators(&self) -> &[(IpAddr, u16)] {
+        &self.locators
+    }
+
+    pub fn logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
+
+    /// Returns the name of the Unicorn service that will be used for dynamic configuration and
+    /// subscriptions.
+    pub fn unicorn(&self) -> &str {
+        &self.unicorn
+    }
+
+    pub fn monitoring(&self) -> &MonitoringConfig {
+        &self.monitoring
+    }
+
+    pub fn pool(&self) -> &PoolConfig {
+        &self.pool
+    }
+
+    pub fn tracing(&self) -> &TracingConfig {
+        &self.tracing
+    }
+
+    /// Returns HTTP headers forward mapping.
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    /// Returns the URL prefix that is stripped before extracting service and event names from the
+    /// path.
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_ref().map(|v| v.as_str())
+    }
+
+    /// Returns the policy of treating paths with an empty or missing event name, if configured.
+    pub fn normalization(&self) -> Option<&NormalizationConfig> {
+        self.normalization.as_ref()
+    }
+
+    /// Returns the routing table, mapping request paths into services and events.
+    pub fn routes(&self) -> &[PathRouteConfig] {
+        &self.routes
+    }
+
+    /// Returns virtual hosts, mapping `Host` headers into services.
+    pub fn virtual_hosts(&self) -> &[VirtualHostConfig] {
+        &self.virtual_hosts
+    }
+
+    /// Returns proxy timeout.
+    pub fn timeout(&self) -> Duration {
+        Duration::new(self.timeout, 0)
+    }
+
+    /// Returns the maximum time a request may wait in a pool queue before being dispatched, if
+    /// limited.
+    pub fn queue_budget(&self) -> Option<Duration> {
+        self.queue_budget.map(|fraction| self.timeout().mul_f64(fraction))
+    }
+
+    /// Returns the maximum request body size in bytes, if limited.
+    pub fn max_body_size(&self) -> Option<usize> {
+        self.max_body_size
+    }
+
+    pub fn service_timeouts(&self) -> &TimeoutsConfig {
+        &self.service_timeouts
     }
 
     /// Returns authorization settings.
@@ -326,8 +2998,343 @@ impl Config {
         &self.auth
     }
 
+    /// Returns request mirroring settings, if enabled.
+    pub fn mirroring(&self) -> Option<&MirroringConfig> {
+        self.mirroring.as_ref()
+    }
+
+    /// Returns secrets masked in diagnostic outputs.
+    pub fn redaction(&self) -> &RedactionConfig {
+        &self.redaction
+    }
+
+    /// Returns per-service response status rewrite rules.
+    pub fn rewrites(&self) -> &HashMap<String, Vec<StatusRewrite>> {
+        &self.rewrites
+    }
+
+    /// Returns per-service filters of buffered response bodies.
+    pub fn filters(&self) -> &HashMap<String, Vec<BodyFilterConfig>> {
+        &self.filters
+    }
+
+    /// Returns routing rules.
+    pub fn rules(&self) -> &[RuleConfig] {
+        &self.rules
+    }
+
+    /// Returns the timezone, in which time windows of routing rules are evaluated.
+    pub fn timezone(&self) -> UtcOffset {
+        self.timezone
+    }
+
+    /// Returns per-service application protocol versions. Services not listed here speak the
+    /// default one.
+    pub fn protocols(&self) -> &HashMap<String, AppProtocol> {
+        &self.protocols
+    }
+
+    /// Returns per-service streaming settings.
+    pub fn streaming(&self) -> &HashMap<String, StreamingConfig> {
+        &self.streaming
+    }
+
+    /// Returns per-service body codecs. Services not listed here exchange bodies as is.
+    pub fn codecs(&self) -> &HashMap<String, BodyCodec> {
+        &self.codecs
+    }
+
+    /// Returns per-service response body size limits in bytes.
+    pub fn response_limits(&self) -> &HashMap<String, usize> {
+        &self.response_limits
+    }
+
+    /// Returns per-service sizes of slices in bytes buffered response bodies are written to clients
+    /// in.
+    pub fn response_slices(&self) -> &HashMap<String, usize> {
+        &self.response_slices
+    }
+
+    /// Returns local HTTP upstreams, into which requests to the given services are forwarded.
+    pub fn local_upstreams(&self) -> &HashMap<String, Endpoint> {
+        &self.local_upstreams
+    }
+
+    /// Returns per-service timeouts of waiting for the first response frame.
+    pub fn response_timeouts(&self) -> HashMap<String, Duration> {
+        self.response_timeouts.iter()
+            .map(|(service, &timeout)| (service.clone(), Duration::from_millis(timeout)))
+            .collect()
+    }
+
+    /// Returns per-service delays after which requests are hedged.
+    pub fn hedging(&self) -> HashMap<String, Duration> {
+        self.hedging.iter()
+            .map(|(service, &delay)| (service.clone(), Duration::from_millis(delay)))
+            .collect()
+    }
+
+    /// Returns per-service canary ramps.
+    pub fn canaries(&self) -> &HashMap<String, CanaryConfig> {
+        &self.canaries
+    }
+
+    /// Returns per-service level objectives.
+    pub fn slos(&self) -> &HashMap<String, SloConfig> {
+        &self.slos
+    }
+
+    /// Returns per-service `Link` header values attached to responses as early hints.
+    pub fn early_hints(&self) -> &HashMap<String, Vec<String>> {
+        &self.early_hints
+    }
+
+    /// Returns error categories mapped to names of subsystems generating them.
+    pub fn error_origins(&self) -> &HashMap<u64, String> {
+        &self.error_origins
+    }
+
+    /// Returns forwarded headers signing settings, if enabled.
+    pub fn signing(&self) -> Option<&SigningConfig> {
+        self.signing.as_ref()
+    }
+
+    /// Returns memory pressure settings, if enabled.
+    pub fn memory(&self) -> Option<&MemoryConfig> {
+        self.memory.as_ref()
+    }
+
+    /// Returns proxy identity settings, if `Via` headers are enabled.
+    pub fn via(&self) -> Option<&ViaConfig> {
+        self.via.as_ref()
+    }
+
+    /// Returns lifecycle hooks.
+    pub fn hooks(&self) -> &LifecycleConfig {
+        &self.hooks
+    }
+
+    /// Returns body digest settings, if enabled.
+    pub fn digest(&self) -> Option<DigestConfig> {
+        self.digest
+    }
+
+    /// Returns Server-Sent Events settings, if bridging event streams is enabled.
+    pub fn sse(&self) -> Option<SseConfig> {
+        self.sse
+    }
+
+    /// Returns warm standby settings, if the proxy starts in standby.
+    pub fn standby(&self) -> Option<&StandbyConfig> {
+        self.standby.as_ref()
+    }
+
+    /// Returns services connected at start, which are warm standby ones followed by ones required
+    /// by the readiness probe, without duplicates.
+    pub fn warmup(&self) -> Vec<String> {
+        let mut services = self.standby().map(|cfg| cfg.warm().to_vec()).unwrap_or_default();
+        for name in self.monitoring().readiness().services() {
+            if !services.contains(name) {
+                services.push(name.clone());
+            }
+        }
+        services
+    }
+
+    /// Returns peer proxies, into which requests are forwarded when the local pool is unhealthy.
+    pub fn peers(&self) -> Option<&PeersConfig> {
+        self.peers.as_ref()
+    }
+
+    /// Returns WebSocket settings, if proxying upgraded connections is enabled.
+    pub fn websocket(&self) -> Option<WebSocketConfig> {
+        self.websocket
+    }
+
+    /// Returns circuit breaker settings, if breakers are enabled.
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreakerConfig> {
+        self.circuit_breaker.as_ref()
+    }
+
+    /// Returns limits of request headers, if they are checked.
+    pub fn request_headers(&self) -> Option<&RequestHeadersConfig> {
+        self.request_headers.as_ref()
+    }
+
+    /// Returns names of headers carrying client deadlines, if they are respected.
+    pub fn request_deadline(&self) -> Option<&RequestDeadlineConfig> {
+        self.request_deadline.as_ref()
+    }
+
+    /// Returns limits of headers accepted from application responses.
+    pub fn response_headers(&self) -> &ResponseHeadersConfig {
+        &self.response_headers
+    }
+
+    /// Returns per-event retry safety overrides, keyed by service and event names.
+    pub fn retry_overrides(&self) -> &HashMap<String, HashMap<String, RetrySafety>> {
+        &self.retry_overrides
+    }
+
+    /// Returns rules mapping Cocaine errors into HTTP statuses, the first matching one wins.
+    pub fn error_statuses(&self) -> &[ErrorStatusRule] {
+        &self.error_statuses
+    }
+
+    /// Returns Cocaine errors, after which requests are retried by default.
+    pub fn retriable_errors(&self) -> &[RetriableError] {
+        &self.retriable_errors
+    }
+
+    /// Returns delays between safe retries.
+    pub fn retry_backoff(&self) -> &BackoffConfig {
+        &self.retry_backoff
+    }
+
+    /// Returns per-service retry budgets, if configured.
+    pub fn retry_budget(&self) -> Option<&RetryBudgetConfig> {
+        self.retry_budget.as_ref()
+    }
+
+    /// Returns per-service events invoked when the request specifies only the service.
+    pub fn default_events(&self) -> &HashMap<String, String> {
+        &self.default_events
+    }
+
+    /// Returns tenants, each representing an isolated Cocaine installation.
+    pub fn tenants(&self) -> &[TenantConfig] {
+        &self.tenants
+    }
+
+    /// Returns a configuration view for the given tenant, i.e. with locators, headers mapping and
+    /// pool limits overridden by the tenant's ones.
+    pub fn for_tenant(&self, tenant: &TenantConfig) -> Config {
+        let mut cfg = self.clone();
+        cfg.locators = tenant.locators.clone();
+
+        if let Some(ref headers) = tenant.headers {
+            cfg.headers = headers.clone();
+        }
+
+        if let Some(pool) = tenant.pool {
+            cfg.pool.limit = pool.limit.unwrap_or(cfg.pool.limit);
+            cfg.pool.lifespan = pool.lifespan.unwrap_or(cfg.pool.lifespan);
+            cfg.pool.reconnection_ratio = pool.reconnection_ratio.unwrap_or(cfg.pool.reconnection_ratio);
+        }
+
+        cfg
+    }
+
+    /// Returns a configuration view for load testing with pool limits overridden by its own ones,
+    /// if load tests are run through an isolated services pool.
+    pub fn for_load_testing(&self) -> Option<Config> {
+        let pool = self.load_testing.as_ref().filter(|v| v.enabled).and_then(|v| v.pool)?;
+
+        let mut cfg = self.clone();
+        cfg.pool.limit = pool.limit.unwrap_or(cfg.pool.limit);
+        cfg.pool.lifespan = pool.lifespan.unwrap_or(cfg.pool.lifespan);
+        cfg.pool.reconnection_ratio = pool.reconnection_ratio.unwrap_or(cfg.pool.reconnection_ratio);
+        // Per-service limits are tuned for live traffic.
+        cfg.pool.services.clear();
+
+        Some(cfg)
+    }
+
     /// Returns `true` when a load testing plugin is enabled.
     pub fn is_load_testing_enabled(&self) -> bool {
         self.load_testing.as_ref().map(|v| v.enabled).unwrap_or(false)
     }
+
+    /// Returns deprecation warnings collected while migrating the config from an old layout.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use serde_yaml::{self, Value};
+
+    use super::{Config, MonitoringConfig, Rename, is_header_name, migrate};
+
+    const RENAMES: &[Rename] = &[
+        Rename { from: &["timeout"], to: &["network", "timeout"], since: "0.4.0" },
+        Rename { from: &["monitoring", "port"], to: &["monitoring", "addr"], since: "0.4.0" },
+    ];
+
+    #[test]
+    fn test_migrate_renamed_keys() {
+        let mut value: Value = serde_yaml::from_str("timeout: 30\nmonitoring:\n  port: 10000\n").unwrap();
+        let warnings = migrate(&mut value, RENAMES);
+
+        let expected: Value = serde_yaml::from_str("network:\n  timeout: 30\nmonitoring:\n  addr: 10000\n").unwrap();
+        assert_eq!(expected, value);
+        assert_eq!(2, warnings.len());
+        assert!(warnings[0].contains("`timeout` is deprecated since 0.4.0, use `network.timeout` instead"));
+    }
+
+    #[test]
+    fn test_migrate_legacy_service_timeouts() {
+        let mut value: Value = serde_yaml::from_str("timeout: 30\ntimeouts:\n  path: /timeouts\n").unwrap();
+        let warnings = migrate(&mut value, super::RENAMES);
+
+        let expected: Value = serde_yaml::from_str("timeout: 30\nservice_timeouts:\n  path: /timeouts\n").unwrap();
+        assert_eq!(expected, value);
+        assert_eq!(vec!["`timeouts` is deprecated since 0.3.20, use `service_timeouts` instead".to_owned()], warnings);
+    }
+
+    #[test]
+    fn test_migrate_prefers_new_layout() {
+        let mut value: Value = serde_yaml::from_str("timeout: 30\nnetwork:\n  timeout: 10\n").unwrap();
+        let warnings = migrate(&mut value, RENAMES);
+
+        let expected: Value = serde_yaml::from_str("network:\n  timeout: 10\n").unwrap();
+        assert_eq!(expected, value);
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("ignored"));
+    }
+
+    #[test]
+    fn test_migrate_current_layout_untouched() {
+        let mut value: Value = serde_yaml::from_str("network:\n  timeout: 10\n").unwrap();
+        let expected = value.clone();
+
+        assert!(migrate(&mut value, RENAMES).is_empty());
+        assert_eq!(expected, value);
+    }
+
+    #[test]
+    fn test_is_header_name() {
+        assert!(is_header_name("X-Request-Id"));
+        assert!(is_header_name("x_custom.header"));
+        assert!(!is_header_name(""));
+        assert!(!is_header_name("X Request"));
+        assert!(!is_header_name("X-Request:"));
+    }
+
+    #[test]
+    fn test_validate_huge_timeout() {
+        let text = include_str!("../config.yaml").replace("\ntimeout: 30\n", "\ntimeout: 18446744073709551615\n");
+        let cfg: Config = serde_yaml::from_str(&text).unwrap();
+
+        assert!(Config::validate(&cfg).contains(&"proxy timeout is too large".to_owned()));
+    }
+
+    #[test]
+    fn test_admin_tokens_are_not_exposed() {
+        let cfg: MonitoringConfig = serde_yaml::from_str(r#"
+            addr: ["::1", 10000]
+            auth:
+              - name: ops
+                token: s3cr3t
+                role: operate
+        "#).unwrap();
+
+        let json = serde_json::to_string(&cfg).unwrap();
+        assert!(json.contains("ops"));
+        assert!(!json.contains("s3cr3t"));
+        assert!(!serde_yaml::to_string(&cfg).unwrap().contains("s3cr3t"));
+        assert!(!format!("{:?}", cfg).contains("s3cr3t"));
+    }
 }
@@ -1,72 +1,158 @@
 // #![feature(box_syntax, fnbox, integer_atomics, never_type)]
 
 extern crate byteorder;
+extern crate bytes;
 #[macro_use]
 extern crate cocaine;
 extern crate futures;
+extern crate hmac;
 #[macro_use]
 extern crate hyper;
 extern crate itertools;
 extern crate jsonrpc_core;
+#[cfg(feature = "kafka")]
+extern crate kafka;
 extern crate libc;
+extern crate md5;
 extern crate net2;
 extern crate num_cpus;
 extern crate rand;
 extern crate regex;
 extern crate rmp;
 extern crate rmp_serde as rmps;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate serde_yaml;
+extern crate sha1;
+extern crate sha2;
 extern crate time;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_rustls;
 extern crate tokio_service;
 extern crate uuid;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error;
+use std::fmt::{self, Display, Formatter};
 use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use futures::Future;
-use futures::sync::mpsc;
-use serde::Serializer;
+use futures::{future, Future};
+use futures::sync::{mpsc, oneshot};
+use regex::Regex;
+use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
 
-use cocaine::{Core, ServiceBuilder};
-use cocaine::logging::Severity;
+use cocaine::{Core, Service, ServiceBuilder};
+use cocaine::logging::{Logger, Severity};
 use cocaine::service::{Locator, Tvm, Unicorn};
 use cocaine::service::tvm::Grant;
 
+pub use self::builder::ProxyBuilder;
 pub use self::config::Config;
-use self::logging::Loggers;
-use self::metrics::{Count, Counter, Meter, RateMeter};
-use self::pool::{Event, EventDispatch, RoutingGroupsAction, SubscribeAction, TicketFactory};
+pub use self::lifecycle::{Lifecycle, Phase, Shutdown};
+pub use self::logging::{AccessRecord, AccessSink, Timings};
+pub use self::net::Endpoint;
+use self::logging::{AccessFormat, AccessQueue, AccessSampler, AccessSinks, AuditLog, Loggers, QueueStats, RecentErrors, Redactor, RequestMirror, SinkStats};
+#[cfg(feature = "kafka")]
+use self::logging::KafkaSink;
+use self::memory::MemoryBudget;
+use self::metrics::{Count, Counter, Histogram, Meter, RateMeter, Slo, SloSummary};
+use self::pool::{BreakerStats, CircuitBreakers, Event, EventDispatch, PoolStats, RoutingGroupsAction,
+    Settings, SettingsChange, SettingsRegistry, SubscribeAction, TicketFactory};
 use self::retry::Retry;
-use self::route::{AppRoute, JsonRpc, PerfRoute, Router};
-use self::server::{ServerConfig, ServerGroup};
+use self::route::{AppRoute, BodyFilter, Canaries, CanaryStats, ErrorStatuses, Failure, HeaderSigner, JsonRpc, LocalUpstream, Peers, PerfRoute, Priorities, Quota, RetryBudget, Router, RoutingTable, Rules, SseRoute, Standby, StandbyRoute, Tenant, Tenants, Via, VirtualHosts, WebSocketRoute};
+use self::server::{Certificates, ServerConfig, ServerGroup};
 use self::service::cocaine::ProxyServiceFactoryFactory;
 use self::service::monitor::MonitorServiceFactoryFactory;
 
-mod common;
+mod builder;
+pub mod common;
 mod config;
+mod lifecycle;
 mod logging;
+mod memory;
 mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
 mod net;
 mod pool;
+mod random;
 mod retry;
 pub mod route;
 mod server;
 mod service;
 
 const DEFAULT_LOCATOR_NAME: &str = "locator";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 const THREAD_NAME_PERIODIC: &str = "periodic";
 
+/// Build information, allowing automation to verify binary capabilities before rollout.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    version: &'static str,
+    /// Commit the binary has been built from, `unknown` for builds outside of the repository.
+    commit: &'static str,
+    rustc: &'static str,
+    features: Vec<&'static str>,
+    config_schema: u32,
+}
+
+impl VersionInfo {
+    pub fn new() -> Self {
+        let mut features = Vec::new();
+        if cfg!(feature = "kafka") {
+            features.push("kafka");
+        }
+        if cfg!(feature = "mock") {
+            features.push("mock");
+        }
+        if cfg!(feature = "serde-headers") {
+            features.push("serde-headers");
+        }
+        if cfg!(feature = "chaos") {
+            features.push("chaos");
+        }
+
+        Self {
+            version: VERSION,
+            commit: env!("BUILD_GIT_COMMIT"),
+            rustc: env!("BUILD_RUSTC_VERSION"),
+            features: features,
+            config_schema: config::SCHEMA_VERSION,
+        }
+    }
+
+    /// Returns JSON representation of the build information.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed to serialize version info")
+    }
+}
+
+impl Default for VersionInfo {
+    fn default() -> Self {
+        VersionInfo::new()
+    }
+}
+
+impl Display for VersionInfo {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{} (commit {}, {}, config schema {})", self.version, self.commit, self.rustc, self.config_schema)?;
+        if !self.features.is_empty() {
+            write!(fmt, ", features: {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default, Serialize)]
 struct ConnectionMetrics {
     #[serde(serialize_with = "serialize_counter")]
@@ -98,10 +184,316 @@ where
     map.end()
 }
 
+fn serialize_memory<S>(budget: &Arc<MemoryBudget>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(3))?;
+    map.serialize_key("used")?;
+    map.serialize_value(&budget.used())?;
+    map.serialize_key("soft_limit")?;
+    map.serialize_value(&budget.soft_limit())?;
+    map.serialize_key("pauses")?;
+    map.serialize_value(&budget.pauses())?;
+    map.end()
+}
+
+fn serialize_queue<S>(stats: &Arc<QueueStats>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(7))?;
+    map.serialize_key("queued")?;
+    map.serialize_value(&stats.queued())?;
+    map.serialize_key("flushed")?;
+    map.serialize_value(&stats.flushed())?;
+    map.serialize_key("dropped")?;
+    map.serialize_value(&stats.dropped())?;
+    map.serialize_key("skipped")?;
+    map.serialize_value(&stats.skipped())?;
+    map.serialize_key("disconnected")?;
+    map.serialize_value(&stats.disconnected())?;
+    map.serialize_key("outages")?;
+    map.serialize_value(&stats.outages())?;
+    map.serialize_key("reconnects")?;
+    map.serialize_value(&stats.reconnects())?;
+    map.end()
+}
+
+fn serialize_sink<S>(stats: &Arc<SinkStats>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(2))?;
+    map.serialize_key("dropped")?;
+    map.serialize_value(&stats.dropped())?;
+    map.serialize_key("failed")?;
+    map.serialize_value(&stats.failed())?;
+    map.end()
+}
+
+fn serialize_canaries<S>(stats: &Arc<CanaryStats>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(3))?;
+    map.serialize_key("weights")?;
+    map.serialize_value(&stats.weights().into_iter().collect::<BTreeMap<_, _>>())?;
+    map.serialize_key("promoted")?;
+    map.serialize_value(&stats.promoted())?;
+    map.serialize_key("rolled_back")?;
+    map.serialize_value(&stats.rolled_back())?;
+    map.end()
+}
+
+fn serialize_breakers<S>(stats: &Arc<BreakerStats>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(4))?;
+    map.serialize_key("opened")?;
+    map.serialize_value(&stats.opened())?;
+    map.serialize_key("half_opened")?;
+    map.serialize_value(&stats.half_opened())?;
+    map.serialize_key("closed")?;
+    map.serialize_value(&stats.closed())?;
+    map.serialize_key("rejected")?;
+    map.serialize_value(&stats.rejected())?;
+    map.end()
+}
+
+fn serialize_histogram<S>(histogram: &Histogram, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(3))?;
+    map.serialize_key("count")?;
+    map.serialize_value(&histogram.count())?;
+    map.serialize_key("sum")?;
+    map.serialize_value(&histogram.sum())?;
+    map.serialize_key("buckets")?;
+    map.serialize_value(&histogram.buckets())?;
+    map.end()
+}
+
+fn serialize_percentiles<S>(histogram: &Histogram, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let mut map = se.serialize_map(Some(4))?;
+    map.serialize_key("count")?;
+    map.serialize_value(&histogram.count())?;
+    for &(name, q) in LATENCY_QUANTILES {
+        map.serialize_key(name)?;
+        map.serialize_value(&histogram.quantile(q))?;
+    }
+    map.end()
+}
+
+fn serialize_slos<S>(slos: &HashMap<String, Arc<Slo>>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let summaries = slos.iter()
+        .map(|(service, slo)| (service, slo.summary()))
+        .collect::<BTreeMap<_, _>>();
+    summaries.serialize(se)
+}
+
+fn serialize_pools<S>(stats: &Arc<PoolStats>, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let services = stats.services();
+    let mut map = se.serialize_map(Some(services.len()))?;
+    for (name, stats) in services {
+        let mut service = HashMap::new();
+        service.insert("invocations", stats.invocations());
+        service.insert("reconnects", stats.reconnects());
+        service.insert("held_resolves", stats.held_resolves());
+        service.insert("cached_resolves", stats.cached_resolves());
+        service.insert("overflows", stats.overflows());
+        service.insert("dual_stack_v4", stats.dual_stack_v4());
+        service.insert("dual_stack_v6", stats.dual_stack_v6());
+        map.serialize_key(&name)?;
+        map.serialize_value(&service)?;
+    }
+    map.end()
+}
+
+/// Number of responses for each status code in [100; 600) range.
+#[derive(Debug)]
+struct StatusCounts {
+    counts: Vec<Counter>,
+}
+
+impl StatusCounts {
+    fn mark(&self, code: u16) {
+        if let Some(counter) = code.checked_sub(100).and_then(|idx| self.counts.get(idx as usize)) {
+            counter.add(1);
+        }
+    }
+
+    /// Returns codes, which have been seen at least once, with their counts.
+    fn counts(&self) -> Vec<(u16, i64)> {
+        self.counts.iter()
+            .enumerate()
+            .map(|(idx, counter)| (idx as u16 + 100, counter.get()))
+            .filter(|&(.., count)| count > 0)
+            .collect()
+    }
+}
+
+impl Default for StatusCounts {
+    fn default() -> Self {
+        Self {
+            counts: (100..600).map(|_| Counter::default()).collect(),
+        }
+    }
+}
+
+fn serialize_status_counts<S>(codes: &StatusCounts, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let counts = codes.counts();
+    let mut map = se.serialize_map(Some(counts.len()))?;
+    for (code, count) in counts {
+        map.serialize_key(&code.to_string())?;
+        map.serialize_value(&count)?;
+    }
+    map.end()
+}
+
 #[derive(Debug, Default, Serialize)]
 struct ResponseMetrics {
+    #[serde(serialize_with = "serialize_meter")]
+    c2xx: RateMeter,
+    #[serde(serialize_with = "serialize_meter")]
+    c3xx: RateMeter,
+    #[serde(serialize_with = "serialize_meter")]
+    c4xx: RateMeter,
     #[serde(serialize_with = "serialize_meter")]
     c5xx: RateMeter,
+    #[serde(serialize_with = "serialize_status_counts")]
+    codes: StatusCounts,
+}
+
+impl ResponseMetrics {
+    /// Marks a response with the given status in both its class and its code counters.
+    fn mark(&self, status: hyper::StatusCode) {
+        let code = u16::from(status);
+        self.codes.mark(code);
+
+        match code / 100 {
+            2 => self.c2xx.mark(1),
+            3 => self.c3xx.mark(1),
+            4 => self.c4xx.mark(1),
+            5 => self.c5xx.mark(1),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct TenantMetrics {
+    #[serde(serialize_with = "serialize_meter")]
+    requests: RateMeter,
+    #[serde(serialize_with = "serialize_meter")]
+    rejected: RateMeter,
+    responses: ResponseMetrics,
+}
+
+/// Stalls of response bodies caused by clients that don't keep up with writing them.
+#[derive(Debug, Default, Serialize)]
+struct StallMetrics {
+    #[serde(serialize_with = "serialize_meter")]
+    stalls: RateMeter,
+    /// Total time in microseconds spent waiting for clients to drain previous slices or chunks.
+    #[serde(serialize_with = "serialize_counter")]
+    time: Counter,
+    /// Responses aborted because clients haven't drained the streaming window.
+    #[serde(serialize_with = "serialize_meter")]
+    overflows: RateMeter,
+}
+
+impl StallMetrics {
+    fn mark(&self, duration: Duration) {
+        let micros = duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64;
+        self.stalls.mark(1);
+        self.time.add(micros as i64);
+    }
+
+    fn mark_overflow(&self) {
+        self.overflows.mark(1);
+    }
+}
+
+/// Quantiles of latency distributions exposed with their names.
+const LATENCY_QUANTILES: &[(&str, f64)] = &[("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+
+/// Latency distributions in seconds with bounded relative error from 100us up to a minute.
+#[derive(Debug, Serialize)]
+struct LatencyMetrics {
+    /// Time from receiving requests to having their responses ready, measured for all routes.
+    #[serde(serialize_with = "serialize_percentiles")]
+    total: Histogram,
+    /// Time spent in Cocaine calls of application routes, spanning all retry attempts.
+    #[serde(serialize_with = "serialize_percentiles")]
+    upstream: Histogram,
+}
+
+impl Default for LatencyMetrics {
+    fn default() -> Self {
+        Self {
+            total: Histogram::exponential(1e-4, 60.0, 20),
+            upstream: Histogram::exponential(1e-4, 60.0, 20),
+        }
+    }
+}
+
+/// Counters of requests failed upstream by the failure kind.
+#[derive(Debug, Default)]
+struct FailureMetrics {
+    kinds: RwLock<HashMap<String, AtomicUsize>>,
+}
+
+impl FailureMetrics {
+    fn mark(&self, failure: Failure) {
+        let kind = failure.to_string();
+        if let Some(count) = self.kinds.read().unwrap().get(&kind) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        self.kinds.write().unwrap()
+            .entry(kind)
+            .or_insert_with(Default::default)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns counters of all failure kinds seen so far, ordered by kind.
+    fn counts(&self) -> Vec<(String, usize)> {
+        let mut counts = self.kinds.read().unwrap()
+            .iter()
+            .map(|(kind, count)| (kind.clone(), count.load(Ordering::Relaxed)))
+            .collect::<Vec<_>>();
+        counts.sort();
+        counts
+    }
+}
+
+fn serialize_failures<S>(failures: &FailureMetrics, se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer
+{
+    let counts = failures.counts();
+    let mut map = se.serialize_map(Some(counts.len()))?;
+    for (kind, count) in counts {
+        map.serialize_key(&kind)?;
+        map.serialize_value(&count)?;
+    }
+    map.end()
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -110,34 +502,381 @@ pub struct Metrics {
     #[serde(serialize_with = "serialize_meter")]
     requests: RateMeter,
     responses: ResponseMetrics,
+    /// Requests whose clients went away before the response was ready.
+    #[serde(serialize_with = "serialize_meter")]
+    aborted: RateMeter,
+    /// Responses discarded because their bodies exceed the configured limit.
+    #[serde(serialize_with = "serialize_meter")]
+    oversized: RateMeter,
+    /// Requests shed by services pools because of their backlog.
+    #[serde(serialize_with = "serialize_meter")]
+    shed: RateMeter,
+    /// Requests dropped from services pools queues, because nobody waited for them anymore.
+    #[serde(serialize_with = "serialize_meter")]
+    canceled: RateMeter,
+    /// Responses rejected because applications have violated the response protocol.
+    #[serde(serialize_with = "serialize_meter")]
+    protocol_violations: RateMeter,
+    /// Responses rejected because their status or headers frame exceeded the size limit.
+    #[serde(serialize_with = "serialize_meter")]
+    oversized_meta: RateMeter,
+    /// Hedged attempts issued and ones that have responded before the attempts they raced.
+    #[serde(serialize_with = "serialize_meter")]
+    hedged: RateMeter,
+    #[serde(serialize_with = "serialize_meter")]
+    hedges_won: RateMeter,
+    /// Retries suppressed because of exhausted retry budgets.
+    #[serde(serialize_with = "serialize_meter")]
+    retries_suppressed: RateMeter,
+    /// Requests rejected because of their headers count or size.
+    #[serde(serialize_with = "serialize_meter")]
+    headers_rejected: RateMeter,
+    /// Requests forwarded into peer proxies because of open circuit breakers.
+    #[serde(serialize_with = "serialize_meter")]
+    peer_forwarded: RateMeter,
+    /// Requests failed upstream by the failure kind.
+    #[serde(serialize_with = "serialize_failures")]
+    failures: FailureMetrics,
+    /// Memory occupied by buffered request bodies.
+    #[serde(serialize_with = "serialize_memory")]
+    memory: Arc<MemoryBudget>,
+    /// Access records waiting to be logged.
+    #[serde(serialize_with = "serialize_queue")]
+    access_log: Arc<QueueStats>,
+    /// Access records of the configured sink, like Kafka.
+    #[serde(serialize_with = "serialize_sink")]
+    access_sink: Arc<SinkStats>,
+    /// Circuit breaker state transitions of all clusters.
+    #[serde(serialize_with = "serialize_breakers")]
+    circuit_breakers: Arc<BreakerStats>,
+    /// Canary ramp weights and transitions.
+    #[serde(serialize_with = "serialize_canaries")]
+    canaries: Arc<CanaryStats>,
+    /// Time from receiving requests to having their responses ready, in seconds.
+    #[serde(serialize_with = "serialize_histogram")]
+    durations: Histogram,
+    latency: LatencyMetrics,
+    /// Per-service counters of services pools.
+    #[serde(serialize_with = "serialize_pools")]
+    pools: Arc<PoolStats>,
+    tenants: HashMap<String, TenantMetrics>,
+    /// Client write stalls for each service with sliced or streamed response bodies.
+    stalls: HashMap<String, Arc<StallMetrics>>,
+    /// Rolling compliance with service level objectives for each service having them.
+    #[serde(serialize_with = "serialize_slos")]
+    slos: HashMap<String, Arc<Slo>>,
+    /// Recent errors generated by the proxy, exposed through the monitoring server separately.
+    #[serde(skip)]
+    recent_errors: Arc<RecentErrors>,
+}
+
+impl Metrics {
+    /// Constructs metrics with per-tenant sections for each tenant configured.
+    fn new(config: &Config) -> Self {
+        let tenants = config.tenants()
+            .iter()
+            .map(|tenant| (tenant.name().to_owned(), TenantMetrics::default()))
+            .collect();
+
+        let memory = config.memory()
+            .map(MemoryBudget::from)
+            .unwrap_or_default();
+
+        let streamed = config.streaming()
+            .iter()
+            .filter(|&(.., cfg)| cfg.response())
+            .map(|(service, ..)| service);
+
+        let stalls = config.response_slices()
+            .keys()
+            .chain(streamed)
+            .map(|service| (service.clone(), Arc::new(StallMetrics::default())))
+            .collect();
+
+        let slos = config.slos()
+            .iter()
+            .map(|(service, cfg)| (service.clone(), Arc::new(Slo::new(*cfg))))
+            .collect();
+
+        Self {
+            memory: Arc::new(memory),
+            tenants: tenants,
+            stalls: stalls,
+            slos: slos,
+            recent_errors: Arc::new(RecentErrors::new(config.monitoring().recent_errors())),
+            ..Default::default()
+        }
+    }
+
+    /// Marks a request, which was routed into the given tenant.
+    fn mark_tenant(&self, tenant: &str, status: hyper::StatusCode) {
+        if let Some(metrics) = self.tenants.get(tenant) {
+            metrics.requests.mark(1);
+            metrics.responses.mark(status);
+        }
+    }
+
+    /// Records the time it took to prepare a response.
+    fn observe_duration(&self, duration: Duration) {
+        self.durations.observe(duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+    }
+
+    /// Records the total time it took to process a request.
+    fn observe_latency(&self, duration: Duration) {
+        self.latency.total.observe(duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+    }
+
+    /// Records the time spent in a Cocaine call.
+    fn observe_upstream(&self, duration: Duration) {
+        self.latency.upstream.observe(duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+    }
+
+    /// Marks a request, which was aborted by the client.
+    fn mark_aborted(&self) {
+        self.aborted.mark(1);
+    }
+
+    /// Marks a response, which was discarded because of its body size.
+    fn mark_oversized(&self) {
+        self.oversized.mark(1);
+    }
+
+    /// Marks a request, which was shed because of a pool backlog.
+    fn mark_shed(&self) {
+        self.shed.mark(1);
+    }
+
+    /// Marks a request, which was dropped from a pool queue after being abandoned.
+    fn mark_canceled(&self) {
+        self.canceled.mark(1);
+    }
+
+    /// Marks a response, which violated the application protocol.
+    fn mark_protocol_violation(&self) {
+        self.protocol_violations.mark(1);
+    }
+
+    /// Marks a response, whose status or headers frame was too large to be parsed.
+    fn mark_oversized_meta(&self) {
+        self.oversized_meta.mark(1);
+    }
+
+    /// Marks a hedged attempt.
+    fn mark_hedged(&self) {
+        self.hedged.mark(1);
+    }
+
+    /// Marks a hedged attempt, which has responded first.
+    fn mark_hedge_won(&self) {
+        self.hedges_won.mark(1);
+    }
+
+    /// Marks a retry, which was suppressed by the retry budget.
+    fn mark_retry_suppressed(&self) {
+        self.retries_suppressed.mark(1);
+    }
+
+    /// Marks a request, which was rejected because of its headers.
+    fn mark_headers_rejected(&self) {
+        self.headers_rejected.mark(1);
+    }
+
+    /// Marks a request, which was forwarded into a peer proxy.
+    fn mark_peer_forwarded(&self) {
+        self.peer_forwarded.mark(1);
+    }
+
+    /// Marks a request, which has failed upstream.
+    fn mark_failure(&self, failure: Failure) {
+        self.failures.mark(failure);
+    }
+
+    /// Returns the objectives tracker of the given service, if it has objectives.
+    fn slo(&self, service: &str) -> Option<Arc<Slo>> {
+        self.slos.get(service).cloned()
+    }
+
+    /// Returns the current compliance of all services with objectives, ordered by their names.
+    fn slo_summaries(&self) -> BTreeMap<String, SloSummary> {
+        self.slos.iter()
+            .map(|(service, slo)| (service.clone(), slo.summary()))
+            .collect()
+    }
+
+    /// Returns client write stall metrics of the given service, if it has any.
+    fn stalls(&self, service: &str) -> Option<Arc<StallMetrics>> {
+        self.stalls.get(service).cloned()
+    }
+
+    /// Marks a request, which was rejected with the given status because of the tenant's quota.
+    fn mark_tenant_rejected(&self, tenant: &str, status: hyper::StatusCode) {
+        if let Some(metrics) = self.tenants.get(tenant) {
+            metrics.rejected.mark(1);
+            metrics.responses.mark(status);
+        }
+    }
+}
+
+/// A Cocaine installation with its own locators and services pools.
+struct Cluster {
+    name: Option<String>,
+    config: Config,
+    dispatch: EventDispatch,
+    rxs: Vec<mpsc::UnboundedReceiver<Event>>,
+}
+
+impl Cluster {
+    fn new(name: Option<String>, config: Config, breakers: &Arc<BreakerStats>) -> Self {
+        // Here we create several event channels that will deliver control events to services
+        // pools. We could create a separate thread pool for processing Cocaine invocation events
+        // with their own event loops, but it appeared that having common thread pool with both
+        // HTTP events and Cocaine one gives more RPS with lower latency.
+        let (txs, rxs): (Vec<_>, Vec<_>) = itertools::repeat_call(|| mpsc::unbounded())
+            .take(config.threads())
+            .unzip();
+
+        // Each cluster has its own breakers, since the same service name means a different service
+        // in each of them.
+        let dispatch = match config.circuit_breaker() {
+            Some(cfg) => EventDispatch::new(txs).with_breakers(Arc::new(CircuitBreakers::new(*cfg, breakers.clone()))),
+            None => EventDispatch::new(txs),
+        };
+
+        Self {
+            name: name,
+            config: config,
+            dispatch: dispatch,
+            rxs: rxs,
+        }
+    }
+
+    fn locator_addrs(&self) -> Vec<SocketAddr> {
+        self.config.locators()
+            .iter()
+            .map(|&(addr, port)| SocketAddr::new(addr, port))
+            .collect()
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn make_access_sink(config: &Config, logging: &Loggers, stats: Arc<SinkStats>) -> Result<Option<Arc<dyn AccessSink>>, io::Error> {
+    match config.logging().kafka() {
+        Some(cfg) => {
+            let sink = KafkaSink::new(cfg, logging.common().logger().clone(), stats)?;
+            cocaine_log!(logging.common().logger(), Severity::Info, "enabled Kafka access log sink into `{}` topic", cfg.topic());
+            Ok(Some(Arc::new(sink)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+fn make_access_sink(_config: &Config, _logging: &Loggers, _stats: Arc<SinkStats>) -> Result<Option<Arc<dyn AccessSink>>, io::Error> {
+    Ok(None)
+}
+
+fn log_settings_changes(log: &Logger, changes: Vec<SettingsChange>) {
+    for change in changes {
+        cocaine_log!(log, Severity::Info, "settings changed: {}", change; {
+            service: change.service.as_str(),
+            setting: change.name,
+            old: change.old.map(|v| v.to_string()).unwrap_or_default(),
+            new: change.new.map(|v| v.to_string()).unwrap_or_default(),
+            version: change.version,
+        });
+    }
 }
 
 pub fn run(config: Config) -> Result<(), Box<dyn error::Error>> {
-    let locator_addrs = config.locators()
-        .iter()
-        .map(|&(addr, port)| SocketAddr::new(addr, port))
-        .collect::<Vec<SocketAddr>>();
+    run_with(config, Lifecycle::new())
+}
+
+/// Runs the proxy until the shutdown is requested through the given lifecycle, calling its hooks
+/// along with the configured ones.
+pub fn run_with(config: Config, lifecycle: Lifecycle) -> Result<(), Box<dyn error::Error>> {
+    ProxyBuilder::new(config).lifecycle(lifecycle).run()
+}
+
+fn serve(builder: ProxyBuilder) -> Result<(), Box<dyn error::Error>> {
+    let ProxyBuilder { config, lifecycle, addr, routes, mut sinks, metrics } = builder;
+    let (hooks, shutdown) = lifecycle.with_config(config.hooks().clone()).split();
 
     let logging = Loggers::from(config.logging());
-    let metrics = Arc::new(Metrics::default());
+    let addr = addr.unwrap_or_else(|| config.network().addr().into());
 
+    cocaine_log!(logging.common().logger(), Severity::Info, "starting Cocaine HTTP Proxy {}", VersionInfo::new());
     cocaine_log!(logging.common().logger(), Severity::Debug, "starting Cocaine HTTP Proxy with {:?}", config);
 
-    // Here we create several event channels that will deliver control events to services pools.
-    // We could create a separate thread pool for processing Cocaine invocation events with their
-    // own event loops, but it appeared that having common thread pool with both HTTP events and
-    // Cocaine one gives more RPS with lower latency.
-    let (txs, rxs): (Vec<_>, Vec<_>) = itertools::repeat_call(|| mpsc::unbounded())
-        .take(config.threads())
-        .unzip();
+    for warning in config.warnings() {
+        cocaine_log!(logging.common().logger(), Severity::Warn, "{}", warning);
+    }
+
+    // Sinks added by the embedder follow the configured one.
+    if let Some(sink) = make_access_sink(&config, &logging, metrics.access_sink.clone())? {
+        sinks.insert(0, sink);
+    }
+    let access_sink = match sinks.len() {
+        0 => None,
+        1 => sinks.pop(),
+        _ => Some(Arc::new(AccessSinks(sinks)) as Arc<dyn AccessSink>),
+    };
+    let redactor = Arc::new(Redactor::from(config.redaction()));
+    let access_format = Arc::new(AccessFormat::new(config.logging().access_format(), redactor.clone()));
+
+    // The default cluster goes first, followed by tenants in the order they are configured.
+    let mut clusters = vec![Cluster::new(None, config.clone(), &metrics.circuit_breakers)];
+    for tenant in config.tenants() {
+        clusters.push(Cluster::new(Some(tenant.name().to_owned()), config.for_tenant(tenant),
+            &metrics.circuit_breakers));
+    }
+
+    let access_queue = AccessQueue::new(config.logging().access_queue(), access_format.clone(),
+        logging.access().logger().clone(), config.logging().access().name().to_owned(), clusters[0].locator_addrs(),
+        metrics.access_log.clone())?;
+
+    let dispatch = clusters[0].dispatch.clone();
 
-    let dispatch = EventDispatch::new(txs);
+    // Load tests go through their own pools, so they can't exhaust ones serving live traffic.
+    let perf_dispatch = match config.for_load_testing() {
+        Some(cfg) => {
+            let cluster = Cluster::new(Some("load testing".into()), cfg, &metrics.circuit_breakers);
+            let dispatch = cluster.dispatch.clone();
+            clusters.push(cluster);
+            dispatch
+        }
+        None => dispatch.clone(),
+    };
+
+    // Per-service settings are shared between all clusters and workers.
+    let settings = Arc::new(SettingsRegistry::new(config.tracing().probability()));
+
+    // Events are queued until pools are spawned by workers, connecting each of them then.
+    for name in config.warmup() {
+        dispatch.send_all(|| Event::Service {
+            name: name.clone(),
+            func: Box::new(|service: &Service, _settings: Settings| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                Box::new(service.connect().then(|_| Ok(())))
+            }),
+        });
+    }
+
+    let standby = Standby::new(config.standby().is_none());
+    if let Some(cfg) = config.standby() {
+        cocaine_log!(logging.common().logger(), Severity::Info, "starting in warm standby with {} warmed services", cfg.warm().len());
+    }
 
     // Start all periodic jobs in a separate thread that will produce control events for pools.
+    // They are stopped once the sender is dropped after all servers have been drained.
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
     let thread: JoinHandle<Result<(), io::Error>> = {
         let cfg = config.clone();
+        let locator_addrs = clusters[0].locator_addrs();
         let log = logging.common().logger().clone();
-        let dispatch = dispatch.clone();
+        let routing = clusters.iter()
+            .map(|cluster| (cluster.locator_addrs(), cluster.dispatch.clone()))
+            .collect::<Vec<_>>();
+        let settings = settings.clone();
+        let standby = standby.clone();
         thread::Builder::new().name(THREAD_NAME_PERIODIC.into()).spawn(move || {
             let mut core = Core::new()?;
 
@@ -145,27 +884,27 @@ pub fn run(config: Config) -> Result<(), Box<dyn error::Error>> {
                 .locator_addrs(locator_addrs.clone())
                 .build(&core.handle());
 
-            let locator = ServiceBuilder::new(DEFAULT_LOCATOR_NAME)
-                .locator_addrs(locator_addrs.clone())
-                .build(&core.handle());
-
             let unicorn = ServiceBuilder::new(cfg.unicorn().to_owned())
                 .locator_addrs(locator_addrs)
                 .build(&core.handle());
 
             let exponential_backoff = |v| Duration::from_secs(2u64.pow(std::cmp::min(6, v)));
 
-            let groups = {
-                let action = RoutingGroupsAction::new(Locator::new(locator), dispatch.clone(), log.clone());
+            // Each cluster has its own routing groups, because they are stored in its own Unicorn.
+            let groups = routing.into_iter().map(|(addrs, dispatch)| {
+                let locator = ServiceBuilder::new(DEFAULT_LOCATOR_NAME)
+                    .locator_addrs(addrs)
+                    .build(&core.handle());
+                let action = RoutingGroupsAction::new(Locator::new(locator), dispatch, log.clone());
                 Retry::new(action, (0..).map(&exponential_backoff), core.handle())
-            };
+            }).collect::<Vec<_>>();
 
             let on_tracing = {
                 let log = log.clone();
-                let dispatch = dispatch.clone();
+                let settings = settings.clone();
                 move |tracing: HashMap<String, f64>| {
                     cocaine_log!(log, Severity::Info, "updated tracing config with {} entries", tracing.len());
-                    dispatch.send_all(|| Event::OnTracingUpdates(tracing.clone()));
+                    log_settings_changes(&log, settings.update_tracing(tracing));
                 }
             };
 
@@ -189,16 +928,43 @@ pub fn run(config: Config) -> Result<(), Box<dyn error::Error>> {
 
             let on_timeouts = {
                 let log = log.clone();
-                let dispatch = dispatch.clone();
+                let settings = settings.clone();
                 move |timeouts: HashMap<String, f64>| {
                     cocaine_log!(log, Severity::Info, "updated timeout config with {} entries", timeouts.len());
-                    dispatch.send_all(|| Event::OnTimeoutUpdates(timeouts.clone()));
+                    log_settings_changes(&log, settings.update_timeouts(timeouts));
                 }
             };
 
+            let on_standby = {
+                let log = log.clone();
+                move |values: HashMap<String, f64>| {
+                    let raised = values.get("active").map(|&value| value >= 1.0).unwrap_or(false);
+                    if raised && standby.activate() {
+                        cocaine_log!(log, Severity::Info, "promoted out of warm standby by the Unicorn flag");
+                    }
+                }
+            };
+
+            // Without the path the proxy is either active from the start or promoted manually.
+            let promotion: Box<dyn Future<Item = (), Error = io::Error>> = match cfg.standby().and_then(|cfg| cfg.path()) {
+                Some(path) => {
+                    let action = SubscribeAction::new(
+                        path.into(),
+                        tm.clone(),
+                        Unicorn::new(unicorn.clone()),
+                        &on_standby,
+                        log.clone()
+                    );
+                    let future = Retry::new(action, (0..).map(&exponential_backoff), core.handle())
+                        .map_err(|err| io::Error::new(ErrorKind::Other, err.to_string()));
+                    Box::new(future)
+                }
+                None => Box::new(future::empty()),
+            };
+
             let timeouts = {
                 let action = SubscribeAction::new(
-                    cfg.timeouts().path().into(),
+                    cfg.service_timeouts().path().into(),
                     tm,
                     Unicorn::new(unicorn.clone()),
                     &on_timeouts,
@@ -207,64 +973,227 @@ pub fn run(config: Config) -> Result<(), Box<dyn error::Error>> {
                 Retry::new(action, (0..).map(&exponential_backoff), core.handle())
             };
 
-            core.run(groups.join3(tracing, timeouts))
-                .map_err(|err| io::Error::new(ErrorKind::Other, err.to_string()))?;
+            let jobs = future::join_all(groups).join3(tracing, timeouts)
+                .map(drop)
+                .map_err(|err| io::Error::new(ErrorKind::Other, err.to_string()))
+                .join(promotion)
+                .map(drop);
+            let stop = stop_rx.then(|_| Ok::<(), io::Error>(()));
+
+            core.run(jobs.select(stop)).map_err(|(err, ..)| err)?;
 
             Ok(())
         })?
     };
 
-    let mut router = Router::new();
-    router.add(Arc::new(AppRoute::new(dispatch.clone(), logging.access().logger().clone())
+    let mut app = AppRoute::new(dispatch.clone(), metrics.clone(), logging.access().logger().clone())
         .with_tracing_header(config.tracing().header().to_owned())
         .with_headers_mapping(config.headers().clone())
-    ));
-    router.add(Arc::new(JsonRpc::new(dispatch.clone(), logging.access().logger().clone())));
+        .with_timeout(config.timeout())
+        .with_queue_budget(config.queue_budget())
+        .with_max_body_size(config.max_body_size())
+        .with_prefix(config.prefix().map(|prefix| prefix.to_owned()))
+        .with_normalization(config.normalization().cloned())
+        .with_routing_table(RoutingTable::new(config.routes()).expect("routing table patterns must be validated during config sanitizing"))
+        .with_virtual_hosts(VirtualHosts::new(config.virtual_hosts()).expect("virtual host patterns must be validated during config sanitizing"))
+        .with_signer(config.signing().map(HeaderSigner::from))
+        .with_tls_forward(config.network().forward().to_vec())
+        .with_status_rewrites(config.rewrites().clone())
+        .with_body_filters(config.filters().iter()
+            .map(|(service, filters)| {
+                let filters = filters.iter()
+                    .map(|cfg| BodyFilter::new(cfg).expect("body filter patterns must be validated during config sanitizing"))
+                    .collect();
+                (service.clone(), filters)
+            })
+            .collect())
+        .with_protocols(config.protocols().clone())
+        .with_streaming(config.streaming().clone())
+        .with_codecs(config.codecs().clone())
+        .with_response_limits(config.response_limits().clone())
+        .with_response_slices(config.response_slices().clone())
+        .with_response_timeouts(config.response_timeouts())
+        .with_early_hints(config.early_hints().clone())
+        .with_hedging(config.hedging())
+        .with_local_upstreams(config.local_upstreams().iter()
+            .map(|(service, endpoint)| (service.clone(), LocalUpstream::new(endpoint.clone())))
+            .collect())
+        .with_retry_overrides(config.retry_overrides().clone())
+        .with_retriable_errors(config.retriable_errors().to_vec())
+        .with_error_statuses(ErrorStatuses::new(config.error_statuses().to_vec()))
+        .with_retry_backoff(*config.retry_backoff())
+        .with_retry_budget(config.retry_budget().cloned().map(RetryBudget::new))
+        .with_default_events(config.default_events().clone())
+        .with_via(config.via().map(Via::from))
+        .with_peers(config.peers().map(|cfg| Peers::new(cfg.endpoints())))
+        .with_priorities(config.pool().priorities().map(Priorities::from))
+        .with_digest(config.digest())
+        .with_error_origins(config.error_origins().clone())
+        .with_request_headers_limit(config.request_headers().cloned())
+        .with_request_deadline(config.request_deadline().cloned())
+        .with_response_headers_limit(*config.response_headers())
+        .with_rules(Rules::new(config.rules(), config.timezone()))
+        .with_canaries(Canaries::new(config.canaries(), metrics.canaries.clone()))
+        .with_access_sink(access_sink)
+        .with_access_queue(Some(Arc::new(access_queue)))
+        .with_access_sampler(config.logging().access_sampling().map(|cfg| AccessSampler::new(cfg, metrics.access_log.clone())));
+
+    let mut tenants = Tenants::new();
+    for (tenant, cluster) in config.tenants().iter().zip(&clusters[1..]) {
+        let hosts = Regex::new(tenant.hosts()).expect("hosts pattern must be validated during config sanitizing");
+        let mut route = Tenant::new(tenant.name().to_owned(), hosts, cluster.dispatch.clone())
+            .with_headers_mapping(cluster.config.headers().clone());
+        if let Some(quota) = tenant.quota() {
+            route = route.with_quota(Quota::from(quota));
+        }
+        tenants.add(route);
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled `{}` tenant for `{}` hosts", tenant.name(), tenant.hosts());
+    }
+
+    let tenants = Arc::new(tenants);
+    app = app.with_tenants(tenants.clone()).with_access_format(access_format);
+    if let Some(cfg) = config.peers() {
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled forwarding into {} peer proxies", cfg.endpoints().len());
+    }
+    if let Some(cfg) = config.mirroring() {
+        app = app.with_mirror(RequestMirror::from(cfg).with_redactor(redactor));
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled request mirroring into `{}` service", cfg.name());
+    }
+
+    let mut router = Router::new().with_tenants(tenants.clone());
+    // Nothing is served until the proxy is promoted out of standby.
+    if config.standby().is_some() {
+        router.add(Arc::new(StandbyRoute::new(standby.clone())));
+    }
+    // Upgrade requests would be served as regular ones otherwise.
+    if let Some(cfg) = config.websocket() {
+        router.add(Arc::new(WebSocketRoute::new(dispatch.clone(), cfg, logging.access().logger().clone()).with_tenants(tenants.clone())));
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled WebSocket proxying");
+    }
+    if let Some(cfg) = config.sse() {
+        router.add(Arc::new(SseRoute::new(dispatch.clone(), cfg, logging.access().logger().clone()).with_tenants(tenants.clone())));
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled Server-Sent Events bridging");
+    }
+    for route in routes {
+        router.add(route);
+    }
+    router.add(Arc::new(app));
+    router.add(Arc::new(JsonRpc::new(dispatch.clone(), logging.access().logger().clone()).with_tenants(tenants)));
 
     if config.is_load_testing_enabled() {
-        router.add(Arc::new(PerfRoute::new(dispatch.clone(), logging.access().logger().clone())));
+        router.add(Arc::new(PerfRoute::new(perf_dispatch.clone(), logging.access().logger().clone())));
         cocaine_log!(logging.common().logger(), Severity::Debug, "enabled performance measuring route");
     }
 
+    // Each worker thread receives its own channel for each cluster.
+    let mut channels: Vec<Vec<_>> = itertools::repeat_call(Vec::new)
+        .take(config.threads())
+        .collect();
+    for cluster in clusters {
+        if let Some(ref name) = cluster.name {
+            cocaine_log!(logging.common().logger(), Severity::Debug, "spawning pools for `{}` cluster", name);
+        }
+
+        let cfg = cluster.config;
+        for (thread, (tx, rx)) in channels.iter_mut().zip(cluster.dispatch.into_iter().zip(cluster.rxs)) {
+            thread.push((cfg.clone(), tx, rx));
+        }
+    }
+
     let factory = ProxyServiceFactoryFactory::new(
-        dispatch.into_iter().zip(rxs),
+        channels.into_iter(),
         config.clone(),
         router,
+        settings,
         metrics.clone(),
         logging.common().logger().clone(),
     );
 
-    let proxy_cfg = ServerConfig::new(config.network().addr())
+    let mut proxy_cfg = ServerConfig::new(addr.clone())
         .backlog(config.network().backlog())
-        .threads(config.threads());
-    let monitoring_cfg = ServerConfig::new(config.monitoring().addr())
+        .threads(config.threads())
+        .forward(config.network().forward().to_vec())
+        .upgrades(config.websocket().is_some());
+    if let Some(cfg) = config.memory() {
+        proxy_cfg = proxy_cfg.memory_budget(metrics.memory.clone(), Duration::from_millis(cfg.pause()));
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled accept pausing when buffered bodies exceed {} bytes", cfg.soft_limit());
+    }
+    if let Some(cfg) = config.network().tls() {
+        let certs = Certificates::load(cfg.cert(), cfg.key(), cfg.client_ca(), cfg.reload_interval())?;
+        proxy_cfg = proxy_cfg.tls(Arc::new(certs));
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled TLS termination with certificates from {}", cfg.cert().display());
+    }
+    if config.network().proxy_protocol() {
+        proxy_cfg = proxy_cfg.proxy_protocol(true);
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled PROXY protocol on TCP connections");
+    }
+    if let Some(interval) = config.network().disconnect_probe() {
+        proxy_cfg = proxy_cfg.disconnect_probe(interval);
+        cocaine_log!(logging.common().logger(), Severity::Info, "enabled probing of gone clients every {:?}", interval);
+    }
+    let monitoring_cfg = ServerConfig::new(config.monitoring().addr().clone())
+        .backlog(config.monitoring().backlog())
+        .threads(config.monitoring().threads())
         .godfather(|id| format!("monitor {:02}", id));
 
+    let audit = match config.monitoring().audit() {
+        Some(path) => Some(Arc::new(AuditLog::open(path)?)),
+        None => None,
+    };
+
     let monitoring = MonitorServiceFactoryFactory::new(
         Arc::new(config.clone()),
+        perf_dispatch,
+        dispatch,
         Arc::new(logging.clone()),
         metrics,
+        audit,
+        standby,
     );
 
-    cocaine_log!(logging.common().logger(), Severity::Info, "started HTTP proxy at {}", config.network().addr());
-    ServerGroup::new(logging.common().logger().clone())?
+    cocaine_log!(logging.common().logger(), Severity::Info, "started HTTP proxy at {}", addr);
+    cocaine_log!(logging.common().logger(), Severity::Info, "started monitoring server at {}", config.monitoring().addr());
+    let group = ServerGroup::new(logging.common().logger().clone())?
         .expose(proxy_cfg, factory)?
-        .expose(monitoring_cfg, monitoring)?
-        .run()?;
+        .expose(monitoring_cfg, monitoring)?;
+
+    hooks.run(Phase::Started, logging.common().logger());
+    let shutdown = shutdown.map(|()| hooks.run(Phase::Draining, logging.common().logger()));
+    group.run_until(shutdown)?;
 
+    drop(stop_tx);
     thread.join().unwrap()?;
+    hooks.run(Phase::Stopped, logging.common().logger());
 
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::THREAD_NAME_PERIODIC;
+    use super::{THREAD_NAME_PERIODIC, VERSION, VersionInfo};
+    use super::logging::THREAD_NAME_ACCESS;
+    use super::config::SCHEMA_VERSION;
 
     #[test]
     fn test_thread_names_fit_in_system_bounds() {
         // For NPTL the thread name is a meaningful C language string, whose length is restricted
         // to 16 characters, including the terminating null byte ('\0').
         assert!(THREAD_NAME_PERIODIC.len() < 16);
+        assert!(THREAD_NAME_ACCESS.len() < 16);
+    }
+
+    #[test]
+    fn test_version_info_json() {
+        let json = VersionInfo::new().to_json();
+        assert!(json.contains(&format!(r#""version":"{}""#, VERSION)));
+        assert!(json.contains(&format!(r#""config_schema":{}"#, SCHEMA_VERSION)));
+        assert!(json.contains(r#""commit":""#));
+        assert!(json.contains(r#""rustc":""#));
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn test_kafka_thread_name_fit_in_system_bounds() {
+        assert!(crate::logging::THREAD_NAME_KAFKA.len() < 16);
     }
 }
@@ -0,0 +1,282 @@
+//! Lifecycle of the proxy: startup, draining and shutdown.
+//!
+//! Orchestration scripts may coordinate with the proxy through hooks instead of polling health
+//! endpoints. Hooks are either configured, running a shell command or posting to a URL, or
+//! registered as callbacks when the proxy is embedded as a library. Each phase runs its hooks
+//! sequentially in the order they were added, configured ones first. Failed hooks are logged and
+//! otherwise ignored.
+//!
+//! Hooks run synchronously, so for example a slow `started` hook delays accepting connections
+//! that have already been queued in the listen backlog. Each hook is limited in time: hung
+//! commands are killed and hung requests are abandoned.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use hyper::{self, Client, Method, Request, Uri};
+use tokio_core::reactor::{Core, Timeout};
+
+use cocaine::logging::{Logger, Severity};
+
+use crate::config::{HookConfig, LifecycleConfig};
+
+/// Time limit of a single hook.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of checking whether a command hook has exited.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Environment variable with the phase name passed to command hooks.
+const PHASE_ENV: &str = "COCAINE_PROXY_PHASE";
+
+/// Lifecycle phase, at the beginning of which hooks are run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Listeners are bound and workers are spawned.
+    Started,
+    /// Shutdown is requested, new connections are no longer accepted from now on.
+    Draining,
+    /// Workers have finished serving existing connections or the drain timeout expired.
+    Stopped,
+}
+
+impl Display for Phase {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Phase::Started => fmt.write_str("started"),
+            Phase::Draining => fmt.write_str("draining"),
+            Phase::Stopped => fmt.write_str("stopped"),
+        }
+    }
+}
+
+/// A handle to request the proxy shutdown, which may be cloned and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl Shutdown {
+    /// Requests the proxy to stop accepting connections, drain existing ones and return.
+    ///
+    /// Subsequent calls have no effect.
+    pub fn shutdown(&self) {
+        if let Some(tx) = self.tx.lock().unwrap().take() {
+            drop(tx.send(()));
+        }
+    }
+}
+
+type Callback = Box<dyn Fn(Phase) + Send>;
+
+/// Lifecycle hooks with the shutdown trigger.
+pub struct Lifecycle {
+    config: LifecycleConfig,
+    callbacks: Vec<(Phase, Callback)>,
+    shutdown: Shutdown,
+    rx: oneshot::Receiver<()>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        let (tx, rx) = oneshot::channel();
+
+        Self {
+            config: LifecycleConfig::default(),
+            callbacks: Vec::new(),
+            shutdown: Shutdown { tx: Arc::new(Mutex::new(Some(tx))) },
+            rx: rx,
+        }
+    }
+
+    /// Registers a callback, which is called when the given phase begins.
+    pub fn with_hook<F>(mut self, phase: Phase, f: F) -> Self
+        where F: Fn(Phase) + Send + 'static
+    {
+        self.callbacks.push((phase, Box::new(f)));
+        self
+    }
+
+    /// Returns a handle to request the proxy shutdown.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Sets hooks from the configuration, which are run before registered callbacks.
+    pub(crate) fn with_config(mut self, config: LifecycleConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Splits the lifecycle into hooks and a future, which resolves when the shutdown is
+    /// requested.
+    ///
+    /// The future never resolves if all shutdown handles are dropped without being used.
+    pub(crate) fn split(self) -> (Hooks, Box<dyn Future<Item = (), Error = ()>>) {
+        let hooks = Hooks {
+            config: self.config,
+            callbacks: self.callbacks,
+        };
+        let shutdown = self.rx.or_else(|oneshot::Canceled| future::empty());
+
+        (hooks, Box::new(shutdown))
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle::new()
+    }
+}
+
+pub(crate) struct Hooks {
+    config: LifecycleConfig,
+    callbacks: Vec<(Phase, Callback)>,
+}
+
+impl Hooks {
+    /// Runs all hooks of the given phase.
+    pub fn run(&self, phase: Phase, log: &Logger) {
+        cocaine_log!(log, Severity::Info, "entering `{}` lifecycle phase", phase);
+
+        for hook in self.config.hooks(phase) {
+            if let Err(err) = run_hook(hook, phase) {
+                cocaine_log!(log, Severity::Warn, "failed to run `{}` lifecycle hook {:?}: {}", phase, hook, err);
+            }
+        }
+
+        for &(ref expected, ref callback) in &self.callbacks {
+            if *expected == phase {
+                callback(phase);
+            }
+        }
+    }
+}
+
+fn run_hook(hook: &HookConfig, phase: Phase) -> Result<(), io::Error> {
+    match *hook {
+        HookConfig::Exec(ref command) => run_command(command, phase, HOOK_TIMEOUT),
+        HookConfig::Url(ref url) => {
+            let uri: Uri = url.parse().map_err(|err: hyper::error::UriError| {
+                io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+            })?;
+
+            let mut core = Core::new()?;
+            let handle = core.handle();
+
+            let mut req = Request::new(Method::Post, uri);
+            req.set_body(phase.to_string());
+
+            let request = Client::new(&handle).request(req)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                .and_then(|resp| {
+                    if resp.status().is_success() {
+                        Ok(())
+                    } else {
+                        Err(io::Error::new(io::ErrorKind::Other, format!("received {} status", resp.status())))
+                    }
+                });
+            let timeout = Timeout::new(HOOK_TIMEOUT, &handle)?
+                .and_then(|()| Err(io::Error::new(io::ErrorKind::TimedOut, "hook timed out")));
+
+            core.run(request.select(timeout)).map(drop).map_err(|(err, ..)| err)
+        }
+    }
+}
+
+/// Runs the shell command, killing it if it doesn't exit within the given timeout.
+fn run_command(command: &str, phase: Phase, timeout: Duration) -> Result<(), io::Error> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env(PHASE_ENV, phase.to_string())
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            // The child may exit right before being killed, which is fine.
+            drop(child.kill());
+            child.wait()?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "command timed out and has been killed"));
+        }
+
+        thread::sleep(EXEC_POLL_INTERVAL);
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("command exited with {}", status)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use cocaine::logging::{LoggerContext, Severity};
+    use futures::Future;
+    use serde_yaml;
+
+    use super::{Lifecycle, Phase, run_command};
+
+    #[test]
+    fn test_hooks() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let config = serde_yaml::from_str(r#"{started: [{exec: "test $COCAINE_PROXY_PHASE = started"}]}"#).unwrap();
+        let lifecycle = Lifecycle::new()
+            .with_config(config)
+            .with_hook(Phase::Started, {
+                let phases = phases.clone();
+                move |phase| phases.lock().unwrap().push(phase)
+            })
+            .with_hook(Phase::Stopped, {
+                let phases = phases.clone();
+                move |phase| phases.lock().unwrap().push(phase)
+            });
+
+        let ctx = LoggerContext::new("test");
+        ctx.filter().set(Severity::Error.into());
+        let log = ctx.create("test");
+
+        let (hooks, ..) = lifecycle.split();
+        hooks.run(Phase::Started, &log);
+        hooks.run(Phase::Draining, &log);
+        hooks.run(Phase::Stopped, &log);
+
+        assert_eq!(vec![Phase::Started, Phase::Stopped], *phases.lock().unwrap());
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let now = Instant::now();
+        let err = run_command("sleep 10", Phase::Started, Duration::from_millis(100)).unwrap_err();
+
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(now.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let lifecycle = Lifecycle::new();
+        let handle = lifecycle.shutdown_handle();
+        let (.., shutdown) = lifecycle.split();
+
+        handle.shutdown();
+        handle.shutdown();
+        assert_eq!(Ok(()), shutdown.wait());
+    }
+}
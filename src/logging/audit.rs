@@ -0,0 +1,54 @@
+//! Append-only audit log for operational changes made through the monitoring server.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde_json;
+use time;
+
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    caller: &'a str,
+    action: &'a str,
+    params: BTreeMap<&'a str, &'a str>,
+}
+
+/// Writes each admin mutation as a single JSON line into a file opened in append mode.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    /// Records the action performed by the given caller with its parameters.
+    pub fn record(&self, caller: &str, action: &str, params: &[(&str, &str)]) -> Result<(), io::Error> {
+        let record = AuditRecord {
+            timestamp: time::now_utc().rfc3339().to_string(),
+            caller: caller,
+            action: action,
+            params: params.iter().cloned().collect(),
+        };
+
+        let mut buf = serde_json::to_vec(&record)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        buf.push(b'\n');
+
+        // Write the whole line at once to keep records intact even if the file is shared.
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&buf)?;
+        file.flush()
+    }
+}
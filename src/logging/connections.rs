@@ -0,0 +1,170 @@
+//! Aggregated logging of client connections.
+//!
+//! Logging each accepted and closed connection becomes a bottleneck itself under high connection
+//! churn, so connections are counted per source network instead and a single summary is logged
+//! periodically. Per-connection records may still be enabled for debugging.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use tokio_core::reactor::{Handle, Interval};
+
+use cocaine::logging::{Logger, Severity};
+
+use crate::config::ConnectionLogConfig;
+
+/// Maximum number of networks mentioned in a single summary.
+const MAX_NETWORKS: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    opened: u64,
+    closed: u64,
+}
+
+/// Returns the source network of the given address, which is `/24` for IPv4 and `/64` for IPv6.
+fn network(addr: Option<SocketAddr>) -> String {
+    match addr.map(|addr| addr.ip()) {
+        Some(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        Some(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+        None => "unix".into(),
+    }
+}
+
+/// Connection counters of a single worker, logged and reset periodically.
+#[derive(Debug)]
+pub struct ConnectionLog {
+    log: Logger,
+    interval: Duration,
+    verbose: bool,
+    counts: Mutex<HashMap<String, Counts>>,
+}
+
+impl ConnectionLog {
+    pub fn new(log: Logger, cfg: &ConnectionLogConfig) -> Self {
+        Self {
+            log: log,
+            interval: cfg.interval(),
+            verbose: cfg.verbose(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accounts a connection accepted from the given address, `None` meaning Unix socket.
+    pub fn on_open(&self, addr: Option<SocketAddr>) {
+        if self.verbose {
+            match addr {
+                Some(addr) => cocaine_log!(self.log, Severity::Info, "accepted connection from {}", addr),
+                None => cocaine_log!(self.log, Severity::Info, "accepted connection from Unix socket"),
+            }
+        }
+
+        self.counts.lock().unwrap().entry(network(addr)).or_default().opened += 1;
+    }
+
+    /// Accounts a connection from the given address being closed.
+    pub fn on_close(&self, addr: Option<SocketAddr>) {
+        if self.verbose {
+            match addr {
+                Some(addr) => cocaine_log!(self.log, Severity::Info, "closed connection from {}", addr),
+                None => cocaine_log!(self.log, Severity::Info, "closed connection from Unix socket"),
+            }
+        }
+
+        self.counts.lock().unwrap().entry(network(addr)).or_default().closed += 1;
+    }
+
+    /// Resets counters, returning the summary of connections accounted since the last call, if
+    /// there were any.
+    fn summary(&self) -> Option<String> {
+        let mut counts = {
+            let mut counts = self.counts.lock().unwrap();
+            if counts.is_empty() {
+                return None;
+            }
+            counts.drain().collect::<Vec<_>>()
+        };
+
+        let total = counts.iter().fold(Counts::default(), |acc, &(.., c)| {
+            Counts { opened: acc.opened + c.opened, closed: acc.closed + c.closed }
+        });
+
+        counts.sort_by(|a, b| (b.1.opened + b.1.closed).cmp(&(a.1.opened + a.1.closed)).then(a.0.cmp(&b.0)));
+
+        let mut networks = counts.iter()
+            .take(MAX_NETWORKS)
+            .map(|(net, c)| format!("{} +{}/-{}", net, c.opened, c.closed))
+            .collect::<Vec<_>>();
+        if counts.len() > MAX_NETWORKS {
+            networks.push(format!("{} more", counts.len() - MAX_NETWORKS));
+        }
+
+        Some(format!("connections in the last {} s: {} opened, {} closed ({})", self.interval.as_secs(), total.opened,
+            total.closed, networks.join(", ")))
+    }
+
+    /// Returns a future, which logs summaries every interval until the reactor is stopped.
+    pub fn run(self: Arc<Self>, handle: &Handle) -> Box<dyn Future<Item = (), Error = ()>> {
+        let interval = match Interval::new(self.interval, handle) {
+            Ok(interval) => interval,
+            Err(err) => {
+                cocaine_log!(self.log, Severity::Warn, "failed to schedule connection summaries: {}", err);
+                return Box::new(future::ok(()));
+            }
+        };
+
+        let future = interval
+            .for_each(move |()| {
+                if let Some(summary) = self.summary() {
+                    cocaine_log!(self.log, Severity::Info, "{}", summary);
+                }
+                Ok(())
+            })
+            .map_err(drop);
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use cocaine::logging::LoggerContext;
+    use serde_yaml;
+
+    use super::{network, ConnectionLog};
+
+    #[test]
+    fn test_network() {
+        assert_eq!("10.1.2.0/24", network(Some("10.1.2.3:80".parse::<SocketAddr>().unwrap())));
+        assert_eq!("2a02:6b8:0:1::/64", network(Some("[2a02:6b8:0:1:2::3]:80".parse::<SocketAddr>().unwrap())));
+        assert_eq!("unix", network(None));
+    }
+
+    #[test]
+    fn test_summary() {
+        let log = LoggerContext::new("test").create("test");
+        let cfg = serde_yaml::from_str("{interval: 60}").unwrap();
+        let connections = ConnectionLog::new(log, &cfg);
+        assert_eq!(None, connections.summary());
+
+        let addr = "10.1.2.3:80".parse().unwrap();
+        connections.on_open(Some(addr));
+        connections.on_open(Some(addr));
+        connections.on_close(Some(addr));
+        connections.on_open(None);
+
+        let expected = "connections in the last 60 s: 3 opened, 1 closed (10.1.2.0/24 +2/-1, unix +1/-0)";
+        assert_eq!(Some(expected.to_owned()), connections.summary());
+        assert_eq!(None, connections.summary());
+    }
+}
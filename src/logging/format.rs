@@ -0,0 +1,153 @@
+//! Access record formatting.
+
+use std::sync::Arc;
+
+use hyper::header::Headers;
+
+use serde_json::{self, Map, Value};
+
+use crate::config::{AccessField, AccessFormatConfig, AccessOutput};
+use crate::logging::{AccessRecord, Redactor};
+
+/// Selects fields and headers of access records, passing them through the redactor.
+#[derive(Debug)]
+pub struct AccessFormat {
+    output: AccessOutput,
+    fields: Vec<AccessField>,
+    headers: Vec<String>,
+    redactor: Arc<Redactor>,
+}
+
+impl AccessFormat {
+    pub fn new(cfg: &AccessFormatConfig, redactor: Arc<Redactor>) -> Self {
+        Self {
+            output: cfg.output(),
+            fields: cfg.fields().to_vec(),
+            headers: cfg.headers().to_vec(),
+            redactor: redactor,
+        }
+    }
+
+    pub fn output(&self) -> AccessOutput {
+        self.output
+    }
+
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    /// Returns redacted values of the configured request headers, which are present.
+    pub fn headers(&self, headers: &Headers) -> Vec<(String, String)> {
+        let headers: Vec<_> = self.headers.iter()
+            .filter_map(|name| {
+                headers.get_raw(name)
+                    .and_then(|raw| raw.one())
+                    .map(|value| (name.clone(), String::from_utf8_lossy(value).into_owned()))
+            })
+            .collect();
+
+        self.redactor.headers(&headers)
+    }
+
+    /// Formats the record as a JSON object of the configured fields and headers.
+    pub fn to_json(&self, record: &AccessRecord) -> String {
+        let mut map = Map::new();
+
+        for field in &self.fields {
+            let (name, value) = match *field {
+                AccessField::Trace => ("trace", Value::from(record.trace)),
+                AccessField::TraceId => ("trace_id", Value::from(record.trace_id.clone())),
+                AccessField::SpanId => ("span_id", record.span_id.clone().map(Value::from).unwrap_or(Value::Null)),
+                AccessField::Duration => ("duration", Value::from(record.duration)),
+                AccessField::Method => ("method", Value::from(record.method.clone())),
+                AccessField::Uri => ("uri", Value::from(record.uri.clone())),
+                AccessField::Prefix => ("prefix", record.prefix.clone().map(Value::from).unwrap_or(Value::Null)),
+                AccessField::Version => ("version", Value::from(record.version.clone())),
+                AccessField::Status => ("status", Value::from(record.status)),
+                AccessField::BytesSent => ("bytes_sent", Value::from(record.bytes_sent)),
+                AccessField::Service => ("service", Value::from(record.service.clone())),
+                AccessField::Event => ("event", Value::from(record.event.clone())),
+                AccessField::Tenant => ("tenant", record.tenant.clone().map(Value::from).unwrap_or(Value::Null)),
+                AccessField::BodyReadTime => ("body_read_time", Value::from(record.timings.body_read)),
+                AccessField::QueueTime => ("queue_time", Value::from(record.timings.queue)),
+                AccessField::ResolveTime => ("resolve_time", Value::from(record.timings.resolve)),
+                AccessField::FirstByteTime => ("first_byte_time", Value::from(record.timings.first_byte)),
+                AccessField::UpstreamTime => ("upstream_time", Value::from(record.timings.upstream)),
+                AccessField::ClientWriteTime => ("client_write_time", Value::from(record.timings.client_write)),
+                AccessField::Attempts => ("attempts", Value::from(record.attempts)),
+                AccessField::Error => ("error", record.error.clone().map(Value::from).unwrap_or(Value::Null)),
+                AccessField::Failure => ("failure", record.failure.clone().map(Value::from).unwrap_or(Value::Null)),
+            };
+            map.insert(name.into(), value);
+        }
+
+        if !record.headers.is_empty() {
+            let headers = record.headers.iter()
+                .map(|&(ref name, ref value)| (name.clone(), Value::from(value.clone())))
+                .collect();
+            map.insert("headers".into(), Value::Object(headers));
+        }
+
+        serde_json::to_string(&map).unwrap_or_default()
+    }
+}
+
+impl Default for AccessFormat {
+    fn default() -> Self {
+        Self::new(&AccessFormatConfig::default(), Arc::new(Redactor::default()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use hyper::header::Headers;
+    use serde_yaml;
+
+    use crate::config::{AccessFormatConfig, RedactionConfig};
+    use crate::logging::{AccessRecord, Redactor, Timings};
+
+    use super::AccessFormat;
+
+    #[test]
+    fn test_to_json() {
+        let cfg: AccessFormatConfig = serde_yaml::from_str(r#"
+            output: json
+            fields: [trace_id, status, attempts, upstream_time, tenant]
+            headers: [User-Agent, Authorization, Referer]
+        "#).unwrap();
+        let redaction: RedactionConfig = serde_yaml::from_str("{}").unwrap();
+        let format = AccessFormat::new(&cfg, Arc::new(Redactor::from(&redaction)));
+
+        let mut headers = Headers::new();
+        headers.set_raw("User-Agent", "curl/7.58");
+        headers.set_raw("Authorization", "Bearer secret");
+
+        let record = AccessRecord {
+            trace: 42,
+            trace_id: "000000000000002a".into(),
+            span_id: None,
+            duration: 0.5,
+            method: "GET".into(),
+            uri: "/app/event".into(),
+            prefix: None,
+            version: "HTTP/1.1".into(),
+            status: 200,
+            bytes_sent: 2,
+            service: "app".into(),
+            event: "event".into(),
+            tenant: None,
+            timings: Timings { upstream: 0.25, ..Timings::default() },
+            attempts: 2,
+            headers: format.headers(&headers),
+            error: None,
+            failure: None,
+        };
+
+        assert_eq!(
+            r#"{"attempts":2,"headers":{"Authorization":"***","User-Agent":"curl/7.58"},"status":200,"tenant":null,"trace_id":"000000000000002a","upstream_time":0.25}"#,
+            format.to_json(&record)
+        );
+    }
+}
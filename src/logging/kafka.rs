@@ -0,0 +1,131 @@
+//! Access log sink that produces records into a Kafka topic.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use kafka::client::Compression;
+use kafka::producer::{Producer, Record, RequiredAcks};
+
+use serde_json;
+
+use cocaine::logging::{Logger, Severity};
+
+use crate::config::{KafkaCompression, KafkaConfig};
+use super::{AccessRecord, AccessSink, SinkStats};
+
+pub const THREAD_NAME_KAFKA: &str = "kafka";
+
+/// Batches access records and produces them into a Kafka topic from a dedicated thread.
+///
+/// Records are passed through a bounded queue and dropped when it is full, so a slow Kafka cluster
+/// cannot block request processing. Drops are accounted and logged once per overflow rather than
+/// for each record.
+#[derive(Debug)]
+pub struct KafkaSink {
+    tx: SyncSender<Vec<u8>>,
+    topic: String,
+    /// Whether records are being dropped since the last successful push.
+    dropping: AtomicBool,
+    stats: Arc<SinkStats>,
+    log: Logger,
+}
+
+impl KafkaSink {
+    pub fn new(cfg: &KafkaConfig, log: Logger, stats: Arc<SinkStats>) -> Result<Self, io::Error> {
+        let compression = match cfg.compression() {
+            KafkaCompression::None => Compression::NONE,
+            KafkaCompression::Gzip => Compression::GZIP,
+            KafkaCompression::Snappy => Compression::SNAPPY,
+        };
+
+        let producer = Producer::from_hosts(cfg.brokers().to_vec())
+            .with_ack_timeout(Duration::from_secs(1))
+            .with_required_acks(RequiredAcks::One)
+            .with_compression(compression)
+            .create()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+        let (tx, rx) = mpsc::sync_channel(cfg.queue_limit());
+
+        let topic = cfg.topic().to_owned();
+        let batch = cfg.batch();
+        {
+            let topic = topic.clone();
+            let stats = stats.clone();
+            let log = log.clone();
+            thread::Builder::new().name(THREAD_NAME_KAFKA.into()).spawn(move || {
+                run(producer, topic, batch, rx, &stats, log)
+            })?;
+        }
+
+        let sink = Self {
+            tx: tx,
+            topic: topic,
+            dropping: AtomicBool::new(false),
+            stats: stats,
+            log: log,
+        };
+
+        Ok(sink)
+    }
+}
+
+impl AccessSink for KafkaSink {
+    fn push(&self, record: &AccessRecord) {
+        let buf = match serde_json::to_vec(record) {
+            Ok(buf) => buf,
+            Err(..) => return,
+        };
+
+        match self.tx.try_send(buf) {
+            Ok(()) => {
+                if self.dropping.swap(false, Ordering::SeqCst) {
+                    cocaine_log!(self.log, Severity::Info, "Kafka access log queue has recovered, {} record(s) dropped so far",
+                        self.stats.dropped(); {
+                        topic: self.topic,
+                    });
+                }
+            }
+            Err(TrySendError::Full(..)) | Err(TrySendError::Disconnected(..)) => {
+                self.stats.mark_dropped();
+                if !self.dropping.swap(true, Ordering::SeqCst) {
+                    cocaine_log!(self.log, Severity::Warn, "Kafka access log queue is full, dropping access records"; {
+                        topic: self.topic,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn run(mut producer: Producer, topic: String, batch: usize, rx: Receiver<Vec<u8>>, stats: &SinkStats, log: Logger) {
+    let mut pending = Vec::with_capacity(batch);
+
+    // Block until at least one record arrives, then grab everything that is already queued.
+    while let Ok(buf) = rx.recv() {
+        pending.push(buf);
+        while pending.len() < batch {
+            match rx.try_recv() {
+                Ok(buf) => pending.push(buf),
+                Err(..) => break,
+            }
+        }
+
+        let records = pending.iter()
+            .map(|buf| Record::from_value(&topic[..], &buf[..]))
+            .collect::<Vec<_>>();
+
+        if let Err(err) = producer.send_all(&records) {
+            stats.mark_failed(records.len());
+            cocaine_log!(log, Severity::Warn, "failed to produce {} access record(s) into Kafka: {}", records.len(), err; {
+                topic: topic,
+            });
+        }
+
+        pending.clear();
+    }
+}
@@ -1,13 +1,38 @@
 use std::error::Error;
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Instant;
 
-use hyper::{Method, StatusCode, Uri};
+use hyper::{Method, StatusCode};
 use hyper::server::Request;
 
+use serde_json;
+
 use cocaine::logging::{Filter, Log, Logger, LoggerContext, Severity};
 
-use crate::config::{LoggingBaseConfig, LoggingConfig};
+use crate::config::{AccessOutput, LoggingBaseConfig, LoggingConfig, MirroringConfig};
+use crate::random;
+
+pub use self::audit::AuditLog;
+pub use self::connections::ConnectionLog;
+pub use self::format::AccessFormat;
+#[cfg(feature = "kafka")]
+pub use self::kafka::{KafkaSink, THREAD_NAME_KAFKA};
+pub use self::queue::{AccessQueue, QueueStats, THREAD_NAME_ACCESS};
+pub use self::recent::{ErrorRecord, RecentErrors};
+pub use self::redact::{redact_json, Redactor};
+pub use self::sampling::AccessSampler;
+
+mod audit;
+mod connections;
+mod format;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod queue;
+mod recent;
+mod redact;
+mod sampling;
 
 #[derive(Clone, Debug)]
 pub struct Entry {
@@ -69,16 +94,168 @@ impl<'a> From<&'a LoggingConfig> for Loggers {
     }
 }
 
-/// Helper for safe debug `Request` formatting without panicking on errors.
-struct SafeRequestDebug<'a>(&'a Request);
+/// Copies a sampled fraction of full requests into a dedicated logging service for offline
+/// analysis.
+///
+/// Unlike the access log, mirrored records contain request headers and a (truncated) body.
+#[derive(Clone, Debug)]
+pub struct RequestMirror {
+    logger: Logger,
+    probability: f64,
+    body_limit: usize,
+    redactor: Arc<Redactor>,
+}
 
-impl<'a> Debug for SafeRequestDebug<'a> {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        match write!(fmt, "{:?}", self.0) {
-            Ok(()) => Ok(()),
-            Err(err) => {
-                write!(fmt, "failed to format `Request` using Debug trait: {}", err)
-            }
+impl RequestMirror {
+    /// Returns `true` if the next request should be mirrored.
+    pub fn sample(&self) -> bool {
+        random::gen::<f64>() < self.probability
+    }
+
+    /// Sets the redactor through which requests pass before being mirrored.
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Returns the redacted body truncated to the limit and whether it has been truncated.
+    fn body(&self, body: &[u8]) -> (String, bool) {
+        // Redacting the whole body first, because truncated JSON can't be parsed.
+        let body = self.redactor.body(body);
+        let body = &body[..];
+        let truncated = body.len() > self.body_limit;
+        let body = if truncated {
+            &body[..self.body_limit]
+        } else {
+            body
+        };
+
+        (String::from_utf8_lossy(body).into_owned(), truncated)
+    }
+
+    /// Writes the request into the mirroring service, redacting and truncating its body.
+    pub fn commit(&self, trace: u64, service: &str, event: &str, method: &Method, uri: &str, headers: &[(String, String)], body: &[u8]) {
+        let (body, truncated) = self.body(body);
+
+        cocaine_log!(self.logger, Severity::Info, "mirrored HTTP request"; {
+            trace: trace,
+            trace_id: format!("{:016x}", trace),
+            service: service,
+            event: event,
+            method: method.to_string(),
+            uri: self.redactor.uri(uri),
+            headers: serde_json::to_string(&self.redactor.headers(headers)).unwrap_or_default(),
+            body: body,
+            truncated: truncated,
+        });
+    }
+}
+
+impl<'a> From<&'a MirroringConfig> for RequestMirror {
+    fn from(cfg: &'a MirroringConfig) -> Self {
+        let ctx = LoggerContext::new(cfg.name().to_owned());
+        ctx.filter().set(Severity::Info.into());
+
+        Self {
+            logger: ctx.create(cfg.source().to_owned()),
+            probability: cfg.probability(),
+            body_limit: cfg.body_limit(),
+            redactor: Arc::new(Redactor::default()),
+        }
+    }
+}
+
+/// Durations of distinct request processing phases in seconds, zero if a phase was not reached.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Timings {
+    /// Reading the request body from the client.
+    pub body_read: f64,
+    /// Waiting in the event queue of a services pool.
+    pub queue: f64,
+    /// Resolving and connecting to the service until the invocation is sent.
+    pub resolve: f64,
+    /// Since the invocation is sent until the first response chunk from the worker.
+    pub first_byte: f64,
+    /// Total time spent upstream, including all retries.
+    pub upstream: f64,
+    /// Waiting for the client to accept chunks of the streamed response body.
+    pub client_write: f64,
+}
+
+/// A summary of a finished HTTP request.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessRecord {
+    pub trace: u64,
+    pub trace_id: String,
+    /// Span id of the last attempt to invoke the application, if any.
+    pub span_id: Option<String>,
+    /// Request duration in seconds.
+    pub duration: f64,
+    pub method: String,
+    pub uri: String,
+    /// URL prefix stripped before routing, if any.
+    pub prefix: Option<String>,
+    pub version: String,
+    pub status: u16,
+    pub bytes_sent: u64,
+    pub service: String,
+    pub event: String,
+    /// Tenant name, if the request was routed into one.
+    pub tenant: Option<String>,
+    pub timings: Timings,
+    /// Number of attempts made to invoke the application.
+    pub attempts: u32,
+    /// Request headers selected by the access format, redacted.
+    pub headers: Vec<(String, String)>,
+    pub error: Option<String>,
+    /// Kind of the upstream failure, if the request has failed upstream.
+    pub failure: Option<String>,
+}
+
+/// An additional destination for access records, apart from the Cocaine logging service.
+///
+/// Implementations must never block, because records are pushed directly from request
+/// completion paths.
+pub trait AccessSink: Debug + Send + Sync {
+    fn push(&self, record: &AccessRecord);
+}
+
+/// Counters of the configured access sink.
+#[derive(Debug, Default)]
+pub struct SinkStats {
+    dropped: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl SinkStats {
+    /// Returns the number of records dropped because the sink could not keep up.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of records the sink has failed to deliver.
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Marks a dropped record, returning the number of records dropped before.
+    pub(crate) fn mark_dropped(&self) -> usize {
+        self.dropped.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub(crate) fn mark_failed(&self, count: usize) {
+        self.failed.fetch_add(count, Ordering::SeqCst);
+    }
+}
+
+/// Pushes access records into each of the given sinks in order.
+#[derive(Debug)]
+pub struct AccessSinks(pub Vec<Arc<dyn AccessSink>>);
+
+impl AccessSink for AccessSinks {
+    fn push(&self, record: &AccessRecord) {
+        for sink in &self.0 {
+            sink.push(record);
         }
     }
 }
@@ -87,53 +264,237 @@ impl<'a> Debug for SafeRequestDebug<'a> {
 pub struct AccessLogger<L> {
     birth: Instant,
     method: Method,
-    uri: Uri,
+    /// Redacted request URI.
+    uri: String,
     version: String,
     service: String,
     event: String,
     trace: u64,
+    span: Option<u64>,
+    tenant: Option<String>,
+    prefix: Option<String>,
+    timings: Timings,
+    attempts: u32,
+    failure: Option<String>,
+    headers: Vec<(String, String)>,
+    format: Arc<AccessFormat>,
     log: L,
+    sink: Option<Arc<dyn AccessSink>>,
+    queue: Option<Arc<AccessQueue>>,
+    sampler: Option<Arc<AccessSampler>>,
 }
 
 impl<L: Log> AccessLogger<L> {
-    pub fn new(log: L, req: &Request, service: String, event: String, trace: u64) -> Self {
+    pub fn new(log: L, req: &Request, service: String, event: String, trace: u64, format: Arc<AccessFormat>) -> Self {
+        let redactor = format.redactor();
+        let uri = redactor.uri(req.uri().as_ref());
+        let headers: Vec<_> = req.headers().iter()
+            .map(|header| (header.name().to_owned(), header.value_string()))
+            .collect();
+
         cocaine_log!(log, Severity::Debug, "processing HTTP request"; {
             service: service,
             event: event,
             trace: trace,
             trace_id: format!("{:016x}", trace),
-            request: format!("{:?}", SafeRequestDebug(req)),
+            request: format!("{} {} {}", req.method(), uri, req.version()),
+            headers: serde_json::to_string(&redactor.headers(&headers)).unwrap_or_default(),
         });
 
         Self {
             birth: Instant::now(),
             method: req.method().clone(),
-            uri: req.uri().clone(),
+            uri: uri,
             version: format!("{}", req.version()),
             service: service,
             event: event,
             trace: trace,
+            span: None,
+            tenant: None,
+            prefix: None,
+            timings: Timings::default(),
+            attempts: 0,
+            failure: None,
+            headers: format.headers(req.headers()),
+            format: format,
             log: log,
+            sink: None,
+            queue: None,
+            sampler: None,
         }
     }
 
+    /// Attaches an additional sink into which access records will be pushed.
+    pub fn with_sink(mut self, sink: Option<Arc<dyn AccessSink>>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Attaches the queue through which records are logged asynchronously instead of being
+    /// written in place.
+    pub fn with_queue(mut self, queue: Option<Arc<AccessQueue>>) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Attaches the sampler, which decides whether the record is written at all.
+    pub fn with_sampler(mut self, sampler: Option<Arc<AccessSampler>>) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Sets the tenant name the request was routed into.
+    pub fn with_tenant(mut self, tenant: Option<String>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// Sets the URL prefix that was stripped before routing.
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Sets durations of request processing phases.
+    pub fn set_timings(&mut self, timings: Timings) {
+        self.timings = timings;
+    }
+
+    /// Sets the number of attempts made to invoke the application.
+    pub fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts;
+    }
+
+    /// Sets the span id of the last attempt to invoke the application.
+    pub fn set_span(&mut self, span: Option<u64>) {
+        self.span = span;
+    }
+
+    /// Sets the kind of the upstream failure.
+    pub fn set_failure(&mut self, failure: Option<String>) {
+        self.failure = failure;
+    }
+
     pub fn commit(self, status: StatusCode, bytes_sent: u64, err: Option<&dyn Error>) {
-        let status: u16 = status.into();
+        if let Some(ref sampler) = self.sampler {
+            if !sampler.sample(status.into()) {
+                return;
+            }
+        }
+
         let elapsed = self.birth.elapsed();
         let elapsed_ms = (elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64) as f64 / 1e6;
 
-        cocaine_log!(self.log, Severity::Info, "request finished in {:.3} ms", elapsed_ms; {
+        let record = AccessRecord {
             trace: self.trace,
             trace_id: format!("{:016x}", self.trace),
+            span_id: self.span.map(|span| format!("{:016x}", span)),
             duration: elapsed_ms / 1000.0,
             method: self.method.to_string(),
-            uri: self.uri.to_string(),
+            uri: self.uri,
+            prefix: self.prefix,
             version: self.version,
-            status: status,
+            status: status.into(),
             bytes_sent: bytes_sent,
             service: self.service,
             event: self.event,
-            error: err.map(|e| e.description()).unwrap_or("No error"),
-        });
+            tenant: self.tenant,
+            timings: self.timings,
+            attempts: self.attempts,
+            headers: self.headers,
+            error: err.map(|e| e.description().to_owned()),
+            failure: self.failure,
+        };
+
+        if let Some(ref sink) = self.sink {
+            sink.push(&record);
+        }
+
+        match self.queue {
+            Some(ref queue) => queue.push(record),
+            None => write(&self.log, &self.format, record),
+        }
+    }
+}
+
+/// Writes the access record into the given logger.
+fn write<L: Log>(log: &L, format: &AccessFormat, record: AccessRecord) {
+    if format.output() == AccessOutput::Json {
+        cocaine_log!(log, Severity::Info, "{}", format.to_json(&record));
+        return;
+    }
+
+    cocaine_log!(log, Severity::Info, "request finished in {:.3} ms", record.duration * 1000.0; {
+        trace: record.trace,
+        trace_id: record.trace_id,
+        span_id: record.span_id.unwrap_or_default(),
+        duration: record.duration,
+        method: record.method,
+        uri: record.uri,
+        prefix: record.prefix.unwrap_or_default(),
+        version: record.version,
+        status: record.status,
+        bytes_sent: record.bytes_sent,
+        service: record.service,
+        event: record.event,
+        tenant: record.tenant.unwrap_or_else(|| "default".to_owned()),
+        body_read_time: record.timings.body_read,
+        queue_time: record.timings.queue,
+        resolve_time: record.timings.resolve,
+        first_byte_time: record.timings.first_byte,
+        upstream_time: record.timings.upstream,
+        client_write_time: record.timings.client_write,
+        attempts: record.attempts,
+        error: record.error.unwrap_or_else(|| "No error".to_owned()),
+        failure: record.failure.unwrap_or_default(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use serde_yaml;
+
+    use crate::config::{MirroringConfig, RedactionConfig};
+    use crate::random;
+
+    use super::{Redactor, RequestMirror};
+
+    fn mirror(probability: f64, body_limit: usize) -> RequestMirror {
+        let cfg: MirroringConfig = serde_yaml::from_str(&format!(
+            "{{name: mirror, source: proxy, probability: {}, body_limit: {}}}", probability, body_limit
+        )).unwrap();
+        let redaction: RedactionConfig = serde_yaml::from_str("fields: [password]").unwrap();
+
+        RequestMirror::from(&cfg).with_redactor(Arc::new(Redactor::from(&redaction)))
+    }
+
+    #[test]
+    fn test_sample() {
+        random::deterministic(42, UNIX_EPOCH + Duration::from_secs(1500000000));
+
+        let never = mirror(0.0, 16);
+        let always = mirror(1.0, 16);
+        assert!((0..100).all(|_| !never.sample()));
+        assert!((0..100).all(|_| always.sample()));
+
+        let half = mirror(0.5, 16);
+        let sampled = (0..1000).filter(|_| half.sample()).count();
+        assert!(sampled > 400 && sampled < 600, "{} of 1000 requests sampled", sampled);
+    }
+
+    #[test]
+    fn test_body_is_redacted_before_truncation() {
+        let mirror = mirror(1.0, 16);
+
+        let (body, truncated) = mirror.body(br#"{"password":"pa$$w0rd"}"#);
+        assert_eq!(r#"{"password":"***"#, body);
+        assert!(truncated);
+
+        let (body, truncated) = mirror.body(b"short");
+        assert_eq!("short", body);
+        assert!(!truncated);
     }
 }
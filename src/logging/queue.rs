@@ -0,0 +1,247 @@
+//! Asynchronous delivery of access records into the logging service.
+
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use tokio_core::reactor::Timeout;
+
+use cocaine::{Core, ServiceBuilder};
+use cocaine::logging::Log;
+
+use crate::config::AccessQueueConfig;
+use super::{AccessFormat, AccessRecord, write};
+
+pub const THREAD_NAME_ACCESS: &str = "access-log";
+
+/// Time given to a single connection attempt to the logging service.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counters of the access log and its queue.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    queued: AtomicUsize,
+    flushed: AtomicUsize,
+    dropped: AtomicUsize,
+    skipped: AtomicUsize,
+    disconnected: AtomicBool,
+    outages: AtomicUsize,
+    reconnects: AtomicUsize,
+}
+
+impl QueueStats {
+    /// Returns the number of records currently waiting to be logged.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of records logged so far.
+    pub fn flushed(&self) -> usize {
+        self.flushed.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of records dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of records skipped by sampling.
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the logging service was found unreachable and records are being held
+    /// until it comes back.
+    pub fn disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of times the logging service was found unreachable.
+    pub fn outages(&self) -> usize {
+        self.outages.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of failed attempts to reconnect to the logging service.
+    pub fn reconnects(&self) -> usize {
+        self.reconnects.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn mark_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Passes access records through a bounded queue to a dedicated thread, which writes them into
+/// the logging service in batches.
+///
+/// Records that do not fit into the queue are dropped and accounted, so a slow logging service
+/// can neither block request completion paths nor grow memory without bound.
+///
+/// The logger buffers and resends records on its own without reporting failures, so records sent
+/// while the logging service is down would pile up there or get lost unnoticed. Instead, before
+/// flushing the service is checked to be reachable, holding records in the queue and reconnecting
+/// with exponential backoff while it is not.
+#[derive(Debug)]
+pub struct AccessQueue {
+    tx: SyncSender<AccessRecord>,
+    stats: Arc<QueueStats>,
+}
+
+impl AccessQueue {
+    /// Constructs the queue, writing into the given logger, which is backed by the named logging
+    /// service resolved through the given locators.
+    pub fn new<L>(cfg: &AccessQueueConfig, format: Arc<AccessFormat>, log: L, service: String,
+        locators: Vec<SocketAddr>, stats: Arc<QueueStats>) -> Result<Self, io::Error>
+        where L: Log + Send + 'static
+    {
+        let (tx, rx) = mpsc::sync_channel(cfg.limit());
+
+        let batch = cfg.batch();
+        let interval = cfg.probe_interval();
+        let max_backoff = cfg.max_backoff();
+        {
+            let stats = stats.clone();
+            thread::Builder::new().name(THREAD_NAME_ACCESS.into()).spawn(move || {
+                let link = Link::new(service, locators, interval, max_backoff)?;
+                run(log, &format, batch, rx, link, &stats);
+                Ok::<(), io::Error>(())
+            })?;
+        }
+
+        Ok(Self { tx: tx, stats: stats })
+    }
+
+    /// Enqueues the record without blocking, dropping it if the queue is full.
+    pub fn push(&self, record: AccessRecord) {
+        self.stats.queued.fetch_add(1, Ordering::SeqCst);
+
+        match self.tx.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(..)) | Err(TrySendError::Disconnected(..)) => {
+                self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+                self.stats.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Returns the delay before the given reconnection attempt, doubling from one second up to the
+/// limit.
+fn backoff(attempt: u32, max: Duration) -> Duration {
+    cmp::min(Duration::from_secs(1 << cmp::min(attempt, 16)), max)
+}
+
+/// Reachability of the logging service, checked from the access log thread.
+struct Link {
+    core: Core,
+    service: String,
+    locators: Vec<SocketAddr>,
+    interval: Duration,
+    max_backoff: Duration,
+    checked: Option<Instant>,
+}
+
+impl Link {
+    fn new(service: String, locators: Vec<SocketAddr>, interval: Duration, max_backoff: Duration) -> Result<Self, io::Error> {
+        let link = Self {
+            core: Core::new()?,
+            service: service,
+            locators: locators,
+            interval: interval,
+            max_backoff: max_backoff,
+            checked: None,
+        };
+
+        Ok(link)
+    }
+
+    /// Connects to the logging service from scratch, so a restarted service is resolved again.
+    fn probe(&mut self) -> bool {
+        let handle = self.core.handle();
+        let service = ServiceBuilder::new(self.service.clone())
+            .locator_addrs(self.locators.clone())
+            .build(&handle);
+
+        let connected = service.connect().then(|result| Ok::<_, io::Error>(result.is_ok()));
+        let timeout = future::result(Timeout::new(PROBE_TIMEOUT, &handle))
+            .flatten()
+            .then(|_| Ok(false));
+
+        self.core.run(connected.select(timeout).map(|(connected, ..)| connected)).unwrap_or(false)
+    }
+
+    /// Blocks until the logging service is known to be reachable, probing it at most once per
+    /// interval while it is.
+    fn wait(&mut self, stats: &QueueStats) {
+        if let Some(checked) = self.checked {
+            if checked.elapsed() < self.interval {
+                return;
+            }
+        }
+
+        let mut attempt = 0;
+        while !self.probe() {
+            if attempt == 0 {
+                stats.disconnected.store(true, Ordering::SeqCst);
+                stats.outages.fetch_add(1, Ordering::SeqCst);
+            } else {
+                stats.reconnects.fetch_add(1, Ordering::SeqCst);
+            }
+
+            thread::sleep(backoff(attempt, self.max_backoff));
+            attempt += 1;
+        }
+
+        stats.disconnected.store(false, Ordering::SeqCst);
+        self.checked = Some(Instant::now());
+    }
+}
+
+fn run<L: Log>(log: L, format: &AccessFormat, batch: usize, rx: Receiver<AccessRecord>, mut link: Link, stats: &QueueStats) {
+    let mut pending = Vec::with_capacity(batch);
+
+    // Block until at least one record arrives, then grab everything that is already queued.
+    while let Ok(record) = rx.recv() {
+        pending.push(record);
+        while pending.len() < batch {
+            match rx.try_recv() {
+                Ok(record) => pending.push(record),
+                Err(..) => break,
+            }
+        }
+
+        // Records keep waiting in the queue during outages, while new ones beyond its limit are
+        // dropped.
+        link.wait(stats);
+
+        let len = pending.len();
+        for record in pending.drain(..) {
+            write(&log, format, record);
+        }
+
+        stats.queued.fetch_sub(len, Ordering::SeqCst);
+        stats.flushed.fetch_add(len, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::backoff;
+
+    #[test]
+    fn test_backoff() {
+        let max = Duration::from_secs(30);
+        assert_eq!(Duration::from_secs(1), backoff(0, max));
+        assert_eq!(Duration::from_secs(8), backoff(3, max));
+        assert_eq!(max, backoff(5, max));
+        assert_eq!(max, backoff(100, max));
+    }
+}
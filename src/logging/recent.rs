@@ -0,0 +1,113 @@
+//! Recent errors generated by the proxy, kept in memory for on-call inspection.
+//!
+//! Each worker thread writes into its own ring, so recording never contends with other workers
+//! unless there are more threads than rings. Rings are merged and ordered by time when read.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of rings, which is more than enough for any reasonable number of workers.
+const RINGS: usize = 16;
+
+static NEXT_RING: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static RING: usize = NEXT_RING.fetch_add(1, Ordering::Relaxed) % RINGS;
+}
+
+/// A single error response generated by the proxy.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ErrorRecord {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Trace id in hex.
+    pub trace: String,
+    pub service: String,
+    pub status: u16,
+    /// Upstream failure kind or the proxy error class.
+    pub class: String,
+    pub message: String,
+}
+
+/// Bounded per-worker rings of the most recent errors.
+#[derive(Debug)]
+pub struct RecentErrors {
+    capacity: usize,
+    rings: Vec<Mutex<VecDeque<ErrorRecord>>>,
+}
+
+impl Default for RecentErrors {
+    fn default() -> Self {
+        RecentErrors::new(0)
+    }
+}
+
+impl RecentErrors {
+    /// Constructs rings, each keeping at most the given number of records. Zero disables
+    /// recording.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity,
+            rings: (0..RINGS).map(|_| Mutex::new(VecDeque::with_capacity(capacity))).collect(),
+        }
+    }
+
+    /// Remembers the record in the ring of the current thread, evicting the oldest one if full.
+    pub fn record(&self, record: ErrorRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut ring = RING.with(|&ring| self.rings[ring].lock().unwrap());
+        if ring.len() >= self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(record);
+    }
+
+    /// Returns at most `limit` most recent records of all workers, newest first.
+    pub fn snapshot(&self, limit: usize) -> Vec<ErrorRecord> {
+        let mut records = self.rings.iter()
+            .flat_map(|ring| ring.lock().unwrap().iter().cloned().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        records.truncate(limit);
+        records
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorRecord, RecentErrors};
+
+    fn record(timestamp: u64) -> ErrorRecord {
+        ErrorRecord {
+            timestamp: timestamp,
+            trace: "2a".into(),
+            service: "app".into(),
+            status: 502,
+            class: "connect".into(),
+            message: "connection refused".into(),
+        }
+    }
+
+    #[test]
+    fn test_ring_evicts_oldest() {
+        let errors = RecentErrors::new(2);
+        for timestamp in 1..4 {
+            errors.record(record(timestamp));
+        }
+
+        let timestamps = errors.snapshot(10).iter().map(|r| r.timestamp).collect::<Vec<_>>();
+        assert_eq!(vec![3, 2], timestamps);
+        assert_eq!(1, errors.snapshot(1).len());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let errors = RecentErrors::new(0);
+        errors.record(record(1));
+        assert!(errors.snapshot(10).is_empty());
+    }
+}
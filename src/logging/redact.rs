@@ -0,0 +1,153 @@
+//! Redaction of secrets from diagnostic outputs.
+//!
+//! Every place, where request details leave the proxy for diagnostics, like access and debug logs
+//! or mirrored requests, passes them through the same redactor, so enabling any of these features
+//! doesn't leak tokens or passwords. Values are masked rather than removed, keeping it visible
+//! that they were sent.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use serde_json::{self, Value};
+
+use crate::config::RedactionConfig;
+
+/// Value replacing redacted secrets.
+pub const REDACTED: &str = "***";
+
+/// Masks values of the named fields at any depth of the JSON document.
+pub fn redact_json(value: &mut Value, fields: &HashSet<String>) {
+    match *value {
+        Value::Object(ref mut map) => {
+            for (name, value) in map.iter_mut() {
+                if fields.contains(name) {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact_json(value, fields);
+                }
+            }
+        }
+        Value::Array(ref mut values) => {
+            for value in values {
+                redact_json(value, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Masks configured headers, query parameters and JSON body fields.
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    /// Lowercase header names.
+    headers: HashSet<String>,
+    params: HashSet<String>,
+    fields: HashSet<String>,
+}
+
+impl Redactor {
+    /// Returns headers with values of the sensitive ones masked.
+    pub fn headers(&self, headers: &[(String, String)]) -> Vec<(String, String)> {
+        headers.iter()
+            .map(|&(ref name, ref value)| {
+                if self.headers.contains(&name.to_ascii_lowercase()) {
+                    (name.clone(), REDACTED.to_owned())
+                } else {
+                    (name.clone(), value.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the URI with values of the sensitive query parameters masked.
+    pub fn uri(&self, uri: &str) -> String {
+        let (path, query) = match uri.find('?') {
+            Some(pos) if !self.params.is_empty() => (&uri[..pos], &uri[pos + 1..]),
+            Some(..) | None => return uri.to_owned(),
+        };
+
+        let query = query.split('&')
+            .map(|pair| {
+                let name = pair.splitn(2, '=').next().unwrap_or_default();
+                if self.params.contains(name) {
+                    format!("{}={}", name, REDACTED)
+                } else {
+                    pair.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        format!("{}?{}", path, query)
+    }
+
+    /// Returns the body with sensitive fields masked if it's a JSON document, otherwise as is.
+    pub fn body<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.fields.is_empty() {
+            return Cow::Borrowed(body);
+        }
+
+        match serde_json::from_slice(body) {
+            Ok(mut value) => {
+                redact_json(&mut value, &self.fields);
+                serde_json::to_vec(&value).map(Cow::Owned).unwrap_or(Cow::Borrowed(body))
+            }
+            Err(..) => Cow::Borrowed(body),
+        }
+    }
+}
+
+impl<'a> From<&'a RedactionConfig> for Redactor {
+    fn from(cfg: &'a RedactionConfig) -> Self {
+        Self {
+            headers: cfg.headers().iter().map(|name| name.to_ascii_lowercase()).collect(),
+            params: cfg.params().iter().cloned().collect(),
+            fields: cfg.fields().iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_yaml;
+
+    use crate::config::RedactionConfig;
+
+    use super::Redactor;
+
+    fn redactor(cfg: &str) -> Redactor {
+        let cfg: RedactionConfig = serde_yaml::from_str(cfg).unwrap();
+        Redactor::from(&cfg)
+    }
+
+    #[test]
+    fn test_headers() {
+        let redactor = redactor("{}");
+        let headers = vec![
+            ("authorization".to_owned(), "Bearer secret".to_owned()),
+            ("X-Request-Id".to_owned(), "42".to_owned()),
+        ];
+
+        assert_eq!(vec![
+            ("authorization".to_owned(), "***".to_owned()),
+            ("X-Request-Id".to_owned(), "42".to_owned()),
+        ], redactor.headers(&headers));
+    }
+
+    #[test]
+    fn test_uri() {
+        let redactor = redactor("params: [token, key]");
+
+        assert_eq!("/app/event?id=1&token=***&key=***", redactor.uri("/app/event?id=1&token=abc&key"));
+        assert_eq!("/app/event?tokens=1", redactor.uri("/app/event?tokens=1"));
+        assert_eq!("/app/event", redactor.uri("/app/event"));
+    }
+
+    #[test]
+    fn test_body() {
+        let redactor = redactor("fields: [password]");
+
+        assert_eq!(&br#"{"login":"me","password":"***"}"#[..], &redactor.body(br#"{"login":"me","password":"pa$$"}"#)[..]);
+        assert_eq!(&b"password=pa$$"[..], &redactor.body(b"password=pa$$")[..]);
+    }
+}
@@ -0,0 +1,81 @@
+//! Sampling of access records.
+//!
+//! Under high load writing every access record may dominate the proxy I/O, while most of them
+//! describe boring successful requests. Sampling keeps only a fraction of records depending on the
+//! response status, for example all server errors and a percent of successes. Skipped records are
+//! accounted, so rates computed from the access log can be scaled back.
+
+use std::sync::Arc;
+
+use crate::config::{AccessSamplingConfig, StatusMatch};
+use crate::random;
+
+use super::QueueStats;
+
+/// Decides whether an access record with the given status should be written.
+#[derive(Debug)]
+pub struct AccessSampler {
+    rate: f64,
+    /// Per-status rates with exact codes going first.
+    statuses: Vec<(StatusMatch, f64)>,
+    stats: Arc<QueueStats>,
+}
+
+impl AccessSampler {
+    pub fn new(cfg: &AccessSamplingConfig, stats: Arc<QueueStats>) -> Self {
+        Self {
+            rate: cfg.rate(),
+            statuses: cfg.statuses(),
+            stats: stats,
+        }
+    }
+
+    /// Returns the fraction of records with the given status to be written.
+    fn rate(&self, status: u16) -> f64 {
+        self.statuses.iter()
+            .find(|&&(ref pattern, ..)| pattern.matches(status))
+            .map(|&(.., rate)| rate)
+            .unwrap_or(self.rate)
+    }
+
+    /// Returns `true` if the record should be written, otherwise accounts it as skipped.
+    pub fn sample(&self, status: u16) -> bool {
+        let rate = self.rate(status);
+        let sampled = rate >= 1.0 || random::gen::<f64>() < rate;
+
+        if !sampled {
+            self.stats.mark_skipped();
+        }
+
+        sampled
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_yaml;
+
+    use crate::config::AccessSamplingConfig;
+    use crate::logging::QueueStats;
+
+    use super::AccessSampler;
+
+    #[test]
+    fn test_sample() {
+        let cfg: AccessSamplingConfig = serde_yaml::from_str(r#"
+            rate: 0.0
+            statuses: {'5xx': 1.0, '503': 0.0}
+        "#).unwrap();
+        let stats = Arc::new(QueueStats::default());
+        let sampler = AccessSampler::new(&cfg, stats.clone());
+
+        assert!(sampler.sample(500));
+        assert!(sampler.sample(502));
+        assert!(!sampler.sample(503));
+        assert!(!sampler.sample(200));
+        assert!(!sampler.sample(404));
+        assert_eq!(3, stats.skipped());
+    }
+}
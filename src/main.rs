@@ -2,25 +2,80 @@
 extern crate clap;
 extern crate cocaine_http_proxy;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
-use cocaine_http_proxy::Config;
+use cocaine_http_proxy::{Config, VersionInfo};
 
 fn main() {
+    let json = Arg::with_name("json")
+        .long("json")
+        .help("Prints version information in JSON format");
+
     let matches = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
+        .setting(AppSettings::DisableVersion)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("config")
             .short("c")
             .long("config")
-            .required(true)
+            .required_unless("version")
             .value_name("FILE")
             .help("Path to the configuration file")
             .takes_value(true))
+        .arg(Arg::with_name("check-config")
+            .long("check-config")
+            .requires("config")
+            .help("Validates the configuration file, reporting all errors found, and exits"))
+        .arg(Arg::with_name("version")
+            .short("V")
+            .long("version")
+            .help("Prints version information"))
+        .arg(json.clone().requires("version"))
+        .subcommand(SubCommand::with_name("version")
+            .about("Prints version information, enabled features and supported config schema")
+            .arg(json))
         .get_matches();
 
+    let version = match matches.subcommand_matches("version") {
+        Some(matches) => Some(matches.is_present("json")),
+        None if matches.is_present("version") => Some(matches.is_present("json")),
+        None => None,
+    };
+
+    if let Some(json) = version {
+        let info = VersionInfo::new();
+        if json {
+            println!("{}", info.to_json());
+        } else {
+            println!("{} {}", crate_name!(), info);
+        }
+        return;
+    }
+
     let path = matches.value_of("config").expect("failed to extract configuration path");
 
+    if matches.is_present("check-config") {
+        let errors = match Config::check(path) {
+            Ok((config, errors)) => {
+                for warning in config.warnings() {
+                    println!("WARNING: {}", warning);
+                }
+                errors
+            }
+            Err(err) => vec![format!("failed to parse configuration: {}", err)],
+        };
+
+        for err in &errors {
+            println!("ERROR: {}", err);
+        }
+        if !errors.is_empty() {
+            std::process::exit(1);
+        }
+        println!("configuration is valid");
+        return;
+    }
+
     let config = match Config::load(path) {
         Ok(path) => path,
         Err(err) => {
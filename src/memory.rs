@@ -0,0 +1,135 @@
+//! Accounting of memory occupied by buffered HTTP bodies.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::MemoryConfig;
+
+/// Memory budget shared between all worker threads.
+///
+/// The budget never rejects anything by itself. Instead, crossing the soft limit is a signal for
+/// the acceptor to stop taking new connections for a while, letting in-flight requests drain.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    soft_limit: Option<usize>,
+    used: AtomicUsize,
+    pauses: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(soft_limit: Option<usize>) -> Self {
+        Self {
+            soft_limit: soft_limit,
+            used: AtomicUsize::new(0),
+            pauses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Accounts the given number of bytes, returning a reservation that releases them on drop.
+    pub fn reserve(budget: &Arc<MemoryBudget>, size: usize) -> MemoryReservation {
+        budget.used.fetch_add(size, Ordering::SeqCst);
+
+        MemoryReservation {
+            budget: budget.clone(),
+            size: size,
+        }
+    }
+
+    /// Returns the number of bytes currently accounted.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn soft_limit(&self) -> Option<usize> {
+        self.soft_limit
+    }
+
+    /// Returns `true` if the accounted memory crosses the soft limit.
+    pub fn is_exceeded(&self) -> bool {
+        match self.soft_limit {
+            Some(limit) => self.used() > limit,
+            None => false,
+        }
+    }
+
+    /// Returns the number of times accepting was paused.
+    pub fn pauses(&self) -> usize {
+        self.pauses.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn mark_paused(&self) {
+        self.pauses.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<'a> From<&'a MemoryConfig> for MemoryBudget {
+    fn from(cfg: &'a MemoryConfig) -> Self {
+        MemoryBudget::new(Some(cfg.soft_limit()))
+    }
+}
+
+/// Releases accounted bytes on drop.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl MemoryReservation {
+    /// Accounts more bytes within the reservation, for bodies buffered chunk by chunk.
+    pub fn grow(&mut self, size: usize) {
+        self.budget.used.fetch_add(size, Ordering::SeqCst);
+        self.size += size;
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_soft_limit() {
+        let budget = Arc::new(MemoryBudget::new(Some(1024)));
+
+        let r1 = MemoryBudget::reserve(&budget, 1000);
+        assert!(!budget.is_exceeded());
+
+        let r2 = MemoryBudget::reserve(&budget, 100);
+        assert!(budget.is_exceeded());
+        assert_eq!(1100, budget.used());
+
+        drop(r1);
+        assert!(!budget.is_exceeded());
+
+        drop(r2);
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn test_grow() {
+        let budget = Arc::new(MemoryBudget::new(Some(1024)));
+
+        let mut reservation = MemoryBudget::reserve(&budget, 0);
+        reservation.grow(1000);
+        reservation.grow(100);
+        assert!(budget.is_exceeded());
+
+        drop(reservation);
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let budget = Arc::new(MemoryBudget::default());
+        let _r = MemoryBudget::reserve(&budget, usize::max_value() / 2);
+        assert!(!budget.is_exceeded());
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default bucket upper bounds for latencies in seconds.
+const DEFAULT_BOUNDS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A histogram with fixed buckets, which counts observations falling into each of them.
+///
+/// Observations greater than the last bound are accounted only in the total count, which plays
+/// the role of the implicit `+Inf` bucket.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// Bits of the `f64` sum of all observed values.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Constructs a histogram with the given bucket upper bounds, which must be sorted.
+    pub fn new(bounds: Vec<f64>) -> Self {
+        let buckets = bounds.iter().map(|_| AtomicU64::new(0)).collect();
+
+        Self {
+            bounds: bounds,
+            buckets: buckets,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    /// Constructs a histogram with exponentially growing buckets covering `[lowest; highest]`
+    /// range, having the given number of buckets per decade.
+    ///
+    /// Like HDR histograms, it keeps the relative error of estimated quantiles bounded regardless
+    /// of the magnitude, i.e. with 20 buckets per decade the error is within 12%.
+    pub fn exponential(lowest: f64, highest: f64, per_decade: u32) -> Self {
+        let factor = 10f64.powf(1.0 / per_decade as f64);
+        // Tolerate rounding errors, which otherwise may produce an extra bound next to the highest.
+        let steps = ((highest / lowest).log10() * per_decade as f64 - 1e-9).ceil() as i32;
+
+        let mut bounds = (0..steps).map(|step| lowest * factor.powi(step)).collect::<Vec<_>>();
+        bounds.push(highest);
+
+        Histogram::new(bounds)
+    }
+
+    /// Records the given value.
+    pub fn observe(&self, value: f64) {
+        if let Some(pos) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[pos].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let mut current = self.sum.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(current) + value).to_bits();
+            match self.sum.compare_exchange_weak(current, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(..) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Returns bucket upper bounds with cumulative counts of observations less than or equal to
+    /// each of them.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds.iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+
+    /// Estimates the given quantile, interpolating linearly within the bucket it falls into.
+    ///
+    /// Returns `None` if nothing has been observed yet. Quantiles falling beyond the last bound
+    /// are clamped to it.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = q * count as f64;
+        let mut lower = (0.0, 0);
+        for (bound, total) in self.buckets() {
+            if total as f64 >= rank {
+                let (prev, prev_total) = lower;
+                let within = (total - prev_total) as f64;
+                let ratio = if within > 0.0 { (rank - prev_total as f64) / within } else { 1.0 };
+                return Some(prev + (bound - prev) * ratio.max(0.0));
+            }
+            lower = (bound, total);
+        }
+
+        Some(lower.0)
+    }
+
+    /// Returns the total number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the sum of all observed values.
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new(DEFAULT_BOUNDS.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Histogram;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(vec![0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.1);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+
+        assert_eq!(vec![(0.1, 2), (1.0, 3)], histogram.buckets());
+        assert_eq!(4, histogram.count());
+        assert!((histogram.sum() - 2.65).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_quantile() {
+        let histogram = Histogram::new(vec![0.1, 1.0]);
+        assert_eq!(None, histogram.quantile(0.5));
+
+        for _ in 0..4 {
+            histogram.observe(0.05);
+        }
+        histogram.observe(0.5);
+        histogram.observe(5.0);
+
+        assert!((histogram.quantile(0.5).unwrap() - 0.075).abs() < 1e-9);
+        assert!((histogram.quantile(0.75).unwrap() - 0.55).abs() < 1e-9);
+        assert_eq!(Some(1.0), histogram.quantile(0.99));
+    }
+
+    #[test]
+    fn test_exponential() {
+        let histogram = Histogram::exponential(0.001, 1.0, 10);
+        let bounds = histogram.buckets().into_iter().map(|(bound, ..)| bound).collect::<Vec<_>>();
+
+        assert_eq!(31, bounds.len());
+        assert_eq!(Some(&0.001), bounds.first());
+        assert_eq!(Some(&1.0), bounds.last());
+        assert!(bounds.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -1,8 +1,14 @@
 //! This module contains implementation of some useful metrics, like `Counter`, `Meter` etc.
 
 pub use self::counter::{Count, Counter};
+pub use self::histogram::Histogram;
 pub use self::meter::{Meter, RateMeter};
+pub use self::slo::{Slo, SloSummary};
 
 mod counter;
 mod ewma;
+mod histogram;
 mod meter;
+pub mod prometheus;
+mod slo;
+pub mod status;
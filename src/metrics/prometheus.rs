@@ -0,0 +1,261 @@
+//! Rendering of metrics in Prometheus text exposition format.
+//!
+//! Meters are exported as counters of marked events, since Prometheus calculates rates itself.
+
+use std::fmt::Write;
+
+use crate::{Metrics, LATENCY_QUANTILES};
+
+use super::{Count, Histogram, Meter};
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const PREFIX: &str = "cocaine_proxy_";
+
+/// Accumulates metric families in the exposition format.
+#[derive(Debug, Default)]
+struct Exposition {
+    buf: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.buf, "# HELP {}{} {}", PREFIX, name, help).unwrap();
+        writeln!(self.buf, "# TYPE {}{} {}", PREFIX, name, kind).unwrap();
+    }
+
+    fn sample<V: ToString>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        write!(self.buf, "{}{}", PREFIX, name).unwrap();
+        if !labels.is_empty() {
+            let labels = labels.iter()
+                .map(|&(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(self.buf, "{{{}}}", labels).unwrap();
+        }
+        writeln!(self.buf, " {}", value.to_string()).unwrap();
+    }
+
+    fn counter<V: ToString>(&mut self, name: &str, help: &str, value: V) {
+        self.header(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    fn gauge<V: ToString>(&mut self, name: &str, help: &str, value: V) {
+        self.header(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    /// Writes a labeled family, whose samples are given as label value with the sample value.
+    fn labeled<V: ToString>(&mut self, name: &str, kind: &str, help: &str, label: &str, samples: Vec<(String, V)>) {
+        self.header(name, kind, help);
+        for (value, sample) in samples {
+            self.sample(name, &[(label, &value)], sample);
+        }
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+
+        let bucket = format!("{}_bucket", name);
+        for (bound, count) in histogram.buckets() {
+            self.sample(&bucket, &[("le", &bound.to_string())], count);
+        }
+        self.sample(&bucket, &[("le", "+Inf")], histogram.count());
+        self.sample(&format!("{}_sum", name), &[], histogram.sum());
+        self.sample(&format!("{}_count", name), &[], histogram.count());
+    }
+
+    /// Writes the histogram as a summary with precalculated quantiles, which are `NaN` until
+    /// anything is observed.
+    fn summary(&mut self, name: &str, help: &str, histogram: &Histogram, quantiles: &[(&str, f64)]) {
+        self.header(name, "summary", help);
+
+        for &(.., q) in quantiles {
+            let value = histogram.quantile(q).unwrap_or(f64::NAN);
+            self.sample(name, &[("quantile", &q.to_string())], value);
+        }
+        self.sample(&format!("{}_sum", name), &[], histogram.sum());
+        self.sample(&format!("{}_count", name), &[], histogram.count());
+    }
+}
+
+/// Escapes the label value as required by the exposition format.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders all metrics in Prometheus text exposition format.
+pub fn render(metrics: &Metrics) -> String {
+    let mut exp = Exposition::default();
+
+    exp.gauge("connections_active", "Number of currently open client connections.",
+        metrics.connections.active.get());
+    exp.counter("connections_accepted_total", "Number of accepted client connections.",
+        metrics.connections.accepted.get());
+    exp.counter("requests_total", "Number of received requests.", metrics.requests.count());
+    exp.counter("responses_5xx_total", "Number of responses with server error status.",
+        metrics.responses.c5xx.count());
+    let responses = &metrics.responses;
+    exp.labeled("responses_total", "counter", "Number of responses by status class.", "class", vec![
+        ("2xx".to_owned(), responses.c2xx.count()),
+        ("3xx".to_owned(), responses.c3xx.count()),
+        ("4xx".to_owned(), responses.c4xx.count()),
+        ("5xx".to_owned(), responses.c5xx.count()),
+    ]);
+    exp.labeled("responses_by_code_total", "counter", "Number of responses by status code.", "code",
+        responses.codes.counts().into_iter().map(|(code, count)| (code.to_string(), count)).collect());
+    exp.counter("requests_aborted_total", "Number of requests aborted by clients.", metrics.aborted.count());
+    exp.counter("responses_oversized_total", "Number of responses discarded because of their body size.",
+        metrics.oversized.count());
+    exp.counter("requests_shed_total", "Number of requests shed by services pools because of their backlog.",
+        metrics.shed.count());
+    exp.labeled("upstream_failures_total", "counter", "Number of requests failed upstream by the failure kind.",
+        "kind", metrics.failures.counts());
+    exp.counter("requests_canceled_total", "Number of queued requests dropped because nobody waited for them anymore.",
+        metrics.canceled.count());
+    exp.counter("protocol_violations_total", "Number of responses rejected because applications have violated the protocol.",
+        metrics.protocol_violations.count());
+    exp.counter("oversized_meta_total", "Number of responses rejected because their status or headers frame was too large.",
+        metrics.oversized_meta.count());
+    exp.counter("requests_hedged_total", "Number of hedged attempts issued.", metrics.hedged.count());
+    exp.counter("requests_hedges_won_total", "Number of hedged attempts responded before the attempts they raced.",
+        metrics.hedges_won.count());
+    exp.counter("retries_suppressed_total", "Number of retries suppressed because of exhausted retry budgets.",
+        metrics.retries_suppressed.count());
+    exp.counter("requests_headers_rejected_total", "Number of requests rejected because of their headers.",
+        metrics.headers_rejected.count());
+    exp.counter("requests_peer_forwarded_total", "Number of requests forwarded into peer proxies.",
+        metrics.peer_forwarded.count());
+    exp.histogram("request_duration_seconds", "Time from receiving requests to having their responses ready.",
+        &metrics.durations);
+    exp.summary("request_latency_seconds", "Total time of processing requests by all routes.",
+        &metrics.latency.total, LATENCY_QUANTILES);
+    exp.summary("upstream_latency_seconds", "Time spent in Cocaine calls, spanning all retry attempts.",
+        &metrics.latency.upstream, LATENCY_QUANTILES);
+
+    exp.gauge("memory_used_bytes", "Memory occupied by buffered request bodies.", metrics.memory.used());
+    exp.counter("memory_pauses_total", "Number of times accepting was paused because of memory pressure.",
+        metrics.memory.pauses());
+
+    exp.gauge("access_log_queued", "Number of access records waiting to be logged.",
+        metrics.access_log.queued());
+    exp.counter("access_log_flushed_total", "Number of access records logged.", metrics.access_log.flushed());
+    exp.counter("access_log_dropped_total", "Number of access records dropped because the queue was full.",
+        metrics.access_log.dropped());
+    exp.counter("access_log_skipped_total", "Number of access records skipped by sampling.",
+        metrics.access_log.skipped());
+    exp.gauge("access_log_disconnected", "Whether access records are held because the logging service is unreachable.",
+        metrics.access_log.disconnected() as u8);
+    exp.counter("access_log_outages_total", "Number of times the logging service was found unreachable.",
+        metrics.access_log.outages());
+    exp.counter("access_log_reconnects_total", "Number of failed attempts to reconnect to the logging service.",
+        metrics.access_log.reconnects());
+    exp.counter("access_sink_dropped_total", "Number of access records dropped because the sink queue was full.",
+        metrics.access_sink.dropped());
+    exp.counter("access_sink_failed_total", "Number of access records the sink has failed to deliver.",
+        metrics.access_sink.failed());
+
+    let breakers = &metrics.circuit_breakers;
+    exp.labeled("circuit_breaker_transitions_total", "counter", "Number of circuit breaker state transitions.",
+        "state", vec![
+            ("open".to_owned(), breakers.opened()),
+            ("half_open".to_owned(), breakers.half_opened()),
+            ("closed".to_owned(), breakers.closed()),
+        ]);
+    exp.counter("circuit_breaker_rejected_total", "Number of requests rejected by open circuit breakers.",
+        breakers.rejected());
+
+    let canaries = &metrics.canaries;
+    exp.labeled("canary_weight_percent", "gauge", "Percentage of requests routed into the canary of the service.",
+        "service", canaries.weights());
+    exp.labeled("canary_transitions_total", "counter", "Number of canary ramp transitions.", "transition", vec![
+        ("promoted".to_owned(), canaries.promoted()),
+        ("rolled_back".to_owned(), canaries.rolled_back()),
+    ]);
+
+    let mut tenants = metrics.tenants.iter().collect::<Vec<_>>();
+    tenants.sort_by(|a, b| a.0.cmp(b.0));
+    exp.labeled("tenant_requests_total", "counter", "Number of requests routed into the tenant.", "tenant",
+        tenants.iter().map(|&(name, m)| (name.clone(), m.requests.count())).collect());
+    exp.labeled("tenant_rejected_total", "counter", "Number of requests rejected by the tenant quota.", "tenant",
+        tenants.iter().map(|&(name, m)| (name.clone(), m.rejected.count())).collect());
+    exp.labeled("tenant_responses_5xx_total", "counter", "Number of tenant responses with server error status.",
+        "tenant", tenants.iter().map(|&(name, m)| (name.clone(), m.responses.c5xx.count())).collect());
+
+    let slos = metrics.slo_summaries();
+    exp.labeled("slo_availability_ratio", "gauge", "Fraction of requests answered without server errors within the SLO window.",
+        "service", slos.iter().map(|(name, s)| (name.clone(), s.availability)).collect());
+    exp.labeled("slo_error_budget_remaining_ratio", "gauge", "Fraction of the availability error budget not spent yet.",
+        "service", slos.iter().map(|(name, s)| (name.clone(), s.error_budget_remaining)).collect());
+    exp.labeled("slo_latency_ratio", "gauge", "Fraction of requests answered within the SLO latency threshold.",
+        "service", slos.iter().filter_map(|(name, s)| s.latency.map(|v| (name.clone(), v))).collect());
+    exp.labeled("slo_latency_budget_remaining_ratio", "gauge", "Fraction of the latency error budget not spent yet.",
+        "service", slos.iter().filter_map(|(name, s)| s.latency_budget_remaining.map(|v| (name.clone(), v))).collect());
+
+    let mut stalls = metrics.stalls.iter().collect::<Vec<_>>();
+    stalls.sort_by(|a, b| a.0.cmp(b.0));
+    exp.labeled("stream_stalls_total", "counter", "Number of streamed response stalls caused by slow clients.",
+        "service", stalls.iter().map(|&(name, m)| (name.clone(), m.stalls.count())).collect());
+    exp.labeled("stream_stall_seconds_total", "counter", "Time spent waiting for slow clients to drain chunks.",
+        "service", stalls.iter().map(|&(name, m)| (name.clone(), m.time.get() as f64 / 1e6)).collect());
+    exp.labeled("stream_overflows_total", "counter", "Number of streamed responses aborted because clients haven't drained the window.",
+        "service", stalls.iter().map(|&(name, m)| (name.clone(), m.overflows.count())).collect());
+
+    let pools = metrics.pools.services();
+    exp.labeled("pool_invocations_total", "counter", "Number of invocations dispatched into the service.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.invocations())).collect());
+    exp.labeled("pool_reconnects_total", "counter", "Number of service connections re-established.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.reconnects())).collect());
+    exp.labeled("pool_held_resolves_total", "counter", "Number of shrunk endpoint sets replaced with previous ones.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.held_resolves())).collect());
+    exp.labeled("pool_cached_resolves_total", "counter", "Number of resolves answered from the cache.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.cached_resolves())).collect());
+    exp.labeled("pool_overflows_total", "counter", "Number of connections opened above the limit because of saturated ones.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.overflows())).collect());
+    exp.labeled("pool_dual_stack_ipv4_total", "counter", "Number of dual-stack resolves, where IPv4 has connected first.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.dual_stack_v4())).collect());
+    exp.labeled("pool_dual_stack_ipv6_total", "counter", "Number of dual-stack resolves, where IPv6 has connected first.",
+        "service", pools.iter().map(|&(ref name, ref stats)| (name.clone(), stats.dual_stack_v6())).collect());
+
+    exp.buf
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::StatusCode;
+
+    use crate::Metrics;
+    use crate::metrics::Meter;
+
+    use super::{escape, render};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(r#"a\\b\"c\nd"#, escape("a\\b\"c\nd"));
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.requests.mark(3);
+        metrics.responses.mark(StatusCode::Ok);
+        metrics.responses.mark(StatusCode::NotFound);
+        metrics.durations.observe(0.02);
+        metrics.latency.upstream.observe(0.02);
+        metrics.pools.service("echo").mark_invocation();
+
+        let text = render(&metrics);
+        assert!(text.contains("# TYPE cocaine_proxy_requests_total counter\ncocaine_proxy_requests_total 3\n"));
+        assert!(text.contains("cocaine_proxy_responses_total{class=\"4xx\"} 1\n"));
+        assert!(text.contains("cocaine_proxy_responses_by_code_total{code=\"200\"} 1\n"));
+        assert!(!text.contains("cocaine_proxy_responses_by_code_total{code=\"500\"}"));
+        assert!(text.contains("cocaine_proxy_request_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("cocaine_proxy_request_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("cocaine_proxy_request_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("cocaine_proxy_request_latency_seconds{quantile=\"0.99\"} NaN\n"));
+        assert!(text.contains("cocaine_proxy_upstream_latency_seconds_count 1\n"));
+        assert!(text.contains("cocaine_proxy_pool_invocations_total{service=\"echo\"} 1\n"));
+    }
+}
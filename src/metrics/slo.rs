@@ -0,0 +1,203 @@
+//! Rolling compliance of services with their service level objectives.
+//!
+//! Outcomes of requests are accumulated in slots covering equal parts of the rolling window, so
+//! the oldest slot is dropped at once when the window moves past it. This makes the compliance
+//! slightly coarse on the window edge, which is fine for alerting purposes.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::SloConfig;
+
+/// Number of slots the rolling window is split into.
+const SLOTS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    /// Number of the slot since the tracker creation, used to detect stale slots.
+    epoch: u64,
+    requests: u64,
+    errors: u64,
+    slow: u64,
+}
+
+/// Snapshot of the compliance over the rolling window.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct SloSummary {
+    /// Number of requests within the window.
+    pub requests: u64,
+    /// Fraction of requests answered without server errors.
+    pub availability: f64,
+    pub availability_target: f64,
+    /// Fraction of the allowed server errors not spent yet, negative when overspent.
+    pub error_budget_remaining: f64,
+    /// Fraction of requests answered within the latency threshold, if one is configured.
+    pub latency: Option<f64>,
+    pub latency_target: Option<f64>,
+    /// Fraction of the allowed slow requests not spent yet, negative when overspent.
+    pub latency_budget_remaining: Option<f64>,
+    /// Whether all objectives are met.
+    pub compliant: bool,
+}
+
+/// Fraction of the budget of bad events that is left, given the target fraction of good ones.
+fn remaining(bad: u64, requests: u64, target: f64) -> f64 {
+    if requests == 0 {
+        1.0
+    } else {
+        1.0 - bad as f64 / ((1.0 - target) * requests as f64)
+    }
+}
+
+fn ratio(good: u64, requests: u64) -> f64 {
+    if requests == 0 {
+        1.0
+    } else {
+        good as f64 / requests as f64
+    }
+}
+
+/// Tracks availability and latency objectives of a single service.
+#[derive(Debug)]
+pub struct Slo {
+    cfg: SloConfig,
+    birth: Instant,
+    slot: Duration,
+    slots: Mutex<Vec<Slot>>,
+}
+
+impl Slo {
+    pub fn new(cfg: SloConfig) -> Self {
+        Self::with_birth(cfg, Instant::now())
+    }
+
+    fn with_birth(cfg: SloConfig, birth: Instant) -> Self {
+        let slot = cfg.window() / SLOTS as u32;
+
+        Self {
+            cfg: cfg,
+            birth: birth,
+            slot: slot,
+            slots: Mutex::new(vec![Slot::default(); SLOTS as usize]),
+        }
+    }
+
+    fn epoch(&self, now: Instant) -> u64 {
+        let elapsed = now.duration_since(self.birth);
+        let slot = self.slot.as_secs() * 1_000_000 + self.slot.subsec_micros() as u64;
+        let elapsed = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+        elapsed / slot.max(1)
+    }
+
+    /// Accounts the outcome of a request, which took the given time to process.
+    pub fn observe(&self, success: bool, latency: Duration) {
+        self.observe_at(Instant::now(), success, latency)
+    }
+
+    fn observe_at(&self, now: Instant, success: bool, latency: Duration) {
+        let epoch = self.epoch(now);
+        let mut slots = self.slots.lock().unwrap();
+        let slot = &mut slots[(epoch % SLOTS) as usize];
+        if slot.epoch != epoch {
+            *slot = Slot { epoch: epoch, ..Default::default() };
+        }
+
+        slot.requests += 1;
+        if !success {
+            slot.errors += 1;
+        }
+        if self.cfg.latency().map(|threshold| latency > threshold).unwrap_or(false) {
+            slot.slow += 1;
+        }
+    }
+
+    /// Returns the compliance over the rolling window.
+    pub fn summary(&self) -> SloSummary {
+        self.summary_at(Instant::now())
+    }
+
+    fn summary_at(&self, now: Instant) -> SloSummary {
+        let epoch = self.epoch(now);
+        let total = self.slots.lock().unwrap()
+            .iter()
+            .filter(|slot| slot.epoch + SLOTS > epoch && slot.epoch <= epoch)
+            .fold(Slot::default(), |acc, slot| {
+                Slot {
+                    epoch: 0,
+                    requests: acc.requests + slot.requests,
+                    errors: acc.errors + slot.errors,
+                    slow: acc.slow + slot.slow,
+                }
+            });
+
+        let availability = ratio(total.requests - total.errors, total.requests);
+        let target = self.cfg.availability();
+        let latency = self.cfg.latency().map(|_| ratio(total.requests - total.slow, total.requests));
+        let latency_target = self.cfg.latency().map(|_| self.cfg.latency_target());
+
+        SloSummary {
+            requests: total.requests,
+            availability: availability,
+            availability_target: target,
+            error_budget_remaining: remaining(total.errors, total.requests, target),
+            latency: latency,
+            latency_target: latency_target,
+            latency_budget_remaining: latency_target.map(|target| remaining(total.slow, total.requests, target)),
+            compliant: availability >= target && latency.map(|v| v >= self.cfg.latency_target()).unwrap_or(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use serde_yaml;
+
+    use super::Slo;
+
+    #[test]
+    fn test_summary() {
+        let cfg = serde_yaml::from_str("{availability: 0.9, latency: 100, latency_target: 0.8, window: 60}").unwrap();
+        let now = Instant::now();
+        let slo = Slo::with_birth(cfg, now);
+
+        for id in 0..20 {
+            let latency = Duration::from_millis(if id < 2 { 200 } else { 50 });
+            slo.observe_at(now, id != 0, latency);
+        }
+
+        let summary = slo.summary_at(now);
+        assert_eq!(20, summary.requests);
+        assert_eq!(0.95, summary.availability);
+        assert!((summary.error_budget_remaining - 0.5).abs() < 1e-9);
+        assert_eq!(Some(0.9), summary.latency);
+        assert!((summary.latency_budget_remaining.unwrap() - 0.5).abs() < 1e-9);
+        assert!(summary.compliant);
+
+        for _ in 0..5 {
+            slo.observe_at(now, false, Duration::from_millis(10));
+        }
+        let summary = slo.summary_at(now);
+        assert!(summary.error_budget_remaining < 0.0);
+        assert!(!summary.compliant);
+    }
+
+    #[test]
+    fn test_window_rolls() {
+        let cfg = serde_yaml::from_str("{availability: 0.99, window: 60}").unwrap();
+        let now = Instant::now();
+        let slo = Slo::with_birth(cfg, now);
+
+        slo.observe_at(now, false, Duration::from_millis(10));
+        slo.observe_at(now + Duration::from_secs(30), true, Duration::from_millis(10));
+
+        assert_eq!(2, slo.summary_at(now + Duration::from_secs(59)).requests);
+
+        let summary = slo.summary_at(now + Duration::from_secs(61));
+        assert_eq!(1, summary.requests);
+        assert_eq!(1.0, summary.availability);
+        assert_eq!(None, summary.latency);
+        assert!(summary.compliant);
+    }
+}
@@ -0,0 +1,134 @@
+//! Human-readable status page.
+//!
+//! The page is self-contained, without scripts or external resources, so it can be opened through
+//! an SSH tunnel on hosts without dashboards. It reflects the state at the moment of rendering and
+//! is refreshed by the browser every few seconds.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::{Metrics, LATENCY_QUANTILES};
+use crate::pool::PoolSnapshot;
+
+use super::{Count, Meter};
+
+pub const CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/// Number of recent errors shown on the page.
+const RECENT_ERRORS: usize = 20;
+
+const STYLE: &str = "body{font-family:monospace;margin:1em 2em}\
+    table{border-collapse:collapse;margin-bottom:1.5em}\
+    th,td{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+    th{background:#eee}";
+
+/// Escapes the text for inclusion into HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes a section with a table of the given columns, or a placeholder when there are no rows.
+fn table(out: &mut String, title: &str, columns: &[&str], rows: Vec<Vec<String>>) {
+    let _ = write!(out, "<h2>{}</h2>", escape(title));
+    if rows.is_empty() {
+        out.push_str("<p>none</p>");
+        return;
+    }
+
+    out.push_str("<table><tr>");
+    for column in columns {
+        let _ = write!(out, "<th>{}</th>", escape(column));
+    }
+    out.push_str("</tr>");
+    for row in rows {
+        out.push_str("<tr>");
+        for cell in row {
+            let _ = write!(out, "<td>{}</td>", escape(&cell));
+        }
+        out.push_str("</tr>");
+    }
+    out.push_str("</table>");
+}
+
+fn meter<M: Meter>(name: &str, meter: &M) -> Vec<String> {
+    vec![name.to_owned(), meter.count().to_string(), format!("{:.2}", meter.m01rate())]
+}
+
+/// Renders the status page from metrics, the state of pools and breakers.
+pub fn render(metrics: &Metrics, pools: &PoolSnapshot, breakers: &BTreeMap<String, &str>) -> String {
+    let mut out = String::new();
+    let _ = write!(out, "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"5\">\
+        <title>cocaine-http-proxy status</title><style>{}</style></head><body>", STYLE);
+    let _ = write!(out, "<h1>cocaine-http-proxy {}</h1>", escape(env!("CARGO_PKG_VERSION")));
+
+    table(&mut out, "Traffic", &["", "count", "rate, 1m"], vec![
+        meter("requests", &metrics.requests),
+        meter("responses 5xx", &metrics.responses.c5xx),
+        meter("aborted", &metrics.aborted),
+        meter("shed", &metrics.shed),
+        meter("protocol violations", &metrics.protocol_violations),
+        vec!["connections active".into(), metrics.connections.active.get().to_string(), String::new()],
+    ]);
+
+    let latency = [("total", &metrics.latency.total), ("upstream", &metrics.latency.upstream)];
+    let mut columns = vec![""];
+    columns.extend(LATENCY_QUANTILES.iter().map(|&(name, ..)| name));
+    table(&mut out, "Latency, ms", &columns, latency.iter()
+        .map(|&(name, histogram)| {
+            let mut row = vec![name.to_owned()];
+            row.extend(LATENCY_QUANTILES.iter().map(|&(.., q)| {
+                histogram.quantile(q).map(|v| format!("{:.1}", v * 1e3)).unwrap_or_else(|| "-".into())
+            }));
+            row
+        })
+        .collect());
+
+    table(&mut out, "Upstream failures", &["kind", "count"], metrics.failures.counts()
+        .into_iter()
+        .map(|(kind, count)| vec![kind, count.to_string()])
+        .collect());
+
+    table(&mut out, &format!("Pools, {} events queued", pools.queued),
+        &["service", "workers", "connections", "connecting", "channels", "limit", "lifespan, s"],
+        pools.services.iter()
+            .map(|(name, s)| {
+                vec![name.clone(), s.workers.to_string(), s.connections.to_string(), s.connecting.to_string(),
+                    s.channels.to_string(), s.limit.to_string(), s.lifespan.to_string()]
+            })
+            .collect());
+
+    table(&mut out, "Circuit breakers", &["service", "state"], breakers.iter()
+        .map(|(name, state)| vec![name.clone(), state.to_string()])
+        .collect());
+
+    table(&mut out, "Service level objectives", &["service", "requests", "availability", "error budget left"],
+        metrics.slo_summaries()
+            .into_iter()
+            .map(|(name, s)| {
+                vec![name, s.requests.to_string(), format!("{:.4}", s.availability),
+                    format!("{:.1}%", s.error_budget_remaining * 100.0)]
+            })
+            .collect());
+
+    table(&mut out, "Recent errors", &["time, ms", "trace", "service", "status", "class", "message"],
+        metrics.recent_errors.snapshot(RECENT_ERRORS)
+            .into_iter()
+            .map(|r| vec![r.timestamp.to_string(), r.trace, r.service, r.status.to_string(), r.class, r.message])
+            .collect());
+
+    out.push_str("</body></html>");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!("&lt;b&gt;&quot;a&quot; &amp; b&lt;/b&gt;", escape("<b>\"a\" & b</b>"));
+    }
+}
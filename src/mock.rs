@@ -0,0 +1,352 @@
+//! Minimal in-process Cocaine runtime for testing.
+//!
+//! Consists of a fake locator, which resolves every service into the fake application endpoint,
+//! and the application itself, which speaks just enough of the streaming protocol to serve HTTP
+//! requests made through `AppRoute`. An empty chunk finishes the request like `close` does, as
+//! with flow control, after which the channel is drained until closed.
+//!
+//! Both endpoints are served by plain blocking threads, so they can be used alongside with an
+//! event loop under test. Threads are leaked intentionally, they die with the test process.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use rmps;
+use serde::Serialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+/// Error category and code the runtime responds with when the application queue is full.
+const QUEUE_FULL: (u64, u64) = (0x52ff, 1);
+
+/// Incoming frame, i.e. `[span, type, args, headers]`, where headers are optional.
+///
+/// Arguments differ by the frame type, so frames are first decoded with arguments skipped.
+#[derive(Debug, Deserialize)]
+struct Frame<A = IgnoredAny> {
+    span: u64,
+    ty: u64,
+    args: A,
+    #[serde(default)]
+    _headers: Option<IgnoredAny>,
+}
+
+/// Decodes arguments of the complete raw frame.
+fn decode_args<A: DeserializeOwned>(raw: &[u8]) -> Result<A, io::Error> {
+    let frame: Frame<A> = rmps::from_slice(raw)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    Ok(frame.args)
+}
+
+/// A request received by the fake application.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    event: String,
+    chunks: Vec<Vec<u8>>,
+    error: Option<(u64, u64, String)>,
+}
+
+impl MockRequest {
+    /// Returns the error the request has been aborted with instead of being closed, if any.
+    pub fn error(&self) -> Option<&(u64, u64, String)> {
+        self.error.as_ref()
+    }
+
+    /// Returns the invoked event name.
+    pub fn event(&self) -> &str {
+        &self.event
+    }
+
+    /// Returns raw chunks received before the `close` frame.
+    pub fn chunks(&self) -> &[Vec<u8>] {
+        &self.chunks
+    }
+
+    /// Returns HTTP headers from the v1 request meta frame.
+    pub fn headers(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let meta: (String, String, String, Vec<(rmps::Raw, rmps::Raw)>, rmps::Raw) =
+            rmps::from_slice(&self.chunks[0]).expect("invalid request meta frame");
+
+        meta.3.into_iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+}
+
+/// A reply of the fake application.
+#[derive(Clone, Debug)]
+pub enum MockReply {
+    /// HTTP response, which is sent using the v1 protocol.
+    Response {
+        code: u32,
+        headers: Vec<(Vec<u8>, Vec<u8>)>,
+        body: Vec<u8>,
+    },
+    /// Raw chunks sent as is, followed by the close frame, for protocol violations.
+    Chunks(Vec<Vec<u8>>),
+    /// Protocol error with category, code and message.
+    Error(u64, u64, String),
+    /// The request is accepted, but never answered.
+    Silence,
+}
+
+impl MockReply {
+    /// Constructs an HTTP response without headers.
+    pub fn response<B: Into<Vec<u8>>>(code: u32, body: B) -> Self {
+        MockReply::Response {
+            code: code,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    /// Adds a header to the HTTP response.
+    pub fn with_header<N: Into<Vec<u8>>, V: Into<Vec<u8>>>(mut self, name: N, value: V) -> Self {
+        if let MockReply::Response { ref mut headers, .. } = self {
+            headers.push((name.into(), value.into()));
+        }
+        self
+    }
+
+    /// Constructs a "queue is full" error, which is considered safe to retry.
+    pub fn queue_full() -> Self {
+        MockReply::Error(QUEUE_FULL.0, QUEUE_FULL.1, "queue is full".into())
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> MockReply + Send + Sync;
+
+/// A fake Cocaine runtime with a single application.
+pub struct MockCocaine {
+    locator: SocketAddr,
+    invocations: Arc<AtomicUsize>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockCocaine {
+    /// Starts the runtime, where each application request is served by the given handler.
+    pub fn start<F>(handler: F) -> Result<Self, io::Error>
+        where F: Fn(&MockRequest) -> MockReply + Send + Sync + 'static
+    {
+        Self::start_with_version(1, handler)
+    }
+
+    /// Like `start`, but the locator reports the given protocol version of the application.
+    pub fn start_with_version<F>(version: u64, handler: F) -> Result<Self, io::Error>
+        where F: Fn(&MockRequest) -> MockReply + Send + Sync + 'static
+    {
+        let app = TcpListener::bind("127.0.0.1:0")?;
+        let locator = TcpListener::bind("127.0.0.1:0")?;
+
+        let app_addr = app.local_addr()?;
+        let locator_addr = locator.local_addr()?;
+
+        let invocations = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        serve(locator, move |stream| serve_locator(stream, app_addr, version))?;
+
+        let handler: Arc<Handler> = Arc::new(handler);
+        {
+            let invocations = invocations.clone();
+            let requests = requests.clone();
+            serve(app, move |stream| serve_app(stream, &*handler, &invocations, &requests))?;
+        }
+
+        let mock = Self {
+            locator: locator_addr,
+            invocations: invocations,
+            requests: requests,
+        };
+
+        Ok(mock)
+    }
+
+    /// Returns the fake locator endpoint.
+    pub fn locator_addr(&self) -> SocketAddr {
+        self.locator
+    }
+
+    /// Returns the number of application invocations, including rejected ones.
+    pub fn invocations(&self) -> usize {
+        self.invocations.load(Ordering::SeqCst)
+    }
+
+    /// Returns requests the application has completely received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn serve<F>(listener: TcpListener, f: F) -> Result<(), io::Error>
+    where F: Fn(TcpStream) -> Result<(), io::Error> + Send + Sync + 'static
+{
+    let f = Arc::new(f);
+    thread::Builder::new().name("mock-accept".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(..) => continue,
+            };
+
+            let f = f.clone();
+            thread::spawn(move || drop(f(stream)));
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Reads frames from the stream until it is closed, passing each of them along with its raw bytes
+/// to the given callback.
+fn read_frames<F>(stream: &mut TcpStream, mut f: F) -> Result<(), io::Error>
+    where F: FnMut(Frame, &[u8]) -> Result<(), io::Error>
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    loop {
+        let nread = stream.read(&mut chunk)?;
+        if nread == 0 {
+            return Ok(());
+        }
+        buf.extend(&chunk[..nread]);
+
+        // Decode as many complete frames as possible, leaving the incomplete tail in the buffer.
+        loop {
+            let mut cur = Cursor::new(&buf[..]);
+            let frame: Frame = match rmps::decode::from_read(&mut cur) {
+                Ok(frame) => frame,
+                Err(..) => break,
+            };
+
+            let consumed = cur.position() as usize;
+            let raw: Vec<u8> = buf.drain(..consumed).collect();
+            f(frame, &raw)?;
+        }
+    }
+}
+
+fn write_frame<T: Serialize>(stream: &mut TcpStream, span: u64, ty: u64, args: T) -> Result<(), io::Error> {
+    let buf = rmps::to_vec(&(span, ty, args))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+    stream.write_all(&buf)
+}
+
+/// Writes the given bytes as a single-argument chunk.
+///
+/// Cocaine expects chunks to be MessagePack strings regardless of their content.
+fn write_chunk(stream: &mut TcpStream, span: u64, data: &[u8]) -> Result<(), io::Error> {
+    write_frame(stream, span, 0, (unsafe { str::from_utf8_unchecked(data) },))
+}
+
+fn serve_locator(mut stream: TcpStream, app: SocketAddr, version: u64) -> Result<(), io::Error> {
+    let mut out = stream.try_clone()?;
+
+    read_frames(&mut stream, |frame, _| {
+        // Both upstream and downstream graphs of the `enqueue` method are streaming.
+        let mut streaming = HashMap::new();
+        streaming.insert(0u64, ("write", None::<()>));
+        streaming.insert(1u64, ("error", None::<()>));
+        streaming.insert(2u64, ("close", None::<()>));
+
+        let mut methods = HashMap::new();
+        methods.insert(0u64, ("enqueue", streaming.clone(), streaming));
+
+        let endpoints = vec![(app.ip().to_string(), app.port())];
+        write_frame(&mut out, frame.span, 0, (endpoints, version, methods))
+    })
+}
+
+fn serve_app(mut stream: TcpStream, handler: &Handler, invocations: &AtomicUsize, requests: &Mutex<Vec<MockRequest>>)
+    -> Result<(), io::Error>
+{
+    let mut out = stream.try_clone()?;
+    let mut channels: HashMap<u64, MockRequest> = HashMap::new();
+    // Answered channels kept open by the proxy for acknowledgements.
+    let mut draining = HashSet::new();
+
+    read_frames(&mut stream, |frame, raw| {
+        let span = frame.span;
+
+        if draining.contains(&span) {
+            if frame.ty != 0 {
+                draining.remove(&span);
+            }
+            return Ok(());
+        }
+
+        // The empty chunk finishes the request body of applications taking part in flow control.
+        let ty = match frame.ty {
+            0 if channels.contains_key(&span) && decode_args::<Vec<rmps::Raw>>(raw)?.iter().all(|v| v.as_bytes().is_empty()) => {
+                draining.insert(span);
+                2
+            }
+            ty => ty,
+        };
+
+        match (channels.remove(&span), ty) {
+            (None, 0) => {
+                invocations.fetch_add(1, Ordering::SeqCst);
+
+                let args: Vec<rmps::Raw> = decode_args(raw)?;
+                let event = args.first()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .unwrap_or_default();
+                channels.insert(span, MockRequest { event: event, chunks: Vec::new(), error: None });
+            }
+            (Some(mut req), 0) => {
+                let args: Vec<rmps::Raw> = decode_args(raw)?;
+                req.chunks.extend(args.iter().map(|v| v.as_bytes().to_vec()));
+                channels.insert(span, req);
+            }
+            (Some(req), 2) => {
+                let reply = handler(&req);
+                requests.lock().unwrap().push(req);
+
+                match reply {
+                    MockReply::Response { code, headers, body } => {
+                        let headers = headers.iter()
+                            .map(|&(ref name, ref value)| unsafe {
+                                (str::from_utf8_unchecked(name), str::from_utf8_unchecked(value))
+                            })
+                            .collect::<Vec<_>>();
+                        let meta = rmps::to_vec(&(code, headers))
+                            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+
+                        write_chunk(&mut out, span, &meta)?;
+                        if !body.is_empty() {
+                            write_chunk(&mut out, span, &body)?;
+                        }
+                        write_frame(&mut out, span, 2, [(); 0])?;
+                    }
+                    MockReply::Chunks(chunks) => {
+                        for chunk in chunks {
+                            write_chunk(&mut out, span, &chunk)?;
+                        }
+                        write_frame(&mut out, span, 2, [(); 0])?;
+                    }
+                    MockReply::Error(category, code, message) => {
+                        write_frame(&mut out, span, 1, ((category, code), message))?;
+                    }
+                    MockReply::Silence => {}
+                }
+            }
+            (Some(mut req), 1) => {
+                // Aborted requests are not answered.
+                let ((category, code), message): ((u64, u64), String) = decode_args(raw)?;
+                req.error = Some((category, code, message));
+                requests.lock().unwrap().push(req);
+            }
+            (.., ty) => {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("unexpected frame type {}", ty)));
+            }
+        }
+
+        Ok(())
+    })
+}
@@ -1,8 +1,15 @@
+use std::error;
+use std::fmt::{self, Display, Formatter};
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use futures::{Async, Poll, Stream};
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de;
+
 use tokio_core::net::{TcpListener, TcpStream};
 
 /// Byte-oriented stream acceptor.
@@ -43,3 +50,192 @@ impl Stream for Incoming {
         }
     }
 }
+
+/// An IP network in CIDR notation, like `10.0.0.0/8` or `2a02:6b8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Returns `true` if the given address belongs to this network.
+    ///
+    /// IPv4-mapped IPv6 addresses are treated as IPv4 ones, because that is how IPv4 peers are
+    /// seen when listening on dual-stack sockets.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::max_value().checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::max_value().checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            (..) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseNetworkError(String);
+
+impl Display for ParseNetworkError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "invalid network `{}`", self.0)
+    }
+}
+
+impl error::Error for ParseNetworkError {}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseNetworkError(s.into());
+
+        let (addr, prefix) = match s.find('/') {
+            Some(pos) => {
+                let addr = IpAddr::from_str(&s[..pos]).map_err(|_| err())?;
+                let prefix = u8::from_str(&s[pos + 1..]).map_err(|_| err())?;
+                (addr, prefix)
+            }
+            None => {
+                let addr = IpAddr::from_str(s).map_err(|_| err())?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+                (addr, prefix)
+            }
+        };
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(err());
+        }
+
+        Ok(Self { addr: addr, prefix: prefix })
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Network {
+    fn serialize<S: Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+        se.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        Network::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// A listening endpoint, either TCP or Unix socket.
+///
+/// In configs TCP endpoints are written as `[address, port]` tuples, while Unix sockets are
+/// written as plain paths.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Tcp(addr)
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Endpoint::Tcp(ref addr) => write!(fmt, "{}", addr),
+            Endpoint::Unix(ref path) => write!(fmt, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EndpointRepr {
+    Tcp(IpAddr, u16),
+    Unix(PathBuf),
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Endpoint::Tcp(ref addr) => (addr.ip(), addr.port()).serialize(se),
+            Endpoint::Unix(ref path) => path.serialize(se),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let endpoint = match EndpointRepr::deserialize(de)? {
+            EndpointRepr::Tcp(addr, port) => Endpoint::Tcp(SocketAddr::new(addr, port)),
+            EndpointRepr::Unix(path) => Endpoint::Unix(path),
+        };
+
+        Ok(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use serde_yaml;
+
+    use super::{Endpoint, Network};
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_network() {
+        assert_eq!("10.0.0.0/8", Network::from_str("10.0.0.0/8").unwrap().to_string());
+        assert_eq!("::1/128", Network::from_str("::1").unwrap().to_string());
+        assert!(Network::from_str("10.0.0.0/33").is_err());
+        assert!(Network::from_str("10.0.0/8").is_err());
+        assert!(Network::from_str("::/129").is_err());
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let net = Network::from_str("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let net = Network::from_str("2a02:6b8::/32").unwrap();
+        assert!(net.contains(ip("2a02:6b8:0:3712::1:6d")));
+        assert!(!net.contains(ip("2a02:6b9::1")));
+
+        assert!(Network::from_str("0.0.0.0/0").unwrap().contains(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn test_deserialize_endpoint() {
+        let endpoint: Endpoint = serde_yaml::from_str(r#"["::1", 10000]"#).unwrap();
+        assert_eq!("[::1]:10000", endpoint.to_string());
+
+        let endpoint: Endpoint = serde_yaml::from_str("/run/cocaine-http-proxy/admin.sock").unwrap();
+        assert_eq!(Endpoint::Unix("/run/cocaine-http-proxy/admin.sock".into()), endpoint);
+    }
+}
@@ -0,0 +1,280 @@
+//! Per-service circuit breakers.
+//!
+//! A breaker counts requests and failures of a service within a fixed time window. Once the
+//! failure ratio crosses the threshold, the breaker opens and requests are rejected without
+//! reaching the service. After the cooldown a single probe request is let through, and its outcome
+//! decides whether the breaker closes or opens again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::config::CircuitBreakerConfig;
+
+/// Counters of breaker state transitions.
+#[derive(Debug, Default)]
+pub struct BreakerStats {
+    opened: AtomicUsize,
+    half_opened: AtomicUsize,
+    closed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+impl BreakerStats {
+    /// Returns the number of times breakers have opened.
+    pub fn opened(&self) -> usize {
+        self.opened.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of times breakers have let a probe request through.
+    pub fn half_opened(&self) -> usize {
+        self.half_opened.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of times breakers have closed after a successful probe.
+    pub fn closed(&self) -> usize {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests rejected by open breakers.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed {
+        since: Instant,
+        requests: u32,
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The moment the last probe was let through. A probe whose outcome is never reported, for
+    /// example because the client went away, is replaced by another one after the cooldown.
+    HalfOpen {
+        probe: Instant,
+    },
+    /// Opened by an operator, stays so until reset manually.
+    Tripped,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match *self {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+            State::Tripped => "tripped",
+        }
+    }
+}
+
+/// Circuit breakers of all services within a cluster, shared between all workers.
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    cfg: CircuitBreakerConfig,
+    states: Mutex<HashMap<String, State>>,
+    stats: Arc<BreakerStats>,
+}
+
+impl CircuitBreakers {
+    pub fn new(cfg: CircuitBreakerConfig, stats: Arc<BreakerStats>) -> Self {
+        Self {
+            cfg: cfg,
+            states: Mutex::new(HashMap::new()),
+            stats: stats,
+        }
+    }
+
+    /// Checks whether a request to the given service may proceed.
+    pub fn admit(&self, service: &str, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+
+        let state = match states.get_mut(service) {
+            Some(state) => state,
+            None => return true,
+        };
+
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { probe: now };
+                self.stats.half_opened.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            State::HalfOpen { probe } if now >= probe + self.cfg.cooldown() => {
+                *state = State::HalfOpen { probe: now };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } | State::Tripped => {
+                self.stats.rejected.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    /// Reports the outcome of an admitted request to the given service.
+    pub fn report(&self, service: &str, success: bool, now: Instant) {
+        let mut states = self.states.lock().unwrap();
+
+        let state = states.entry(service.to_owned()).or_insert_with(|| State::Closed {
+            since: now,
+            requests: 0,
+            failures: 0,
+        });
+
+        match *state {
+            State::Closed { since, requests, failures } => {
+                let (since, requests, failures) = if now >= since + self.cfg.window() {
+                    (now, 0, 0)
+                } else {
+                    (since, requests, failures)
+                };
+
+                let requests = requests + 1;
+                let failures = if success { failures } else { failures + 1 };
+
+                let tripped = requests >= self.cfg.min_requests() &&
+                    failures as f64 >= requests as f64 * self.cfg.threshold();
+
+                if tripped {
+                    *state = State::Open { until: now + self.cfg.cooldown() };
+                    self.stats.opened.fetch_add(1, Ordering::SeqCst);
+                } else {
+                    *state = State::Closed { since: since, requests: requests, failures: failures };
+                }
+            }
+            State::HalfOpen { .. } => {
+                if success {
+                    *state = State::Closed { since: now, requests: 0, failures: 0 };
+                    self.stats.closed.fetch_add(1, Ordering::SeqCst);
+                } else {
+                    *state = State::Open { until: now + self.cfg.cooldown() };
+                    self.stats.opened.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            // Late outcomes of requests admitted before the breaker opened.
+            State::Open { .. } | State::Tripped => {}
+        }
+    }
+
+    /// Opens the breaker of the given service until it is reset manually.
+    pub fn trip(&self, service: &str) {
+        self.states.lock().unwrap().insert(service.to_owned(), State::Tripped);
+        self.stats.opened.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Closes the breaker of the given service, forgetting its recent failures.
+    pub fn reset(&self, service: &str) {
+        if let Some(state) = self.states.lock().unwrap().remove(service) {
+            if let State::Closed { .. } = state {
+                return;
+            }
+            self.stats.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the state name of each breaker that has seen requests, ordered by services.
+    pub fn states(&self) -> BTreeMap<String, &'static str> {
+        self.states.lock().unwrap()
+            .iter()
+            .map(|(service, state)| (service.clone(), state.name()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use serde_yaml;
+
+    use crate::config::CircuitBreakerConfig;
+
+    use super::{BreakerStats, CircuitBreakers};
+
+    fn breakers() -> CircuitBreakers {
+        let cfg: CircuitBreakerConfig = serde_yaml::from_str("{window: 10, min_requests: 4, threshold: 0.5, cooldown: 5}")
+            .unwrap();
+        CircuitBreakers::new(cfg, Arc::new(BreakerStats::default()))
+    }
+
+    #[test]
+    fn test_trip_and_recover() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            breakers.report("app", true, now);
+        }
+        breakers.report("app", false, now);
+        assert!(breakers.admit("app", now));
+
+        breakers.report("app", false, now);
+        assert!(!breakers.admit("app", now));
+        assert!(breakers.admit("other", now));
+        assert_eq!((1, 1), (breakers.stats.opened(), breakers.stats.rejected()));
+
+        // After the cooldown only a single probe passes.
+        let later = now + Duration::from_secs(5);
+        assert!(breakers.admit("app", later));
+        assert!(!breakers.admit("app", later));
+        assert_eq!(1, breakers.stats.half_opened());
+
+        breakers.report("app", true, later);
+        assert!(breakers.admit("app", later));
+        assert_eq!(1, breakers.stats.closed());
+    }
+
+    #[test]
+    fn test_failed_probe_opens_again() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            breakers.report("app", false, now);
+        }
+
+        let later = now + Duration::from_secs(5);
+        assert!(breakers.admit("app", later));
+        breakers.report("app", false, later);
+
+        assert!(!breakers.admit("app", later + Duration::from_secs(1)));
+        assert_eq!(2, breakers.stats.opened());
+    }
+
+    #[test]
+    fn test_manual_trip_and_reset() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        breakers.trip("app");
+        assert!(!breakers.admit("app", now + Duration::from_secs(3600)));
+        breakers.report("app", true, now);
+        assert_eq!(Some(&"tripped"), breakers.states().get("app"));
+
+        breakers.reset("app");
+        assert!(breakers.admit("app", now));
+        assert_eq!((1, 1), (breakers.stats.opened(), breakers.stats.closed()));
+        assert!(breakers.states().is_empty());
+    }
+
+    #[test]
+    fn test_window_resets_counters() {
+        let breakers = breakers();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            breakers.report("app", false, now);
+        }
+
+        let later = now + Duration::from_secs(10);
+        breakers.report("app", false, later);
+        assert!(breakers.admit("app", later));
+    }
+}
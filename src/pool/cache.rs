@@ -0,0 +1,245 @@
+//! Caching of service resolves.
+//!
+//! Every pool connection resolves its service on its own, so a burst of requests to a service
+//! that can't be resolved, for example a misspelled one, turns into a burst of locator requests.
+//! Resolved endpoints are remembered for a while and failures for a brief moment, letting
+//! connections of all pools within a worker share a single resolve.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+
+use cocaine::{Error, Resolve, ResolveInfo, Resolver};
+
+use crate::config::ResolveCacheConfig;
+use crate::pool::PoolStats;
+
+/// Number of entries, above which expired ones are purged on insertion.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// Resolve failure, which is replayed with the original kind and message while cached.
+#[derive(Clone, Debug, PartialEq)]
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Failure {
+    fn new(err: &Error) -> Self {
+        // Only I/O errors carry a kind, other ones are described by their messages alone.
+        let kind = match *err {
+            Error::Io(ref err) => err.kind(),
+            _ => ErrorKind::Other,
+        };
+
+        Self {
+            kind: kind,
+            message: err.to_string(),
+        }
+    }
+
+    fn to_error(&self) -> Error {
+        Error::from(io::Error::new(self.kind, format!("{} (cached resolve failure)", self.message)))
+    }
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    result: Result<T, Failure>,
+    expires: Instant,
+}
+
+/// Resolve results of services, either endpoints or failures.
+#[derive(Debug)]
+struct Entries<T> {
+    services: HashMap<String, Entry<T>>,
+}
+
+impl<T: Clone> Entries<T> {
+    fn new() -> Self {
+        Self { services: HashMap::new() }
+    }
+
+    fn get(&mut self, name: &str, now: Instant) -> Option<Result<T, Failure>> {
+        let expired = match self.services.get(name) {
+            Some(entry) if entry.expires > now => return Some(entry.result.clone()),
+            Some(..) => true,
+            None => false,
+        };
+
+        if expired {
+            self.services.remove(name);
+        }
+
+        None
+    }
+
+    fn put(&mut self, name: &str, result: Result<T, Failure>, ttl: Duration, now: Instant) {
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        // Names come from requests, so entries of unknown services must not pile up.
+        if self.services.len() >= PURGE_THRESHOLD {
+            self.services.retain(|_, entry| entry.expires > now);
+        }
+
+        let entry = Entry {
+            result: result,
+            expires: now + ttl,
+        };
+        self.services.insert(name.to_owned(), entry);
+    }
+
+    /// Forgets results of the given service or of all services, returning the number of them.
+    fn clear(&mut self, name: Option<&str>) -> usize {
+        match name {
+            Some(name) => self.services.remove(name).map_or(0, |_| 1),
+            None => self.services.drain().count(),
+        }
+    }
+}
+
+/// Resolver, which remembers resolve results for configured TTLs.
+///
+/// All clones share remembered results, so a single cache serves the whole worker. Without the
+/// config it resolves as is.
+#[derive(Clone)]
+pub struct ResolveCache {
+    resolver: Resolver,
+    cfg: Option<ResolveCacheConfig>,
+    entries: Arc<Mutex<Entries<ResolveInfo<SocketAddr>>>>,
+    stats: Arc<PoolStats>,
+}
+
+impl ResolveCache {
+    pub fn new(resolver: Resolver, cfg: Option<ResolveCacheConfig>) -> Self {
+        Self {
+            resolver: resolver,
+            cfg: cfg,
+            entries: Arc::new(Mutex::new(Entries::new())),
+            stats: Arc::new(PoolStats::default()),
+        }
+    }
+
+    /// Sets per-service counters, where resolves answered from the cache are accounted.
+    pub fn with_stats(mut self, stats: Arc<PoolStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Forgets cached results of the given service or of all services, returning the number of
+    /// them.
+    pub fn clear(&self, name: Option<&str>) -> usize {
+        self.entries.lock().unwrap().clear(name)
+    }
+}
+
+impl Resolve for ResolveCache {
+    type Future = Box<dyn Future<Item = ResolveInfo<SocketAddr>, Error = Error>>;
+
+    fn resolve(&mut self, name: &str) -> Self::Future {
+        let cfg = match self.cfg {
+            Some(cfg) => cfg,
+            None => return Box::new(self.resolver.resolve(name)),
+        };
+
+        if let Some(result) = self.entries.lock().unwrap().get(name, Instant::now()) {
+            self.stats.service(name).mark_cached_resolve();
+
+            return match result {
+                Ok(info) => Box::new(future::ok(info)),
+                Err(failure) => Box::new(future::err(failure.to_error())),
+            };
+        }
+
+        let name = name.to_owned();
+        let entries = self.entries.clone();
+
+        let future = self.resolver.resolve(&name).then(move |result| {
+            let mut entries = entries.lock().unwrap();
+
+            match result {
+                Ok(info) => {
+                    entries.put(&name, Ok(info.clone()), cfg.ttl(), Instant::now());
+                    Ok(info)
+                }
+                Err(err) => {
+                    entries.put(&name, Err(Failure::new(&err)), cfg.negative_ttl(), Instant::now());
+                    Err(err)
+                }
+            }
+        });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, ErrorKind};
+    use std::time::{Duration, Instant};
+
+    use cocaine::Error;
+
+    use super::{Entries, Failure, PURGE_THRESHOLD};
+
+    fn not_found() -> Failure {
+        Failure::new(&Error::from(io::Error::new(ErrorKind::NotFound, "not found")))
+    }
+
+    #[test]
+    fn test_get_expires() {
+        let mut entries = Entries::new();
+        let now = Instant::now();
+
+        entries.put("echo", Ok(1), Duration::from_secs(5), now);
+        entries.put("ehco", Err::<u32, _>(not_found()), Duration::from_secs(1), now);
+
+        assert_eq!(Some(Ok(1)), entries.get("echo", now + Duration::from_secs(1)));
+        assert_eq!(None, entries.get("ehco", now + Duration::from_secs(1)));
+        assert_eq!(None, entries.get("echo", now + Duration::from_secs(5)));
+        assert!(entries.services.is_empty());
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_caching() {
+        let mut entries = Entries::new();
+        let now = Instant::now();
+
+        entries.put("echo", Err::<u32, _>(not_found()), Duration::from_secs(0), now);
+        assert_eq!(None, entries.get("echo", now));
+    }
+
+    #[test]
+    fn test_failure_keeps_kind() {
+        let failure = Failure::new(&Error::from(io::Error::new(ErrorKind::TimedOut, "timed out")));
+        assert_eq!(ErrorKind::TimedOut, failure.kind);
+
+        match failure.to_error() {
+            Error::Io(ref err) => {
+                assert_eq!(ErrorKind::TimedOut, err.kind());
+                assert!(err.to_string().contains("timed out"));
+            }
+            ref err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_put_purges_expired() {
+        let mut entries = Entries::new();
+        let now = Instant::now();
+
+        for id in 0..PURGE_THRESHOLD {
+            entries.put(&id.to_string(), Ok(id), Duration::from_secs(1), now);
+        }
+        entries.put("echo", Ok(0), Duration::from_secs(5), now + Duration::from_secs(2));
+
+        assert_eq!(1, entries.services.len());
+        assert_eq!(1, entries.clear(None));
+    }
+}
@@ -0,0 +1,190 @@
+//! Simulated discovery faults for resilience testing.
+//!
+//! Available with the `chaos` feature only and meant for test and staging installations. Faults
+//! are injected into resolves made by service pools before the resolve guard sees them, so circuit
+//! breakers, the guard and reconnection backoff can be exercised without breaking a real Cocaine
+//! cluster. Established connections are not affected until they reconnect.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::{self, Either};
+use tokio_core::reactor::{Handle, Timeout};
+
+use cocaine::{Error, ResolveInfo};
+
+use crate::random;
+
+/// Service name, whose faults apply to all services without their own ones.
+pub const ANY_SERVICE: &str = "*";
+
+/// Faults injected into resolves of a service.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Fault {
+    /// Probability of a resolve failing as if the locator were unreachable.
+    pub flap: f64,
+    /// Fraction of resolved endpoints that disappear.
+    pub drop: f64,
+    /// Delay before each resolve in milliseconds.
+    pub delay: u64,
+}
+
+impl Fault {
+    /// Parses the fault from `flap`, `drop` and `delay` query parameters, all of which are
+    /// optional.
+    pub fn from_query(query: &HashMap<&str, &str>) -> Result<Self, String> {
+        let fraction = |name: &str| -> Result<f64, String> {
+            match query.get(name) {
+                Some(value) => match f64::from_str(value) {
+                    Ok(value) if value >= 0.0 && value <= 1.0 => Ok(value),
+                    _ => Err(format!("`{}` must be a number in [0; 1] range", name)),
+                },
+                None => Ok(0.0),
+            }
+        };
+
+        let delay = match query.get("delay") {
+            Some(delay) => u64::from_str(delay).map_err(|_| "`delay` must be an integer number of milliseconds".to_owned())?,
+            None => 0,
+        };
+
+        let fault = Fault {
+            flap: fraction("flap")?,
+            drop: fraction("drop")?,
+            delay: delay,
+        };
+
+        Ok(fault)
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "flap {}, drop {}, delay {} ms", self.flap, self.drop, self.delay)
+    }
+}
+
+/// Removes the given fraction of endpoints, starting from the given offset and wrapping around.
+fn shrink(mut addrs: Vec<SocketAddr>, drop: f64, offset: usize) -> Vec<SocketAddr> {
+    if addrs.is_empty() {
+        return addrs;
+    }
+
+    let len = addrs.len();
+    let dropped = (len as f64 * drop).round() as usize;
+    addrs.rotate_left(offset % len);
+    addrs.truncate(len - dropped);
+    addrs
+}
+
+/// Faults currently simulated by a single worker.
+#[derive(Debug, Default)]
+pub struct Chaos {
+    faults: Mutex<HashMap<String, Fault>>,
+}
+
+impl Chaos {
+    /// Sets faults of the given service, or clears them.
+    pub fn set(&self, service: String, fault: Option<Fault>) {
+        let mut faults = self.faults.lock().unwrap();
+        match fault {
+            Some(fault) => faults.insert(service, fault),
+            None => faults.remove(&service),
+        };
+    }
+
+    /// Returns all faults currently simulated.
+    pub fn faults(&self) -> BTreeMap<String, Fault> {
+        self.faults.lock().unwrap()
+            .iter()
+            .map(|(service, &fault)| (service.clone(), fault))
+            .collect()
+    }
+
+    fn fault(&self, service: &str) -> Option<Fault> {
+        let faults = self.faults.lock().unwrap();
+        faults.get(service).or_else(|| faults.get(ANY_SERVICE)).cloned()
+    }
+
+    /// Wraps the resolve of the given service with its faults, if any.
+    pub fn inject<F>(&self, service: &str, future: F, handle: &Handle) -> Box<dyn Future<Item = ResolveInfo<SocketAddr>, Error = Error>>
+        where F: Future<Item = ResolveInfo<SocketAddr>, Error = Error> + 'static
+    {
+        let fault = match self.fault(service) {
+            Some(fault) => fault,
+            None => return Box::new(future),
+        };
+
+        let delay = future::result(Timeout::new(Duration::from_millis(fault.delay), handle))
+            .flatten()
+            .map_err(Error::from);
+
+        let flap = random::gen::<f64>() < fault.flap;
+        let offset = random::gen::<usize>();
+
+        let future = delay.and_then(move |()| {
+            if flap {
+                let err = io::Error::new(ErrorKind::ConnectionRefused, "locator flap simulated by chaos mode");
+                return Either::A(future::err(Error::from(err)));
+            }
+
+            Either::B(future.map(move |mut info| {
+                info.addrs = shrink(info.addrs, fault.drop, offset);
+                info
+            }))
+        });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use super::{shrink, Chaos, Fault};
+
+    fn addrs(count: u16) -> Vec<SocketAddr> {
+        (0..count).map(|port| SocketAddr::from(([127, 0, 0, 1], 10000 + port))).collect()
+    }
+
+    #[test]
+    fn test_shrink() {
+        assert_eq!(addrs(10)[3..8].to_vec(), shrink(addrs(10), 0.5, 3));
+        assert_eq!(addrs(4), shrink(addrs(4), 0.0, 0));
+        assert!(shrink(addrs(4), 1.0, 1).is_empty());
+        assert!(shrink(Vec::new(), 0.5, 1).is_empty());
+    }
+
+    #[test]
+    fn test_from_query() {
+        let mut query = HashMap::new();
+        query.insert("flap", "0.5");
+        query.insert("delay", "200");
+        assert_eq!(Ok(Fault { flap: 0.5, drop: 0.0, delay: 200 }), Fault::from_query(&query));
+
+        query.insert("drop", "2");
+        assert!(Fault::from_query(&query).is_err());
+    }
+
+    #[test]
+    fn test_any_service() {
+        let chaos = Chaos::default();
+        let fault = Fault { flap: 1.0, ..Default::default() };
+        chaos.set("*".into(), Some(fault));
+        chaos.set("echo".into(), Some(Fault::default()));
+
+        assert_eq!(Some(fault), chaos.fault("storage"));
+        assert_eq!(Some(Fault::default()), chaos.fault("echo"));
+
+        chaos.set("*".into(), None);
+        assert_eq!(None, chaos.fault("storage"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Happy eyeballs for dual-stack services.
+//!
+//! Services connect to resolved endpoints one by one in the locator order, so a broken IPv6 path
+//! costs a whole connect timeout on every reconnection before IPv4 endpoints are even tried. When
+//! a service resolves into endpoints of both families, connections to the first endpoint of each
+//! family are raced in the spirit of RFC 8305, giving IPv6 a head start, and resolved endpoints
+//! are reordered to alternate families starting with the one connected first.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::Future;
+use futures::future::{self, Either};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+
+use cocaine::{Error, Resolve, ResolveInfo};
+use cocaine::logging::{Logger, Severity};
+
+use crate::config::HappyEyeballsConfig;
+use crate::pool::{PoolStats, ResolveGuard};
+use crate::pool::guard::Resolved;
+
+/// Address family of an endpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(addr: &SocketAddr) -> Self {
+        if addr.is_ipv4() {
+            Family::V4
+        } else {
+            Family::V6
+        }
+    }
+}
+
+/// Reorders endpoints so that families alternate starting with the preferred one, keeping their
+/// relative order within each family.
+fn interleave(addrs: Vec<SocketAddr>, preferred: Family) -> Vec<SocketAddr> {
+    let (first, second): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| Family::of(addr) == preferred);
+
+    let mut result = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+
+    result
+}
+
+/// Races connections to the given endpoints, resolving with the family of the one connected
+/// first, or with `None` if neither has connected in time.
+///
+/// The IPv4 attempt is started after the configured delay, unless IPv6 has connected earlier.
+fn race(v6: SocketAddr, v4: SocketAddr, cfg: &HappyEyeballsConfig, handle: &Handle)
+    -> Box<dyn Future<Item = Option<Family>, Error = ()>>
+{
+    let first = TcpStream::connect(&v6, handle).map(|_| Family::V6);
+
+    let connect = handle.clone();
+    let second = future::result(Timeout::new(cfg.delay(), handle))
+        .flatten()
+        .and_then(move |()| TcpStream::connect(&v4, &connect))
+        .map(|_| Family::V4);
+
+    let winner = first.select(second).then(|result| match result {
+        Ok((family, ..)) => Either::A(future::ok(Some(family))),
+        // The other attempt may still succeed.
+        Err((.., other)) => Either::B(other.then(|result| Ok::<_, io::Error>(result.ok()))),
+    });
+
+    let timeout = future::result(Timeout::new(cfg.timeout(), handle))
+        .flatten()
+        .then(|_| Ok(None));
+
+    let future = winner.select(timeout)
+        .map(|(family, ..)| family)
+        .map_err(|_| ());
+
+    Box::new(future)
+}
+
+/// Resolver, which orders endpoints of dual-stack services by the family connected first.
+///
+/// Without the config it resolves as is.
+#[derive(Clone)]
+pub struct DualStackResolver {
+    resolver: ResolveGuard,
+    cfg: Option<HappyEyeballsConfig>,
+    handle: Handle,
+    stats: Arc<PoolStats>,
+    log: Logger,
+}
+
+impl DualStackResolver {
+    pub fn new(resolver: ResolveGuard, cfg: Option<HappyEyeballsConfig>, handle: Handle, log: Logger) -> Self {
+        Self {
+            resolver: resolver,
+            cfg: cfg,
+            handle: handle,
+            stats: Arc::new(PoolStats::default()),
+            log: log,
+        }
+    }
+
+    /// Sets per-service counters, where families connected first are accounted.
+    pub fn with_stats(mut self, stats: Arc<PoolStats>) -> Self {
+        self.resolver = self.resolver.with_stats(stats.clone());
+        self.stats = stats;
+        self
+    }
+
+    /// Returns endpoints each service has been resolved into most recently, before reordering.
+    pub fn resolved(&self) -> HashMap<String, Resolved> {
+        self.resolver.resolved()
+    }
+
+    /// Forgets cached resolve results of the given service or of all services, returning the
+    /// number of them.
+    pub fn clear_cache(&self, name: Option<&str>) -> usize {
+        self.resolver.clear_cache(name)
+    }
+}
+
+impl Resolve for DualStackResolver {
+    type Future = Box<dyn Future<Item = ResolveInfo<SocketAddr>, Error = Error>>;
+
+    fn resolve(&mut self, name: &str) -> Self::Future {
+        let future = self.resolver.resolve(name);
+
+        let cfg = match self.cfg {
+            Some(cfg) => cfg,
+            None => return future,
+        };
+
+        let name = name.to_owned();
+        let handle = self.handle.clone();
+        let stats = self.stats.clone();
+        let log = self.log.clone();
+
+        let future = future.and_then(move |mut info| {
+            let v6 = info.addrs.iter().find(|addr| addr.is_ipv6()).cloned();
+            let v4 = info.addrs.iter().find(|addr| addr.is_ipv4()).cloned();
+
+            let (v6, v4) = match (v6, v4) {
+                (Some(v6), Some(v4)) => (v6, v4),
+                _ => return Either::A(future::ok(info)),
+            };
+
+            let future = race(v6, v4, &cfg, &handle).then(move |result| {
+                match result {
+                    Ok(Some(family)) => {
+                        cocaine_log!(log, Severity::Debug, "`{}` has connected over {:?} first", name, family);
+                        stats.service(&name).mark_dual_stack(family);
+                        let addrs = mem::replace(&mut info.addrs, Vec::new());
+                        info.addrs = interleave(addrs, family);
+                    }
+                    Ok(None) | Err(()) => {
+                        cocaine_log!(log, Severity::Warn, "neither {} nor {} endpoint of `{}` has connected in {:?}, keeping resolved order",
+                            v6, v4, name, cfg.timeout());
+                    }
+                }
+
+                Ok(info)
+            });
+
+            Either::B(future)
+        });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{interleave, Family};
+
+    #[test]
+    fn test_interleave() {
+        let v6: Vec<SocketAddr> = vec!["[::1]:10053".parse().unwrap(), "[::2]:10053".parse().unwrap()];
+        let v4: Vec<SocketAddr> = vec![
+            "10.0.0.1:10053".parse().unwrap(),
+            "10.0.0.2:10053".parse().unwrap(),
+            "10.0.0.3:10053".parse().unwrap(),
+        ];
+        let addrs = vec![v6[0], v6[1], v4[0], v4[1], v4[2]];
+
+        assert_eq!(vec![v4[0], v6[0], v4[1], v6[1], v4[2]], interleave(addrs.clone(), Family::V4));
+        assert_eq!(vec![v6[0], v4[0], v6[1], v4[1], v4[2]], interleave(addrs, Family::V6));
+    }
+}
@@ -0,0 +1,245 @@
+//! Protection of service pools against discovery flaps.
+//!
+//! Every pool connection resolves its service on its own, so a locator that briefly returns a
+//! fraction of endpoints makes reconnecting services pile up on the few remaining ones, which is a
+//! common cause of cascading failures. The guard remembers the last accepted endpoint set of each
+//! service and keeps resolving into it for a grace period once a drastically smaller set arrives.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use futures::Future;
+#[cfg(feature = "chaos")]
+use tokio_core::reactor::Handle;
+
+use cocaine::{Error, Resolve, ResolveInfo};
+use cocaine::logging::{Logger, Severity};
+
+use crate::config::ResolveGuardConfig;
+use crate::pool::{PoolStats, ResolveCache};
+#[cfg(feature = "chaos")]
+use crate::pool::chaos::Chaos;
+
+/// The last accepted endpoint set of a service.
+#[derive(Debug)]
+struct Known {
+    addrs: Vec<SocketAddr>,
+    /// When the currently held back shrunk set has first been seen.
+    shrunk_at: Option<Instant>,
+}
+
+/// Endpoints a service has been resolved into most recently, after the guard verdict.
+#[derive(Clone, Debug)]
+pub struct Resolved {
+    pub addrs: Vec<SocketAddr>,
+    pub at: SystemTime,
+}
+
+#[derive(Debug)]
+enum Verdict {
+    Accept,
+    Hold,
+    /// The set is still shrunk after the grace period.
+    Expire,
+}
+
+#[derive(Debug, Default)]
+struct Endpoints {
+    services: HashMap<String, Known>,
+}
+
+impl Endpoints {
+    fn check(&mut self, cfg: &ResolveGuardConfig, name: &str, addrs: &[SocketAddr], now: Instant) -> Verdict {
+        let known = match self.services.get_mut(name) {
+            Some(known) => known,
+            None => {
+                self.services.insert(name.to_owned(), Known { addrs: addrs.to_vec(), shrunk_at: None });
+                return Verdict::Accept;
+            }
+        };
+
+        if addrs.len() as f64 >= known.addrs.len() as f64 * cfg.min_ratio() {
+            known.addrs = addrs.to_vec();
+            known.shrunk_at = None;
+            return Verdict::Accept;
+        }
+
+        let shrunk_at = *known.shrunk_at.get_or_insert(now);
+        if now.duration_since(shrunk_at) < cfg.grace_period() {
+            Verdict::Hold
+        } else {
+            known.addrs = addrs.to_vec();
+            known.shrunk_at = None;
+            Verdict::Expire
+        }
+    }
+
+    fn addrs(&self, name: &str) -> Vec<SocketAddr> {
+        self.services.get(name).map(|known| known.addrs.clone()).unwrap_or_default()
+    }
+}
+
+/// Resolver, which keeps previous endpoints when the resolved set shrinks drastically.
+///
+/// All clones share remembered endpoints, so a single guard serves the whole pool. Without the
+/// config it resolves as is.
+#[derive(Clone)]
+pub struct ResolveGuard {
+    resolver: ResolveCache,
+    cfg: Option<ResolveGuardConfig>,
+    endpoints: Arc<Mutex<Endpoints>>,
+    resolved: Arc<Mutex<HashMap<String, Resolved>>>,
+    stats: Arc<PoolStats>,
+    log: Logger,
+    #[cfg(feature = "chaos")]
+    chaos: Option<(Arc<Chaos>, Handle)>,
+}
+
+impl ResolveGuard {
+    pub fn new(resolver: ResolveCache, cfg: Option<ResolveGuardConfig>, log: Logger) -> Self {
+        Self {
+            resolver: resolver,
+            cfg: cfg,
+            endpoints: Arc::new(Mutex::new(Endpoints::default())),
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(PoolStats::default()),
+            log: log,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    /// Sets per-service counters, where held back resolves are accounted.
+    pub fn with_stats(mut self, stats: Arc<PoolStats>) -> Self {
+        self.resolver = self.resolver.with_stats(stats.clone());
+        self.stats = stats;
+        self
+    }
+
+    /// Injects simulated faults into resolves, before they are checked by the guard.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>, handle: Handle) -> Self {
+        self.chaos = Some((chaos, handle));
+        self
+    }
+
+    /// Returns endpoints each service has been resolved into most recently.
+    pub fn resolved(&self) -> HashMap<String, Resolved> {
+        self.resolved.lock().unwrap().clone()
+    }
+
+    /// Forgets cached resolve results of the given service or of all services, returning the
+    /// number of them.
+    pub fn clear_cache(&self, name: Option<&str>) -> usize {
+        self.resolver.clear(name)
+    }
+}
+
+/// Remembers the endpoints the service has been resolved into.
+fn remember(resolved: &Mutex<HashMap<String, Resolved>>, name: &str, addrs: &[SocketAddr]) {
+    let entry = Resolved {
+        addrs: addrs.to_vec(),
+        at: SystemTime::now(),
+    };
+    resolved.lock().unwrap().insert(name.to_owned(), entry);
+}
+
+impl Resolve for ResolveGuard {
+    type Future = Box<dyn Future<Item = ResolveInfo<SocketAddr>, Error = Error>>;
+
+    fn resolve(&mut self, name: &str) -> Self::Future {
+        let future = self.resolver.resolve(name);
+
+        #[cfg(feature = "chaos")]
+        let future: Self::Future = match self.chaos {
+            Some((ref chaos, ref handle)) => chaos.inject(name, future, handle),
+            None => Box::new(future),
+        };
+
+        let name = name.to_owned();
+        let resolved = self.resolved.clone();
+
+        let cfg = match self.cfg {
+            Some(cfg) => cfg,
+            None => {
+                let future = future.map(move |info| {
+                    remember(&resolved, &name, &info.addrs);
+                    info
+                });
+                return Box::new(future);
+            }
+        };
+
+        let endpoints = self.endpoints.clone();
+        let stats = self.stats.clone();
+        let log = self.log.clone();
+
+        let future = future.map(move |mut info| {
+            let mut endpoints = endpoints.lock().unwrap();
+
+            match endpoints.check(&cfg, &name, &info.addrs, Instant::now()) {
+                Verdict::Accept => {}
+                Verdict::Hold => {
+                    let addrs = endpoints.addrs(&name);
+                    cocaine_log!(log, Severity::Warn, "locator has resolved `{}` into {} of {} known endpoints, keeping previous ones",
+                        name, info.addrs.len(), addrs.len());
+                    stats.service(&name).mark_held_resolve();
+                    info.addrs = addrs;
+                }
+                Verdict::Expire => {
+                    cocaine_log!(log, Severity::Error, "`{}` has been resolved into {} endpoints for the whole grace period, accepting them",
+                        name, info.addrs.len());
+                }
+            }
+
+            remember(&resolved, &name, &info.addrs);
+            info
+        });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use serde_yaml;
+
+    use super::{Endpoints, Verdict};
+
+    fn addrs(count: u16) -> Vec<SocketAddr> {
+        (0..count).map(|port| SocketAddr::from(([127, 0, 0, 1], 10000 + port))).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let cfg = serde_yaml::from_str("{min_ratio: 0.5, grace_period: 30}").unwrap();
+        let mut endpoints = Endpoints::default();
+        let now = Instant::now();
+
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(10), now), Verdict::Accept));
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(5), now), Verdict::Accept));
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(1), now), Verdict::Hold));
+        assert_eq!(addrs(5), endpoints.addrs("echo"));
+
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(1), now + Duration::from_secs(10)), Verdict::Hold));
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(1), now + Duration::from_secs(30)), Verdict::Expire));
+        assert_eq!(addrs(1), endpoints.addrs("echo"));
+    }
+
+    #[test]
+    fn test_check_recovered_set_resets_grace_period() {
+        let cfg = serde_yaml::from_str("{}").unwrap();
+        let mut endpoints = Endpoints::default();
+        let now = Instant::now();
+
+        endpoints.check(&cfg, "echo", &addrs(4), now);
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(0), now), Verdict::Hold));
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(4), now + Duration::from_secs(20)), Verdict::Accept));
+        assert!(matches!(endpoints.check(&cfg, "echo", &addrs(1), now + Duration::from_secs(40)), Verdict::Hold));
+    }
+}
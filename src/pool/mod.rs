@@ -1,15 +1,19 @@
 use std::cmp;
-use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::iter;
 use std::mem;
-use std::time::{Duration, SystemTime};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec::IntoIter;
 
-use futures::{Async, Future, Poll, Stream};
+use futures::{future, task, Async, Future, Poll, Stream};
 use futures::future::Loop;
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use rand;
-use tokio_core::reactor::Handle;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Timeout};
 use uuid::Uuid;
 
 use cocaine::{Error, Resolver, Service, ServiceBuilder};
@@ -21,12 +25,59 @@ use cocaine::service::tvm::{Grant, Tvm};
 use cocaine::service::unicorn::{Close, Unicorn, Version};
 
 use crate::config::{Config, PoolConfig, ServicePoolConfig};
+use crate::random;
 use crate::retry::Action;
 
-#[derive(Clone, Copy, Debug)]
+pub use self::breaker::{BreakerStats, CircuitBreakers};
+pub use self::cache::ResolveCache;
+#[cfg(feature = "chaos")]
+pub use self::chaos::Fault;
+pub use self::eyeballs::DualStackResolver;
+pub use self::guard::ResolveGuard;
+pub use self::protocol::Negotiator;
+pub use self::settings::{SettingsChange, SettingsRegistry};
+pub use self::stats::{PoolStats, ServiceStats};
+
+mod breaker;
+mod cache;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod eyeballs;
+mod guard;
+mod protocol;
+mod settings;
+mod stats;
+
+#[derive(Debug)]
 pub struct Settings {
     pub verbose: bool,
     pub timeout: Option<f64>,
+    /// The event is shed because of the pool backlog, so it must be answered immediately without
+    /// invoking the service.
+    pub shed: bool,
+    /// Accounts a channel opened over the selected connection while alive.
+    pub channel: Option<ChannelGuard>,
+    /// Asks the locator for protocol versions of services.
+    pub negotiator: Option<Negotiator>,
+}
+
+/// Accounts a channel multiplexed over a service connection until dropped.
+#[derive(Debug)]
+pub struct ChannelGuard {
+    channels: Arc<AtomicUsize>,
+}
+
+impl ChannelGuard {
+    fn new(channels: Arc<AtomicUsize>) -> Self {
+        channels.fetch_add(1, Ordering::Relaxed);
+        Self { channels: channels }
+    }
+}
+
+impl Drop for ChannelGuard {
+    fn drop(&mut self) {
+        self.channels.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub enum Event {
@@ -38,22 +89,172 @@ pub enum Event {
     },
     OnServiceConnect(Service),
     OnRoutingUpdates(HashMap<String, HashRing>),
-    OnTracingUpdates(HashMap<String, f64>),
-    OnTimeoutUpdates(HashMap<String, f64>),
+    /// The event is processed by the same pool after the given delay.
+    Delayed(Duration, Box<Event>),
+    /// The sender is completed after the given delay, allowing futures that have no access to the
+    /// reactor to wait for timeouts.
+    Timer(Duration, oneshot::Sender<()>),
+    /// The event is scheduled according to the given priority class, where zero is the highest.
+    Prioritized(usize, Box<Event>),
+    /// The sender is completed with the current state of the pool.
+    Inspect(oneshot::Sender<PoolSnapshot>),
+    /// Sets or clears simulated faults of the given service, `*` meaning all services.
+    #[cfg(feature = "chaos")]
+    Chaos(String, Option<Fault>),
+    /// Forgets cached resolve results of the given service or of all services, completing the
+    /// sender with the number of them.
+    FlushResolves(Option<String>, oneshot::Sender<usize>),
+}
+
+/// State of connections to a single service, summed over pools of all workers.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ServiceSnapshot {
+    /// Number of pools holding connections to the service.
+    pub workers: usize,
+    pub connections: usize,
+    pub connecting: usize,
+    /// Number of channels currently open over all connections.
+    pub channels: usize,
+    /// Pool settings of a single worker.
+    pub limit: usize,
+    pub max_overflow: usize,
+    pub max_channels: Option<usize>,
+    pub lifespan: u64,
+    /// Endpoints the service has been resolved into most recently by any worker.
+    pub endpoints: Vec<SocketAddr>,
+    /// When the service has been resolved most recently, in milliseconds since the Unix epoch.
+    pub resolved_at: Option<u64>,
+    /// Currently effective invocation settings.
+    pub timeout: Option<f64>,
+    pub tracing: f64,
+}
+
+/// State of services pools, summed over all workers.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PoolSnapshot {
+    /// Number of events waiting in priority queues.
+    pub queued: usize,
+    pub services: BTreeMap<String, ServiceSnapshot>,
+    /// Faults currently simulated by any worker.
+    #[cfg(feature = "chaos")]
+    pub chaos: BTreeMap<String, Fault>,
+}
+
+impl PoolSnapshot {
+    fn merge(mut self, other: PoolSnapshot) -> Self {
+        self.queued += other.queued;
+        #[cfg(feature = "chaos")]
+        self.chaos.extend(other.chaos);
+        for (name, service) in other.services {
+            let merged = self.services.entry(name).or_insert_with(|| ServiceSnapshot {
+                workers: 0,
+                ..service.clone()
+            });
+            merged.workers += service.workers;
+            merged.connections += service.connections;
+            merged.connecting += service.connecting;
+            merged.channels += service.channels;
+            if service.resolved_at > merged.resolved_at {
+                merged.resolved_at = service.resolved_at;
+                merged.endpoints = service.endpoints;
+            }
+        }
+        self
+    }
 }
 
 #[derive(Clone)]
 pub struct EventDispatch {
     senders: Vec<UnboundedSender<Event>>,
+    breakers: Option<Arc<CircuitBreakers>>,
+}
+
+impl Debug for EventDispatch {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("EventDispatch")
+            .field("senders", &self.senders.len())
+            .field("breakers", &self.breakers.is_some())
+            .finish()
+    }
 }
 
 impl EventDispatch {
     pub fn new(senders: Vec<UnboundedSender<Event>>) -> Self {
-        Self { senders }
+        Self {
+            senders: senders,
+            breakers: None,
+        }
+    }
+
+    /// Enables per-service circuit breakers for services invoked through this dispatcher.
+    pub fn with_breakers(mut self, breakers: Arc<CircuitBreakers>) -> Self {
+        self.breakers = Some(breakers);
+        self
+    }
+
+    /// Checks whether the circuit breaker of the given service lets a request through.
+    ///
+    /// Always succeeds if breakers are disabled.
+    pub fn admit(&self, service: &str) -> bool {
+        match self.breakers {
+            Some(ref breakers) => breakers.admit(service, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Reports the outcome of an admitted request into the circuit breaker of the given service.
+    pub fn report(&self, service: &str, success: bool) {
+        if let Some(ref breakers) = self.breakers {
+            breakers.report(service, success, Instant::now());
+        }
+    }
+
+    /// Returns circuit breakers of this dispatcher, if enabled.
+    pub fn breakers(&self) -> Option<&Arc<CircuitBreakers>> {
+        self.breakers.as_ref()
+    }
+
+    /// Collects the state of pools of all workers.
+    pub fn inspect(&self) -> Box<dyn Future<Item = PoolSnapshot, Error = ()> + Send> {
+        let snapshots = self.senders.iter()
+            .map(|sender| {
+                let (tx, rx) = oneshot::channel();
+                mem::drop(sender.unbounded_send(Event::Inspect(tx)));
+                rx
+            })
+            .collect::<Vec<_>>();
+
+        let future = future::join_all(snapshots)
+            .map(|snapshots| snapshots.into_iter().fold(PoolSnapshot::default(), PoolSnapshot::merge))
+            .map_err(drop);
+        Box::new(future)
+    }
+
+    /// Forgets cached resolve results of the given service or of all services in pools of all
+    /// workers, resolving with the number of them.
+    pub fn flush_resolves(&self, service: Option<&str>) -> Box<dyn Future<Item = usize, Error = ()> + Send> {
+        let counts = self.senders.iter()
+            .map(|sender| {
+                let (tx, rx) = oneshot::channel();
+                mem::drop(sender.unbounded_send(Event::FlushResolves(service.map(|v| v.to_owned()), tx)));
+                rx
+            })
+            .collect::<Vec<_>>();
+
+        let future = future::join_all(counts)
+            .map(|counts| counts.into_iter().sum())
+            .map_err(drop);
+        Box::new(future)
+    }
+
+    /// Sets or clears simulated faults of the given service in pools of all workers.
+    #[cfg(feature = "chaos")]
+    pub fn set_fault(&self, service: &str, fault: Option<Fault>) {
+        self.send_all(|| Event::Chaos(service.to_owned(), fault));
     }
 
     pub fn send(&self, event: Event) {
-        let rand = rand::random::<usize>();
+        let rand = random::gen::<usize>();
         let roll = rand % self.senders.len();
         mem::drop(self.senders[roll].unbounded_send(event));
     }
@@ -80,6 +281,8 @@ impl IntoIterator for EventDispatch {
 struct WatchedService {
     service: Service,
     created_at: SystemTime,
+    /// Number of channels currently multiplexed over the connection.
+    channels: Arc<AtomicUsize>,
 }
 
 impl WatchedService {
@@ -87,13 +290,18 @@ impl WatchedService {
         Self {
             service: service,
             created_at: created_at,
+            channels: Arc::new(AtomicUsize::new(0)),
         }
     }
+
+    fn channels(&self) -> usize {
+        self.channels.load(Ordering::Relaxed)
+    }
 }
 
 struct ServicePool {
     log: Logger,
-    resolver: Resolver,
+    resolver: DualStackResolver,
 
     /// Next service.
     counter: usize,
@@ -106,12 +314,19 @@ struct ServicePool {
 
     connecting: usize,
     connecting_limit: usize,
+    /// Maximum number of channels per connection, above which another one is selected or opened.
+    max_channels: Option<usize>,
+    /// Maximum number of connections above the limit.
+    max_overflow: usize,
     services: VecDeque<WatchedService>,
     tx: UnboundedSender<Event>,
+    stats: Arc<ServiceStats>,
 }
 
 impl ServicePool {
-    fn new(name: String, cfg: ServicePoolConfig, resolver: Resolver, handle: &Handle, tx: UnboundedSender<Event>, log: Logger) -> Self {
+    fn new(name: String, cfg: ServicePoolConfig, resolver: DualStackResolver, handle: &Handle, tx: UnboundedSender<Event>,
+        stats: Arc<ServiceStats>, log: Logger) -> Self
+    {
         let now = SystemTime::now();
 
         Self {
@@ -125,6 +340,8 @@ impl ServicePool {
             last_traverse: now,
             connecting: 0,
             connecting_limit: cmp::max(1, (cfg.limit() as f64 * cfg.reconnection_ratio()).ceil() as usize),
+            max_channels: cfg.max_channels(),
+            max_overflow: cfg.max_overflow(),
             services: iter::repeat(name)
                 .take(cfg.limit())
                 .map(|name| {
@@ -135,6 +352,7 @@ impl ServicePool {
                 })
                 .collect(),
             tx: tx,
+            stats: stats,
         }
     }
 
@@ -149,6 +367,7 @@ impl ServicePool {
     fn reconnect(&mut self) {
         cocaine_log!(self.log, Severity::Info, "reconnecting `{}` service", self.name);
         self.connecting += 1;
+        self.stats.mark_reconnect();
 
         let service = ServiceBuilder::new(self.name.clone())
             .resolver(self.resolver.clone())
@@ -178,7 +397,48 @@ impl ServicePool {
         }
     }
 
-    fn next(&mut self) -> &Service {
+    /// Selects the next connection with spare channels, opening an overflow one if all are
+    /// saturated, or the least loaded one if no more connections are allowed.
+    fn select_unsaturated(&mut self, max_channels: usize) {
+        let len = self.services.len();
+        let spare = (0..len)
+            .map(|offset| (self.counter + offset) % len)
+            .find(|&id| self.services[id].channels() < max_channels);
+
+        self.counter = match spare {
+            Some(id) => id,
+            None if len < self.limit + self.max_overflow => {
+                cocaine_log!(self.log, Severity::Debug, "opening overflow connection to `{}` service with {} connections saturated",
+                    self.name, len);
+                self.stats.mark_overflow();
+
+                let service = ServiceBuilder::new(self.name.clone())
+                    .resolver(self.resolver.clone())
+                    .build(&self.handle);
+                self.services.push_back(WatchedService::new(service, SystemTime::now()));
+                len
+            }
+            None => {
+                (0..len).min_by_key(|&id| self.services[id].channels()).unwrap_or(0)
+            }
+        };
+    }
+
+    fn snapshot(&self) -> ServiceSnapshot {
+        ServiceSnapshot {
+            workers: 1,
+            connections: self.services.len(),
+            connecting: self.connecting,
+            channels: self.services.iter().map(WatchedService::channels).sum(),
+            limit: self.limit,
+            max_overflow: self.max_overflow,
+            max_channels: self.max_channels,
+            lifespan: self.lifespan.as_secs(),
+            ..Default::default()
+        }
+    }
+
+    fn next(&mut self) -> (&Service, ChannelGuard) {
         let now = SystemTime::now();
 
         // No more than once every 5 seconds we're checking services for reconnection.
@@ -204,34 +464,12 @@ impl ServicePool {
         }
 
         self.counter = (self.counter + 1) % self.services.len();
-
-        &self.services[self.counter].service
-    }
-}
-
-struct Tracing {
-    default: f64,
-    precise: HashMap<String, f64>,
-}
-
-impl Tracing {
-    fn new(default: f64) -> Self {
-        Self {
-            default: default,
-            precise: HashMap::new(),
+        if let Some(max_channels) = self.max_channels {
+            self.select_unsaturated(max_channels);
         }
-    }
 
-    fn reset(&mut self, precise: HashMap<String, f64>) {
-        self.precise = precise;
-    }
-
-    fn probability_for(&self, name: &String) -> f64 {
-        *self.precise.get(name).unwrap_or(&self.default)
-    }
-
-    fn calculate_trace_bit(&mut self, name: &String) -> bool {
-        rand::random::<f64>() <= self.probability_for(name)
+        let service = &self.services[self.counter];
+        (&service.service, ChannelGuard::new(service.channels.clone()))
     }
 }
 
@@ -245,7 +483,7 @@ impl Tracing {
 /// - RG notifiers.
 pub struct PoolTask {
     handle: Handle,
-    resolver: Resolver,
+    resolver: DualStackResolver,
     log: Logger,
 
     tx: UnboundedSender<Event>,
@@ -254,12 +492,34 @@ pub struct PoolTask {
     cfg: PoolConfig,
     pool: HashMap<String, ServicePool>,
 
-    tracing: Tracing,
-    timeouts: HashMap<String, f64>,
+    /// Prioritized events waiting to be processed, a queue per class from the highest priority.
+    queues: Vec<VecDeque<Event>>,
+    /// Backlog sizes at which events of each class are shed.
+    thresholds: Vec<Option<usize>>,
+    /// Maximum number of queued events processed before yielding to the event loop.
+    batch: usize,
+
+    settings: Arc<SettingsRegistry>,
+    negotiator: Option<Negotiator>,
+    stats: Arc<PoolStats>,
+    #[cfg(feature = "chaos")]
+    chaos: Arc<chaos::Chaos>,
 }
 
 impl PoolTask {
-    pub fn new(handle: Handle, resolver: Resolver, log: Logger, tx: UnboundedSender<Event>, rx: UnboundedReceiver<Event>, cfg: Config) -> Self {
+    pub fn new(handle: Handle, resolver: Resolver, log: Logger, tx: UnboundedSender<Event>, rx: UnboundedReceiver<Event>, cfg: Config, settings: Arc<SettingsRegistry>) -> Self {
+        let resolver = ResolveCache::new(resolver, cfg.pool().resolve_cache());
+        let resolver = ResolveGuard::new(resolver, cfg.pool().resolve_guard(), log.clone());
+        #[cfg(feature = "chaos")]
+        let chaos = Arc::new(chaos::Chaos::default());
+        #[cfg(feature = "chaos")]
+        let resolver = resolver.with_chaos(chaos.clone(), handle.clone());
+        let resolver = DualStackResolver::new(resolver, cfg.pool().happy_eyeballs(), handle.clone(), log.clone());
+        let (thresholds, batch) = match cfg.pool().priorities() {
+            Some(priorities) => (priorities.thresholds(), priorities.batch()),
+            None => (Vec::new(), 0),
+        };
+
         Self {
             handle: handle,
             resolver: resolver,
@@ -268,23 +528,44 @@ impl PoolTask {
             rx: rx,
             cfg: cfg.pool().clone(),
             pool: HashMap::new(),
-            tracing: Tracing::new(cfg.tracing().probability()),
-            timeouts: HashMap::new(),
+            queues: thresholds.iter().map(|_| VecDeque::new()).collect(),
+            thresholds: thresholds,
+            batch: batch,
+            settings: settings,
+            negotiator: None,
+            stats: Arc::new(PoolStats::default()),
+            #[cfg(feature = "chaos")]
+            chaos: chaos,
         }
     }
 
-    fn select_service(&mut self, name: String, handle: &Handle) -> &Service {
+    /// Sets the negotiator of application protocol versions, which is passed to invocations.
+    pub fn with_negotiator(mut self, negotiator: Negotiator) -> Self {
+        self.negotiator = Some(negotiator);
+        self
+    }
+
+    /// Sets per-service counters, which are usually shared with other pools.
+    pub fn with_stats(mut self, stats: Arc<PoolStats>) -> Self {
+        self.resolver = self.resolver.with_stats(stats.clone());
+        self.stats = stats;
+        self
+    }
+
+    fn select_service(&mut self, name: String, handle: &Handle) -> (&Service, ChannelGuard) {
         // TODO: Do not clone if not needed.
         let tx = self.tx.clone();
         let log = self.log.clone();
         let cfg = self.cfg.config(&name);
         let resolver = self.resolver.clone();
+        let stats = &self.stats;
 
         let pool = {
             let name = name.clone();
             self.pool.entry(name.clone())
-                .or_insert_with(|| ServicePool::new(name, cfg, resolver, handle, tx, log))
+                .or_insert_with(|| ServicePool::new(name.clone(), cfg, resolver, handle, tx, stats.service(&name), log))
         };
+        pool.stats.mark_invocation();
 
         let now = SystemTime::now();
         while pool.services.len() + pool.connecting < 10 {
@@ -295,6 +576,137 @@ impl PoolTask {
     }
 }
 
+impl PoolTask {
+    fn process(&mut self, event: Event, shed: bool) {
+        match event {
+            Event::Service { name, func } => {
+                let mut settings = self.settings.settings(&name);
+                settings.shed = shed;
+
+                // Select the next service that is not reconnecting right now.
+                let handle = self.handle.clone();
+                let (service, channel) = self.select_service(name, &handle);
+                settings.channel = Some(channel);
+                settings.negotiator = self.negotiator.clone();
+
+                let future = func(service, settings);
+                handle.spawn(future);
+            }
+            Event::OnServiceConnect(service) => {
+                match self.pool.get_mut(service.name()) {
+                    Some(pool) => {
+                        pool.connecting -= 1;
+                        pool.push(service);
+                    }
+                    None => {
+                        println!("dropping service `{}` to unknown pool", service.name());
+                    }
+                }
+            }
+            Event::OnRoutingUpdates(groups) => {
+                for (group, ..) in groups {
+                    if let Some(pool) = self.pool.get_mut(&group) {
+                        cocaine_log!(self.log, Severity::Info, "updated `{}` pool", group);
+                        pool.reconnect_all();
+                    }
+                }
+            }
+            Event::Delayed(delay, event) => {
+                let tx = self.tx.clone();
+                match Timeout::new(delay, &self.handle) {
+                    Ok(timeout) => {
+                        self.handle.spawn(timeout.then(move |_| {
+                            drop(tx.unbounded_send(*event));
+                            Ok(())
+                        }));
+                    }
+                    Err(err) => {
+                        cocaine_log!(self.log, Severity::Warn, "failed to delay event, processing immediately: {}", err);
+                        drop(tx.unbounded_send(*event));
+                    }
+                }
+            }
+            Event::Prioritized(class, event) => {
+                self.enqueue(class, *event);
+            }
+            Event::Timer(delay, tx) => {
+                match Timeout::new(delay, &self.handle) {
+                    Ok(timeout) => {
+                        self.handle.spawn(timeout.then(move |_| {
+                            drop(tx.send(()));
+                            Ok(())
+                        }));
+                    }
+                    Err(err) => {
+                        // Dropping the sender cancels the timer, so waiters never fire.
+                        cocaine_log!(self.log, Severity::Warn, "failed to arm timer: {}", err);
+                    }
+                }
+            }
+            Event::Inspect(tx) => {
+                let mut resolved = self.resolver.resolved();
+                let services = self.pool.iter()
+                    .map(|(name, pool)| {
+                        let mut snapshot = pool.snapshot();
+                        if let Some(resolved) = resolved.remove(name) {
+                            snapshot.endpoints = resolved.addrs;
+                            snapshot.resolved_at = Some(random::epoch_millis(resolved.at));
+                        }
+                        snapshot.timeout = self.settings.timeout(name);
+                        snapshot.tracing = self.settings.tracing(name);
+                        (name.clone(), snapshot)
+                    })
+                    .collect();
+
+                let snapshot = PoolSnapshot {
+                    queued: self.queues.iter().map(VecDeque::len).sum(),
+                    services: services,
+                    #[cfg(feature = "chaos")]
+                    chaos: self.chaos.faults(),
+                };
+                drop(tx.send(snapshot));
+            }
+            #[cfg(feature = "chaos")]
+            Event::Chaos(service, fault) => {
+                match fault {
+                    Some(fault) => cocaine_log!(self.log, Severity::Warn, "simulating faults of `{}`: {}", service, fault),
+                    None => cocaine_log!(self.log, Severity::Info, "stopped simulating faults of `{}`", service),
+                }
+                self.chaos.set(service, fault);
+            }
+            Event::FlushResolves(service, tx) => {
+                let count = self.resolver.clear_cache(service.as_ref().map(|v| v.as_str()));
+                cocaine_log!(self.log, Severity::Debug, "flushed {} cached resolve results of {}", count,
+                    service.map(|v| format!("`{}`", v)).unwrap_or_else(|| "all services".into()));
+                drop(tx.send(count));
+            }
+        }
+    }
+
+    /// Queues the event according to its priority class, or sheds it if the backlog has reached
+    /// the threshold of the class.
+    fn enqueue(&mut self, class: usize, event: Event) {
+        if self.queues.is_empty() {
+            self.process(event, false);
+            return;
+        }
+
+        let class = cmp::min(class, self.queues.len() - 1);
+        let backlog: usize = self.queues.iter().map(VecDeque::len).sum();
+
+        if self.thresholds[class].map(|limit| backlog >= limit).unwrap_or(false) {
+            self.process(event, true);
+        } else {
+            self.queues[class].push_back(event);
+        }
+    }
+
+    /// Pops the next queued event of the highest priority.
+    fn dequeue(&mut self) -> Option<Event> {
+        self.queues.iter_mut().filter_map(VecDeque::pop_front).next()
+    }
+}
+
 impl Future for PoolTask {
     type Item = ();
     type Error = ();
@@ -303,56 +715,33 @@ impl Future for PoolTask {
         loop {
             match self.rx.poll() {
                 Ok(Async::Ready(Some(event))) => {
-                    match event {
-                        Event::Service { name, func } => {
-                            let settings = Settings {
-                                verbose: self.tracing.calculate_trace_bit(&name),
-                                timeout: self.timeouts.get(&name).map(|v| *v).clone()
-                            };
-
-                            // Select the next service that is not reconnecting right now.
-                            let handle = self.handle.clone();
-                            let ref service = self.select_service(name, &handle);
-
-                            let future = func(service, settings);
-                            handle.spawn(future);
-                        }
-                        Event::OnServiceConnect(service) => {
-                            match self.pool.get_mut(service.name()) {
-                                Some(pool) => {
-                                    pool.connecting -= 1;
-                                    pool.push(service);
-                                }
-                                None => {
-                                    println!("dropping service `{}` to unknown pool", service.name());
-                                }
-                            }
-                        }
-                        Event::OnRoutingUpdates(groups) => {
-                            for (group, ..) in groups {
-                                if let Some(pool) = self.pool.get_mut(&group) {
-                                    cocaine_log!(self.log, Severity::Info, "updated `{}` pool", group);
-                                    pool.reconnect_all();
-                                }
-                            }
-                        }
-                        Event::OnTracingUpdates(tracing) => {
-                            self.tracing.reset(tracing);
-                        }
-                        Event::OnTimeoutUpdates(timeouts) => {
-                            self.timeouts = timeouts;
-                        }
-                    }
+                    self.process(event, false);
                 }
                 Ok(Async::NotReady) => {
                     break;
                 }
                 Ok(Async::Ready(None)) | Err(..) => {
+                    while let Some(event) = self.dequeue() {
+                        self.process(event, false);
+                    }
                     return Ok(Async::Ready(()));
                 }
             }
         }
 
+        // Processing only a batch of queued events at once lets higher priority ones, which are
+        // received meanwhile, overtake the rest.
+        for _ in 0..self.batch {
+            match self.dequeue() {
+                Some(event) => self.process(event, false),
+                None => break,
+            }
+        }
+
+        if self.queues.iter().any(|queue| !queue.is_empty()) {
+            task::current().notify();
+        }
+
         Ok(Async::NotReady)
     }
 }
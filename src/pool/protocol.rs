@@ -0,0 +1,68 @@
+//! Negotiation of application protocol versions.
+//!
+//! The locator reports the protocol version along with endpoints of each service it resolves.
+//! Services configured to speak a newer HTTP protocol are checked against the reported version
+//! before their requests are framed, so that applications not supporting it yet keep receiving
+//! requests they understand.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+
+use cocaine::{Error, Service};
+use cocaine::service::Locator;
+
+/// How long reported versions are remembered, so that redeployed applications are noticed.
+const VERSION_TTL: Duration = Duration::from_secs(60);
+
+/// Asks the locator for protocol versions of services, remembering them for a while.
+///
+/// All clones share remembered versions, so a single negotiator serves the whole worker.
+#[derive(Clone)]
+pub struct Negotiator {
+    locator: Service,
+    versions: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+}
+
+impl Negotiator {
+    /// Constructs a negotiator asking the locator behind the given service.
+    pub fn new(locator: Service) -> Self {
+        Self {
+            locator: locator,
+            versions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the protocol version the locator reports for the given service.
+    ///
+    /// Failures are not remembered, so the next request asks again.
+    pub fn version(&self, name: &str) -> Box<dyn Future<Item = u64, Error = Error> + Send> {
+        let now = Instant::now();
+        if let Some(&(version, at)) = self.versions.lock().unwrap().get(name) {
+            if now.duration_since(at) < VERSION_TTL {
+                return Box::new(future::ok(version));
+            }
+        }
+
+        let name = name.to_owned();
+        let versions = self.versions.clone();
+        let future = Locator::new(self.locator.clone()).resolve(&name).map(move |info| {
+            let version = info.version();
+            versions.lock().unwrap().insert(name, (version, Instant::now()));
+            version
+        });
+
+        Box::new(future)
+    }
+}
+
+impl Debug for Negotiator {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("Negotiator")
+            .field("versions", &self.versions.lock().unwrap().len())
+            .finish()
+    }
+}
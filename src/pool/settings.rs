@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, RwLock};
+
+use crate::random;
+
+use super::Settings;
+
+/// A single per-service setting modification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SettingsChange {
+    pub service: String,
+    pub name: &'static str,
+    pub old: Option<f64>,
+    pub new: Option<f64>,
+    /// Version of the settings snapshot this change belongs to.
+    pub version: u64,
+}
+
+impl Display for SettingsChange {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        let show = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_else(|| "none".into());
+        write!(fmt, "`{}` {} changed from {} to {}", self.service, self.name, show(self.old), show(self.new))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    version: u64,
+    tracing: HashMap<String, f64>,
+    timeouts: HashMap<String, f64>,
+}
+
+/// Per-service settings overrides shared between all workers.
+///
+/// Updates replace the whole snapshot at once, so every worker observes new values starting from
+/// the same moment, instead of receiving them through its own event channel at its own pace.
+#[derive(Debug)]
+pub struct SettingsRegistry {
+    /// Default tracing probability for services without precise one.
+    probability: f64,
+    current: RwLock<Arc<Snapshot>>,
+}
+
+impl SettingsRegistry {
+    pub fn new(probability: f64) -> Self {
+        Self {
+            probability: probability,
+            current: RwLock::new(Arc::new(Snapshot::default())),
+        }
+    }
+
+    /// Returns settings for the next invocation of the given service.
+    pub fn settings(&self, name: &str) -> Settings {
+        let snapshot = self.current.read().unwrap().clone();
+        let probability = snapshot.tracing.get(name).cloned().unwrap_or(self.probability);
+
+        Settings {
+            verbose: random::gen::<f64>() <= probability,
+            timeout: snapshot.timeouts.get(name).cloned(),
+            shed: false,
+            channel: None,
+            negotiator: None,
+        }
+    }
+
+    /// Returns the tracing probability currently effective for the given service.
+    pub fn tracing(&self, name: &str) -> f64 {
+        self.current.read().unwrap().tracing.get(name).cloned().unwrap_or(self.probability)
+    }
+
+    /// Returns the timeout override currently effective for the given service, if any.
+    pub fn timeout(&self, name: &str) -> Option<f64> {
+        self.current.read().unwrap().timeouts.get(name).cloned()
+    }
+
+    /// Replaces per-service tracing probabilities, returning the list of changes.
+    pub fn update_tracing(&self, tracing: HashMap<String, f64>) -> Vec<SettingsChange> {
+        self.update("tracing", |snapshot| &mut snapshot.tracing, tracing)
+    }
+
+    /// Replaces per-service timeouts, returning the list of changes.
+    pub fn update_timeouts(&self, timeouts: HashMap<String, f64>) -> Vec<SettingsChange> {
+        self.update("timeout", |snapshot| &mut snapshot.timeouts, timeouts)
+    }
+
+    fn update<F>(&self, name: &'static str, f: F, values: HashMap<String, f64>) -> Vec<SettingsChange>
+        where F: Fn(&mut Snapshot) -> &mut HashMap<String, f64>
+    {
+        let mut current = self.current.write().unwrap();
+
+        let mut snapshot = Snapshot {
+            version: current.version + 1,
+            tracing: current.tracing.clone(),
+            timeouts: current.timeouts.clone(),
+        };
+
+        let changes = diff(name, snapshot.version, f(&mut snapshot), &values);
+        *f(&mut snapshot) = values;
+
+        if !changes.is_empty() {
+            *current = Arc::new(snapshot);
+        }
+
+        changes
+    }
+}
+
+fn diff(name: &'static str, version: u64, old: &HashMap<String, f64>, new: &HashMap<String, f64>) -> Vec<SettingsChange> {
+    let mut services = old.keys().chain(new.keys()).collect::<Vec<_>>();
+    services.sort();
+    services.dedup();
+
+    services.into_iter()
+        .map(|service| (service, old.get(service).cloned(), new.get(service).cloned()))
+        .filter(|&(_, old, new)| old != new)
+        .map(|(service, old, new)| {
+            SettingsChange {
+                service: service.clone(),
+                name: name,
+                old: old,
+                new: new,
+                version: version,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::SettingsRegistry;
+
+    #[test]
+    fn test_update_timeouts() {
+        let registry = SettingsRegistry::new(0.0);
+        assert_eq!(None, registry.settings("echo").timeout);
+
+        let mut timeouts = HashMap::new();
+        timeouts.insert("echo".to_owned(), 1.5);
+        timeouts.insert("storage".to_owned(), 30.0);
+
+        let changes = registry.update_timeouts(timeouts.clone());
+        assert_eq!(2, changes.len());
+        assert_eq!("echo", changes[0].service);
+        assert_eq!((None, Some(1.5), 1), (changes[0].old, changes[0].new, changes[0].version));
+        assert_eq!(Some(1.5), registry.settings("echo").timeout);
+        assert_eq!(Some(1.5), registry.timeout("echo"));
+
+        // Nothing changes, nothing to report.
+        assert!(registry.update_timeouts(timeouts).is_empty());
+
+        let mut timeouts = HashMap::new();
+        timeouts.insert("echo".to_owned(), 2.0);
+
+        let changes = registry.update_timeouts(timeouts);
+        assert_eq!(2, changes.len());
+        assert_eq!((Some(1.5), Some(2.0), 2), (changes[0].old, changes[0].new, changes[0].version));
+        assert_eq!((Some(30.0), None), (changes[1].old, changes[1].new));
+        assert_eq!(None, registry.settings("storage").timeout);
+    }
+
+    #[test]
+    fn test_update_tracing() {
+        let registry = SettingsRegistry::new(0.0);
+        assert!(!registry.settings("echo").verbose);
+
+        let mut tracing = HashMap::new();
+        tracing.insert("echo".to_owned(), 1.0);
+        registry.update_tracing(tracing);
+
+        assert!(registry.settings("echo").verbose);
+        assert!(!registry.settings("storage").verbose);
+    }
+}
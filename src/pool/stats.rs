@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::eyeballs::Family;
+
+/// Counters of a single service, summed over pools of all workers.
+#[derive(Debug, Default)]
+pub struct ServiceStats {
+    invocations: AtomicUsize,
+    reconnects: AtomicUsize,
+    held_resolves: AtomicUsize,
+    cached_resolves: AtomicUsize,
+    overflows: AtomicUsize,
+    dual_stack_v4: AtomicUsize,
+    dual_stack_v6: AtomicUsize,
+}
+
+impl ServiceStats {
+    /// Returns the number of invocations dispatched into the service.
+    pub fn invocations(&self) -> usize {
+        self.invocations.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of service connections re-established.
+    pub fn reconnects(&self) -> usize {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of resolves, whose drastically shrunk endpoint set has been replaced
+    /// with the previous one.
+    pub fn held_resolves(&self) -> usize {
+        self.held_resolves.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of resolves answered from the cache without asking the locator.
+    pub fn cached_resolves(&self) -> usize {
+        self.cached_resolves.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections opened above the pool limit because all others were
+    /// saturated with channels.
+    pub fn overflows(&self) -> usize {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dual-stack resolves, where an IPv4 endpoint has connected first.
+    pub fn dual_stack_v4(&self) -> usize {
+        self.dual_stack_v4.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of dual-stack resolves, where an IPv6 endpoint has connected first.
+    pub fn dual_stack_v6(&self) -> usize {
+        self.dual_stack_v6.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_invocation(&self) {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_held_resolve(&self) {
+        self.held_resolves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_cached_resolve(&self) {
+        self.cached_resolves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_overflow(&self) {
+        self.overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_dual_stack(&self, family: Family) {
+        match family {
+            Family::V4 => self.dual_stack_v4.fetch_add(1, Ordering::Relaxed),
+            Family::V6 => self.dual_stack_v6.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Per-service counters of services pools, shared between all workers.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    services: RwLock<HashMap<String, Arc<ServiceStats>>>,
+}
+
+impl PoolStats {
+    /// Returns counters of the given service, registering it on the first call.
+    pub fn service(&self, name: &str) -> Arc<ServiceStats> {
+        if let Some(stats) = self.services.read().unwrap().get(name) {
+            return stats.clone();
+        }
+
+        self.services.write().unwrap()
+            .entry(name.to_owned())
+            .or_insert_with(Default::default)
+            .clone()
+    }
+
+    /// Returns counters of all services seen so far, ordered by service name.
+    pub fn services(&self) -> Vec<(String, Arc<ServiceStats>)> {
+        let mut services = self.services.read().unwrap()
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.clone()))
+            .collect::<Vec<_>>();
+        services.sort_by(|a, b| a.0.cmp(&b.0));
+        services
+    }
+}
@@ -0,0 +1,111 @@
+//! Sources of nondeterminism: random numbers and the wall clock.
+//!
+//! Trace and span ids, sampling decisions and jitter are all taken from a per-thread source,
+//! which means per worker, since each worker runs its own event loop on a dedicated thread. By
+//! default the source is backed by the OS entropy and the system clock, but a thread can be
+//! switched into deterministic mode, where numbers come from a seeded generator and the clock is
+//! frozen, making request processing reproducible in tests.
+
+use std::cell::RefCell;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::{self, Rand, Rng, SeedableRng, XorShiftRng};
+
+enum Source {
+    Entropy,
+    Deterministic {
+        rng: XorShiftRng,
+        now: SystemTime,
+    },
+}
+
+thread_local! {
+    static SOURCE: RefCell<Source> = RefCell::new(Source::Entropy);
+}
+
+/// Switches the current thread into deterministic mode with the given seed and frozen time.
+pub fn deterministic(seed: u64, now: SystemTime) {
+    // The generator must not be seeded with all zeros, hence the constant half.
+    let seed = [seed as u32, (seed >> 32) as u32, 0x9e3779b9, 0x7f4a7c15];
+
+    SOURCE.with(|source| {
+        *source.borrow_mut() = Source::Deterministic {
+            rng: XorShiftRng::from_seed(seed),
+            now: now,
+        };
+    });
+}
+
+/// Switches the current thread back to the OS entropy and the system clock.
+pub fn reset() {
+    SOURCE.with(|source| *source.borrow_mut() = Source::Entropy);
+}
+
+/// Generates a random value from the current thread source.
+pub fn gen<T: Rand>() -> T {
+    SOURCE.with(|source| {
+        match *source.borrow_mut() {
+            Source::Entropy => rand::random(),
+            Source::Deterministic { ref mut rng, .. } => rng.gen(),
+        }
+    })
+}
+
+/// Returns the current time from the current thread source.
+pub fn now() -> SystemTime {
+    SOURCE.with(|source| {
+        match *source.borrow() {
+            Source::Entropy => SystemTime::now(),
+            Source::Deterministic { now, .. } => now,
+        }
+    })
+}
+
+/// Converts the given point in time into milliseconds since UNIX epoch.
+pub fn epoch_millis(time: SystemTime) -> u64 {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{deterministic, epoch_millis, gen, now, reset};
+
+    #[test]
+    fn test_deterministic() {
+        let time = UNIX_EPOCH + Duration::from_secs(1500000000);
+
+        deterministic(42, time);
+        let first = (gen::<u64>(), gen::<f64>());
+        assert_eq!(time, now());
+
+        deterministic(42, time);
+        assert_eq!(first, (gen::<u64>(), gen::<f64>()));
+
+        reset();
+        assert_ne!(time, now());
+    }
+
+    #[test]
+    fn test_epoch_millis() {
+        assert_eq!(0, epoch_millis(UNIX_EPOCH));
+        assert_eq!(1500000000123, epoch_millis(UNIX_EPOCH + Duration::new(1500000000, 123456789)));
+    }
+
+    #[test]
+    fn test_per_thread() {
+        deterministic(42, UNIX_EPOCH);
+        let expected = gen::<u64>();
+
+        let other = thread::spawn(|| {
+            deterministic(42, UNIX_EPOCH);
+            gen::<u64>()
+        }).join().unwrap();
+
+        assert_eq!(expected, other);
+        reset();
+    }
+}
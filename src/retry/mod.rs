@@ -4,6 +4,7 @@
 use std::error;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::io;
+use std::cmp;
 use std::iter::IntoIterator;
 use std::time::Duration;
 
@@ -12,6 +13,8 @@ use futures::future::Loop;
 
 use tokio_core::reactor::{Handle, Timeout};
 
+use crate::random;
+
 /// Represents the errors possible during the execution of the `Retry`.
 #[derive(Debug)]
 pub enum Error<T, E> {
@@ -51,6 +54,38 @@ impl<T: Debug, E: Debug> error::Error for Error<T, E> {
     }
 }
 
+/// An infinite retry policy with exponentially growing delays, capped by the maximum one.
+///
+/// Each delay is randomly shortened by up to the `jitter` fraction of it, which spreads retries
+/// of simultaneously failed requests over time instead of making them hit the target at once.
+#[derive(Clone, Debug)]
+pub struct ExponentialBackoff {
+    current: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl ExponentialBackoff {
+    pub fn new(base: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            current: cmp::min(base, max),
+            max: max,
+            jitter: jitter,
+        }
+    }
+}
+
+impl Iterator for ExponentialBackoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let delay = self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+
+        Some(delay.mul_f64(1.0 - self.jitter * random::gen::<f64>()))
+    }
+}
+
 enum State<F> {
     Run(F),
     Sleep(Timeout),
@@ -161,3 +196,29 @@ impl<T, F, R, U, P> Future for Retry<T, F, U, P>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::ExponentialBackoff;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let delays = ExponentialBackoff::new(Duration::from_millis(10), Duration::from_millis(50), 0.0)
+            .take(5)
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![10, 20, 40, 50, 50], delays.iter().map(|d| d.as_millis()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_exponential_backoff_jitter() {
+        for delay in ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(1), 0.5).take(100) {
+            assert!(delay <= Duration::from_secs(1));
+        }
+
+        let delay = ExponentialBackoff::new(Duration::from_millis(100), Duration::from_secs(1), 0.5).next().unwrap();
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+    }
+}
@@ -1,20 +1,23 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::error;
 use std::fmt::{self, Display, Formatter};
+use std::mem;
 use std::str;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use byteorder::{ByteOrder, LittleEndian};
 
-use rand;
+use futures::{self, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream, future, stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
 
-use futures::{self, Async, Future, Poll, Stream, future};
-use futures::sync::oneshot;
-
-use hyper::{self, HttpVersion, Method, StatusCode};
-use hyper::header::{Headers, Header};
+use hyper::{self, Body, Chunk, HttpVersion, Method, StatusCode};
+use hyper::header::{ContentLength, Headers, Header, Host, Location};
 use hyper::server::{Request, Response};
 
 use regex::Regex;
@@ -25,14 +28,65 @@ use serde::Serializer;
 
 use cocaine::{self, Dispatch, Service};
 use cocaine::hpack::{self, Header as CocaineHeader};
-use cocaine::logging::Log;
+use cocaine::logging::{Log, Severity};
 use cocaine::protocol::{self, Flatten};
 
 use crate::common::{TracingPolicy, XCocaineEvent, XCocaineService, XPoweredBy, XRequestId, XTracingPolicy,
     XCocaineApp, XErrorGeneratedBy};
-use crate::logging::AccessLogger;
-use crate::pool::{Event, EventDispatch, Settings};
-use crate::route::{Match, Route, serialize};
+use crate::config::{AppProtocol, BackoffConfig, BodyCodec, DigestAlgorithm, DigestConfig, NormalizationConfig, NormalizationPolicy, RequestDeadlineConfig, RequestHeadersConfig, ResponseHeadersConfig, RetriableError, RetrySafety,
+                    StatusRewrite, StreamingConfig, TlsAttribute};
+use crate::{Metrics, StallMetrics};
+use crate::memory::{MemoryBudget, MemoryReservation};
+use crate::logging::{AccessFormat, AccessLogger, AccessQueue, AccessSampler, AccessSink, ErrorRecord, RequestMirror, Timings};
+use crate::pool::{ChannelGuard, Event, EventDispatch, Negotiator, Settings};
+use crate::random;
+use crate::retry::ExponentialBackoff;
+use crate::route::{Canaries, ErrorStatuses, Failure, HeaderSigner, Match, Quota, RetryBudget, Route, RoutingTable, Rules, VirtualHosts, serialize};
+use crate::route::canary::{Change, Sample};
+use crate::route::codec::{self, Encoded};
+use crate::route::digest;
+use crate::route::filter::{self, BodyFilter};
+use crate::route::local::LocalUpstream;
+use crate::route::peer::Peers;
+use crate::route::priority::Priorities;
+use crate::route::signing::{self, REAL_IP_HEADER, TENANT_HEADER};
+use crate::route::tenant::{Tenant, Tenants};
+use crate::route::timeout;
+use crate::route::via::{self, Via, VIA_HEADER};
+use crate::server::tls_header_name;
+
+/// Non-standard status code used to account requests whose clients went away before the response
+/// was ready.
+pub const CLIENT_CLOSED_REQUEST: StatusCode = StatusCode::Unregistered(499);
+
+/// Number of request body chunks buffered between the client and the application in streaming
+/// mode.
+const BODY_STREAM_BUFFER: usize = 4;
+
+/// Error category and code sent to the application when the client goes away in the middle of
+/// streaming the request body, i.e. `ECONNABORTED` from the system category.
+const BODY_ABORTED: (u64, u64) = (1, 103);
+
+/// Error category and code sent to the application when the proxy abandons the response, for
+/// example because it exceeds the size limit, i.e. `ECANCELED` from the system category.
+const RESPONSE_ABORTED: (u64, u64) = (1, 125);
+
+/// Cocaine header with the attempt number and the attempts limit, sent with each invocation.
+const ATTEMPT_HEADER: &[u8] = b"X-Cocaine-Attempt";
+
+/// Header of informational frames with status 100, in which applications taking part in flow
+/// control acknowledge the total number of request body bytes consumed so far.
+const ACK_HEADER: &str = "X-Cocaine-Ack";
+
+/// Streamed response chunks or an error aborting the response.
+type ChunkSender = mpsc::UnboundedSender<Result<Chunk, hyper::Error>>;
+
+/// Request body chunks, where `None` marks the end of the body, along with their reservations in
+/// the memory budget, if accounted.
+///
+/// An explicit marker allows to distinguish the complete body from the one, whose client has
+/// gone away, because in both cases the channel is just closed.
+pub(crate) type BodyReceiver = Box<dyn Stream<Item = (Option<Vec<u8>>, Option<MemoryReservation>), Error = ()> + Send>;
 
 fn pack_u64(v: u64) -> Vec<u8> {
     let mut buf = vec![0; 8];
@@ -40,6 +94,140 @@ fn pack_u64(v: u64) -> Vec<u8> {
     buf
 }
 
+/// Strips the prefix from the given path, returning the rest, which is either empty or starts with
+/// either a slash or a query.
+///
+/// Returns `None` if the path doesn't start with the prefix.
+fn strip_prefix<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    if !path.starts_with(prefix) {
+        return None;
+    }
+
+    let rest = &path[prefix.len()..];
+    if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Result of matching the request path against the configured normalization policy.
+#[derive(Debug, PartialEq)]
+enum PathMatch {
+    /// Service, event and the rest of the URI.
+    Route(String, String, String),
+    /// The path must be requested again using the given one.
+    Redirect(String),
+    Reject(&'static str),
+    /// The path is not routable by this route.
+    None,
+}
+
+/// Collapses repeated slashes in the path.
+fn collapse_slashes(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    for ch in path.chars() {
+        if ch != '/' || !result.ends_with('/') {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+/// Splits the path into service, event and the rest, where the event is `None` when there is no
+/// slash after the service name at all.
+fn split_path(path: &str) -> Option<(&str, Option<&str>, &str)> {
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let path = &path[1..];
+    let (service, path) = match path.find('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => return Some((path, None, "")),
+    };
+
+    let (event, rest) = match path.find('/') {
+        Some(pos) => (&path[..pos], &path[pos..]),
+        None => (path, ""),
+    };
+
+    Some((service, Some(event), rest))
+}
+
+/// Extracts the service name and the query from paths consisting of a single segment, like
+/// `/service` or `/service/`.
+fn single_segment(path: &str) -> Option<(&str, &str)> {
+    let (path, query) = match path.find('?') {
+        Some(pos) => (&path[..pos], &path[pos..]),
+        None => (path, ""),
+    };
+
+    match split_path(path) {
+        Some((service, None, ..)) | Some((service, Some(""), "")) if !service.is_empty() => Some((service, query)),
+        Some(..) | None => None,
+    }
+}
+
+/// Extracts service, event and the rest of URI from the path with the query, applying the given
+/// normalization policy to paths with an empty or missing event.
+fn normalize_path(path: &str, cfg: &NormalizationConfig) -> PathMatch {
+    let (path, query) = match path.find('?') {
+        Some(pos) => (&path[..pos], &path[pos..]),
+        None => (path, ""),
+    };
+
+    let (service, event, rest) = match split_path(path) {
+        Some((service, event, rest)) => (service, event, rest),
+        None => return PathMatch::None,
+    };
+
+    if service.is_empty() {
+        if path == "/" {
+            return PathMatch::None;
+        }
+        return PathMatch::Reject("empty service name");
+    }
+
+    let uri = |rest: &str| {
+        if rest.is_empty() {
+            format!("/{}", query)
+        } else {
+            format!("{}{}", rest, query)
+        }
+    };
+
+    match event {
+        Some(event) if !event.is_empty() => {
+            return PathMatch::Route(service.into(), event.into(), uri(rest));
+        }
+        Some(..) | None => {}
+    }
+
+    match (cfg.policy(), cfg.event()) {
+        (NormalizationPolicy::Reject, ..) => PathMatch::Reject("empty event name"),
+        (NormalizationPolicy::Default, Some(default)) => {
+            PathMatch::Route(service.into(), default.into(), uri(rest))
+        }
+        (NormalizationPolicy::Default, None) => PathMatch::Reject("empty event name"),
+        (NormalizationPolicy::Redirect, default) => {
+            let collapsed = collapse_slashes(path);
+            match split_path(&collapsed) {
+                Some((.., Some(event), _)) if !event.is_empty() => {
+                    PathMatch::Redirect(format!("{}{}", collapsed, query))
+                }
+                Some(..) | None => match default {
+                    Some(default) => {
+                        let rest = collapsed.trim_end_matches('/');
+                        PathMatch::Redirect(format!("{}/{}{}", rest, default, query))
+                    }
+                    None => PathMatch::Reject("empty event name"),
+                },
+            }
+        }
+    }
+}
+
 trait Call {
     type Call: Fn(&Service, Settings) -> Box<dyn Future<Item = (), Error = ()> + Send> + Send;
     type Future: Future<Item = Response, Error = Error>;
@@ -49,21 +237,123 @@ trait Call {
     fn call_service(&self, name: String, callback: Self::Call) -> Self::Future;
 }
 
+/// Where the request is routed to.
+enum Target {
+    /// Service, event, URI and whether the configured prefix was stripped from the path.
+    Invoke(String, String, String, bool),
+    /// The client must request the given location instead.
+    Redirect(String),
+}
+
+/// Settings of a single service gathered from per-service config sections, which are looked up
+/// once per request and shared by all its attempts.
+#[derive(Clone, Default)]
+struct ServiceSettings {
+    /// Response status rewrite rules.
+    rewrites: Vec<StatusRewrite>,
+    /// Buffered response body filters.
+    filters: Vec<BodyFilter>,
+    protocol: AppProtocol,
+    codec: BodyCodec,
+    streaming: StreamingConfig,
+    /// Maximum response body size in bytes.
+    response_limit: Option<usize>,
+    /// Size of slices the buffered response body is written to the client in.
+    response_slice: Option<usize>,
+    /// Time to wait for the first response frame of each attempt.
+    response_timeout: Option<Duration>,
+    /// Configured `Link` header values.
+    hints: Vec<String>,
+    /// Delay after which requests safe to repeat are hedged.
+    hedge_delay: Option<Duration>,
+    /// Retry safety overrides keyed by event names.
+    retry_overrides: HashMap<String, RetrySafety>,
+    /// Event invoked when the request specifies only the service.
+    default_event: Option<String>,
+    /// Local HTTP upstream serving the service instead of the Cocaine.
+    local: Option<LocalUpstream>,
+}
+
 pub struct AppRoute<L> {
     dispatcher: EventDispatch,
+    tenants: Arc<Tenants>,
+    metrics: Arc<Metrics>,
     headers: HashMap<String, String>,
     tracing_header: Cow<'static, str>,
+    prefix: Option<String>,
+    normalization: Option<NormalizationConfig>,
+    routing: RoutingTable,
+    vhosts: VirtualHosts,
+    timeout: Option<Duration>,
+    queue_budget: Option<Duration>,
+    max_body_size: Option<usize>,
+    mirror: Option<Arc<RequestMirror>>,
+    access_format: Arc<AccessFormat>,
+    services: HashMap<String, Arc<ServiceSettings>>,
+    /// Settings of services not listed in any per-service section.
+    defaults: Arc<ServiceSettings>,
+    request_headers_limit: Option<RequestHeadersConfig>,
+    request_deadline: Option<RequestDeadlineConfig>,
+    headers_limit: ResponseHeadersConfig,
+    retriable_errors: Arc<Vec<RetriableError>>,
+    error_statuses: Arc<ErrorStatuses>,
+    retry_backoff: BackoffConfig,
+    retry_budget: Option<Arc<RetryBudget>>,
+    via: Option<Arc<Via>>,
+    peers: Option<Arc<Peers>>,
+    priorities: Option<Priorities>,
+    digest: Option<DigestConfig>,
+    error_origins: Arc<HashMap<u64, String>>,
+    signer: Option<HeaderSigner>,
+    tls_forward: Vec<TlsAttribute>,
+    rules: Rules,
+    canaries: Canaries,
+    access_sink: Option<Arc<dyn AccessSink>>,
+    access_queue: Option<Arc<AccessQueue>>,
+    access_sampler: Option<Arc<AccessSampler>>,
     regex: Regex,
     log: L,
 }
 
 impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
-    pub fn new(dispatcher: EventDispatch, log: L) -> Self {
+    pub fn new(dispatcher: EventDispatch, metrics: Arc<Metrics>, log: L) -> Self {
         let header = XRequestId::header_name();
         Self {
             dispatcher: dispatcher,
+            tenants: Arc::new(Tenants::new()),
+            metrics: metrics,
             headers: HashMap::new(),
             tracing_header: header.into(),
+            prefix: None,
+            normalization: None,
+            routing: RoutingTable::default(),
+            vhosts: VirtualHosts::default(),
+            timeout: None,
+            queue_budget: None,
+            max_body_size: None,
+            mirror: None,
+            access_format: Arc::new(AccessFormat::default()),
+            services: HashMap::new(),
+            defaults: Arc::new(ServiceSettings::default()),
+            request_headers_limit: None,
+            request_deadline: None,
+            headers_limit: ResponseHeadersConfig::default(),
+            retriable_errors: Arc::new(vec![RetriableError::queue_full()]),
+            error_statuses: Arc::new(ErrorStatuses::default()),
+            retry_backoff: BackoffConfig::default(),
+            retry_budget: None,
+            via: None,
+            peers: None,
+            priorities: None,
+            digest: None,
+            error_origins: Arc::new(HashMap::new()),
+            signer: None,
+            tls_forward: Vec::new(),
+            rules: Rules::default(),
+            canaries: Canaries::default(),
+            access_sink: None,
+            access_queue: None,
+            access_sampler: None,
             regex: Regex::new("/([^/]*)/([^/?]*)(.*)").expect("invalid URI regex in app route"),
             log: log,
         }
@@ -81,18 +371,376 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
         self
     }
 
+    /// Sets the URL prefix, which is stripped before extracting service and event names from the
+    /// path.
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Sets the policy of treating paths with an empty or missing event name.
+    ///
+    /// Without the policy such paths are matched as is, which results in an empty event name.
+    pub fn with_normalization(mut self, normalization: Option<NormalizationConfig>) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Sets the routing table, which maps request paths into services and events before the
+    /// default `/service/event` layout is tried.
+    pub fn with_routing_table(mut self, routing: RoutingTable) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Sets virtual hosts, which map requests into services by their `Host` header, taking
+    /// precedence over the routing table.
+    pub fn with_virtual_hosts(mut self, vhosts: VirtualHosts) -> Self {
+        self.vhosts = vhosts;
+        self
+    }
+
+    /// Sets the client-facing timeout, which is used to calculate an absolute deadline passed to
+    /// workers.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time a request may wait in a pool queue, after which it's answered with
+    /// 503 without calling the application.
+    pub fn with_queue_budget(mut self, budget: Option<Duration>) -> Self {
+        self.queue_budget = budget;
+        self
+    }
+
+    /// Sets the maximum request body size in bytes, larger requests are rejected with 413.
+    pub fn with_max_body_size(mut self, size: Option<usize>) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// Sets per-service rules that rewrite upstream response statuses.
+    pub fn with_status_rewrites(mut self, rewrites: HashMap<String, Vec<StatusRewrite>>) -> Self {
+        for (service, rules) in rewrites {
+            self.service_mut(service).rewrites = rules;
+        }
+        self
+    }
+
+    /// Sets per-service filters applied to buffered response bodies.
+    pub fn with_body_filters(mut self, filters: HashMap<String, Vec<BodyFilter>>) -> Self {
+        for (service, filters) in filters {
+            self.service_mut(service).filters = filters;
+        }
+        self
+    }
+
+    /// Sets per-service application protocol versions, which are spoken once negotiated with the
+    /// locator.
+    pub fn with_protocols(mut self, protocols: HashMap<String, AppProtocol>) -> Self {
+        for (service, protocol) in protocols {
+            self.service_mut(service).protocol = protocol;
+        }
+        self
+    }
+
+    /// Sets per-service streaming settings.
+    pub fn with_streaming(mut self, streaming: HashMap<String, StreamingConfig>) -> Self {
+        for (service, streaming) in streaming {
+            self.service_mut(service).streaming = streaming;
+        }
+        self
+    }
+
+    /// Sets per-service codecs of request and response bodies.
+    pub fn with_codecs(mut self, codecs: HashMap<String, BodyCodec>) -> Self {
+        for (service, codec) in codecs {
+            self.service_mut(service).codec = codec;
+        }
+        self
+    }
+
+    /// Sets per-service response body size limits in bytes.
+    pub fn with_response_limits(mut self, limits: HashMap<String, usize>) -> Self {
+        for (service, limit) in limits {
+            self.service_mut(service).response_limit = Some(limit);
+        }
+        self
+    }
+
+    /// Sets per-service sizes of slices in bytes buffered response bodies are written to clients
+    /// in, accounting time spent waiting for slow clients.
+    pub fn with_response_slices(mut self, slices: HashMap<String, usize>) -> Self {
+        for (service, slice) in slices {
+            self.service_mut(service).response_slice = Some(slice);
+        }
+        self
+    }
+
+    /// Sets per-service timeouts of waiting for the first response frame of each attempt.
+    ///
+    /// Unlike the client-facing timeout, it detects applications that have accepted the request,
+    /// but never started answering, leaving the rest of the time budget for a retry.
+    pub fn with_response_timeouts(mut self, timeouts: HashMap<String, Duration>) -> Self {
+        for (service, timeout) in timeouts {
+            self.service_mut(service).response_timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Sets per-service delays after which requests are hedged with another attempt, racing the
+    /// first one.
+    pub fn with_hedging(mut self, delays: HashMap<String, Duration>) -> Self {
+        for (service, delay) in delays {
+            self.service_mut(service).hedge_delay = Some(delay);
+        }
+        self
+    }
+
+    /// Sets per-service `Link` header values hinting clients at resources to preload.
+    pub fn with_early_hints(mut self, hints: HashMap<String, Vec<String>>) -> Self {
+        for (service, hints) in hints {
+            self.service_mut(service).hints = hints;
+        }
+        self
+    }
+
+    /// Sets limits of the number and total size of request headers forwarded to applications.
+    pub fn with_request_headers_limit(mut self, limit: Option<RequestHeadersConfig>) -> Self {
+        self.request_headers_limit = limit;
+        self
+    }
+
+    /// Sets names of headers, with which clients may shrink the timeout of their requests.
+    pub fn with_request_deadline(mut self, cfg: Option<RequestDeadlineConfig>) -> Self {
+        self.request_deadline = cfg;
+        self
+    }
+
+    /// Sets limits of the number and total size of headers accepted from application responses.
+    pub fn with_response_headers_limit(mut self, limit: ResponseHeadersConfig) -> Self {
+        self.headers_limit = limit;
+        self
+    }
+
+    /// Sets per-event overrides of the retry safety classification, keyed by service and event
+    /// names.
+    pub fn with_retry_overrides(mut self, overrides: HashMap<String, HashMap<String, RetrySafety>>) -> Self {
+        for (service, overrides) in overrides {
+            self.service_mut(service).retry_overrides = overrides;
+        }
+        self
+    }
+
+    /// Sets rules mapping Cocaine errors into HTTP statuses and bodies.
+    pub fn with_error_statuses(mut self, statuses: ErrorStatuses) -> Self {
+        self.error_statuses = Arc::new(statuses);
+        self
+    }
+
+    /// Sets Cocaine errors, after which requests are retried unless overridden.
+    pub fn with_retriable_errors(mut self, errors: Vec<RetriableError>) -> Self {
+        self.retriable_errors = Arc::new(errors);
+        self
+    }
+
+    /// Sets the table of error categories mapped to names of subsystems generating them, which
+    /// are reported in `X-Error-Generated-By` header.
+    pub fn with_error_origins(mut self, origins: HashMap<u64, String>) -> Self {
+        self.error_origins = Arc::new(origins);
+        self
+    }
+
+    /// Sets the signer of forwarded headers.
+    ///
+    /// When set, the proxy overrides the client address and tenant headers with its own values
+    /// and signs the configured headers, allowing applications to verify them.
+    pub fn with_signer(mut self, signer: Option<HeaderSigner>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// Sets TLS connection attributes the listener forwards in request headers, which are kept and
+    /// may be signed like headers the route sets itself.
+    pub fn with_tls_forward(mut self, forward: Vec<TlsAttribute>) -> Self {
+        self.tls_forward = forward;
+        self
+    }
+
+    /// Sets routing rules, which may redirect requests into another destination service.
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Sets canary ramps, which gradually shift requests of services into their canary versions.
+    pub fn with_canaries(mut self, canaries: Canaries) -> Self {
+        self.canaries = canaries;
+        self
+    }
+
+    /// Sets an additional sink for access records.
+    pub fn with_access_sink(mut self, sink: Option<Arc<dyn AccessSink>>) -> Self {
+        self.access_sink = sink;
+        self
+    }
+
+    /// Sets delays between safe retries.
+    pub fn with_retry_backoff(mut self, backoff: BackoffConfig) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Sets per-service budgets, which suppress retries once they outnumber the configured
+    /// fraction of recent requests.
+    pub fn with_retry_budget(mut self, budget: Option<RetryBudget>) -> Self {
+        self.retry_budget = budget.map(Arc::new);
+        self
+    }
+
+    /// Sets per-service events, which are invoked when the request specifies only the service,
+    /// either with `X-Cocaine-Service` header or with a single path segment.
+    pub fn with_default_events(mut self, events: HashMap<String, String>) -> Self {
+        for (service, event) in events {
+            self.service_mut(service).default_event = Some(event);
+        }
+        self
+    }
+
+    /// Forwards requests to the given services into local HTTP upstreams instead of the Cocaine.
+    pub fn with_local_upstreams(mut self, upstreams: HashMap<String, LocalUpstream>) -> Self {
+        for (service, upstream) in upstreams {
+            self.service_mut(service).local = Some(upstream);
+        }
+        self
+    }
+
+    /// Sets the proxy identity, which is appended to `Via` headers of requests and responses,
+    /// enabling loop detection.
+    pub fn with_via(mut self, via: Option<Via>) -> Self {
+        self.via = via.map(Arc::new);
+        self
+    }
+
+    /// Sets priority classes, by which requests are scheduled in services pools.
+    pub fn with_priorities(mut self, priorities: Option<Priorities>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Sets peer proxies, into which requests rejected by open circuit breakers are forwarded.
+    pub fn with_peers(mut self, peers: Option<Peers>) -> Self {
+        self.peers = peers.map(Arc::new);
+        self
+    }
+
+    /// Sets body digest settings, enabling verification of request bodies and generation of
+    /// response digests.
+    pub fn with_digest(mut self, digest: Option<DigestConfig>) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// Sets the queue through which access records are logged asynchronously.
+    pub fn with_access_queue(mut self, queue: Option<Arc<AccessQueue>>) -> Self {
+        self.access_queue = queue;
+        self
+    }
+
+    /// Sets the sampler, which writes only a fraction of access records depending on their status.
+    pub fn with_access_sampler(mut self, sampler: Option<AccessSampler>) -> Self {
+        self.access_sampler = sampler.map(Arc::new);
+        self
+    }
+
+    /// Sets tenants, which requests were resolved into by the router.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Enables sampled mirroring of full requests.
+    pub fn with_mirror(mut self, mirror: RequestMirror) -> Self {
+        self.mirror = Some(Arc::new(mirror));
+        self
+    }
+
+    /// Sets the format of access records, which also masks secrets in them.
+    pub fn with_access_format(mut self, format: Arc<AccessFormat>) -> Self {
+        self.access_format = format;
+        self
+    }
+
+    /// Returns settings of the given service for modification, starting from the defaults for
+    /// services not seen yet.
+    fn service_mut(&mut self, service: String) -> &mut ServiceSettings {
+        Arc::make_mut(self.services.entry(service).or_insert_with(Default::default))
+    }
+
+    /// Returns the event invoked when the request specifies only the given service.
+    fn default_event(&self, service: &str) -> Option<&String> {
+        self.services.get(service).and_then(|settings| settings.default_event.as_ref())
+    }
+
     /// Extracts required parameters from the request.
-    fn extract_parameters(&self, req: &Request) -> Option<Result<(String, String, String), Error>> {
+    fn extract_parameters(&self, req: &Request) -> Option<Result<Target, Error>> {
         let service = req.headers().get::<XCocaineService>();
         let event = req.headers().get::<XCocaineEvent>();
 
         match (service, event) {
             (Some(service), Some(event)) => {
-                Some(Ok((service.to_string(), event.to_string(), req.uri().to_string())))
+                Some(Ok(Target::Invoke(service.to_string(), event.to_string(), req.uri().to_string(), false)))
+            }
+            (Some(service), None) => {
+                match self.default_event(&service.0) {
+                    Some(event) => {
+                        Some(Ok(Target::Invoke(service.to_string(), event.clone(), req.uri().to_string(), false)))
+                    }
+                    None => Some(Err(Error::IncompleteHeadersMatch)),
+                }
             }
-            (Some(..), None) | (None, Some(..)) => Some(Err(Error::IncompleteHeadersMatch)),
+            (None, Some(..)) => Some(Err(Error::IncompleteHeadersMatch)),
             (None, None) => {
-                self.regex.captures(req.uri().as_ref()).and_then(|cap| {
+                let path = req.uri().as_ref();
+                let (path, stripped) = match self.prefix.as_ref().and_then(|prefix| strip_prefix(prefix, path)) {
+                    Some(rest) => (rest, true),
+                    None => (path, false),
+                };
+
+                let vhost = req.headers().get::<Host>().and_then(|host| self.vhosts.route(host.hostname()));
+                if let Some((service, event)) = vhost {
+                    return Some(Ok(Target::Invoke(service, event, path.to_owned(), stripped)));
+                }
+
+                if let Some((service, event, uri)) = self.routing.route(path) {
+                    return Some(Ok(Target::Invoke(service, event, uri, stripped)));
+                }
+
+                // Per-service default events take precedence over the normalization policy.
+                if let Some((service, query)) = single_segment(path) {
+                    if let Some(event) = self.default_event(service) {
+                        let uri = format!("/{}", query);
+                        return Some(Ok(Target::Invoke(service.into(), event.clone(), uri, stripped)));
+                    }
+                }
+
+                if let Some(ref cfg) = self.normalization {
+                    return match normalize_path(path, cfg) {
+                        PathMatch::Route(service, event, uri) => Some(Ok(Target::Invoke(service, event, uri, stripped))),
+                        PathMatch::Redirect(location) => {
+                            let location = match self.prefix {
+                                Some(ref prefix) if stripped => format!("{}{}", prefix, location),
+                                Some(..) | None => location,
+                            };
+                            Some(Ok(Target::Redirect(location)))
+                        }
+                        PathMatch::Reject(reason) => Some(Err(Error::InvalidPath(reason))),
+                        PathMatch::None => None,
+                    };
+                }
+
+                self.regex.captures(path).and_then(|cap| {
                     match (cap.get(1), cap.get(2), cap.get(3)) {
                         (Some(service), Some(event), Some(other)) => {
                             let uri = other.as_str();
@@ -102,7 +750,7 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
                                 format!("/{}", uri)
                             };
 
-                            Some(Ok((service.as_str().into(), event.as_str().into(), uri)))
+                            Some(Ok(Target::Invoke(service.as_str().into(), event.as_str().into(), uri, stripped)))
                         }
                         (..) => None,
                     }
@@ -111,8 +759,8 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
         }
     }
 
-    fn map_headers(&self, headers: &Headers) -> Vec<hpack::RawHeader> {
-        self.headers.iter()
+    fn map_headers(mapping: &HashMap<String, String>, headers: &Headers) -> Vec<hpack::RawHeader> {
+        mapping.iter()
             .filter_map(|(name, mapped)| headers.get_raw(name).map(|v| (mapped, v)))
             .map(|(name, value)| {
                 let value = value.into_iter().fold(Vec::new(), |mut vec, v| {
@@ -125,9 +773,40 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
             .collect()
     }
 
-    fn invoke(&self, service: String, event: String, req: Request, uri: String)
+    fn invoke(&self, service: String, event: String, req: Request, uri: String, prefix: Option<String>)
         -> Box<dyn Future<Item = Response, Error = Error>>
     {
+        for transition in self.rules.update(random::now()) {
+            let state = if transition.active { "activated" } else { "deactivated" };
+            cocaine_log!(self.log, Severity::Info, "{} time window of routing rule from `{}` into `{}`", state,
+                transition.service, transition.target);
+        }
+
+        for transition in self.canaries.update(Instant::now()) {
+            match transition.change {
+                Change::Promoted(weight) => {
+                    cocaine_log!(self.log, Severity::Info, "promoted canary `{}` of `{}` service to {}% of requests",
+                        transition.target, transition.service, weight);
+                }
+                Change::RolledBack { canary, baseline } => {
+                    cocaine_log!(self.log, Severity::Warn, "rolled back canary `{}` of `{}` service: error ratio {:.4} exceeds baseline {:.4}",
+                        transition.target, transition.service, canary, baseline);
+                }
+            }
+        }
+
+        // Requests pinned by routing rules are left out of canary ramps.
+        let (service, canary) = match self.rules.select(&service, req.headers()) {
+            Some(target) => (target.to_owned(), None),
+            None => {
+                let canary = self.canaries.select(&service);
+                match canary.as_ref().and_then(Sample::target) {
+                    Some(target) => (target.to_owned(), canary),
+                    None => (service, canary),
+                }
+            }
+        };
+
         let trace = if let Some(trace) = req.headers().get_raw(&self.tracing_header) {
             match XRequestId::parse_header(trace) {
                 Ok(v) => v.into(),
@@ -138,7 +817,7 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
             }
         } else {
             // TODO: Log (debug) trace id source (header or generated).
-            rand::random::<u64>()
+            random::gen::<u64>()
         };
 
         let tracing_policy = req.headers()
@@ -146,47 +825,418 @@ impl<L: Log + Clone + Send + Sync + 'static> AppRoute<L> {
             .map(|&v| v.into())
             .unwrap_or(TracingPolicy::Auto);
 
-        let log = AccessLogger::new(self.log.clone(), &req, service.clone(), event.clone(), trace);
-        let headers = self.map_headers(req.headers());
-        let mut app_request = AppRequest::new(service.clone(), event, trace, &req, uri);
-        let dispatcher = self.dispatcher.clone();
-        let future = req.body()
-            .concat2()
-            .map_err(Error::InvalidBodyRead)
-            .and_then(move |body| {
-                app_request.set_body(body.to_vec());
-                AppWithSafeRetry::new(app_request, headers, dispatcher, 3, tracing_policy)
-            })
-            .then(move |result| {
-                match result {
-                    Ok((mut resp, size)) => {
-                        resp.headers_mut().set(XPoweredBy::default());
-                        resp.headers_mut().set(XCocaineApp(service));
-
-                        log.commit(resp.status(), size, None);
-                        Ok(resp)
-                    }
-                    Err(err) => {
-                        log.commit(StatusCode::InternalServerError, 0, Some(&err));
-                        Err(err)
-                    }
-                }
-            });
+        let tenant = self.tenants.get(req.headers());
+        let (dispatcher, mapping) = match tenant {
+            Some(tenant) => (tenant.dispatcher(), tenant.headers()),
+            None => (&self.dispatcher, &self.headers),
+        };
+        let quota = tenant.and_then(Tenant::quota);
+        let tenant = tenant.map(|tenant| tenant.name().to_owned());
 
-        Box::new(future)
-    }
-}
+        let log = AccessLogger::new(self.log.clone(), &req, service.clone(), event.clone(), trace, self.access_format.clone())
+            .with_sink(self.access_sink.clone())
+            .with_queue(self.access_queue.clone())
+            .with_sampler(self.access_sampler.clone())
+            .with_tenant(tenant.clone())
+            .with_prefix(prefix);
 
-impl<L: Log + Clone + Send + Sync + 'static> Route for AppRoute<L> {
-    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+        // The permit is held until the response is ready, i.e. it is moved into the final closure.
+        let permit = match quota.map(Quota::acquire) {
+            Some(None) => {
+                let tenant = tenant.unwrap_or_default();
+                let err = Error::QuotaExceeded(tenant.clone());
+                self.metrics.mark_tenant_rejected(&tenant, err.code());
+                log.commit(err.code(), 0, Some(&err));
+                return Box::new(future::err(err));
+            }
+            Some(Some(permit)) => Some(permit),
+            None => None,
+        };
 
-    fn process(&self, req: Request) -> Match<Self::Future> {
+        let chain = via::chain(req.headers());
+        if let Some(ref via) = self.via {
+            if chain.as_ref().map(|chain| via.is_looped(chain)).unwrap_or(false) {
+                let err = Error::LoopDetected;
+                if let Some(ref tenant) = tenant {
+                    self.metrics.mark_tenant(tenant, err.code());
+                }
+                log.commit(err.code(), 0, Some(&err));
+                return Box::new(future::err(err));
+            }
+        }
+
+        if let Some(ref limit) = self.request_headers_limit {
+            if let Err(err) = check_request_headers(req.headers(), limit) {
+                self.metrics.mark_headers_rejected();
+                if let Some(ref tenant) = tenant {
+                    self.metrics.mark_tenant(tenant, err.code());
+                }
+                log.commit(err.code(), 0, Some(&err));
+                return Box::new(future::err(err));
+            }
+        }
+
+        if let Some(limit) = self.max_body_size {
+            if req.headers().get::<ContentLength>().map(|&ContentLength(len)| len > limit as u64).unwrap_or(false) {
+                let err = Error::PayloadTooLarge(limit);
+                if let Some(ref tenant) = tenant {
+                    self.metrics.mark_tenant(tenant, err.code());
+                }
+                log.commit(err.code(), 0, Some(&err));
+                return Box::new(future::err(err));
+            }
+        }
+
+        let now = random::now();
+        let budget = match self.request_deadline {
+            Some(ref cfg) => client_budget(req.headers(), cfg, now),
+            None => Ok(None),
+        };
+        let budget = match budget {
+            Ok(budget) => budget,
+            Err(err) => {
+                if let Some(ref tenant) = tenant {
+                    self.metrics.mark_tenant(tenant, err.code());
+                }
+                log.commit(err.code(), 0, Some(&err));
+                return Box::new(future::err(err));
+            }
+        };
+
+        if !dispatcher.admit(&service) {
+            // Requests forwarded by another proxy are not forwarded again to prevent loops.
+            if let Some(peers) = self.peers.as_ref().filter(|_| !Peers::is_forwarded(req.headers())) {
+                let mut headers = Vec::new();
+                if let Some(ref via) = self.via {
+                    let value = via.append(chain.as_ref().map(String::as_str), &req.version());
+                    headers.push((VIA_HEADER.to_owned(), value));
+                }
+                self.metrics.mark_peer_forwarded();
+
+                let metrics = self.metrics.clone();
+                let future = peers.forward(req, &headers).then(move |result| {
+                    drop(permit);
+
+                    let result = result.map_err(|err| Error::Peer(err.to_string()));
+                    let status = match result {
+                        Ok((ref resp, ..)) => resp.status(),
+                        Err(ref err) => err.code(),
+                    };
+                    if let Some(ref tenant) = tenant {
+                        metrics.mark_tenant(tenant, status);
+                    }
+                    match result {
+                        Ok((resp, size)) => {
+                            log.commit(status, size, None);
+                            Ok(resp)
+                        }
+                        Err(err) => {
+                            log.commit(status, 0, Some(&err));
+                            Err(err)
+                        }
+                    }
+                });
+
+                return Box::new(future);
+            }
+
+            let err = Error::CircuitOpen(service);
+            if let Some(ref tenant) = tenant {
+                self.metrics.mark_tenant(tenant, err.code());
+            }
+            log.commit(err.code(), 0, Some(&err));
+            return Box::new(future::err(err));
+        }
+
+        let headers = Self::map_headers(mapping, req.headers());
+        let settings = self.services.get(&service).unwrap_or(&self.defaults).clone();
+        let mut app_request = AppRequest::new(service.clone(), event, trace, &req, uri, settings.clone());
+        if let Some(ref signer) = self.signer {
+            let headers = &mut app_request.frame.headers;
+            signer.strip(headers);
+            // These are set by the listener, which has already dropped the ones sent by the client.
+            for &attribute in &self.tls_forward {
+                let name = tls_header_name(attribute);
+                if let Some(value) = req.headers().get_raw(name).and_then(|raw| raw.one()) {
+                    signing::set_header(headers, name, String::from_utf8_lossy(value).into_owned());
+                }
+            }
+            if let Some(addr) = req.remote_addr() {
+                signing::set_header(headers, REAL_IP_HEADER, addr.ip().to_string());
+            }
+            signing::set_header(headers, TENANT_HEADER, tenant.clone().unwrap_or_else(|| "default".into()));
+            signer.sign(headers, random::epoch_millis(random::now()));
+        }
+        if let Some(ref via) = self.via {
+            let value = via.append(chain.as_ref().map(String::as_str), &req.version());
+            signing::set_header(&mut app_request.frame.headers, VIA_HEADER, value);
+        }
+        // The client budget shrinks the proxy timeout, if it is shorter.
+        let timeout = match (self.timeout, budget) {
+            (Some(timeout), Some(budget)) => Some(cmp::min(timeout, budget)),
+            (timeout, budget) => timeout.or(budget),
+        };
+        if let Some(timeout) = timeout {
+            app_request.set_deadline(now + timeout);
+        }
+        app_request.client_deadline = budget.is_some();
+        app_request.stalls = self.metrics.stalls(&service);
+        app_request.queue_budget = self.queue_budget;
+        app_request.body_limit = self.max_body_size;
+        app_request.priority = self.priorities.as_ref().map(|priorities| {
+            let (class, name) = priorities.classify(&service, tenant.as_ref().map(String::as_str), req.headers());
+            (class, name.to_owned())
+        });
+        app_request.digest = self.digest;
+        app_request.headers_limit = self.headers_limit;
+        app_request.origins = self.error_origins.clone();
+        app_request.retry = settings.retry_overrides.get(&app_request.event).cloned();
+        // Hedged attempts are duplicates, so only requests safe to repeat are hedged.
+        let repeatable = match app_request.retry {
+            Some(RetrySafety::Safe) => true,
+            Some(RetrySafety::Forbidden) => false,
+            None => *req.method() == Method::Get || *req.method() == Method::Head,
+        };
+        app_request.hedge_delay = settings.hedge_delay.filter(|_| repeatable);
+        if let Some(ref budget) = self.retry_budget {
+            budget.deposit(&service);
+        }
+        app_request.retry_budget = self.retry_budget.clone();
+        app_request.retriable = self.retriable_errors.clone();
+        app_request.statuses = self.error_statuses.clone();
+        let dispatcher = dispatcher.clone();
+        let breaker = (dispatcher.clone(), service.clone());
+        // Only budgets shorter than the proxy timeout need a timer of their own.
+        let expiry = budget
+            .filter(|&budget| self.timeout.map(|timeout| budget < timeout).unwrap_or(true))
+            .map(|budget| (budget, dispatcher.clone(), app_request.timer.clone()));
+        let proxy = self.via.clone();
+        let metrics = self.metrics.clone();
+        let retry_log = self.log.clone();
+        let mirror = self.mirror.clone().filter(|mirror| mirror.sample());
+        let mut log = PendingLog::new(log, metrics.clone(), app_request.timer.clone(), (service.clone(), trace));
+        let slo = metrics.slo(&service).map(|slo| (slo, app_request.timer.clone()));
+        let backoff = &self.retry_backoff;
+        let backoff = ExponentialBackoff::new(backoff.base(), backoff.max(), backoff.jitter());
+
+        let future = if let Some(ref upstream) = settings.local {
+            let value = XRequestId(trace).to_string();
+            signing::set_header(&mut app_request.frame.headers, &self.tracing_header, value);
+            Self::forward_local(upstream, app_request, req, metrics.clone())
+        } else if settings.streaming.request() {
+            Self::invoke_streaming(app_request, req, headers, dispatcher, backoff, metrics.clone(), tracing_policy,
+                retry_log)
+        } else {
+            Self::invoke_buffered(app_request, req, headers, dispatcher, backoff, metrics.clone(), mirror,
+                tracing_policy, retry_log)
+        };
+
+        let future = match expiry {
+            Some((budget, dispatcher, timer)) => {
+                let (tx, rx) = oneshot::channel();
+                dispatcher.send(Event::Timer(budget, tx));
+
+                let expired = rx.then(move |result| -> Box<dyn Future<Item = (Response, u64), Error = Error>> {
+                    match result {
+                        Ok(()) => {
+                            timer.on_failure(Failure::Timeout);
+                            Box::new(future::err(Error::DeadlineExceeded))
+                        }
+                        // The timer can't be armed, so the request is limited only by the proxy timeout.
+                        Err(futures::Canceled) => Box::new(future::empty()),
+                    }
+                });
+
+                Box::new(future.select(expired).map(|(resp, ..)| resp).map_err(|(err, ..)| err))
+            }
+            None => future,
+        };
+
+        let future = future
+            .then(move |result| {
+                drop(permit);
+
+                let (dispatcher, name) = breaker;
+                match result {
+                    Ok((mut resp, size)) => {
+                        dispatcher.report(&name, !resp.status().is_server_error());
+                        if let Some(ref canary) = canary {
+                            canary.report(!resp.status().is_server_error());
+                        }
+                        if let Some(proxy) = proxy {
+                            let value = proxy.append(via::chain(resp.headers()).as_ref().map(String::as_str),
+                                &HttpVersion::Http11);
+                            resp.headers_mut().set_raw(VIA_HEADER, value);
+                        }
+                        resp.headers_mut().set(XPoweredBy::default());
+                        resp.headers_mut().set(XCocaineApp(service));
+
+                        if let Some(ref tenant) = tenant {
+                            metrics.mark_tenant(tenant, resp.status());
+                        }
+                        if let Some((ref slo, ref timer)) = slo {
+                            slo.observe(!resp.status().is_server_error(), timer.birth.elapsed());
+                        }
+                        // Streamed responses are logged once their body is finished, with its final size.
+                        match log.timer.take_stream() {
+                            Some(end) => {
+                                let status = resp.status();
+                                end.on_finish(move |size, client_write| {
+                                    log.timer.on_client_write(client_write);
+                                    log.commit(status, size, None);
+                                });
+                            }
+                            None => log.commit(resp.status(), size, None),
+                        }
+                        Ok(resp)
+                    }
+                    Err(Error::ClientAborted) => {
+                        log.abort();
+                        Err(Error::ClientAborted)
+                    }
+                    Err(err) => {
+                        dispatcher.report(&name, false);
+                        if let Some(ref canary) = canary {
+                            canary.report(false);
+                        }
+                        match err {
+                            Error::ResponseTooLarge(..) => metrics.mark_oversized(),
+                            Error::Shed(..) => metrics.mark_shed(),
+                            _ => {}
+                        }
+                        if let Some(ref tenant) = tenant {
+                            metrics.mark_tenant(tenant, err.code());
+                        }
+                        if let Some((ref slo, ref timer)) = slo {
+                            slo.observe(!err.code().is_server_error(), timer.birth.elapsed());
+                        }
+                        log.commit(err.code(), 0, Some(&err));
+                        Err(err)
+                    }
+                }
+            });
+
+        Box::new(future)
+    }
+
+    /// Forwards the request into the local upstream, streaming bodies both ways.
+    fn forward_local(upstream: &LocalUpstream, app_request: AppRequest, req: Request, metrics: Arc<Metrics>)
+        -> Box<dyn Future<Item = (Response, u64), Error = Error>>
+    {
+        let birth = Instant::now();
+        let future = upstream.forward(&app_request.frame, req.body())
+            .map_err(|err| Error::LocalUpstream(err.to_string()))
+            .map(move |result| {
+                metrics.observe_upstream(birth.elapsed());
+                result
+            });
+
+        Box::new(future)
+    }
+
+    /// Reads the whole request body and then invokes the application, retrying safe failures.
+    fn invoke_buffered(mut app_request: AppRequest, req: Request, headers: Vec<hpack::RawHeader>,
+        dispatcher: EventDispatch, backoff: ExponentialBackoff, metrics: Arc<Metrics>, mirror: Option<Arc<RequestMirror>>,
+        tracing_policy: TracingPolicy, log: L) -> Box<dyn Future<Item = (Response, u64), Error = Error>>
+    {
+        // Account the buffered body as it arrives until the request is finished, including retries.
+        let reservation = MemoryBudget::reserve(&metrics.memory, 0);
+        let future = limit_body(req.body(), app_request.body_limit)
+            .fold((reservation, Vec::new()), |(mut reservation, mut body), chunk| {
+                reservation.grow(chunk.len());
+                body.extend_from_slice(&chunk);
+                Ok::<_, Error>((reservation, body))
+            })
+            .and_then(move |(reservation, body)| -> Box<dyn Future<Item = (Response, u64), Error = Error>> {
+                app_request.timer.on_body_read();
+                if app_request.digest.map(|digest| digest.verify()).unwrap_or(false) {
+                    if let Err(header) = digest::verify(&app_request.frame.headers, &body) {
+                        return Box::new(future::err(Error::DigestMismatch(header)));
+                    }
+                }
+
+                app_request.set_body(body);
+                if let Some(mirror) = mirror {
+                    let frame = &app_request.frame;
+                    mirror.commit(app_request.trace, &app_request.service, &app_request.event, &frame.method,
+                        &frame.uri, &frame.headers, &frame.body);
+                }
+                let future = AppWithSafeRetry::new(app_request, headers, dispatcher, 3, backoff, metrics, tracing_policy, log)
+                    .then(move |result| {
+                        drop(reservation);
+                        result
+                    });
+
+                Box::new(future)
+            });
+
+        Box::new(future)
+    }
+
+    /// Invokes the application immediately, forwarding request body chunks as they arrive.
+    ///
+    /// The body can't be replayed, so there is only a single attempt. The application may respond
+    /// before consuming the whole body, in which case the rest of it is dropped.
+    fn invoke_streaming(mut app_request: AppRequest, req: Request, headers: Vec<hpack::RawHeader>,
+        dispatcher: EventDispatch, backoff: ExponentialBackoff, metrics: Arc<Metrics>, tracing_policy: TracingPolicy,
+        log: L) -> Box<dyn Future<Item = (Response, u64), Error = Error>>
+    {
+        // Chunks are accounted while queued and sent, until the application consumes them.
+        let (tx, rx) = mpsc::channel(BODY_STREAM_BUFFER);
+        app_request.stream = Arc::new(Mutex::new(Some(Box::new(rx) as BodyReceiver)));
+
+        let memory = metrics.memory.clone();
+        let timer = app_request.timer.clone();
+        let forward = limit_body(req.body(), app_request.body_limit)
+            .map(|chunk| Some(chunk.to_vec()))
+            .chain(stream::once(Ok(None)))
+            .map(move |chunk| {
+                let size = chunk.as_ref().map_or(0, |chunk| chunk.len());
+                (chunk, Some(MemoryBudget::reserve(&memory, size)))
+            })
+            // The receiver is gone only when the application has stopped reading the body.
+            .forward(tx.sink_map_err(|_| Error::Canceled))
+            .map(move |_| timer.on_body_read());
+
+        let future = AppWithSafeRetry::new(app_request, headers, dispatcher, 1, backoff, metrics, tracing_policy, log)
+            .select2(forward)
+            .then(|result| -> Box<dyn Future<Item = (Response, u64), Error = Error>> {
+                match result {
+                    Ok(Either::A((resp, ..))) => Box::new(future::ok(resp)),
+                    Ok(Either::B(((), resp))) => Box::new(resp),
+                    Err(Either::A((err, ..))) => Box::new(future::err(err)),
+                    Err(Either::B((Error::Canceled, resp))) => Box::new(resp),
+                    Err(Either::B((err, ..))) => Box::new(future::err(err)),
+                }
+            });
+
+        Box::new(future)
+    }
+}
+
+impl<L: Log + Clone + Send + Sync + 'static> Route for AppRoute<L> {
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn process(&self, req: Request) -> Match<Self::Future> {
         match self.extract_parameters(&req) {
-            Some(Ok((service, event, uri))) => {
-                let future = self.invoke(service, event, req, uri).then(|resp| {
+            Some(Ok(Target::Redirect(location))) => {
+                // Permanent redirect preserves the method and the body, unlike 301.
+                let status = match *req.method() {
+                    Method::Get | Method::Head => StatusCode::MovedPermanently,
+                    _ => StatusCode::PermanentRedirect,
+                };
+                let resp = Response::new()
+                    .with_status(status)
+                    .with_header(Location::new(location));
+                Match::Some(Box::new(future::ok(resp)))
+            }
+            Some(Ok(Target::Invoke(service, event, uri, stripped))) => {
+                let prefix = if stripped { self.prefix.clone() } else { None };
+                let future = self.invoke(service, event, req, uri, prefix).then(|resp| {
                     resp.or_else(|err| {
                         let resp = Response::new()
                             .with_status(err.code())
+                            .with_header(XErrorGeneratedBy::proxy())
                             .with_body(err.to_string());
                         Ok(resp)
                     })
@@ -196,6 +1246,7 @@ impl<L: Log + Clone + Send + Sync + 'static> Route for AppRoute<L> {
             Some(Err(err)) => {
                 let resp = Response::new()
                     .with_status(err.code())
+                    .with_header(XErrorGeneratedBy::proxy())
                     .with_body(err.to_string());
                 Match::Some(Box::new(future::ok(resp)))
             }
@@ -204,6 +1255,227 @@ impl<L: Log + Clone + Send + Sync + 'static> Route for AppRoute<L> {
     }
 }
 
+/// An access logger of the request being processed.
+///
+/// If dropped without being committed, which happens when the client closes the connection before
+/// the response is ready and hyper drops the response future, the request is accounted as aborted.
+/// The only exception is the proxy timeout, after which the future is dropped too, which the
+/// timeout middleware signals explicitly.
+struct PendingLog<L: Log> {
+    log: Option<AccessLogger<L>>,
+    metrics: Arc<Metrics>,
+    timer: Arc<RequestTimer>,
+    /// Service and trace id of the request, remembered along with generated errors.
+    origin: (String, u64),
+}
+
+impl<L: Log> PendingLog<L> {
+    fn new(log: AccessLogger<L>, metrics: Arc<Metrics>, timer: Arc<RequestTimer>, origin: (String, u64)) -> Self {
+        Self {
+            log: Some(log),
+            metrics: metrics,
+            timer: timer,
+            origin: origin,
+        }
+    }
+
+    /// Remembers a server error generated by the proxy rather than responded by the application.
+    fn remember(&self, status: StatusCode, failure: Option<Failure>, err: Option<&dyn error::Error>) {
+        let (class, message) = match (failure, err) {
+            (Some(failure), Some(err)) => (failure.to_string(), err.to_string()),
+            (Some(failure), None) => (failure.to_string(), self.timer.error().unwrap_or_default()),
+            (None, Some(err)) => (status.as_u16().to_string(), err.to_string()),
+            (None, None) => return,
+        };
+
+        self.metrics.recent_errors.record(ErrorRecord {
+            timestamp: random::epoch_millis(random::now()),
+            trace: format!("{:x}", self.origin.1),
+            service: self.origin.0.clone(),
+            status: status.as_u16(),
+            class: class,
+            message: message,
+        });
+    }
+
+    fn commit(&mut self, status: StatusCode, bytes_sent: u64, err: Option<&dyn error::Error>) {
+        if let Some(mut log) = self.log.take() {
+            self.metrics.observe_duration(self.timer.birth.elapsed());
+            let failure = self.timer.failure();
+            if let Some(failure) = failure {
+                self.metrics.mark_failure(failure);
+            }
+            if status.is_server_error() {
+                self.remember(status, failure, err);
+            }
+            log.set_timings(self.timer.timings());
+            log.set_attempts(self.timer.attempts());
+            log.set_span(self.timer.span());
+            log.set_failure(failure.map(|failure| failure.to_string()));
+            log.commit(status, bytes_sent, err);
+        }
+    }
+
+    /// Accounts the request as aborted by the client.
+    fn abort(&mut self) {
+        if let Some(mut log) = self.log.take() {
+            self.metrics.mark_aborted();
+            log.set_timings(self.timer.timings());
+            log.set_attempts(self.timer.attempts());
+            log.set_span(self.timer.span());
+            log.commit(CLIENT_CLOSED_REQUEST, 0, Some(&Error::ClientAborted));
+        }
+    }
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Debug, Default)]
+struct TimerState {
+    timings: Timings,
+    /// Moment the first attempt was enqueued.
+    upstream: Option<Instant>,
+    /// Moment the invocation of the current attempt was sent.
+    sent: Option<Instant>,
+    attempts: u32,
+    /// Span id of the current attempt.
+    span: Option<u64>,
+    /// Upstream failure of the current attempt.
+    failure: Option<Failure>,
+    /// Message of the upstream error of the current attempt.
+    error: Option<String>,
+    /// End of the response body streamed to the client, if any.
+    stream: Option<Arc<StreamEnd>>,
+}
+
+/// Records durations of request processing phases, shared between all attempts.
+///
+/// Phases of the last attempt win, except the total upstream time, which spans all of them.
+#[derive(Debug)]
+struct RequestTimer {
+    birth: Instant,
+    state: Mutex<TimerState>,
+}
+
+impl RequestTimer {
+    fn new() -> Self {
+        Self {
+            birth: Instant::now(),
+            state: Mutex::new(TimerState::default()),
+        }
+    }
+
+    fn on_body_read(&self) {
+        self.state.lock().unwrap().timings.body_read = as_secs(self.birth.elapsed());
+    }
+
+    /// Marks an attempt with the given span being enqueued into a services pool, returning the
+    /// current moment.
+    fn on_enqueue(&self, span: u64) -> Instant {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.upstream.get_or_insert(now);
+        state.attempts += 1;
+        state.span = Some(span);
+        state.failure = None;
+        state.error = None;
+        now
+    }
+
+    /// Marks an attempt being taken by a services pool, returning the current moment.
+    fn on_dequeue(&self, queued: Instant) -> Instant {
+        let now = Instant::now();
+        self.state.lock().unwrap().timings.queue = as_secs(now.duration_since(queued));
+        now
+    }
+
+    /// Marks an invocation being sent into the service.
+    fn on_send(&self, dequeued: Instant) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.timings.resolve = as_secs(now.duration_since(dequeued));
+        state.timings.first_byte = 0.0;
+        state.sent = Some(now);
+    }
+
+    /// Marks a response chunk being received, remembering only the first one of the attempt.
+    fn on_chunk(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(sent) = state.sent.take() {
+            state.timings.first_byte = as_secs(sent.elapsed());
+        }
+    }
+
+    /// Marks the current attempt as failed upstream.
+    fn on_failure(&self, failure: Failure) {
+        self.state.lock().unwrap().failure = Some(failure);
+    }
+
+    /// Marks the current attempt as failed upstream with the given error.
+    fn on_error(&self, failure: Failure, message: String) {
+        let mut state = self.state.lock().unwrap();
+        state.failure = Some(failure);
+        state.error = Some(message);
+    }
+
+    /// Accounts time spent waiting for the client to accept the streamed response body.
+    fn on_client_write(&self, duration: Duration) {
+        self.state.lock().unwrap().timings.client_write = as_secs(duration);
+    }
+
+    /// Marks the response being sent to the client while its body is still streamed.
+    fn on_stream(&self, end: Arc<StreamEnd>) {
+        self.state.lock().unwrap().stream = Some(end);
+    }
+
+    /// Takes the end of the streamed response body, if the response is streamed.
+    fn take_stream(&self) -> Option<Arc<StreamEnd>> {
+        self.state.lock().unwrap().stream.take()
+    }
+
+    /// Returns the message of the upstream error of the last attempt, if any.
+    fn error(&self) -> Option<String> {
+        self.state.lock().unwrap().error.clone()
+    }
+
+    /// Returns the number of attempts enqueued so far.
+    fn attempts(&self) -> u32 {
+        self.state.lock().unwrap().attempts
+    }
+
+    /// Returns the span id of the last attempt, if any has been made.
+    fn span(&self) -> Option<u64> {
+        self.state.lock().unwrap().span
+    }
+
+    /// Returns the upstream failure of the last attempt, if any.
+    fn failure(&self) -> Option<Failure> {
+        self.state.lock().unwrap().failure
+    }
+
+    fn timings(&self) -> Timings {
+        let state = self.state.lock().unwrap();
+        let mut timings = state.timings;
+        if let Some(upstream) = state.upstream {
+            timings.upstream = as_secs(upstream.elapsed());
+        }
+        timings
+    }
+}
+
+impl<L: Log> Drop for PendingLog<L> {
+    fn drop(&mut self) {
+        if timeout::is_timed_out() {
+            self.timer.on_failure(Failure::Timeout);
+            self.commit(StatusCode::GatewayTimeout, 0, None);
+        } else {
+            self.abort();
+        }
+    }
+}
+
 /// A meta frame of HTTP request for cocaine application HTTP protocol.
 #[derive(Clone, Serialize)]
 pub(crate) struct RequestMeta {
@@ -219,49 +1491,9 @@ pub(crate) struct RequestMeta {
     pub(crate) body: Vec<u8>,
 }
 
-#[inline]
-fn serialize_method<S>(method: &Method, se: S) -> Result<S::Ok, S::Error>
-    where S: Serializer
-{
-    se.serialize_str(&method.to_string())
-}
-
-#[inline]
-fn serialize_version<S>(version: &HttpVersion, se: S) -> Result<S::Ok, S::Error>
-    where S: Serializer
-{
-    let v = if let &HttpVersion::Http11 = version {
-        "1.1"
-    } else {
-        "1.0"
-    };
-
-    se.serialize_str(v)
-}
-
-#[inline]
-fn serialize_body<S>(body: &[u8], se: S) -> Result<S::Ok, S::Error>
-    where S: Serializer
-{
-    se.serialize_str(unsafe { str::from_utf8_unchecked(body) })
-}
-
-#[derive(Debug, Deserialize)]
-struct ResponseMeta {
-    code: u32,
-    headers: Vec<(String, String)>
-}
-
-#[derive(Clone)]
-struct AppRequest {
-    service: String,
-    event: String,
-    trace: u64,
-    frame: RequestMeta,
-}
-
-impl AppRequest {
-    fn new(service: String, event: String, trace: u64, req: &Request, uri: String) -> Self {
+impl RequestMeta {
+    /// Builds the meta frame from the request head, leaving the body empty.
+    pub(crate) fn new(req: &Request, uri: String) -> Self {
         let headers = req.headers()
             .iter()
             .map(|header| {
@@ -275,400 +1507,3280 @@ impl AppRequest {
             })
             .collect();
 
-        let frame = RequestMeta {
+        Self {
             method: req.method().clone(),
             version: req.version(),
             // TODO: Test that uri is sent properly (previously only path was sent).
             uri: uri,
             headers: headers,
             body: Vec::new(),
-        };
+        }
+    }
+}
+
+/// A meta frame of HTTP request for cocaine application HTTP protocol v1 with the body encoded by
+/// the service codec.
+#[derive(Serialize)]
+struct RequestMetaV1<'a> {
+    #[serde(serialize_with = "serialize_method")]
+    method: &'a Method,
+    uri: &'a str,
+    #[serde(serialize_with = "serialize_version")]
+    version: &'a HttpVersion,
+    headers: &'a [(String, String)],
+    body: Encoded<'a>,
+}
 
+impl<'a> RequestMetaV1<'a> {
+    fn new(meta: &'a RequestMeta, codec: BodyCodec) -> Self {
         Self {
-            service: service,
-            event: event,
-            trace: trace,
-            frame: frame,
+            method: &meta.method,
+            uri: &meta.uri,
+            version: &meta.version,
+            headers: &meta.headers,
+            body: Encoded::new(codec, &meta.body),
         }
     }
+}
 
-    fn set_body(&mut self, body: Vec<u8>) {
-        self.frame.body = body;
+/// A meta frame of HTTP request for cocaine application HTTP protocol v2, where headers and body
+/// are transmitted in separate frames.
+#[derive(Serialize)]
+struct RequestMetaV2<'a> {
+    #[serde(serialize_with = "serialize_method")]
+    method: &'a Method,
+    uri: &'a str,
+    #[serde(serialize_with = "serialize_version")]
+    version: &'a HttpVersion,
+}
+
+impl<'a> From<&'a RequestMeta> for RequestMetaV2<'a> {
+    fn from(meta: &'a RequestMeta) -> Self {
+        Self {
+            method: &meta.method,
+            uri: &meta.uri,
+            version: &meta.version,
+        }
     }
 }
 
-/// A future that retries application invocation on receiving "safe" error,
-/// meaning that it is safe to retry it again until the specified limit reached.
+/// Makes the header telling applications the number of the attempt and the attempts limit, for
+/// example `2/3`.
+fn attempt_header(attempt: u32, limit: u32) -> hpack::RawHeader {
+    hpack::RawHeader::new(ATTEMPT_HEADER, format!("{}/{}", attempt, limit).into_bytes())
+}
+
+/// Picks the protocol spoken with the application, given the one configured for its service and
+/// the version reported by the locator.
 ///
-/// In this context "safety" means, that the request is guaranteed not to be
-/// delivered to the worker, for example when the queue is full.
-struct AppWithSafeRetry {
-    attempts: u32,
-    limit: u32,
-    request: Arc<AppRequest>,
-    dispatcher: EventDispatch,
-    headers: Vec<hpack::RawHeader>,
-    current: Option<Box<dyn Future<Item=Option<(Response, u64)>, Error=Error> + Send>>,
-    verbose: Arc<AtomicBool>,
-    tracing_policy: TracingPolicy,
+/// Newer protocols are spoken only if the locator confirms the application supports them, falling
+/// back to `v1` otherwise, including when the version can't be obtained.
+fn select_protocol(configured: AppProtocol, reported: Option<u64>) -> AppProtocol {
+    match (configured, reported) {
+        (AppProtocol::V2, Some(version)) if version >= 2 => AppProtocol::V2,
+        (..) => AppProtocol::V1,
+    }
 }
 
-impl AppWithSafeRetry {
-    fn new(request: AppRequest, headers: Vec<hpack::RawHeader>, dispatcher: EventDispatch, limit: u32, tracing_policy: TracingPolicy) -> Self {
-        let headers = Self::make_headers(headers, request.trace);
+/// Negotiates the protocol for an invocation of the given service.
+///
+/// The locator is asked only for services configured to speak a newer protocol than `v1`.
+fn negotiate(configured: AppProtocol, service: &str, negotiator: Option<Negotiator>)
+    -> Box<dyn Future<Item = AppProtocol, Error = cocaine::Error> + Send>
+{
+    match (configured, negotiator) {
+        (AppProtocol::V2, Some(negotiator)) => {
+            let future = negotiator.version(service).then(move |version| {
+                Ok(select_protocol(configured, version.ok()))
+            });
+            Box::new(future)
+        }
+        (..) => Box::new(future::ok(AppProtocol::V1)),
+    }
+}
 
-        let mut res = Self {
-            attempts: 1,
-            limit: limit,
-            request: Arc::new(request),
-            dispatcher: dispatcher,
-            headers: headers,
-            current: None,
-            verbose: Arc::new(AtomicBool::new(false)),
-            tracing_policy: tracing_policy,
-        };
+/// Wraps the given raw bytes into a chunk frame.
+pub(crate) fn make_chunk(buf: &[u8]) -> cocaine::Request {
+    cocaine::Request::new(0, &[unsafe { ::std::str::from_utf8_unchecked(buf) }]).unwrap()
+}
 
-        res.current = Some(res.make_future());
+#[derive(Debug)]
+enum UpstreamState {
+    /// The invocation is not sent yet.
+    Pending,
+    Open(cocaine::Sender),
+    /// The request body is finished, but the channel is kept open to acknowledge response bytes
+    /// until the response is finished, which happens only with flow control.
+    Draining(cocaine::Sender),
+    /// Either closed or aborted, so no more frames may be sent.
+    Finished,
+}
 
-        res
+/// Window-based flow control of body bytes in both directions.
+///
+/// The application acknowledges request body bytes it has consumed in informational frames with
+/// `ACK_HEADER`, while the proxy acknowledges response body bytes the client has drained in
+/// chunks following the request body, which is finished with an empty chunk instead of `close`
+/// for that purpose. Neither side sends more while its unacknowledged bytes exceed the window.
+#[derive(Debug)]
+struct Flow {
+    window: u64,
+    /// Request body bytes sent into the application.
+    sent: u64,
+    /// Request body bytes the application has acknowledged.
+    acked: u64,
+    /// Task sending the request body, waiting for the window to open.
+    task: Option<Task>,
+    /// Response body bytes drained by the client.
+    drained: u64,
+    /// Response body bytes acknowledged to the application.
+    credited: u64,
+    /// Set once the application has started responding.
+    responding: bool,
+    /// Set once the response is finished, after which there is nobody to acknowledge anything.
+    responded: bool,
+}
+
+impl Flow {
+    fn new(window: u64) -> Self {
+        Self {
+            window: window,
+            sent: 0,
+            acked: 0,
+            task: None,
+            drained: 0,
+            credited: 0,
+            responding: false,
+            responded: false,
+        }
     }
 
-    fn make_headers(headers: Vec<hpack::RawHeader>, trace: u64) -> Vec<hpack::RawHeader> {
-        let mut headers = if headers.is_empty() {
-            Vec::with_capacity(4)
+    /// Returns `true` if no more request body bytes may be sent until acknowledged.
+    fn is_full(&self) -> bool {
+        !self.responded && self.sent - self.acked >= self.window
+    }
+
+    /// Accounts the total number of request body bytes the application has consumed so far.
+    fn ack(&mut self, total: u64) {
+        // Acknowledging more than has been sent is bogus, but must not open the window forever.
+        self.acked = cmp::max(self.acked, cmp::min(total, self.sent));
+    }
+
+    /// Returns the total number of drained response body bytes to acknowledge, once enough of them
+    /// are collected to be worth a frame.
+    fn take_credit(&mut self) -> Option<u64> {
+        if self.drained - self.credited >= cmp::max(self.window / 2, 1) {
+            self.credited = self.drained;
+            Some(self.drained)
         } else {
-            headers
-        };
+            None
+        }
+    }
+}
 
-        let span = rand::random::<u64>();
-        headers.push(hpack::TraceId(trace).into_raw());
-        headers.push(hpack::SpanId(span).into_raw());
-        headers.push(hpack::ParentId(trace).into_raw());
+#[derive(Debug)]
+struct Channel {
+    state: UpstreamState,
+    /// Present only for applications taking part in flow control.
+    flow: Option<Flow>,
+    /// Reservations of sent request body chunks along with the number of bytes sent including
+    /// them, held until the application consumes the chunks.
+    reserved: VecDeque<(u64, MemoryReservation)>,
+}
 
-        headers
+impl Channel {
+    /// Releases reservations of chunks the application has consumed.
+    ///
+    /// Without flow control it is unknown until the channel is finished, so the whole sent body
+    /// stays accounted until then.
+    fn release(&mut self) {
+        let consumed = match (&self.state, &self.flow) {
+            (&UpstreamState::Finished, ..) => u64::MAX,
+            (.., &Some(ref flow)) if flow.responded => u64::MAX,
+            (.., &Some(ref flow)) => flow.acked,
+            (.., &None) => return,
+        };
+
+        while self.reserved.front().map(|&(sent, ..)| sent <= consumed).unwrap_or(false) {
+            self.reserved.pop_front();
+        }
     }
+}
 
-    fn make_future(&self) -> Box<dyn Future<Item=Option<(Response, u64)>, Error=Error> + Send> {
-        let (tx, rx) = oneshot::channel();
+/// Sending half of an invocation channel, shared with the dispatch reading the response.
+///
+/// The dispatch may give up on the response while the request is still being sent, in which case
+/// the upstream is finished with an error frame, letting the application stop processing instead
+/// of producing a response nobody reads. A closed upstream is left alone, because Cocaine allows
+/// no frames after `close`.
+#[derive(Clone, Debug)]
+pub(crate) struct Upstream {
+    channel: Arc<Mutex<Channel>>,
+}
 
-        let request = self.request.clone();
-        let verbose = self.verbose.clone();
-        let attempt = self.attempts;
-        let headers = self.headers.clone();
+impl Upstream {
+    pub(crate) fn new() -> Self {
+        Self::with_flow(None)
+    }
 
-        let manual_verbose = match self.tracing_policy {
-            TracingPolicy::Auto => None,
-            TracingPolicy::Manual(v) => Some(rand::random::<f64>() <= v),
-        };
+    /// Constructs the upstream of an application taking part in flow control with the given
+    /// window in bytes.
+    fn with_window(window: u64) -> Self {
+        Self::with_flow(Some(Flow::new(window)))
+    }
 
-        let ev = Event::Service {
-            name: request.service.clone(),
-            func: Box::new(move |service: &Service, mut settings: Settings| {
-                let mut headers = headers.clone();
-                if let Some(true) = manual_verbose {
-                    settings.verbose = true;
-                }
+    fn with_flow(flow: Option<Flow>) -> Self {
+        let channel = Channel {
+            state: UpstreamState::Pending,
+            flow: flow,
+            reserved: VecDeque::new(),
+        };
 
-                if settings.verbose && attempt == 1 {
-                    verbose.store(true, Ordering::Release);
-                }
+        Self { channel: Arc::new(Mutex::new(channel)) }
+    }
 
-                if verbose.load(Ordering::Acquire) {
-                    headers.push(hpack::TraceBit(true).into_raw());
-                }
+    /// Returns `true` if the application takes part in flow control.
+    fn is_flow_controlled(&self) -> bool {
+        self.channel.lock().unwrap().flow.is_some()
+    }
 
-                if let Some(timeout) = settings.timeout {
-                    headers.push(hpack::RawHeader::new(&b"request_timeout"[..], pack_u64((timeout * 1000.0) as u64)));
-                }
+    /// Attaches the sender of the sent invocation, returning `false` if the upstream has been
+    /// aborted before, in which case the application is told so at once.
+    pub(crate) fn attach(&self, tx: cocaine::Sender) -> bool {
+        let mut channel = self.channel.lock().unwrap();
+        match channel.state {
+            UpstreamState::Pending => {
+                channel.state = UpstreamState::Open(tx);
+                true
+            }
+            UpstreamState::Open(..) | UpstreamState::Draining(..) | UpstreamState::Finished => {
+                tx.send(cocaine::Request::new(1, &(RESPONSE_ABORTED, "request abandoned by proxy")).unwrap());
+                false
+            }
+        }
+    }
 
-                let req = cocaine::Request::new(0, &[request.event.clone()]).unwrap()
-                    .add_headers(headers);
+    /// Sends the frame, returning `false` if the request is already finished.
+    pub(crate) fn send(&self, req: cocaine::Request) -> bool {
+        match self.channel.lock().unwrap().state {
+            UpstreamState::Open(ref tx) => {
+                tx.send(req);
+                true
+            }
+            UpstreamState::Pending | UpstreamState::Draining(..) | UpstreamState::Finished => false,
+        }
+    }
 
-                let future = service.call(req, AppReadDispatch {
-                    tx: tx,
-                    method: request.frame.method.clone(),
-                    body: None,
-                    trace: request.trace,
-                    response: Some(Response::new()),
-                }).and_then(move |tx| {
-                    let buf = serialize::to_vec(&request.frame).unwrap();
-                    tx.send(cocaine::Request::new(0, &[unsafe { ::std::str::from_utf8_unchecked(&buf) }]).unwrap());
-                    tx.send(cocaine::Request::new(2, &[0; 0]).unwrap());
-                    Ok(())
-                }).then(|_| {
-                    // TODO: Consider if it is okay to always finish the future with OK. May be log?
-                    Ok(())
-                });
+    /// Sends the request body chunk of the given size, accounting it in the window and holding
+    /// its reservation until the application consumes it.
+    fn send_body(&self, req: cocaine::Request, len: usize, reservation: Option<MemoryReservation>) -> bool {
+        let mut channel = self.channel.lock().unwrap();
+        let channel = &mut *channel;
+        match channel.state {
+            UpstreamState::Open(ref tx) => tx.send(req),
+            UpstreamState::Pending | UpstreamState::Draining(..) | UpstreamState::Finished => return false,
+        }
 
-                // box future as Box<Future<Item = (), Error = ()> + Send>
-                Box::new(future)
-            }),
+        let sent = match channel.flow {
+            Some(ref mut flow) => {
+                flow.sent += len as u64;
+                flow.sent
+            }
+            None => 0,
         };
+        if let Some(reservation) = reservation {
+            channel.reserved.push_back((sent, reservation));
+        }
+        true
+    }
 
-        self.dispatcher.send(ev);
+    /// Checks whether the window has room for more request body bytes, otherwise the current task
+    /// is notified once the application acknowledges consumed ones.
+    fn poll_window(&self) -> Async<()> {
+        match self.channel.lock().unwrap().flow {
+            Some(ref mut flow) if flow.is_full() => {
+                flow.task = Some(task::current());
+                Async::NotReady
+            }
+            Some(..) | None => Async::Ready(()),
+        }
+    }
 
-        let future = rx.map_err(|futures::Canceled| Error::Canceled);
-        Box::new(future)
+    /// Accounts the total number of request body bytes the application has consumed so far.
+    fn ack(&self, total: u64) {
+        let mut channel = self.channel.lock().unwrap();
+        if let Some(ref mut flow) = channel.flow {
+            flow.ack(total);
+            if let Some(task) = flow.task.take() {
+                task.notify();
+            }
+        }
+        channel.release();
     }
-}
 
-impl Future for AppWithSafeRetry {
-    type Item = (Response, u64);
-    type Error = Error;
+    /// Marks the application started responding, after which the rest of the request body may be
+    /// dropped.
+    fn on_response(&self) {
+        if let Some(ref mut flow) = self.channel.lock().unwrap().flow {
+            flow.responding = true;
+        }
+    }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut future = self.current.take().unwrap();
+    /// Returns `true` if the application taking part in flow control has started responding.
+    fn is_responding(&self) -> bool {
+        self.channel.lock().unwrap().flow.as_ref().map(|flow| flow.responding).unwrap_or(false)
+    }
 
-        match future.poll() {
-            Ok(Async::Ready(Some((res, bytes)))) => return Ok(Async::Ready((res, bytes))),
-            Ok(Async::Ready(None)) => {
-                if self.attempts < self.limit {
-                    self.current = Some(self.make_future());
-                    self.attempts += 1;
-                    return self.poll();
-                } else {
-                    let body = "Retry limit exceeded: queue is full";
-                    let bytes = body.len() as u64;
-                    let resp = Response::new()
-                        .with_status(StatusCode::InternalServerError)
-                        .with_header(XRequestId(self.request.trace))
-                        .with_body(body);
-                    return Ok(Async::Ready((resp, bytes)));
+    /// Acknowledges the total number of response body bytes drained by the client.
+    fn credit(&self, total: u64) {
+        let mut channel = self.channel.lock().unwrap();
+        let channel = &mut *channel;
+        if let Some(ref mut flow) = channel.flow {
+            flow.drained = total;
+            if let UpstreamState::Draining(ref tx) = channel.state {
+                if let Some(total) = flow.take_credit() {
+                    tx.send(make_ack(total));
                 }
             }
-            Ok(Async::NotReady) => {}
-            Err(err) => {
-                return Err(err);
+        }
+    }
+
+    /// Finishes the request body, either with the `close` frame or, with flow control, with an
+    /// empty chunk, keeping the channel open until the response is finished.
+    fn end_body(&self) {
+        let mut channel = self.channel.lock().unwrap();
+        let channel = &mut *channel;
+        let flow = match channel.flow {
+            Some(ref mut flow) if !flow.responded => flow,
+            Some(..) | None => {
+                close(&mut channel.state);
+                return channel.release();
+            }
+        };
+
+        match mem::replace(&mut channel.state, UpstreamState::Finished) {
+            UpstreamState::Open(tx) => {
+                tx.send(make_chunk(&[]));
+                // Bytes drained while the request body was being sent are acknowledged at once.
+                if flow.drained > flow.credited {
+                    tx.send(make_ack(flow.drained));
+                    flow.credited = flow.drained;
+                }
+                channel.state = UpstreamState::Draining(tx);
             }
+            state => channel.state = state,
         }
+    }
 
-        self.current = Some(future);
-        Ok(Async::NotReady)
+    /// Marks the response finished, closing the channel kept open for acknowledgements.
+    fn finish(&self) {
+        let mut channel = self.channel.lock().unwrap();
+        let channel = &mut *channel;
+        if let Some(ref mut flow) = channel.flow {
+            flow.responded = true;
+            if let Some(task) = flow.task.take() {
+                task.notify();
+            }
+            if let UpstreamState::Draining(..) = channel.state {
+                close(&mut channel.state);
+            }
+        }
+        channel.release();
+    }
+
+    /// Finishes the upstream with an error frame, unless it is already finished, returning
+    /// whether the frame has been sent.
+    fn abort(&self, code: (u64, u64), reason: &str) -> bool {
+        let mut channel = self.channel.lock().unwrap();
+        let sent = match mem::replace(&mut channel.state, UpstreamState::Finished) {
+            UpstreamState::Open(tx) | UpstreamState::Draining(tx) => {
+                tx.send(cocaine::Request::new(1, &(code, reason)).unwrap());
+                true
+            }
+            UpstreamState::Pending | UpstreamState::Finished => false,
+        };
+        channel.release();
+        sent
     }
 }
 
-#[derive(Debug)]
-enum Error {
-    /// Either none or both `X-Cocaine-Service` and `X-Cocaine-Event` headers
-    /// must be specified.
-    IncompleteHeadersMatch,
-    /// Failed to parse special tracing header, by default `X-Request-Id`.
-    InvalidRequestIdHeader(Cow<'static, str>),
-//    RetryLimitExceeded(u32),
-//    Service(cocaine::Error),
-    InvalidBodyRead(hyper::Error),
-    Canceled,
+fn close(state: &mut UpstreamState) {
+    match mem::replace(state, UpstreamState::Finished) {
+        UpstreamState::Open(tx) | UpstreamState::Draining(tx) => {
+            tx.send(cocaine::Request::new(2, &[0; 0]).unwrap());
+        }
+        UpstreamState::Pending | UpstreamState::Finished => {}
+    }
 }
 
-impl Error {
-    fn code(&self) -> StatusCode {
-        match *self {
-            Error::IncompleteHeadersMatch |
-            Error::InvalidRequestIdHeader(..) => StatusCode::BadRequest,
-            Error::InvalidBodyRead(..) |
-            Error::Canceled => StatusCode::InternalServerError,
+/// Wraps the total number of acknowledged response body bytes into a chunk frame.
+fn make_ack(total: u64) -> cocaine::Request {
+    make_chunk(total.to_string().as_bytes())
+}
+
+/// Parses the total number of acknowledged request body bytes from informational frame headers.
+fn parse_ack(headers: &[(String, String)]) -> Option<u64> {
+    headers.iter()
+        .find(|&&(ref name, ..)| name.eq_ignore_ascii_case(ACK_HEADER))
+        .and_then(|&(.., ref value)| value.trim().parse().ok())
+}
+
+/// Sends the buffered request body into the upstream in pieces of the given size, each once the
+/// window has room, finishing the body afterwards.
+///
+/// Without flow control the window never fills, so the whole body is sent at once.
+struct SendBody {
+    upstream: Upstream,
+    request: Arc<AppRequest>,
+    piece: usize,
+    offset: usize,
+}
+
+impl SendBody {
+    fn new(upstream: Upstream, request: Arc<AppRequest>) -> Self {
+        let streaming = &request.settings.streaming;
+        let piece = if streaming.flow_control() {
+            streaming.window()
+        } else {
+            request.frame.body.len()
+        };
+
+        Self {
+            upstream: upstream,
+            request: request,
+            piece: piece,
+            offset: 0,
         }
     }
 }
 
-impl Display for Error {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            Error::IncompleteHeadersMatch => fmt.write_str(error::Error::description(self)),
-            Error::InvalidRequestIdHeader(ref name) => {
-                write!(fmt, "Invalid `{}` header value", name)
+impl Future for SendBody {
+    type Item = ();
+    type Error = cocaine::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let body = &self.request.frame.body;
+        while self.offset < body.len() {
+            if let Async::NotReady = self.upstream.poll_window() {
+                return Ok(Async::NotReady);
             }
-            Error::InvalidBodyRead(ref err) => write!(fmt, "{}", err),
-            Error::Canceled => fmt.write_str("canceled"),
+
+            let end = cmp::min(self.offset + self.piece, body.len());
+            if !self.upstream.send_body(codec::make_chunk(self.request.settings.codec, &body[self.offset..end]), end - self.offset) {
+                return Ok(Async::Ready(()));
+            }
+            self.offset = end;
         }
+
+        self.upstream.end_body();
+        Ok(Async::Ready(()))
     }
 }
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::IncompleteHeadersMatch => {
-                "either none or both `X-Cocaine-Service` and `X-Cocaine-Event` headers must be specified"
-            }
-            Error::InvalidRequestIdHeader(..) => "invalid tracing header value",
-            Error::InvalidBodyRead(..) => "failed to read HTTP body",
-            Error::Canceled => "canceled",
+fn body_error(err: hyper::Error) -> Error {
+    match err {
+        hyper::Error::Incomplete | hyper::Error::Closed | hyper::Error::Io(..) => Error::ClientAborted,
+        err => Error::InvalidBodyRead(err),
+    }
+}
+
+/// Fails the request body stream as soon as it exceeds the limit, so the rest of it is never read.
+fn limit_body(body: Body, limit: Option<usize>) -> Box<dyn Stream<Item = Chunk, Error = Error>> {
+    let body = body.map_err(body_error);
+
+    match limit {
+        Some(limit) => {
+            let mut size = 0;
+            let body = body.and_then(move |chunk| {
+                size += chunk.len();
+                if size > limit {
+                    Err(Error::PayloadTooLarge(limit))
+                } else {
+                    Ok(chunk)
+                }
+            });
+            Box::new(body)
         }
+        None => Box::new(body),
     }
 }
 
-struct AppReadDispatch {
-    tx: oneshot::Sender<Option<(Response, u64)>>,
-    method: Method,
-    body: Option<Vec<u8>>,
-    trace: u64,
-    response: Option<Response>,
+/// Sends streamed request body chunks into the upstream, finishing it once the body is complete.
+///
+/// If the client goes away in the middle, the upstream is finished with an error instead, letting
+/// the application know that the body is truncated. Sending stops as soon as the upstream is
+/// aborted by the dispatch. With flow control the body is read only while the window has room,
+/// so a slow application holds the client back instead of piling chunks up in the proxy.
+pub(crate) fn send_stream(upstream: Upstream, stream: BodyReceiver, codec: BodyCodec) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let future = SendStream {
+        upstream: upstream,
+        stream: stream,
+        codec: codec,
+    };
+
+    Box::new(future)
 }
 
-impl Dispatch for AppReadDispatch {
-    fn process(mut self: Box<Self>, response: &cocaine::Response) -> Option<Box<dyn Dispatch>> {
-        match response.deserialize::<protocol::Streaming<rmps::RawRef>>().flatten() {
-            // TODO: Support chunked transfer encoding.
-            Ok(Some(data)) => {
-                if self.body.is_none() {
-                    let meta: ResponseMeta = match rmps::from_slice(data.as_bytes()) {
-                        Ok(meta) => meta,
-                        Err(err) => {
-                            let err = err.to_string();
-                            let body_size = err.len();
-                            let resp = Response::new()
-                                .with_status(StatusCode::InternalServerError)
-                                .with_header(XRequestId(self.trace))
-                                .with_body(err);
-                            drop(self.tx.send(Some((resp, body_size as u64))));
-                            return None
-                        }
-                    };
+struct SendStream {
+    upstream: Upstream,
+    stream: BodyReceiver,
+    codec: BodyCodec,
+}
 
-                    let status = StatusCode::try_from(meta.code as u16)
-                        .unwrap_or(StatusCode::InternalServerError);
+impl Future for SendStream {
+    type Item = ();
+    type Error = ();
 
-                    let mut resp = self.response.take().unwrap();
-                    resp.set_status(status);
-                    resp.headers_mut().set(XRequestId(self.trace));
-                    for (name, value) in meta.headers {
-                        // TODO: Filter headers - https://tools.ietf.org/html/draft-ietf-httpbis-p1-messaging-14#section-7.1.3
-                        resp.headers_mut().set_raw(name, value);
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Async::NotReady = self.upstream.poll_window() {
+                return Ok(Async::NotReady);
+            }
+
+            let chunk = match self.stream.poll()? {
+                Async::Ready(chunk) => chunk,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            match chunk {
+                // Empty chunks carry nothing, while with flow control they finish the body.
+                Some((Some(ref chunk), ..)) if chunk.is_empty() => {}
+                Some((Some(chunk), reservation)) => {
+                    if !self.upstream.send_body(codec::make_chunk(self.codec, &chunk), chunk.len(), reservation) {
+                        return Err(());
                     }
-                    self.response = Some(resp);
-                    self.body = Some(Vec::with_capacity(64));
-                } else {
-                    // TODO: If TE: chunked - feed parser. Consume chunks until None and send.
-                    // TODO: Otherwise - just send.
-                    self.body.as_mut().unwrap().extend(data.as_bytes());
                 }
-                Some(self)
+                Some((None, ..)) => {
+                    self.upstream.end_body();
+                    return Ok(Async::Ready(()));
+                }
+                None => {
+                    // The rest of the body is dropped once the application has responded, but
+                    // with flow control the channel is still needed for acknowledgements.
+                    if self.upstream.is_responding() {
+                        self.upstream.end_body();
+                    } else {
+                        self.upstream.abort(BODY_ABORTED, "client closed request");
+                    }
+                    return Ok(Async::Ready(()));
+                }
             }
-            Ok(None) => {
-                let (resp, size) = match self.body.take() {
-                    Some(body) => {
-                        use hyper::header::ContentLength;
-                        let mut resp = self.response.take().unwrap();
+        }
+    }
+}
 
-                        // Special handling for responses with no body.
-                        // See https://www.w3.org/Protocols/rfc2616/rfc2616-sec10.html for more.
-                        let size = if self.method == Method::Head {
-                            // We shouldn't remove Content-Length header here, because according to
-                            // RFC: the Content-Length entity-header field indicates the size of the
-                            // entity-body, in decimal number of OCTETs, sent to the recipient or,
-                            // in the case of the HEAD method, the size of the entity-body that
-                            // would have been sent had the request been a GET.
-                            0
-                        } else {
-                            match resp.status() {
-                                StatusCode::NoContent |
-                                StatusCode::NotModified => {
-                                    0
-                                }
-                                _ => {
-                                    let mut has_body = !body.is_empty();
-                                    has_body = has_body & resp.headers().get::<ContentLength>().map(|head| {
-                                        match *head {
-                                            ContentLength(len) => return len > 0,
-                                        }
-                                    }).unwrap_or(true);
+#[inline]
+fn serialize_method<S>(method: &Method, se: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    se.serialize_str(&method.to_string())
+}
 
-                                    if has_body {
-                                        let size = body.len();
-                                        resp.set_body(body);
-                                        size
-                                    } else {
-                                        0
-                                    }
-                                }
-                            }
-                        };
+#[inline]
+fn serialize_version<S>(version: &HttpVersion, se: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    let v = match *version {
+        HttpVersion::Http09 => "0.9",
+        HttpVersion::Http10 => "1.0",
+        HttpVersion::Http11 => "1.1",
+        // Unknown versions are reported as the most common one.
+        _ => "1.1",
+    };
 
-                        (resp, size)
-                    }
-                    None => {
-                        let err = "received `close` event without prior meta info";
-                        let size = err.len();
-                        let resp = Response::new()
-                            .with_status(StatusCode::InternalServerError)
-                            .with_header(XRequestId(self.trace))
-                            .with_body(err);
+    se.serialize_str(v)
+}
 
-                        (resp, size)
-                    }
-                };
+#[inline]
+fn serialize_body<S>(body: &[u8], se: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    se.serialize_str(unsafe { str::from_utf8_unchecked(body) })
+}
 
-                drop(self.tx.send(Some((resp, size as u64))));
-                None
-            }
-            // TODO: Make names for category and code.
-            Err(cocaine::Error::Service(ref err)) if err.category() == 0x52ff && err.code() == 1 => {
-                drop(self.tx.send(None));
-                None
-            }
-            Err(err) => {
-                let body = err.to_string();
-                let body_len = body.len() as u64;
+#[derive(Debug, Deserialize)]
+struct ResponseMeta {
+    code: u32,
+    headers: Vec<(String, String)>
+}
 
-                let mut resp = Response::new()
-                    .with_status(StatusCode::InternalServerError)
-                    .with_header(XRequestId(self.trace))
-                    .with_body(body);
+/// A status frame of HTTP response for cocaine application HTTP protocol v2, which is followed by
+/// a separate headers frame.
+#[derive(Debug, Deserialize)]
+struct ResponseStatus {
+    code: u32,
+}
 
-                if let cocaine::Error::Service(ref err) = err {
-                    if err.category() == 0x54ff {
-                        resp.headers_mut().set(XErrorGeneratedBy("vicodyn".to_string()));
-                    }
-                }
+#[derive(Clone)]
+struct AppRequest {
+    service: String,
+    event: String,
+    trace: u64,
+    /// Absolute client deadline in milliseconds since UNIX epoch.
+    deadline: Option<u64>,
+    /// Whether the deadline has been shrunk by the client, in which case the time left is passed
+    /// to workers as the request timeout.
+    client_deadline: bool,
+    settings: Arc<ServiceSettings>,
+    stalls: Option<Arc<StallMetrics>>,
+    /// Delay after which a hedged attempt races the first one.
+    hedge_delay: Option<Duration>,
+    /// Time each attempt may wait in a pool queue.
+    queue_budget: Option<Duration>,
+    /// Maximum request body size in bytes.
+    body_limit: Option<usize>,
+    /// Index and name of the priority class.
+    priority: Option<(usize, String)>,
+    digest: Option<DigestConfig>,
+    headers_limit: ResponseHeadersConfig,
+    /// Configured retry safety of the event, overriding the error-based one.
+    retry: Option<RetrySafety>,
+    retriable: Arc<Vec<RetriableError>>,
+    statuses: Arc<ErrorStatuses>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    origins: Arc<HashMap<u64, String>>,
+    /// Shared between all attempts.
+    timer: Arc<RequestTimer>,
+    /// Request body chunks in streaming mode, taken by the only attempt.
+    stream: Arc<Mutex<Option<BodyReceiver>>>,
+    frame: RequestMeta,
+}
 
-                drop(self.tx.send(Some((resp, body_len))));
-                None
+impl AppRequest {
+    fn new(service: String, event: String, trace: u64, req: &Request, uri: String, settings: Arc<ServiceSettings>) -> Self {
+        let frame = RequestMeta::new(req, uri);
+
+        Self {
+            service: service,
+            event: event,
+            trace: trace,
+            deadline: None,
+            client_deadline: false,
+            settings: settings,
+            stalls: None,
+            hedge_delay: None,
+            queue_budget: None,
+            body_limit: None,
+            priority: None,
+            digest: None,
+            headers_limit: ResponseHeadersConfig::default(),
+            retry: None,
+            retriable: Arc::new(Vec::new()),
+            statuses: Arc::new(ErrorStatuses::default()),
+            retry_budget: None,
+            origins: Arc::new(HashMap::new()),
+            timer: Arc::new(RequestTimer::new()),
+            stream: Arc::new(Mutex::new(None)),
+            frame: frame,
+        }
+    }
+
+    fn set_body(&mut self, body: Vec<u8>) {
+        self.frame.body = body;
+    }
+
+    fn set_deadline(&mut self, deadline: SystemTime) {
+        self.deadline = Some(random::epoch_millis(deadline));
+    }
+}
+
+/// A future that retries application invocation on receiving "safe" error,
+/// meaning that it is safe to retry it again until the specified limit reached.
+///
+/// In this context "safety" means, that the request is guaranteed not to be
+/// delivered to the worker, for example when the queue is full.
+struct AppWithSafeRetry<L> {
+    attempts: u32,
+    limit: u32,
+    request: Arc<AppRequest>,
+    dispatcher: EventDispatch,
+    headers: Vec<hpack::RawHeader>,
+    /// Span id of the last attempt, which becomes the parent for the next one. Initially equals
+    /// to the trace id, meaning that the first attempt is a child of the root span.
+    span: u64,
+    current: Option<Box<dyn Future<Item=Option<(Response, u64)>, Error=Error> + Send>>,
+    /// Fires when the current attempt runs out of time to start responding.
+    watchdog: Option<oneshot::Receiver<()>>,
+    /// Whether the current attempt has received any response frame.
+    answered: Arc<AtomicBool>,
+    /// Hedged attempt racing the current one.
+    hedge: Option<Box<dyn Future<Item=Option<(Response, u64)>, Error=Error> + Send>>,
+    /// Fires when the hedged attempt is due.
+    hedge_timer: Option<oneshot::Receiver<()>>,
+    /// Delays before each next attempt.
+    backoff: ExponentialBackoff,
+    /// Moment the first attempt was made, used to measure the Cocaine call latency.
+    birth: Instant,
+    metrics: Arc<Metrics>,
+    verbose: Arc<AtomicBool>,
+    tracing_policy: TracingPolicy,
+    log: L,
+}
+
+impl<L: Log> AppWithSafeRetry<L> {
+    fn new(request: AppRequest, headers: Vec<hpack::RawHeader>, dispatcher: EventDispatch, limit: u32,
+        backoff: ExponentialBackoff, metrics: Arc<Metrics>, tracing_policy: TracingPolicy, log: L) -> Self
+    {
+        let headers = Self::make_headers(headers, request.trace);
+        let span = request.trace;
+
+        let mut res = Self {
+            attempts: 1,
+            limit: limit,
+            request: Arc::new(request),
+            dispatcher: dispatcher,
+            headers: headers,
+            span: span,
+            current: None,
+            watchdog: None,
+            answered: Arc::new(AtomicBool::new(false)),
+            hedge: None,
+            hedge_timer: None,
+            backoff: backoff,
+            birth: Instant::now(),
+            metrics: metrics,
+            verbose: Arc::new(AtomicBool::new(false)),
+            tracing_policy: tracing_policy,
+            log: log,
+        };
+
+        res.current = Some(res.make_future(None));
+
+        if let Some(delay) = res.request.hedge_delay.filter(|_| res.limit > 1) {
+            let (tx, rx) = oneshot::channel();
+            res.dispatcher.send(Event::Timer(delay, tx));
+            res.hedge_timer = Some(rx);
+        }
+
+        res
+    }
+
+    /// Returns `true` once the hedged attempt is due, unless the current one has already started
+    /// responding.
+    fn is_hedge_due(&mut self) -> bool {
+        match self.hedge_timer.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => {
+                self.hedge_timer = None;
+                self.hedge.is_none() && self.attempts < self.limit && !self.answered.load(Ordering::Acquire)
+            }
+            Some(Ok(Async::NotReady)) | None => false,
+            // The timer can't be armed, so the request is not hedged.
+            Some(Err(futures::Canceled)) => {
+                self.hedge_timer = None;
+                false
             }
         }
     }
 
-    fn discard(self: Box<Self>, err: &cocaine::Error) {
-        let body = err.to_string();
-        let body_len = body.as_bytes().len() as u64;
+    /// Issues the hedged attempt, which races the current one.
+    ///
+    /// The response timeout keeps watching the current attempt. The hedged attempt is enqueued
+    /// like any other, so it is not kept off the connection of the current one, and the worker is
+    /// picked by the runtime, which may hand both attempts to the same slow worker.
+    fn launch_hedge(&mut self) {
+        cocaine_log!(self.log, Severity::Info, "hedging request: no response within {:?}, attempt {}/{}",
+            self.request.hedge_delay.unwrap_or_default(), self.attempts, self.limit; {
+            service: self.request.service,
+            event: self.request.event,
+            trace: self.request.trace,
+            trace_id: format!("{:016x}", self.request.trace),
+            span_id: format!("{:016x}", self.span),
+        });
+
+        let watchdog = self.watchdog.take();
+        let answered = self.answered.clone();
+        self.attempts += 1;
+        self.hedge = Some(self.make_future(None));
+        self.watchdog = watchdog;
+        self.answered = answered;
+        self.metrics.mark_hedged();
+    }
 
-        let status = if let cocaine::Error::Service(ref err) = *err {
-            if err.category() == 10 && err.code() == 1 {
-                StatusCode::ServiceUnavailable
-            } else {
-                StatusCode::InternalServerError
+    /// Replaces the failed current attempt with the hedged one, if it is in flight.
+    fn fall_back_to_hedge(&mut self) -> bool {
+        match self.hedge.take() {
+            Some(hedge) => {
+                // The hedged attempt is limited only by the client timeout.
+                self.watchdog = None;
+                self.current = Some(hedge);
+                true
             }
+            None => false,
+        }
+    }
+
+    fn make_headers(headers: Vec<hpack::RawHeader>, trace: u64) -> Vec<hpack::RawHeader> {
+        let mut headers = if headers.is_empty() {
+            Vec::with_capacity(4)
         } else {
-            StatusCode::InternalServerError
+            headers
         };
 
-        let resp = Response::new()
-            .with_status(status)
-            .with_header(XRequestId(self.trace))
-            .with_body(body);
-        drop(self.tx.send(Some((resp, body_len))));
+        headers.push(hpack::TraceId(trace).into_raw());
+
+        headers
     }
-}
 
-#[cfg(test)]
-mod test {
-    use hyper::HttpVersion;
-    use serde_json::Serializer;
+    /// Generates a fresh span for the next attempt, making it a child of the previous one, so
+    /// retries are visible as separate spans in the trace.
+    fn next_span(&mut self) -> (u64, u64) {
+        let parent = self.span;
+        self.span = random::gen::<u64>();
 
-    use super::serialize_version;
+        (self.span, parent)
+    }
 
-    #[test]
-    fn test_serialize_version() {
-        let mut se = Serializer::new(Vec::new());
-        serialize_version(&HttpVersion::Http10, &mut se).unwrap();
-        assert_eq!(&b"\"1.0\""[..], &se.into_inner()[..]);
+    /// Tries to spend the retry budget of the service, if any, on the next attempt.
+    fn spend_retry(&self) -> bool {
+        let budget = match self.request.retry_budget {
+            Some(ref budget) => budget,
+            None => return true,
+        };
 
-        let mut se = Serializer::new(Vec::new());
-        serialize_version(&HttpVersion::Http11, &mut se).unwrap();
-        assert_eq!(&b"\"1.1\""[..], &se.into_inner()[..]);
+        if budget.withdraw(&self.request.service) {
+            return true;
+        }
+
+        self.metrics.mark_retry_suppressed();
+        cocaine_log!(self.log, Severity::Warn, "retry suppressed: budget of `{}` service is exhausted", self.request.service; {
+            service: self.request.service,
+            event: self.request.event,
+            trace: self.request.trace,
+            trace_id: format!("{:016x}", self.request.trace),
+            span_id: format!("{:016x}", self.span),
+        });
+
+        false
     }
-}
 
-// TODO: Test HEAD responses with body.
-// TODO: Test NoContent responses with body.
-// TODO: Test NotModified responses with body.
-// TODO: Test invalid UTF8 headers in request/response.
+    /// Enqueues the next attempt into the services pool, optionally after the given delay.
+    fn make_future(&mut self, delay: Option<Duration>) -> Box<dyn Future<Item=Option<(Response, u64)>, Error=Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+
+        let (span, parent) = self.next_span();
+        let request = self.request.clone();
+        let metrics = self.metrics.clone();
+        let verbose = self.verbose.clone();
+        let answered = Arc::new(AtomicBool::new(false));
+        self.answered = answered.clone();
+        let sent = Arc::new(AtomicBool::new(false));
+        let streaming = self.request.settings.streaming;
+        let upstream = if streaming.flow_control() {
+            Upstream::with_window(streaming.window() as u64)
+        } else {
+            Upstream::new()
+        };
+        let attempt = self.attempts;
+        let mut headers = self.headers.clone();
+        headers.push(hpack::SpanId(span).into_raw());
+        headers.push(hpack::ParentId(parent).into_raw());
+        headers.push(attempt_header(attempt, self.limit));
+
+        let manual_verbose = match self.tracing_policy {
+            TracingPolicy::Auto => None,
+            TracingPolicy::Manual(v) => Some(random::gen::<f64>() <= v),
+        };
+
+        // The delay is not a part of the queue time.
+        let queued = request.timer.on_enqueue(span) + delay.unwrap_or_default();
+        let request_priority = request.priority.as_ref().map(|&(class, ..)| class);
+
+        let ev = Event::Service {
+            name: request.service.clone(),
+            func: Box::new(move |service: &Service, mut settings: Settings| {
+                let dequeued = request.timer.on_dequeue(queued);
+
+                // The attempt has been abandoned while being queued, either because the client
+                // has gone or because of the proxy timeout, so nobody waits for its response and
+                // there is no reason to occupy a worker with it.
+                if tx.is_canceled() {
+                    metrics.mark_canceled();
+                    return Box::new(future::ok(()));
+                }
+
+                if settings.shed {
+                    let class = request.priority.as_ref().map(|&(.., ref name)| name.clone()).unwrap_or_default();
+                    drop(tx.send(Err(Error::Shed(class))));
+                    return Box::new(future::ok(()));
+                }
+
+                // There is hardly any time left to respond, so the worker is better spent on
+                // requests that still have a chance.
+                if let Some(budget) = request.queue_budget {
+                    let wait = dequeued.duration_since(queued);
+                    if wait > budget {
+                        drop(tx.send(Err(Error::QueueBudgetExceeded(wait))));
+                        return Box::new(future::ok(()));
+                    }
+                }
+
+                let mut headers = headers.clone();
+                if let Some(true) = manual_verbose {
+                    settings.verbose = true;
+                }
+
+                if settings.verbose && attempt == 1 {
+                    verbose.store(true, Ordering::Release);
+                }
+
+                if verbose.load(Ordering::Acquire) {
+                    headers.push(hpack::TraceBit(true).into_raw());
+                }
+
+                let mut timeout = settings.timeout.map(|timeout| (timeout * 1000.0) as u64);
+                // The client budget left after waiting in the queue bounds the configured timeout.
+                if let Some(deadline) = request.deadline.filter(|_| request.client_deadline) {
+                    let left = deadline.saturating_sub(random::epoch_millis(random::now()));
+                    timeout = Some(timeout.map(|timeout| cmp::min(timeout, left)).unwrap_or(left));
+                }
+
+                if let Some(timeout) = timeout {
+                    headers.push(hpack::RawHeader::new(&b"request_timeout"[..], pack_u64(timeout)));
+                }
+
+                // Unlike the relative timeout above, the deadline stays the same across the whole
+                // invocation chain, allowing nested services to make consistent decisions.
+                if let Some(deadline) = request.deadline {
+                    headers.push(hpack::RawHeader::new(&b"request_deadline"[..], pack_u64(deadline)));
+                }
+
+                let req = cocaine::Request::new(0, &[request.event.clone()]).unwrap()
+                    .add_headers(headers);
+
+                // The protocol is negotiated while the service is being connected, so it is known
+                // before the application receives the request and starts responding.
+                let protocol = Arc::new(Mutex::new(AppProtocol::V1));
+                let negotiation = negotiate(request.settings.protocol, &request.service, settings.negotiator.take());
+
+                let (feed, stream, forward) = if streaming.response() {
+                    let (stream, forward) = ResponseStream::new(request.stalls.clone(), streaming.window(), upstream.clone());
+                    (None, Some(stream), Some(forward))
+                } else if let Some(slice) = request.settings.response_slice {
+                    let (feed, forward) = ResponseFeed::new(slice, request.stalls.clone());
+                    (Some(feed), None, Some(forward))
+                } else {
+                    (None, None, None)
+                };
+
+                let future = service.call(req, AppReadDispatch {
+                    tx: Some(tx),
+                    feed: feed,
+                    stream: stream,
+                    method: request.frame.method.clone(),
+                    body: None,
+                    trace: request.trace,
+                    response: Some(Response::new()),
+                    settings: request.settings.clone(),
+                    body_override: None,
+                    headers_limit: request.headers_limit,
+                    retry: request.retry,
+                    retriable: request.retriable.clone(),
+                    statuses: request.statuses.clone(),
+                    origins: request.origins.clone(),
+                    protocol: protocol.clone(),
+                    code: None,
+                    hints: request.settings.hints.clone(),
+                    digest: request.digest.and_then(|digest| digest.generate()),
+                    timer: request.timer.clone(),
+                    answered: answered.clone(),
+                    sent: sent.clone(),
+                    channel: settings.channel.take(),
+                    upstream: upstream.clone(),
+                    metrics: metrics.clone(),
+                }).join(negotiation).and_then(move |(tx, negotiated)| -> Box<dyn Future<Item = (), Error = cocaine::Error> + Send> {
+                    request.timer.on_send(dequeued);
+                    sent.store(true, Ordering::Release);
+                    *protocol.lock().unwrap() = negotiated;
+                    if !upstream.attach(tx) {
+                        return Box::new(future::ok(()));
+                    }
+
+                    let frame = &request.frame;
+                    match negotiated {
+                        AppProtocol::V1 => {
+                            upstream.send(make_chunk(&serialize::to_vec(&RequestMetaV1::new(frame, request.settings.codec)).unwrap()));
+                        }
+                        AppProtocol::V2 => {
+                            upstream.send(make_chunk(&serialize::to_vec(&RequestMetaV2::from(frame)).unwrap()));
+                            upstream.send(make_chunk(&serialize::to_vec(&frame.headers).unwrap()));
+                        }
+                    }
+
+                    // In streaming mode the body follows the meta frame chunk by chunk.
+                    if let Some(stream) = request.stream.lock().unwrap().take() {
+                        return Box::new(send_stream(upstream, stream, request.settings.codec).then(|_| Ok(())));
+                    }
+
+                    // So does the buffered one in the v2 protocol, while v1 carries it in the meta
+                    // frame.
+                    match negotiated {
+                        AppProtocol::V1 => {
+                            upstream.end_body();
+                            Box::new(future::ok(()))
+                        }
+                        AppProtocol::V2 => Box::new(SendBody::new(upstream, request)),
+                    }
+                }).then(|_| {
+                    // TODO: Consider if it is okay to always finish the future with OK. May be log?
+                    Ok(())
+                });
+
+                // box future as Box<Future<Item = (), Error = ()> + Send>
+                match forward {
+                    Some(forward) => Box::new(future.join(forward).map(|_| ())),
+                    None => Box::new(future),
+                }
+            }),
+        };
+
+        let ev = match request_priority {
+            Some(class) => Event::Prioritized(class, Box::new(ev)),
+            None => ev,
+        };
+
+        let ev = match delay {
+            Some(delay) => Event::Delayed(delay, Box::new(ev)),
+            None => ev,
+        };
+
+        self.dispatcher.send(ev);
+
+        self.watchdog = self.request.settings.response_timeout.map(|timeout| {
+            let (tx, rx) = oneshot::channel();
+            self.dispatcher.send(Event::Timer(delay.unwrap_or_default() + timeout, tx));
+            rx
+        });
+
+        let future = rx.map_err(|futures::Canceled| Error::Canceled).and_then(future::result);
+        Box::new(future)
+    }
+}
+
+impl<L: Log> Future for AppWithSafeRetry<L> {
+    type Item = (Response, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.is_hedge_due() {
+            self.launch_hedge();
+        }
+
+        if let Some(mut hedge) = self.hedge.take() {
+            match hedge.poll() {
+                Ok(Async::Ready(Some((res, bytes)))) => {
+                    self.metrics.mark_hedge_won();
+                    self.metrics.observe_upstream(self.birth.elapsed());
+                    // Dropping the current attempt detaches it, so its late response is discarded.
+                    return Ok(Async::Ready((res, bytes)));
+                }
+                Ok(Async::NotReady) => self.hedge = Some(hedge),
+                // The current attempt decides the outcome alone from now on.
+                Ok(Async::Ready(None)) | Err(..) => {}
+            }
+        }
+
+        let mut future = self.current.take().unwrap();
+
+        match future.poll() {
+            Ok(Async::Ready(Some((res, bytes)))) => {
+                self.metrics.observe_upstream(self.birth.elapsed());
+                return Ok(Async::Ready((res, bytes)));
+            }
+            Ok(Async::Ready(None)) if self.fall_back_to_hedge() => {
+                return self.poll();
+            }
+            Ok(Async::Ready(None)) => {
+                // Retriable attempts are always classified by the dispatch.
+                let reason = self.request.timer.failure().map(|failure| failure.to_string()).unwrap_or_default();
+
+                if self.attempts < self.limit && self.spend_retry() {
+                    let delay = self.backoff.next();
+                    cocaine_log!(self.log, Severity::Info, "retrying request in {:?}: {}, attempt {}/{}",
+                        delay.unwrap_or_default(), reason, self.attempts, self.limit; {
+                        service: self.request.service,
+                        event: self.request.event,
+                        trace: self.request.trace,
+                        trace_id: format!("{:016x}", self.request.trace),
+                        span_id: format!("{:016x}", self.span),
+                    });
+
+                    self.attempts += 1;
+                    self.current = Some(self.make_future(delay));
+                    return self.poll();
+                } else {
+                    cocaine_log!(self.log, Severity::Warn, "retry limit exceeded: {}", reason; {
+                        service: self.request.service,
+                        event: self.request.event,
+                        trace: self.request.trace,
+                        trace_id: format!("{:016x}", self.request.trace),
+                        span_id: format!("{:016x}", self.span),
+                    });
+
+                    let body = format!("Retry limit exceeded: {}", reason);
+                    let bytes = body.len() as u64;
+                    let resp = Response::new()
+                        .with_status(StatusCode::InternalServerError)
+                        .with_header(XRequestId(self.request.trace))
+                        .with_header(XErrorGeneratedBy::proxy())
+                        .with_body(body);
+                    return Ok(Async::Ready((resp, bytes)));
+                }
+            }
+            Ok(Async::NotReady) => {}
+            Err(..) if self.fall_back_to_hedge() => {
+                return self.poll();
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+
+        let expired = match self.watchdog.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => !self.answered.load(Ordering::Acquire),
+            Some(Ok(Async::NotReady)) | None => false,
+            // The timer can't be armed, so the attempt is limited only by the client timeout.
+            Some(Err(futures::Canceled)) => {
+                self.watchdog = None;
+                false
+            }
+        };
+
+        if expired && self.fall_back_to_hedge() {
+            return self.poll();
+        }
+
+        if expired {
+            self.watchdog = None;
+            let timeout = self.request.settings.response_timeout.unwrap_or_default();
+
+            // The request has been delivered to the worker, so it may be repeated only if the
+            // event is explicitly marked as safe.
+            if self.attempts < self.limit && self.request.retry == Some(RetrySafety::Safe) && self.spend_retry() {
+                let delay = self.backoff.next();
+                cocaine_log!(self.log, Severity::Info, "retrying request in {:?}: no response within {:?}, attempt {}/{}",
+                    delay.unwrap_or_default(), timeout, self.attempts, self.limit; {
+                    service: self.request.service,
+                    event: self.request.event,
+                    trace: self.request.trace,
+                    trace_id: format!("{:016x}", self.request.trace),
+                    span_id: format!("{:016x}", self.span),
+                });
+
+                // Dropping the future detaches the stale attempt, so its late response is discarded.
+                self.attempts += 1;
+                self.current = Some(self.make_future(delay));
+                return self.poll();
+            }
+
+            self.request.timer.on_failure(Failure::Timeout);
+            return Err(Error::ResponseTimeout(timeout));
+        }
+
+        self.current = Some(future);
+        Ok(Async::NotReady)
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Either none or both `X-Cocaine-Service` and `X-Cocaine-Event` headers
+    /// must be specified.
+    IncompleteHeadersMatch,
+    /// Failed to parse special tracing header, by default `X-Request-Id`.
+    InvalidRequestIdHeader(Cow<'static, str>),
+    /// The request path is rejected by the normalization policy.
+    InvalidPath(&'static str),
+//    RetryLimitExceeded(u32),
+//    Service(cocaine::Error),
+    InvalidBodyRead(hyper::Error),
+    /// Tenant's request-rate or concurrency quota is exceeded.
+    QuotaExceeded(String),
+    /// The client went away before the response was ready.
+    ClientAborted,
+    /// Request body exceeds the configured limit in bytes.
+    PayloadTooLarge(usize),
+    /// Request headers exceed the configured count or total size limit.
+    RequestHeadersTooLarge(String),
+    /// Upstream response body exceeds the configured limit in bytes.
+    ResponseTooLarge(usize),
+    /// Upstream response headers exceed the configured count or total size limit.
+    ResponseHeadersTooLarge(String),
+    /// The application has violated the response protocol in the described way.
+    ProtocolViolation(String),
+    /// The request has already passed through this proxy instance.
+    LoopDetected,
+    /// The circuit breaker of the service is open.
+    CircuitOpen(String),
+    /// The application hasn't started responding within the configured time.
+    ResponseTimeout(Duration),
+    /// The request body doesn't match the digest in the given header.
+    DigestMismatch(&'static str),
+    /// The local upstream has failed to respond.
+    LocalUpstream(String),
+    /// The peer proxy, into which the request was forwarded, has failed to respond.
+    Peer(String),
+    /// The request has waited in a pool queue for too long to be worth dispatching.
+    QueueBudgetExceeded(Duration),
+    /// The request of the given priority class was shed because of the pool backlog.
+    Shed(String),
+    /// Failed to parse the given client deadline header.
+    InvalidDeadlineHeader(String),
+    /// The client deadline has passed.
+    DeadlineExceeded,
+    Canceled,
+}
+
+impl Error {
+    fn code(&self) -> StatusCode {
+        match *self {
+            Error::IncompleteHeadersMatch |
+            Error::InvalidRequestIdHeader(..) |
+            Error::InvalidPath(..) |
+            Error::InvalidDeadlineHeader(..) |
+            Error::DigestMismatch(..) => StatusCode::BadRequest,
+            Error::QuotaExceeded(..) => StatusCode::TooManyRequests,
+            Error::PayloadTooLarge(..) => StatusCode::PayloadTooLarge,
+            Error::RequestHeadersTooLarge(..) => StatusCode::RequestHeaderFieldsTooLarge,
+            Error::ClientAborted => CLIENT_CLOSED_REQUEST,
+            Error::ResponseTooLarge(..) |
+            Error::ResponseHeadersTooLarge(..) |
+            Error::ProtocolViolation(..) |
+            Error::LocalUpstream(..) |
+            Error::Peer(..) => StatusCode::BadGateway,
+            Error::CircuitOpen(..) |
+            Error::QueueBudgetExceeded(..) |
+            Error::Shed(..) => StatusCode::ServiceUnavailable,
+            Error::ResponseTimeout(..) |
+            Error::DeadlineExceeded => StatusCode::GatewayTimeout,
+            Error::LoopDetected => StatusCode::LoopDetected,
+            Error::InvalidBodyRead(..) |
+            Error::Canceled => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::IncompleteHeadersMatch => fmt.write_str(error::Error::description(self)),
+            Error::InvalidRequestIdHeader(ref name) => {
+                write!(fmt, "Invalid `{}` header value", name)
+            }
+            Error::InvalidPath(reason) => write!(fmt, "Invalid request path: {}", reason),
+            Error::InvalidBodyRead(ref err) => write!(fmt, "{}", err),
+            Error::QuotaExceeded(ref tenant) => write!(fmt, "Quota exceeded for `{}` tenant", tenant),
+            Error::ClientAborted => fmt.write_str(error::Error::description(self)),
+            Error::PayloadTooLarge(limit) => write!(fmt, "Request body exceeds {} bytes limit", limit),
+            Error::RequestHeadersTooLarge(ref reason) => write!(fmt, "Request headers exceed {}", reason),
+            Error::ResponseTooLarge(limit) => {
+                write!(fmt, "Response body from the application exceeds {} bytes limit", limit)
+            }
+            Error::ResponseHeadersTooLarge(ref reason) => {
+                write!(fmt, "Response headers from the application exceed {}", reason)
+            }
+            Error::ProtocolViolation(ref reason) => write!(fmt, "Application protocol violation: {}", reason),
+            Error::LoopDetected => fmt.write_str(error::Error::description(self)),
+            Error::CircuitOpen(ref service) => {
+                write!(fmt, "Service `{}` is temporarily unavailable due to high error rate", service)
+            }
+            Error::DigestMismatch(header) => write!(fmt, "Request body doesn't match `{}` header", header),
+            Error::ResponseTimeout(timeout) => {
+                write!(fmt, "Application hasn't started responding within {} ms", timeout.as_millis())
+            }
+            Error::LocalUpstream(ref err) => write!(fmt, "Local upstream has failed to respond: {}", err),
+            Error::Peer(ref err) => write!(fmt, "Peer proxy has failed to respond: {}", err),
+            Error::QueueBudgetExceeded(wait) => {
+                write!(fmt, "Request has waited in queue for {} ms, leaving no time to respond", wait.as_millis())
+            }
+            Error::Shed(ref class) => write!(fmt, "Request of `{}` priority class is shed due to overload", class),
+            Error::InvalidDeadlineHeader(ref name) => write!(fmt, "Invalid `{}` header value", name),
+            Error::DeadlineExceeded => fmt.write_str(error::Error::description(self)),
+            Error::Canceled => fmt.write_str("canceled"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::IncompleteHeadersMatch => {
+                "either none or both `X-Cocaine-Service` and `X-Cocaine-Event` headers must be specified"
+            }
+            Error::InvalidRequestIdHeader(..) => "invalid tracing header value",
+            Error::InvalidPath(..) => "invalid request path",
+            Error::InvalidBodyRead(..) => "failed to read HTTP body",
+            Error::QuotaExceeded(..) => "tenant quota exceeded",
+            Error::ClientAborted => "client closed request",
+            Error::PayloadTooLarge(..) => "request body is too large",
+            Error::RequestHeadersTooLarge(..) => "request headers are too large",
+            Error::ResponseTooLarge(..) => "response body is too large",
+            Error::ResponseHeadersTooLarge(..) => "response headers are too large",
+            Error::ProtocolViolation(..) => "application protocol violation",
+            Error::CircuitOpen(..) => "circuit breaker is open",
+            Error::ResponseTimeout(..) => "application response timed out",
+            Error::DigestMismatch(..) => "request body digest mismatch",
+            Error::LoopDetected => "request loop detected",
+            Error::LocalUpstream(..) => "local upstream failed",
+            Error::Peer(..) => "peer proxy failed",
+            Error::QueueBudgetExceeded(..) => "queue wait budget exceeded",
+            Error::Shed(..) => "request shed",
+            Error::InvalidDeadlineHeader(..) => "invalid deadline header value",
+            Error::DeadlineExceeded => "client deadline exceeded",
+            Error::Canceled => "canceled",
+        }
+    }
+}
+
+/// Returns the time left until the earliest of client deadlines, given either as an absolute one
+/// or as a timeout, if any.
+fn client_budget(headers: &Headers, cfg: &RequestDeadlineConfig, now: SystemTime) -> Result<Option<Duration>, Error> {
+    let parse = |name: &str| -> Result<Option<u64>, Error> {
+        match headers.get_raw(name) {
+            Some(raw) => raw.one()
+                .and_then(|value| str::from_utf8(value).ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Some)
+                .ok_or_else(|| Error::InvalidDeadlineHeader(name.to_owned())),
+            None => Ok(None),
+        }
+    };
+
+    let deadline = parse(cfg.deadline_header())?.map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+    let timeout = parse(cfg.timeout_header())?.map(|millis| now + Duration::from_millis(millis));
+
+    let deadline = match (deadline, timeout) {
+        (Some(deadline), Some(timeout)) => cmp::min(deadline, timeout),
+        (Some(deadline), None) | (None, Some(deadline)) => deadline,
+        (None, None) => return Ok(None),
+    };
+
+    match deadline.duration_since(now) {
+        Ok(budget) if budget > Duration::new(0, 0) => Ok(Some(budget)),
+        Ok(..) | Err(..) => Err(Error::DeadlineExceeded),
+    }
+}
+
+/// Checks that request headers fit in both the count and the total size limits.
+fn check_request_headers(headers: &Headers, limit: &RequestHeadersConfig) -> Result<(), Error> {
+    if headers.len() > limit.count() {
+        return Err(Error::RequestHeadersTooLarge(format!("{} headers count limit", limit.count())));
+    }
+
+    let size = headers.iter().fold(0, |size, header| {
+        size + header.name().len() + header.raw().into_iter().map(|value| value.len()).sum::<usize>()
+    });
+    if size > limit.size() {
+        return Err(Error::RequestHeadersTooLarge(format!("{} bytes size limit", limit.size())));
+    }
+
+    Ok(())
+}
+
+/// Checks that response headers fit in both the count and the total size limits.
+fn check_headers(headers: &[(String, String)], limit: &ResponseHeadersConfig) -> Result<(), Error> {
+    if headers.len() > limit.count() {
+        return Err(Error::ResponseHeadersTooLarge(format!("{} headers count limit", limit.count())));
+    }
+
+    let size = headers.iter().fold(0, |size, &(ref name, ref value)| size + name.len() + value.len());
+    if size > limit.size() {
+        return Err(Error::ResponseHeadersTooLarge(format!("{} bytes size limit", limit.size())));
+    }
+
+    Ok(())
+}
+
+/// State of the streamed response body, as seen by its access record.
+enum EndState {
+    Streaming,
+    /// The body is finished with the given number of bytes sent and time the client has stalled.
+    Finished(u64, Duration),
+    /// The record is waiting for the body to finish.
+    Waiting(Box<dyn FnOnce(u64, Duration) + Send>),
+}
+
+/// Meets the access record of a streamed response with the size of its body and time spent
+/// writing it.
+///
+/// The response is handed to the client right after the meta frame, so the record is committed
+/// only when the body is finished, whichever of them comes last.
+struct StreamEnd {
+    state: Mutex<EndState>,
+}
+
+impl StreamEnd {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(EndState::Streaming),
+        }
+    }
+
+    /// Marks the body finished with the given number of bytes sent and time the client has
+    /// stalled.
+    fn finish(&self, size: u64, stalled: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if let EndState::Waiting(commit) = mem::replace(&mut *state, EndState::Finished(size, stalled)) {
+            drop(state);
+            commit(size, stalled);
+        }
+    }
+
+    /// Calls the given function with the number of bytes sent and time the client has stalled once
+    /// the body is finished.
+    fn on_finish<F>(&self, commit: F)
+        where F: FnOnce(u64, Duration) + Send + 'static
+    {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            EndState::Finished(size, stalled) => {
+                drop(state);
+                commit(size, stalled);
+            }
+            _ => *state = EndState::Waiting(Box::new(commit)),
+        }
+    }
+}
+
+impl fmt::Debug for StreamEnd {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let state = match *self.state.lock().unwrap() {
+            EndState::Streaming => "streaming",
+            EndState::Finished(..) => "finished",
+            EndState::Waiting(..) => "waiting",
+        };
+
+        fmt.debug_struct("StreamEnd")
+            .field("state", &state)
+            .finish()
+    }
+}
+
+/// Feeds response body slices or chunks into the HTTP body, accounting time spent waiting for the
+/// client to drain previous ones.
+struct BodySink {
+    tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+    /// Bytes queued by the dispatch, but not yet taken by the HTTP body, if bounded by a window.
+    pending: Option<Arc<AtomicUsize>>,
+    /// Bytes taken by the HTTP body.
+    sent: u64,
+    /// Moment the client stopped accepting data.
+    stalled: Option<Instant>,
+    /// Total time the client has not been accepting chunks.
+    stalled_for: Duration,
+    stalls: Option<Arc<StallMetrics>>,
+    /// Finish of the streamed response body, awaited by its access record.
+    end: Option<Arc<StreamEnd>>,
+    /// Acknowledges drained bytes to the application with flow control.
+    upstream: Upstream,
+}
+
+impl BodySink {
+    fn unstall(&mut self) {
+        if let Some(since) = self.stalled.take() {
+            let elapsed = since.elapsed();
+            self.stalled_for += elapsed;
+            if let Some(ref stalls) = self.stalls {
+                stalls.mark(elapsed);
+            }
+        }
+    }
+}
+
+impl Sink for BodySink {
+    type SinkItem = Result<Chunk, hyper::Error>;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let len = item.as_ref().map(|chunk| chunk.len()).unwrap_or(0);
+
+        match self.tx.start_send(item).map_err(|_| ())? {
+            AsyncSink::Ready => {
+                if let Some(ref pending) = self.pending {
+                    pending.fetch_sub(len, Ordering::AcqRel);
+                }
+                self.sent += len as u64;
+                self.upstream.credit(self.sent);
+                self.unstall();
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(item) => {
+                self.stalled.get_or_insert_with(Instant::now);
+                Ok(AsyncSink::NotReady(item))
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.tx.poll_complete().map_err(|_| ())
+    }
+}
+
+impl Drop for BodySink {
+    fn drop(&mut self) {
+        if let Some(ref end) = self.end {
+            // The client may have gone away while stalled.
+            let stalled = self.stalled_for + self.stalled.map(|since| since.elapsed()).unwrap_or_default();
+            end.finish(self.sent, stalled);
+        }
+    }
+}
+
+/// Buffered response body, which is written to the client slice by slice.
+///
+/// Writing the next slice waits for the client to drain previous ones, so the time spent waiting
+/// tells slow clients apart from slow applications.
+struct ResponseFeed {
+    slice: usize,
+    body: Body,
+    tx: oneshot::Sender<Vec<u8>>,
+}
+
+impl ResponseFeed {
+    /// Constructs the feed with the future writing the body, which must be spawned alongside with
+    /// the invocation.
+    fn new(slice: usize, stalls: Option<Arc<StallMetrics>>) -> (Self, Box<dyn Future<Item = (), Error = ()> + Send>) {
+        let (tx, body) = Body::pair();
+        let (feed, rx) = oneshot::channel();
+
+        let sink = BodySink {
+            tx: tx,
+            pending: None,
+            sent: 0,
+            stalled: None,
+            stalled_for: Duration::default(),
+            stalls: stalls,
+            end: None,
+            upstream: Upstream::new(),
+        };
+
+        let feed = Self {
+            slice: slice,
+            body: body,
+            tx: feed,
+        };
+
+        // Responses, which don't need slicing, drop the feed, while failures here mean that the
+        // client has gone away.
+        let forward = rx.map_err(|_| ())
+            .and_then(move |body: Vec<u8>| {
+                let slices = body.chunks(slice)
+                    .map(|part| Ok(Chunk::from(part.to_vec())))
+                    .collect::<Vec<_>>();
+                stream::iter_ok::<_, ()>(slices).forward(sink)
+            })
+            .then(|_| Ok(()));
+
+        (feed, Box::new(forward))
+    }
+
+    /// Sets the response body, slicing it if it doesn't fit into a single slice.
+    fn set_body(self, resp: &mut Response, body: Vec<u8>) {
+        use hyper::header::ContentLength;
+
+        if body.len() <= self.slice {
+            resp.set_body(body);
+            return;
+        }
+
+        // Otherwise the body would be sent with chunked transfer encoding.
+        if resp.headers().get::<ContentLength>().is_none() {
+            resp.headers_mut().set(ContentLength(body.len() as u64));
+        }
+        resp.set_body(self.body);
+        drop(self.tx.send(body));
+    }
+}
+
+/// Outcome of queueing a streamed response chunk.
+#[derive(Debug, PartialEq)]
+enum Push {
+    Queued,
+    /// The client hasn't drained the window, so the chunk is rejected. With flow control this
+    /// means that the application ignores the window.
+    Overflow,
+    /// The client has gone away.
+    Gone,
+}
+
+/// Response body, which is streamed to the client as chunks arrive from the application.
+///
+/// Chunks are queued and moved into the HTTP body by a separate future at the client's pace.
+/// Bytes the client hasn't drained yet are bounded by the window. Applications taking part in flow
+/// control pause once they run out of it, waiting for drained bytes to be acknowledged. Others
+/// can't be paused, so the response is aborted rather than buffered without limits.
+struct ResponseStream {
+    /// Taken once the response meta is received.
+    body: Option<Body>,
+    chunks: ChunkSender,
+    size: usize,
+    /// Bytes queued, but not yet taken by the HTTP body.
+    pending: Arc<AtomicUsize>,
+    window: usize,
+    /// Whether the application takes part in flow control.
+    flow_control: bool,
+    stalls: Option<Arc<StallMetrics>>,
+    end: Arc<StreamEnd>,
+}
+
+impl ResponseStream {
+    /// Constructs the stream with the future moving chunks into the HTTP body, which must be
+    /// spawned alongside with the invocation.
+    fn new(stalls: Option<Arc<StallMetrics>>, window: usize, upstream: Upstream)
+        -> (Self, Box<dyn Future<Item = (), Error = ()> + Send>)
+    {
+        let flow_control = upstream.is_flow_controlled();
+        let (tx, body) = Body::pair();
+        let (chunks, rx) = mpsc::unbounded();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let end = Arc::new(StreamEnd::new());
+
+        let sink = BodySink {
+            tx: tx,
+            pending: Some(pending.clone()),
+            sent: 0,
+            stalled: None,
+            stalled_for: Duration::default(),
+            stalls: stalls.clone(),
+            end: Some(end.clone()),
+            upstream: upstream,
+        };
+
+        let stream = Self {
+            body: Some(body),
+            chunks: chunks,
+            size: 0,
+            pending: pending,
+            window: window,
+            flow_control: flow_control,
+            stalls: stalls,
+            end: end,
+        };
+
+        // Failures here mean that the client has gone away, which is noticed by the dispatch.
+        let forward = rx.forward(sink).then(|_| Ok(()));
+
+        (stream, Box::new(forward))
+    }
+
+    /// Queues the chunk unless it overflows the window.
+    ///
+    /// A chunk is always accepted while nothing is pending, so chunks larger than the window
+    /// still pass to clients that keep up. With flow control the application may send a chunk
+    /// whenever the window has room, so only chunks sent into the full window overflow it.
+    fn push(&mut self, data: Vec<u8>) -> Push {
+        let len = data.len();
+        let pending = self.pending.fetch_add(len, Ordering::AcqRel);
+        let overflow = if self.flow_control {
+            pending > 0 && pending >= self.window
+        } else {
+            pending > 0 && pending + len > self.window
+        };
+
+        if overflow {
+            self.pending.fetch_sub(len, Ordering::AcqRel);
+            if let Some(ref stalls) = self.stalls {
+                stalls.mark_overflow();
+            }
+            return Push::Overflow;
+        }
+
+        self.size += len;
+        match self.chunks.unbounded_send(Ok(Chunk::from(data))) {
+            Ok(()) => Push::Queued,
+            Err(..) => Push::Gone,
+        }
+    }
+}
+
+struct AppReadDispatch {
+    tx: Option<oneshot::Sender<Result<Option<(Response, u64)>, Error>>>,
+    /// Sliced response body, if configured for the service.
+    feed: Option<ResponseFeed>,
+    /// Response body in streaming mode.
+    stream: Option<ResponseStream>,
+    method: Method,
+    body: Option<Vec<u8>>,
+    trace: u64,
+    response: Option<Response>,
+    settings: Arc<ServiceSettings>,
+    /// Matched rewrite rule with a body, replacing the one received from the worker.
+    body_override: Option<StatusRewrite>,
+    /// Negotiated once the service is connected.
+    protocol: Arc<Mutex<AppProtocol>>,
+    /// Status code received in the v2 status frame, while waiting for the headers frame.
+    code: Option<u32>,
+    /// `Link` header values, both configured and received in early hints frames, which are
+    /// attached to the final response.
+    hints: Vec<String>,
+    /// Algorithm of the digest generated for the buffered body.
+    digest: Option<DigestAlgorithm>,
+    headers_limit: ResponseHeadersConfig,
+    retry: Option<RetrySafety>,
+    retriable: Arc<Vec<RetriableError>>,
+    statuses: Arc<ErrorStatuses>,
+    /// Error categories mapped to names of subsystems generating them.
+    origins: Arc<HashMap<u64, String>>,
+    timer: Arc<RequestTimer>,
+    /// Set once any response frame is received.
+    answered: Arc<AtomicBool>,
+    /// Set once the invocation is sent, telling connection failures from broken channels.
+    sent: Arc<AtomicBool>,
+    /// Accounts the channel on its connection until the response is finished.
+    channel: Option<ChannelGuard>,
+    /// Sending half of the channel, aborted when the response is abandoned.
+    upstream: Upstream,
+    metrics: Arc<Metrics>,
+}
+
+impl AppReadDispatch {
+    /// Completes the attempt with the given result, unless it is already completed.
+    fn send(&mut self, result: Result<Option<(Response, u64)>, Error>) {
+        if let Some(tx) = self.tx.take() {
+            drop(tx.send(result));
+        }
+    }
+
+    /// Returns `true` if the response has already been sent to the client, while its body is
+    /// still being streamed.
+    fn is_streaming(&self) -> bool {
+        self.stream.is_some() && self.tx.is_none()
+    }
+
+    /// Aborts the streamed response, letting the client notice that the body is truncated.
+    fn abort_stream(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            drop(stream.chunks.unbounded_send(Err(hyper::Error::Incomplete)));
+        }
+    }
+
+    /// Tells the application, which is still receiving the request, that its response is
+    /// abandoned for the given reason.
+    fn abort_upstream(&self, reason: &str) {
+        self.upstream.abort(RESPONSE_ABORTED, reason);
+    }
+
+    /// Applies the first matching status rewrite rule, if any, to the given upstream status code.
+    fn rewrite_status(&mut self, code: u16) -> u16 {
+        let rule = self.settings.rewrites.iter().find(|rule| rule.from() == code).cloned();
+
+        match rule {
+            Some(rule) => {
+                let to = rule.to();
+                if rule.body().is_some() {
+                    self.body_override = Some(rule);
+                }
+                to
+            }
+            None => code,
+        }
+    }
+
+    /// Returns `true` if the request may be retried after the given error.
+    ///
+    /// By default it is safe to retry only when the request is guaranteed not to be delivered to
+    /// the worker, i.e. after configured errors, like the full queue, or after connection failures
+    /// happened before the request was written. Configured overrides either allow to retry on any
+    /// error or forbid retries at all.
+    fn is_retriable(&self, err: &cocaine::Error) -> bool {
+        match self.retry {
+            Some(RetrySafety::Safe) => true,
+            Some(RetrySafety::Forbidden) => false,
+            None => {
+                match *err {
+                    cocaine::Error::Service(ref err) => {
+                        self.retriable.iter().any(|retriable| retriable.matches(err.category(), err.code()))
+                    }
+                    _ => !self.sent.load(Ordering::Acquire),
+                }
+            }
+        }
+    }
+
+    /// Accounts the attempt as failed upstream because of the given error.
+    fn fail(&self, err: &cocaine::Error) {
+        self.timer.on_error(Failure::classify(err, self.sent.load(Ordering::Acquire)), err.to_string());
+    }
+
+    /// Remembers `Link` header values of an early hints frame, skipping already known ones.
+    fn collect_hints(&mut self, headers: Vec<(String, String)>) {
+        for (name, value) in headers {
+            if name.eq_ignore_ascii_case("Link") && !self.hints.contains(&value) {
+                self.hints.push(value);
+            }
+        }
+    }
+
+    /// Appends remembered `Link` header values the response doesn't carry yet.
+    fn attach_hints(&mut self, resp: &mut Response) {
+        for hint in self.hints.drain(..) {
+            let present = resp.headers().get_raw("Link")
+                .map(|raw| raw.iter().any(|line| line == hint.as_bytes()))
+                .unwrap_or(false);
+
+            if !present {
+                resp.headers_mut().append_raw("Link", hint);
+            }
+        }
+    }
+
+    /// Returns the status and the body of the response for the given error, which is 500 with
+    /// the error message unless a configured rule matches.
+    fn error_response(&self, err: &cocaine::Error) -> (StatusCode, String) {
+        match self.statuses.map(err) {
+            Some((status, body)) => (status, body.unwrap_or_else(|| err.to_string())),
+            None => (StatusCode::InternalServerError, err.to_string()),
+        }
+    }
+
+    /// Returns the name of the error origin if its category is known.
+    fn error_origin(&self, err: &cocaine::Error) -> Option<String> {
+        match *err {
+            cocaine::Error::Service(ref err) => self.origins.get(&err.category()).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Fails the attempt, because the application has violated the response protocol.
+    fn violate(&mut self, reason: String) {
+        self.timer.on_failure(Failure::Protocol);
+        self.metrics.mark_protocol_violation();
+        self.send(Err(Error::ProtocolViolation(reason)));
+    }
+
+    fn protocol(&self) -> AppProtocol {
+        *self.protocol.lock().unwrap()
+    }
+
+    /// Returns the name of the frame expected before the response body.
+    fn expected_frame(&self) -> &'static str {
+        match (self.protocol(), self.code) {
+            (AppProtocol::V1, ..) => "meta",
+            (AppProtocol::V2, None) => "status",
+            (AppProtocol::V2, Some(..)) => "headers",
+        }
+    }
+
+    /// Parses response meta information from the given chunk.
+    ///
+    /// Returns `None` if more frames are required to complete it, which is the case for the v2
+    /// protocol status frame.
+    fn parse_meta(&mut self, data: &[u8]) -> Result<Option<(u32, Vec<(String, String)>)>, rmps::decode::Error> {
+        match (self.protocol(), self.code.take()) {
+            (AppProtocol::V1, ..) => {
+                let meta: ResponseMeta = rmps::from_slice(data)?;
+                Ok(Some((meta.code, meta.headers)))
+            }
+            (AppProtocol::V2, None) => {
+                let status: ResponseStatus = rmps::from_slice(data)?;
+                self.code = Some(status.code);
+                Ok(None)
+            }
+            (AppProtocol::V2, Some(code)) => {
+                let headers = rmps::from_slice(data)?;
+                Ok(Some((code, headers)))
+            }
+        }
+    }
+}
+
+impl Drop for AppReadDispatch {
+    fn drop(&mut self) {
+        self.upstream.finish();
+    }
+}
+
+impl Dispatch for AppReadDispatch {
+    fn process(mut self: Box<Self>, response: &cocaine::Response) -> Option<Box<dyn Dispatch>> {
+        self.timer.on_chunk();
+        self.answered.store(true, Ordering::Release);
+
+        match response.deserialize::<protocol::Streaming<rmps::RawRef>>().flatten() {
+            // Empty chunks carry nothing in any state, while some applications flush them.
+            Ok(Some(ref data)) if data.as_bytes().is_empty() => Some(self),
+            Ok(Some(data)) => {
+                if self.body.is_none() {
+                    let expected = self.expected_frame();
+
+                    // Parsing a whole body sent in place of meta is expensive and fails anyway.
+                    let limit = self.headers_limit.frame();
+                    if data.as_bytes().len() > limit {
+                        self.metrics.mark_oversized_meta();
+                        self.violate(format!("{} frame of {} bytes exceeds {} bytes limit", expected,
+                            data.as_bytes().len(), limit));
+                        return None;
+                    }
+
+                    let (code, headers) = match self.parse_meta(data.as_bytes()) {
+                        Ok(Some(meta)) => meta,
+                        Ok(None) => return Some(self),
+                        Err(err) => {
+                            // Most likely body chunks are sent before the meta frame.
+                            self.violate(format!("expected {} frame, but received a malformed one: {}", expected, err));
+                            return None
+                        }
+                    };
+
+                    // Pathological headers are rejected before touching the response, because
+                    // otherwise they may balloon memory or make the response unserializable.
+                    if let Err(err) = check_headers(&headers, &self.headers_limit) {
+                        self.timer.on_failure(Failure::Protocol);
+                        self.send(Err(err));
+                        return None;
+                    }
+
+                    // Early hints precede the final meta frame and only contribute links to it.
+                    if code == 103 {
+                        self.collect_hints(headers);
+                        return Some(self);
+                    }
+
+                    // So do acknowledgements of applications taking part in flow control.
+                    if code == 100 && self.upstream.is_flow_controlled() {
+                        match parse_ack(&headers) {
+                            Some(total) => {
+                                self.upstream.ack(total);
+                                return Some(self);
+                            }
+                            None => {
+                                self.violate(format!("informational frame without valid `{}` header", ACK_HEADER));
+                                return None;
+                            }
+                        }
+                    }
+                    self.upstream.on_response();
+
+                    let code = self.rewrite_status(code as u16);
+                    let status = StatusCode::try_from(code)
+                        .unwrap_or(StatusCode::InternalServerError);
+
+                    let mut resp = self.response.take().unwrap();
+                    resp.set_status(status);
+                    resp.headers_mut().set(XRequestId(self.trace));
+                    for (name, value) in headers {
+                        // TODO: Filter headers - https://tools.ietf.org/html/draft-ietf-httpbis-p1-messaging-14#section-7.1.3
+                        resp.headers_mut().set_raw(name, value);
+                    }
+                    self.attach_hints(&mut resp);
+                    self.body = Some(Vec::with_capacity(64));
+
+                    // Responses without body and with overridden one are buffered as usual.
+                    let has_body = match status {
+                        StatusCode::NoContent | StatusCode::NotModified => false,
+                        _ => self.method != Method::Head && self.body_override.is_none(),
+                    };
+
+                    match self.stream.as_mut().and_then(|stream| stream.body.take().map(|body| (body, stream.end.clone()))) {
+                        Some((body, end)) if has_body => {
+                            // Without `Content-Length` hyper uses chunked transfer encoding.
+                            resp.set_body(body);
+                            // The size is unknown yet, so the access record waits for the body.
+                            self.timer.on_stream(end);
+                            self.send(Ok(Some((resp, 0))));
+                        }
+                        Some(..) | None => {
+                            self.stream = None;
+                            self.response = Some(resp);
+                        }
+                    }
+                    return Some(self);
+                }
+
+                let data = match codec::decode(self.settings.codec, data.as_bytes()) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        self.violate(format!("failed to decode body chunk: {}", err));
+                        return None;
+                    }
+                };
+
+                if self.is_streaming() {
+                    let stream = self.stream.as_mut().unwrap();
+
+                    if let Some(limit) = self.settings.response_limit {
+                        if stream.size + data.len() > limit {
+                            self.metrics.mark_oversized();
+                            self.abort_stream();
+                            self.abort_upstream("response is too large");
+                            return None;
+                        }
+                    }
+
+                    match stream.push(data.into_owned()) {
+                        Push::Queued => {}
+                        Push::Overflow if self.upstream.is_flow_controlled() => {
+                            self.metrics.mark_protocol_violation();
+                            self.abort_stream();
+                            self.abort_upstream("application exceeds flow control window");
+                            return None;
+                        }
+                        Push::Overflow => {
+                            self.abort_stream();
+                            self.abort_upstream("client doesn't keep up with the response");
+                            return None;
+                        }
+                        // The body is dropped only when the client has gone away.
+                        Push::Gone => return None,
+                    }
+                } else {
+                    let body = self.body.as_mut().unwrap();
+
+                    if let Some(limit) = self.settings.response_limit {
+                        if body.len() + data.len() > limit {
+                            // Dropping the dispatch detaches it from the channel, so the rest of
+                            // the response is discarded.
+                            self.send(Err(Error::ResponseTooLarge(limit)));
+                            self.abort_upstream("response is too large");
+                            return None;
+                        }
+                    }
+
+                    body.extend_from_slice(&data);
+                    // Buffered bytes are drained at once, so the application is never held back.
+                    self.upstream.credit(body.len() as u64);
+                }
+                Some(self)
+            }
+            Ok(None) => {
+                // Dropping the stream finishes the body.
+                if self.is_streaming() {
+                    return None;
+                }
+
+                let (resp, size) = match self.body.take() {
+                    Some(body) => {
+                        let mut resp = self.response.take().unwrap();
+
+                        let body = match (self.body_override.take(), &self.settings.filters) {
+                            (Some(rule), ..) => {
+                                let body = rule.body().unwrap_or_default();
+
+                                // Headers describing the application body don't match the new one.
+                                let headers = resp.headers_mut();
+                                headers.remove_raw("Content-Encoding");
+                                match rule.content_type() {
+                                    Some(content_type) => headers.set_raw("Content-Type", content_type.to_owned()),
+                                    None => headers.remove_raw("Content-Type"),
+                                }
+                                headers.set(ContentLength(body.len() as u64));
+
+                                body.as_bytes().to_vec()
+                            }
+                            (None, filters) if !filters.is_empty() && self.method != Method::Head => {
+                                let content_type = resp.headers().get_raw("Content-Type")
+                                    .and_then(|raw| raw.one())
+                                    .map(|value| String::from_utf8_lossy(value).into_owned());
+                                let body = filter::apply(filters, content_type.as_ref().map(String::as_str), body);
+
+                                // The application might have specified the length of the original body.
+                                if resp.headers().has::<ContentLength>() {
+                                    resp.headers_mut().set(ContentLength(body.len() as u64));
+                                }
+                                body
+                            }
+                            (None, ..) => body,
+                        };
+
+                        if let Some(algorithm) = self.digest {
+                            for (name, value) in digest::headers(algorithm, &body) {
+                                resp.headers_mut().set_raw(name, value);
+                            }
+                        }
+
+                        // Special handling for responses with no body.
+                        // See https://www.w3.org/Protocols/rfc2616/rfc2616-sec10.html for more.
+                        let size = if self.method == Method::Head {
+                            // We shouldn't remove Content-Length header here, because according to
+                            // RFC: the Content-Length entity-header field indicates the size of the
+                            // entity-body, in decimal number of OCTETs, sent to the recipient or,
+                            // in the case of the HEAD method, the size of the entity-body that
+                            // would have been sent had the request been a GET.
+                            0
+                        } else {
+                            match resp.status() {
+                                StatusCode::NoContent |
+                                StatusCode::NotModified => {
+                                    0
+                                }
+                                _ => {
+                                    let mut has_body = !body.is_empty();
+                                    has_body = has_body & resp.headers().get::<ContentLength>().map(|head| {
+                                        match *head {
+                                            ContentLength(len) => return len > 0,
+                                        }
+                                    }).unwrap_or(true);
+
+                                    if has_body {
+                                        let size = body.len();
+                                        match self.feed.take() {
+                                            Some(feed) => feed.set_body(&mut resp, body),
+                                            None => resp.set_body(body),
+                                        }
+                                        size
+                                    } else {
+                                        0
+                                    }
+                                }
+                            }
+                        };
+
+                        (resp, size)
+                    }
+                    None => {
+                        let expected = self.expected_frame();
+                        self.violate(format!("received `close` event without prior {} frame", expected));
+                        return None;
+                    }
+                };
+
+                self.send(Ok(Some((resp, size as u64))));
+                None
+            }
+            // Nothing can be done once the response is sent.
+            Err(ref err) if self.is_streaming() => {
+                self.fail(err);
+                self.abort_stream();
+                None
+            }
+            Err(ref err) if self.is_retriable(err) => {
+                self.fail(err);
+                self.send(Ok(None));
+                None
+            }
+            Err(err) => {
+                self.fail(&err);
+                let (status, body) = self.error_response(&err);
+                let body_len = body.len() as u64;
+
+                let mut resp = Response::new()
+                    .with_status(status)
+                    .with_header(XRequestId(self.trace))
+                    .with_body(body);
+
+                // Errors of unknown categories are generated by the application itself.
+                if let Some(origin) = self.error_origin(&err) {
+                    resp.headers_mut().set(XErrorGeneratedBy(origin));
+                }
+
+                self.send(Ok(Some((resp, body_len))));
+                None
+            }
+        }
+    }
+
+    fn discard(mut self: Box<Self>, err: &cocaine::Error) {
+        self.fail(err);
+
+        if self.is_streaming() {
+            self.abort_stream();
+            return;
+        }
+
+        if self.is_retriable(err) {
+            self.send(Ok(None));
+            return;
+        }
+
+        let (status, body) = self.error_response(err);
+        let body_len = body.as_bytes().len() as u64;
+
+        // Connection-level errors are attributed to the proxy unless their category is known.
+        let origin = self.error_origin(err).unwrap_or_else(|| XErrorGeneratedBy::proxy().0);
+
+        let resp = Response::new()
+            .with_status(status)
+            .with_header(XRequestId(self.trace))
+            .with_header(XErrorGeneratedBy(origin))
+            .with_body(body);
+        self.send(Ok(Some((resp, body_len))));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use hyper::{HttpVersion, Method};
+    use hyper::header::Headers;
+    use serde_json::Serializer;
+    use serde_yaml;
+
+    use crate::memory::MemoryBudget;
+    use crate::route::serialize;
+
+    use crate::config::{AppProtocol, BodyCodec, NormalizationConfig, NormalizationPolicy, RequestDeadlineConfig, RequestHeadersConfig, ResponseHeadersConfig};
+
+    use super::{Channel, Flow, PathMatch, Push, RequestMeta, RequestMetaV1, RequestMetaV2, RequestTimer, ResponseStream, StreamEnd, Upstream,
+                UpstreamState, check_headers, check_request_headers, client_budget, normalize_path, parse_ack,
+                select_protocol, serialize_version, single_segment, strip_prefix};
+
+    #[test]
+    fn test_select_protocol() {
+        assert_eq!(AppProtocol::V2, select_protocol(AppProtocol::V2, Some(2)));
+        assert_eq!(AppProtocol::V2, select_protocol(AppProtocol::V2, Some(3)));
+
+        // Falls back unless the locator confirms the support.
+        assert_eq!(AppProtocol::V1, select_protocol(AppProtocol::V2, Some(1)));
+        assert_eq!(AppProtocol::V1, select_protocol(AppProtocol::V2, None));
+        assert_eq!(AppProtocol::V1, select_protocol(AppProtocol::V1, Some(2)));
+    }
+
+    #[test]
+    fn test_serialize_version() {
+        let mut se = Serializer::new(Vec::new());
+        serialize_version(&HttpVersion::Http10, &mut se).unwrap();
+        assert_eq!(&b"\"1.0\""[..], &se.into_inner()[..]);
+
+        let mut se = Serializer::new(Vec::new());
+        serialize_version(&HttpVersion::Http11, &mut se).unwrap();
+        assert_eq!(&b"\"1.1\""[..], &se.into_inner()[..]);
+
+        let mut se = Serializer::new(Vec::new());
+        serialize_version(&HttpVersion::Http09, &mut se).unwrap();
+        assert_eq!(&b"\"0.9\""[..], &se.into_inner()[..]);
+    }
+
+    #[test]
+    fn test_serialize_request_meta_v2() {
+        let meta = RequestMeta {
+            method: Method::Get,
+            uri: "/ping".into(),
+            version: HttpVersion::Http11,
+            headers: vec![("Accept".into(), "*/*".into())],
+            body: b"ignored".to_vec(),
+        };
+
+        // '\x93\xa3GET\xa5/ping\xa31.1'
+        assert_eq!(
+            vec![0x93, 0xa3, 0x47, 0x45, 0x54, 0xa5, 0x2f, 0x70, 0x69, 0x6e, 0x67, 0xa3, 0x31, 0x2e, 0x31],
+            serialize::to_vec(&RequestMetaV2::from(&meta)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_serialize_request_meta_v1_codecs() {
+        let meta = RequestMeta {
+            method: Method::Post,
+            uri: "/upload".into(),
+            version: HttpVersion::Http11,
+            headers: Vec::new(),
+            body: b"foo".to_vec(),
+        };
+
+        let identity = serialize::to_vec(&RequestMetaV1::new(&meta, BodyCodec::Identity)).unwrap();
+        assert_eq!(serialize::to_vec(&meta).unwrap(), identity);
+
+        let binary = serialize::to_vec(&RequestMetaV1::new(&meta, BodyCodec::MsgpackBinary)).unwrap();
+        assert_eq!(&[0xc4, 3, b'f', b'o', b'o'], &binary[binary.len() - 5..]);
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        assert_eq!(Some("/app/event"), strip_prefix("/api/v2", "/api/v2/app/event"));
+        assert_eq!(Some("?q=1"), strip_prefix("/api/v2", "/api/v2?q=1"));
+        assert_eq!(Some(""), strip_prefix("/api/v2", "/api/v2"));
+        assert_eq!(None, strip_prefix("/api/v2", "/api/v20/app/event"));
+        assert_eq!(None, strip_prefix("/api/v2", "/app/event"));
+    }
+
+    fn normalization(policy: NormalizationPolicy, event: Option<&str>) -> NormalizationConfig {
+        NormalizationConfig::new(policy, event.map(|event| event.to_owned()))
+    }
+
+    fn route(service: &str, event: &str, uri: &str) -> PathMatch {
+        PathMatch::Route(service.into(), event.into(), uri.into())
+    }
+
+    #[test]
+    fn test_normalize_regular_paths() {
+        let cfg = normalization(NormalizationPolicy::Reject, None);
+
+        assert_eq!(route("echo", "ping", "/"), normalize_path("/echo/ping", &cfg));
+        assert_eq!(route("echo", "ping", "/"), normalize_path("/echo/ping/", &cfg));
+        assert_eq!(route("echo", "ping", "/a/b"), normalize_path("/echo/ping/a/b", &cfg));
+        assert_eq!(route("echo", "ping", "/?x=1"), normalize_path("/echo/ping?x=1", &cfg));
+        assert_eq!(route("echo", "ping", "/a?x=/y"), normalize_path("/echo/ping/a?x=/y", &cfg));
+        assert_eq!(PathMatch::None, normalize_path("/", &cfg));
+        assert_eq!(PathMatch::None, normalize_path("*", &cfg));
+    }
+
+    #[test]
+    fn test_normalize_reject() {
+        let cfg = normalization(NormalizationPolicy::Reject, Some("index"));
+
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo", &cfg));
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo/", &cfg));
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo//ping", &cfg));
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo?x=1", &cfg));
+        assert_eq!(PathMatch::Reject("empty service name"), normalize_path("//ping", &cfg));
+    }
+
+    #[test]
+    fn test_normalize_default_event() {
+        let cfg = normalization(NormalizationPolicy::Default, Some("index"));
+
+        assert_eq!(route("echo", "index", "/"), normalize_path("/echo", &cfg));
+        assert_eq!(route("echo", "index", "/"), normalize_path("/echo/", &cfg));
+        assert_eq!(route("echo", "index", "/?x=1"), normalize_path("/echo?x=1", &cfg));
+        assert_eq!(route("echo", "index", "/ping"), normalize_path("/echo//ping", &cfg));
+        assert_eq!(PathMatch::Reject("empty service name"), normalize_path("//ping", &cfg));
+    }
+
+    #[test]
+    fn test_normalize_redirect() {
+        let cfg = normalization(NormalizationPolicy::Redirect, None);
+
+        assert_eq!(PathMatch::Redirect("/echo/ping".into()), normalize_path("/echo//ping", &cfg));
+        assert_eq!(PathMatch::Redirect("/echo/ping/a?x=1".into()), normalize_path("/echo///ping//a?x=1", &cfg));
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo", &cfg));
+        assert_eq!(PathMatch::Reject("empty event name"), normalize_path("/echo/", &cfg));
+
+        let cfg = normalization(NormalizationPolicy::Redirect, Some("index"));
+
+        assert_eq!(PathMatch::Redirect("/echo/index".into()), normalize_path("/echo", &cfg));
+        assert_eq!(PathMatch::Redirect("/echo/index".into()), normalize_path("/echo//", &cfg));
+        assert_eq!(PathMatch::Redirect("/echo/index?x=1".into()), normalize_path("/echo/?x=1", &cfg));
+        assert_eq!(PathMatch::Redirect("/echo/ping".into()), normalize_path("/echo//ping", &cfg));
+    }
+
+    #[test]
+    fn test_response_stream_window() {
+        let (mut stream, forward) = ResponseStream::new(None, 8, Upstream::new());
+
+        // The first chunk passes even if it doesn't fit in the window.
+        assert_eq!(Push::Queued, stream.push(vec![0; 16]));
+        assert_eq!(Push::Overflow, stream.push(vec![0; 1]));
+
+        // The client has drained the chunk.
+        stream.pending.fetch_sub(16, Ordering::AcqRel);
+        assert_eq!(Push::Queued, stream.push(vec![0; 5]));
+        assert_eq!(Push::Queued, stream.push(vec![0; 3]));
+        assert_eq!(Push::Overflow, stream.push(vec![0; 1]));
+        assert_eq!(24, stream.size);
+
+        drop(forward);
+        stream.pending.store(0, Ordering::Release);
+        assert_eq!(Push::Gone, stream.push(vec![0; 1]));
+    }
+
+    #[test]
+    fn test_response_stream_flow_control() {
+        let (mut stream, _forward) = ResponseStream::new(None, 8, Upstream::with_window(8));
+
+        // The application may send a chunk whenever the window has room.
+        assert_eq!(Push::Queued, stream.push(vec![0; 6]));
+        assert_eq!(Push::Queued, stream.push(vec![0; 6]));
+        assert_eq!(Push::Overflow, stream.push(vec![0; 1]));
+
+        stream.pending.fetch_sub(6, Ordering::AcqRel);
+        assert_eq!(Push::Queued, stream.push(vec![0; 1]));
+    }
+
+    #[test]
+    fn test_flow_window() {
+        let mut flow = Flow::new(8);
+        assert!(!flow.is_full());
+
+        flow.sent = 8;
+        assert!(flow.is_full());
+        flow.ack(4);
+        assert!(!flow.is_full());
+
+        // Acknowledgements never go back, nor beyond sent bytes.
+        flow.ack(2);
+        assert_eq!(4, flow.acked);
+        flow.ack(100);
+        assert_eq!(8, flow.acked);
+
+        // Nobody acknowledges anything once the response is finished.
+        flow.sent = 16;
+        assert!(flow.is_full());
+        flow.responded = true;
+        assert!(!flow.is_full());
+    }
+
+    #[test]
+    fn test_channel_release() {
+        let budget = Arc::new(MemoryBudget::new(None));
+        let mut channel = Channel {
+            state: UpstreamState::Pending,
+            flow: Some(Flow::new(8)),
+            reserved: VecDeque::new(),
+        };
+        channel.reserved.push_back((4, MemoryBudget::reserve(&budget, 4)));
+        channel.reserved.push_back((8, MemoryBudget::reserve(&budget, 4)));
+        channel.flow.as_mut().unwrap().sent = 8;
+
+        // Chunks stay accounted until consumed.
+        channel.release();
+        assert_eq!(8, budget.used());
+        channel.flow.as_mut().unwrap().ack(6);
+        channel.release();
+        assert_eq!(4, budget.used());
+
+        channel.state = UpstreamState::Finished;
+        channel.release();
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn test_flow_credit() {
+        let mut flow = Flow::new(8);
+
+        flow.drained = 3;
+        assert_eq!(None, flow.take_credit());
+        flow.drained = 4;
+        assert_eq!(Some(4), flow.take_credit());
+        assert_eq!(None, flow.take_credit());
+        flow.drained = 9;
+        assert_eq!(Some(9), flow.take_credit());
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let headers = vec![("x-cocaine-ack".to_owned(), "1024".to_owned())];
+        assert_eq!(Some(1024), parse_ack(&headers));
+
+        let headers = vec![("X-Cocaine-Ack".to_owned(), "lots".to_owned())];
+        assert_eq!(None, parse_ack(&headers));
+        assert_eq!(None, parse_ack(&[]));
+    }
+
+    #[test]
+    fn test_single_segment() {
+        assert_eq!(Some(("app", "")), single_segment("/app"));
+        assert_eq!(Some(("app", "")), single_segment("/app/"));
+        assert_eq!(Some(("app", "?a=1")), single_segment("/app?a=1"));
+        assert_eq!(Some(("app", "?a=1")), single_segment("/app/?a=1"));
+        assert_eq!(None, single_segment("/app/event"));
+        assert_eq!(None, single_segment("/app//rest"));
+        assert_eq!(None, single_segment("/"));
+        assert_eq!(None, single_segment("app"));
+    }
+
+    #[test]
+    fn test_request_timer() {
+        let timer = RequestTimer::new();
+        assert_eq!(0.0, timer.timings().upstream);
+
+        let queued = timer.on_enqueue(42);
+        let dequeued = timer.on_dequeue(queued);
+        timer.on_send(dequeued);
+        thread::sleep(Duration::from_millis(10));
+        timer.on_chunk();
+        let first_byte = timer.timings().first_byte;
+        assert!(first_byte >= 0.01);
+
+        // Only the first chunk of an attempt counts.
+        thread::sleep(Duration::from_millis(10));
+        timer.on_chunk();
+        assert_eq!(first_byte, timer.timings().first_byte);
+
+        // The upstream time spans all attempts.
+        let timings = timer.timings();
+        assert!(timings.upstream >= 0.02);
+        assert!(timings.queue <= timings.upstream);
+        assert_eq!(1, timer.attempts());
+        assert_eq!(Some(42), timer.span());
+    }
+
+    #[test]
+    fn test_stream_end() {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+
+        // The record waits for the body.
+        let end = StreamEnd::new();
+        let clone = sizes.clone();
+        end.on_finish(move |size, stalled| clone.lock().unwrap().push((size, stalled)));
+        assert!(sizes.lock().unwrap().is_empty());
+        end.finish(42, Duration::from_millis(10));
+        assert_eq!(vec![(42, Duration::from_millis(10))], *sizes.lock().unwrap());
+
+        // The body is finished before the response is logged.
+        let end = StreamEnd::new();
+        end.finish(24, Duration::default());
+        let clone = sizes.clone();
+        end.on_finish(move |size, stalled| clone.lock().unwrap().push((size, stalled)));
+        assert_eq!(vec![(42, Duration::from_millis(10)), (24, Duration::default())], *sizes.lock().unwrap());
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let limit = ResponseHeadersConfig::default();
+
+        let headers = vec![("Content-Type".to_owned(), "text/plain".to_owned())];
+        assert!(check_headers(&headers, &limit).is_ok());
+
+        let headers = (0..limit.count() + 1)
+            .map(|id| (format!("X-Header-{}", id), String::new()))
+            .collect::<Vec<_>>();
+        assert!(check_headers(&headers, &limit).is_err());
+
+        let headers = vec![("X-Huge".to_owned(), "x".repeat(limit.size()))];
+        assert!(check_headers(&headers, &limit).is_err());
+    }
+
+    #[test]
+    fn test_check_request_headers() {
+        let limit: RequestHeadersConfig = serde_yaml::from_str("{count: 2, size: 32}").unwrap();
+
+        let mut headers = Headers::new();
+        headers.set_raw("Host", "localhost");
+        assert!(check_request_headers(&headers, &limit).is_ok());
+
+        headers.set_raw("X-Huge", "x".repeat(32));
+        assert!(check_request_headers(&headers, &limit).is_err());
+
+        headers.set_raw("X-Huge", "x");
+        headers.set_raw("X-Other", "x");
+        assert!(check_request_headers(&headers, &limit).is_err());
+    }
+
+    #[test]
+    fn test_client_budget() {
+        let cfg: RequestDeadlineConfig = serde_yaml::from_str("{}").unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1500000000);
+
+        let mut headers = Headers::new();
+        assert_eq!(None, client_budget(&headers, &cfg, now).unwrap());
+
+        headers.set_raw("X-Request-Timeout", "1500");
+        assert_eq!(Some(Duration::from_millis(1500)), client_budget(&headers, &cfg, now).unwrap());
+
+        // The earliest deadline wins.
+        headers.set_raw("X-Request-Deadline", "1500000000200");
+        assert_eq!(Some(Duration::from_millis(200)), client_budget(&headers, &cfg, now).unwrap());
+
+        headers.set_raw("X-Request-Deadline", "1499999999000");
+        assert!(client_budget(&headers, &cfg, now).is_err());
+
+        headers.set_raw("X-Request-Deadline", "soon");
+        assert!(client_budget(&headers, &cfg, now).is_err());
+    }
+
+    #[cfg(feature = "mock")]
+    mod mock {
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use futures::{Future, Sink, Stream};
+        use futures::sync::mpsc;
+        use hyper::{Body, Chunk, Method, StatusCode};
+        use hyper::header::{ContentLength, Headers};
+        use hyper::server::Request;
+        use rmps;
+        use serde_yaml;
+        use tokio_core::reactor::Core;
+
+        use cocaine::{Resolver, ServiceBuilder};
+        use cocaine::logging::{Logger, LoggerContext, Severity};
+        use cocaine::service::Locator;
+
+        use crate::{Metrics, DEFAULT_LOCATOR_NAME};
+        use crate::common::XCocaineService;
+        use crate::config::{AppProtocol, Config, RetriableError, RetrySafety, StatusRewrite, StreamingConfig};
+        use crate::metrics::Meter;
+        use crate::mock::{MockCocaine, MockReply};
+        use crate::pool::{EventDispatch, Negotiator, PoolTask, SettingsRegistry};
+        use crate::random;
+        use crate::route::{BodyFilter, Match, Route, Via};
+
+        use super::super::AppRoute;
+
+        /// Passes the request through `AppRoute` backed by the given fake runtime, returning the
+        /// response status, headers and body.
+        fn invoke(mock: &MockCocaine, req: Request) -> (StatusCode, Headers, Vec<u8>) {
+            invoke_with(mock, req, |route| route)
+        }
+
+        /// Like `invoke`, but allows to configure the route before passing the request through.
+        ///
+        /// Ids and sampling decisions are deterministic, so the same request is always processed the
+        /// same way.
+        fn invoke_with<F>(mock: &MockCocaine, req: Request, f: F) -> (StatusCode, Headers, Vec<u8>)
+            where F: FnOnce(AppRoute<Logger>) -> AppRoute<Logger>
+        {
+            let (status, headers, body) = invoke_with_metrics(mock, req, Arc::new(Metrics::default()), f);
+            (status, headers, body.unwrap())
+        }
+
+        /// Like `invoke_with`, but accounts the request in the given metrics and returns the body
+        /// as read, so responses aborted in the middle can be inspected.
+        fn invoke_with_metrics<F>(mock: &MockCocaine, req: Request, metrics: Arc<Metrics>, f: F) -> (StatusCode, Headers, Result<Vec<u8>, hyper::Error>)
+            where F: FnOnce(AppRoute<Logger>) -> AppRoute<Logger>
+        {
+            random::deterministic(42, UNIX_EPOCH + Duration::from_secs(1500000000));
+
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+
+            let config: Config = serde_yaml::from_str(include_str!("../../config.yaml")).unwrap();
+
+            let ctx = LoggerContext::new("test");
+            ctx.filter().set(Severity::Error.into());
+            let log = ctx.create("test");
+
+            let locator = ServiceBuilder::new(DEFAULT_LOCATOR_NAME)
+                .locator_addrs(vec![mock.locator_addr()])
+                .build(&handle);
+            let negotiator = Negotiator::new(locator.clone());
+            let resolver = Resolver::new(Locator::new(locator));
+
+            let settings = Arc::new(SettingsRegistry::new(config.tracing().probability()));
+            let (tx, rx) = mpsc::unbounded();
+            let pool = PoolTask::new(handle.clone(), resolver, log.clone(), tx.clone(), rx, config, settings)
+                .with_negotiator(negotiator);
+            handle.spawn(pool);
+
+            let route = f(AppRoute::new(EventDispatch::new(vec![tx]), metrics, log));
+            let resp = match route.process(req) {
+                Match::Some(future) => core.run(future).unwrap(),
+                Match::None(..) => panic!("request must be matched by the app route"),
+            };
+
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let body = core.run(resp.body().concat2()).map(|body| body.to_vec());
+
+            (status, headers, body)
+        }
+
+        fn request(method: Method) -> Request {
+            Request::new(method, "/app/event".parse().unwrap())
+        }
+
+        #[test]
+        fn test_head_response_with_body() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(200, "hello").with_header("Content-Length", "5")
+            }).unwrap();
+
+            let (status, headers, body) = invoke(&mock, request(Method::Head));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(5)), headers.get::<ContentLength>());
+            assert!(body.is_empty());
+            assert_eq!("event", mock.requests()[0].event());
+        }
+
+        #[test]
+        fn test_no_content_response_with_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(204, "garbage")).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::NoContent, status);
+            assert!(body.is_empty());
+        }
+
+        #[test]
+        fn test_not_modified_response_with_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(304, "garbage")).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::NotModified, status);
+            assert!(body.is_empty());
+        }
+
+        #[test]
+        fn test_invalid_utf8_request_headers_are_passed_as_is() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Get);
+            req.headers_mut().set_raw("X-Binary", vec![0xff, 0xfe]);
+            let (status, _, body) = invoke(&mock, req);
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+
+            let headers = mock.requests()[0].headers();
+            assert!(headers.contains(&(b"X-Binary".to_vec(), vec![0xff, 0xfe])));
+        }
+
+        #[test]
+        fn test_invalid_utf8_response_headers_are_rejected() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(200, "ok").with_header("X-Binary", vec![0xff, 0xfe])
+            }).unwrap();
+
+            let (status, _, _) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::BadGateway, status);
+        }
+
+        #[test]
+        fn test_body_before_meta_is_rejected() {
+            let mock = MockCocaine::start(|_| MockReply::Chunks(vec![b"hello".to_vec()])).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::BadGateway, status);
+            assert!(String::from_utf8(body).unwrap().starts_with("Application protocol violation: expected meta frame"));
+        }
+
+        #[test]
+        fn test_oversized_meta_is_rejected() {
+            let mock = MockCocaine::start(|_| MockReply::Chunks(vec![vec![b'x'; 200000]])).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::BadGateway, status);
+            assert!(String::from_utf8(body).unwrap().ends_with("meta frame of 200000 bytes exceeds 131072 bytes limit"));
+        }
+
+        #[test]
+        fn test_empty_chunks_are_tolerated() {
+            let meta = rmps::to_vec(&(200, Vec::<(String, String)>::new())).unwrap();
+            let mock = MockCocaine::start(move |_| {
+                MockReply::Chunks(vec![Vec::new(), meta.clone(), b"hello".to_vec(), Vec::new()])
+            }).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"hello".to_vec(), body);
+        }
+
+        #[test]
+        fn test_sliced_response_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "hello, sliced world")).unwrap();
+
+            let slices = vec![("app".to_owned(), 4)].into_iter().collect();
+            let (status, headers, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_response_slices(slices)
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(19)), headers.get::<ContentLength>());
+            assert_eq!(b"hello, sliced world".to_vec(), body);
+        }
+
+        #[test]
+        fn test_deterministic_tracing_headers() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            invoke(&mock, request(Method::Get));
+            invoke(&mock, request(Method::Get));
+
+            let tracing = |headers: Vec<(Vec<u8>, Vec<u8>)>| {
+                headers.into_iter()
+                    .filter(|&(ref name, ..)| {
+                        [&b"trace_id"[..], b"span_id", b"parent_id"].contains(&&name[..])
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let requests = mock.requests();
+            let headers = tracing(requests[0].headers());
+            assert_eq!(3, headers.len());
+            assert_eq!(headers, tracing(requests[1].headers()));
+        }
+
+        #[test]
+        fn test_via_is_appended() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Get);
+            req.headers_mut().set_raw("Via", "1.1 nginx");
+            let (status, headers, _) = invoke_with(&mock, req, |route| {
+                route.with_via(Some(Via::new("proxy-1".into(), false)))
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            let via = headers.get_raw("Via").and_then(|raw| raw.one()).unwrap();
+            assert!(via.starts_with(b"1.1 proxy-1 "));
+
+            let headers = mock.requests()[0].headers();
+            let via = headers.iter().find(|&&(ref name, ..)| name == b"Via").map(|&(.., ref value)| value).unwrap();
+            assert!(via.starts_with(b"1.1 nginx, 1.1 proxy-1 "));
+        }
+
+        #[test]
+        fn test_via_loop_is_rejected() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Get);
+            req.headers_mut().set_raw("Via", "1.1 proxy-1 (cocaine-http-proxy/0.1), 1.1 nginx");
+            let (status, ..) = invoke_with(&mock, req, |route| {
+                route.with_via(Some(Via::new("proxy-1".into(), false)))
+            });
+
+            assert_eq!(StatusCode::LoopDetected, status);
+            assert_eq!(0, mock.invocations());
+        }
+
+        #[test]
+        fn test_digest_mismatch_is_rejected() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Post);
+            req.headers_mut().set_raw("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==");
+            req.set_body("hell0");
+            let digest = serde_yaml::from_str("verify: true").unwrap();
+            let (status, _, _) = invoke_with(&mock, req, |route| route.with_digest(Some(digest)));
+
+            assert_eq!(StatusCode::BadRequest, status);
+            assert_eq!(0, mock.invocations());
+        }
+
+        #[test]
+        fn test_oversized_request_body_is_rejected() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Post);
+            req.set_body("hello, world");
+            let (status, ..) = invoke_with(&mock, req, |route| route.with_max_body_size(Some(5)));
+            assert_eq!(StatusCode::PayloadTooLarge, status);
+
+            let mut req = request(Method::Post);
+            req.headers_mut().set(ContentLength(12));
+            let (status, ..) = invoke_with(&mock, req, |route| route.with_max_body_size(Some(5)));
+            assert_eq!(StatusCode::PayloadTooLarge, status);
+
+            assert_eq!(0, mock.invocations());
+        }
+
+        #[test]
+        fn test_digest_is_generated() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "hello")).unwrap();
+
+            let mut req = request(Method::Post);
+            req.headers_mut().set_raw("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==");
+            req.set_body("hello");
+            let digest = serde_yaml::from_str("{verify: true, generate: sha-256}").unwrap();
+            let (status, headers, _) = invoke_with(&mock, req, |route| route.with_digest(Some(digest)));
+
+            assert_eq!(StatusCode::Ok, status);
+            let digest = headers.get_raw("Digest").and_then(|raw| raw.one()).unwrap();
+            assert_eq!(&b"sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="[..], digest);
+        }
+
+        #[test]
+        fn test_body_filters_are_applied() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(200, "token=abc").with_header("Content-Length", "9")
+            }).unwrap();
+
+            let filters: Vec<_> = serde_yaml::from_str("[{replace: {pattern: 'token=\\w+', replacement: 'token=<redacted>'}}]").unwrap();
+            let filters = filters.iter().map(|cfg| BodyFilter::new(cfg).unwrap()).collect();
+            let (status, headers, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_body_filters(vec![("app".to_owned(), filters)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(16)), headers.get::<ContentLength>());
+            assert_eq!(&b"token=<redacted>"[..], &body[..]);
+        }
+
+        #[test]
+        fn test_status_is_rewritten() {
+            let mock = MockCocaine::start(|_| MockReply::response(502, "bad gateway")).unwrap();
+
+            let rewrites: Vec<StatusRewrite> = serde_yaml::from_str("[{from: 404, to: 200}, {from: 502, to: 503}]").unwrap();
+            let (status, _, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_status_rewrites(vec![("app".to_owned(), rewrites)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::ServiceUnavailable, status);
+            assert_eq!(b"bad gateway".to_vec(), body);
+        }
+
+        #[test]
+        fn test_status_rewrite_replaces_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(404, "not found")).unwrap();
+
+            let rewrites: Vec<StatusRewrite> = serde_yaml::from_str("[{from: 404, to: 200, body: '{}'}]").unwrap();
+            let (status, headers, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_status_rewrites(vec![("app".to_owned(), rewrites)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(2)), headers.get::<ContentLength>());
+            assert_eq!(b"{}".to_vec(), body);
+        }
+
+        #[test]
+        fn test_status_rewrite_replaces_body_headers() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(404, "not found")
+                    .with_header("Content-Type", "text/html")
+                    .with_header("Content-Length", "9")
+            }).unwrap();
+
+            let rewrites: Vec<StatusRewrite> = serde_yaml::from_str(
+                "[{from: 404, to: 200, body: '{}', content_type: application/json}]").unwrap();
+            let (status, headers, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_status_rewrites(vec![("app".to_owned(), rewrites)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(2)), headers.get::<ContentLength>());
+            assert_eq!(b"application/json", &headers.get_raw("Content-Type").unwrap().one().unwrap()[..]);
+            assert_eq!(b"{}".to_vec(), body);
+        }
+
+        #[test]
+        fn test_status_rewrite_clears_content_type() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(404, "not found").with_header("Content-Type", "text/html")
+            }).unwrap();
+
+            let rewrites: Vec<StatusRewrite> = serde_yaml::from_str("[{from: 404, to: 200, body: '{}'}]").unwrap();
+            let (status, headers, _) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_status_rewrites(vec![("app".to_owned(), rewrites)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert!(headers.get_raw("Content-Type").is_none());
+        }
+
+        #[test]
+        fn test_status_rewrite_of_other_service_is_ignored() {
+            let mock = MockCocaine::start(|_| MockReply::response(404, "not found")).unwrap();
+
+            let rewrites: Vec<StatusRewrite> = serde_yaml::from_str("[{from: 404, to: 200}]").unwrap();
+            let (status, _, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_status_rewrites(vec![("other".to_owned(), rewrites)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::NotFound, status);
+            assert_eq!(b"not found".to_vec(), body);
+        }
+
+        #[test]
+        fn test_early_hints_are_attached() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(200, "ok").with_header("Link", "</app.css>; rel=preload")
+            }).unwrap();
+
+            let hints = vec!["</app.css>; rel=preload".to_owned(), "</app.js>; rel=preload".to_owned()];
+            let (status, headers, _) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_early_hints(vec![("app".to_owned(), hints)].into_iter().collect())
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            let links: Vec<_> = headers.get_raw("Link").unwrap().iter().collect();
+            assert_eq!(vec![&b"</app.css>; rel=preload"[..], &b"</app.js>; rel=preload"[..]], links);
+        }
+
+        #[test]
+        fn test_retry_on_queue_full() {
+            let counter = AtomicUsize::new(0);
+            let mock = MockCocaine::start(move |_| {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    MockReply::queue_full()
+                } else {
+                    MockReply::response(200, "ok")
+                }
+            }).unwrap();
+
+            let (status, _, body) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+            assert_eq!(3, mock.invocations());
+        }
+
+        #[test]
+        fn test_retry_on_configured_error() {
+            let counter = AtomicUsize::new(0);
+            let mock = MockCocaine::start(move |_| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    MockReply::Error(42, 7, "busy".into())
+                } else {
+                    MockReply::response(200, "ok")
+                }
+            }).unwrap();
+
+            let (status, _, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_retriable_errors(vec![RetriableError::new(42, None)])
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+            assert_eq!(2, mock.invocations());
+        }
+
+        #[test]
+        fn test_retry_limit_exceeded() {
+            let mock = MockCocaine::start(|_| MockReply::queue_full()).unwrap();
+
+            let (status, _, _) = invoke(&mock, request(Method::Get));
+
+            assert_eq!(StatusCode::InternalServerError, status);
+            assert_eq!(3, mock.invocations());
+        }
+
+        #[test]
+        fn test_response_timeout() {
+            let mock = MockCocaine::start(|_| MockReply::Silence).unwrap();
+
+            let mut timeouts = HashMap::new();
+            timeouts.insert("app".into(), Duration::from_millis(50));
+            let (status, _, _) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_response_timeouts(timeouts)
+            });
+
+            // Events are not safe to retry by default, because the request has been delivered.
+            assert_eq!(StatusCode::GatewayTimeout, status);
+            assert_eq!(1, mock.invocations());
+        }
+
+        #[test]
+        fn test_response_timeout_is_retried_when_safe() {
+            let counter = AtomicUsize::new(0);
+            let mock = MockCocaine::start(move |_| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    MockReply::Silence
+                } else {
+                    MockReply::response(200, "ok")
+                }
+            }).unwrap();
+
+            let mut timeouts = HashMap::new();
+            timeouts.insert("app".into(), Duration::from_millis(50));
+            let mut overrides = HashMap::new();
+            overrides.insert("app".into(), vec![("event".into(), RetrySafety::Safe)].into_iter().collect());
+            let (status, _, body) = invoke_with(&mock, request(Method::Get), |route| {
+                route.with_response_timeouts(timeouts).with_retry_overrides(overrides)
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+            assert_eq!(2, mock.invocations());
+        }
+
+        #[test]
+        fn test_slow_request_is_hedged() {
+            // Each connection is served by its own thread and connections are picked in turn, so
+            // the delayed current attempt doesn't hold the hedged one back.
+            let counter = AtomicUsize::new(0);
+            let mock = MockCocaine::start(move |_| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    thread::sleep(Duration::from_millis(200));
+                    MockReply::response(200, "current")
+                } else {
+                    MockReply::response(200, "hedged")
+                }
+            }).unwrap();
+
+            let mut hedging = HashMap::new();
+            hedging.insert("app".into(), Duration::from_millis(20));
+            let metrics = Arc::new(Metrics::default());
+            let (status, _, body) = invoke_with_metrics(&mock, request(Method::Get), metrics.clone(), |route| {
+                route.with_hedging(hedging)
+            });
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"hedged".to_vec(), body.unwrap());
+            assert_eq!(2, mock.invocations());
+            assert_eq!(1, metrics.hedged.count());
+            assert_eq!(1, metrics.hedges_won.count());
+        }
+
+        #[test]
+        fn test_protocol_v2() {
+            let status = rmps::to_vec(&(201,)).unwrap();
+            let headers = rmps::to_vec(&vec![("X-Protocol", "v2")]).unwrap();
+            let mock = MockCocaine::start_with_version(2, move |_| {
+                MockReply::Chunks(vec![status.clone(), headers.clone(), b"created".to_vec()])
+            }).unwrap();
+
+            let mut req = request(Method::Post);
+            req.headers_mut().set_raw("X-Custom", "value");
+            req.set_body("hello");
+
+            let mut protocols = HashMap::new();
+            protocols.insert("app".to_owned(), AppProtocol::V2);
+            let (status, headers, body) = invoke_with(&mock, req, |route| route.with_protocols(protocols));
+
+            assert_eq!(StatusCode::Created, status);
+            assert_eq!(Some(&b"v2"[..]), headers.get_raw("X-Protocol").and_then(|raw| raw.one()));
+            assert_eq!(b"created".to_vec(), body);
+
+            // The request meta, headers and body are sent as separate frames.
+            let chunks = mock.requests()[0].chunks().to_vec();
+            assert_eq!(3, chunks.len());
+            let (method, _, version): (String, String, String) = rmps::from_slice(&chunks[0]).unwrap();
+            assert_eq!(("POST", "1.1"), (&method[..], &version[..]));
+            let headers: Vec<(String, String)> = rmps::from_slice(&chunks[1]).unwrap();
+            assert!(headers.contains(&("X-Custom".to_owned(), "value".to_owned())));
+            assert_eq!(b"hello".to_vec(), chunks[2]);
+        }
+
+        #[test]
+        fn test_protocol_v2_fallback() {
+            // The locator reports the application speaks only the first version.
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut req = request(Method::Post);
+            req.headers_mut().set_raw("X-Custom", "value");
+            req.set_body("hello");
+
+            let mut protocols = HashMap::new();
+            protocols.insert("app".to_owned(), AppProtocol::V2);
+            let (status, _, body) = invoke_with(&mock, req, |route| route.with_protocols(protocols));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+
+            // The request meta, headers and body are packed into a single frame.
+            let requests = mock.requests();
+            assert_eq!(1, requests[0].chunks().len());
+            assert!(requests[0].headers().contains(&(b"X-Custom".to_vec(), b"value".to_vec())));
+        }
+
+        #[test]
+        fn test_default_event_for_service_header() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut events = HashMap::new();
+            events.insert("app".to_owned(), "index".to_owned());
+
+            let mut req = Request::new(Method::Get, "/some/path".parse().unwrap());
+            req.headers_mut().set(XCocaineService("app".into()));
+            let (status, ..) = invoke_with(&mock, req, |route| route.with_default_events(events));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!("index", mock.requests()[0].event());
+        }
+
+        #[test]
+        fn test_default_event_for_single_segment() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let mut events = HashMap::new();
+            events.insert("app".to_owned(), "index".to_owned());
+
+            let req = Request::new(Method::Get, "/app?q=1".parse().unwrap());
+            let (status, ..) = invoke_with(&mock, req, |route| route.with_default_events(events));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!("index", mock.requests()[0].event());
+        }
+
+        #[test]
+        fn test_streaming_request_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "ok")).unwrap();
+
+            let (tx, body) = Body::pair();
+            thread::spawn(move || {
+                let tx = tx.send(Ok(Chunk::from("hello, "))).wait().unwrap();
+                tx.send(Ok(Chunk::from("world"))).wait().unwrap();
+            });
+
+            let mut req = request(Method::Post);
+            req.set_body(body);
+
+            let mut streaming = HashMap::new();
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>("request: true").unwrap());
+            let (status, _, body) = invoke_with(&mock, req, |route| route.with_streaming(streaming));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"ok".to_vec(), body);
+
+            // The meta frame with an empty body is followed by body chunks as they were sent.
+            let requests = mock.requests();
+            assert_eq!(1, requests.len());
+            assert_eq!(&[b"hello, ".to_vec(), b"world".to_vec()], &requests[0].chunks()[1..]);
+        }
+
+        #[test]
+        fn test_streaming_request_is_not_retried() {
+            let mock = MockCocaine::start(|_| MockReply::queue_full()).unwrap();
+
+            let mut streaming = HashMap::new();
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>("request: true").unwrap());
+            let (status, _, _) = invoke_with(&mock, request(Method::Post), |route| route.with_streaming(streaming));
+
+            assert_eq!(StatusCode::InternalServerError, status);
+            assert_eq!(1, mock.invocations());
+        }
+
+        #[test]
+        fn test_flow_control() {
+            let ack = rmps::to_vec(&(100, vec![("X-Cocaine-Ack", "12")])).unwrap();
+            let meta = rmps::to_vec(&(200, Vec::<(String, String)>::new())).unwrap();
+            let mock = MockCocaine::start(move |_| {
+                MockReply::Chunks(vec![ack.clone(), meta.clone(), b"done".to_vec()])
+            }).unwrap();
+
+            let (tx, body) = Body::pair();
+            thread::spawn(move || {
+                let tx = tx.send(Ok(Chunk::from("hello, "))).wait().unwrap();
+                tx.send(Ok(Chunk::from("world"))).wait().unwrap();
+            });
+
+            let mut req = request(Method::Post);
+            req.set_body(body);
+
+            let mut streaming = HashMap::new();
+            let cfg = "{request: true, flow_control: true}";
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>(cfg).unwrap());
+            let (status, _, body) = invoke_with(&mock, req, |route| route.with_streaming(streaming));
+
+            // The acknowledgement is not mistaken for the response.
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(b"done".to_vec(), body);
+
+            // The body is finished with an empty chunk, which is not a part of it.
+            let requests = mock.requests();
+            assert_eq!(1, requests.len());
+            assert_eq!(&[b"hello, ".to_vec(), b"world".to_vec()], &requests[0].chunks()[1..]);
+        }
+
+        #[test]
+        fn test_streaming_response_body() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "hello")).unwrap();
+
+            let mut streaming = HashMap::new();
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>("response: true").unwrap());
+            let (status, headers, body) = invoke_with(&mock, request(Method::Get), |route| route.with_streaming(streaming));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(None, headers.get::<ContentLength>());
+            assert_eq!(b"hello".to_vec(), body);
+        }
+
+        #[test]
+        fn test_streaming_response_to_head_is_buffered() {
+            let mock = MockCocaine::start(|_| {
+                MockReply::response(200, "hello").with_header("Content-Length", "5")
+            }).unwrap();
+
+            let mut streaming = HashMap::new();
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>("response: true").unwrap());
+            let (status, headers, body) = invoke_with(&mock, request(Method::Head), |route| route.with_streaming(streaming));
+
+            assert_eq!(StatusCode::Ok, status);
+            assert_eq!(Some(&ContentLength(5)), headers.get::<ContentLength>());
+            assert!(body.is_empty());
+        }
+
+        #[test]
+        fn test_streaming_response_too_large() {
+            let mock = MockCocaine::start(|_| MockReply::response(200, "hello, world")).unwrap();
+
+            let mut streaming = HashMap::new();
+            streaming.insert("app".to_owned(), serde_yaml::from_str::<StreamingConfig>("response: true").unwrap());
+            let mut limits = HashMap::new();
+            limits.insert("app".to_owned(), 5);
+            let metrics = Arc::new(Metrics::default());
+            let (status, _, body) = invoke_with_metrics(&mock, request(Method::Get), metrics.clone(), |route| {
+                route.with_streaming(streaming).with_response_limits(limits)
+            });
+
+            // The status is already sent, so the connection is aborted instead.
+            assert_eq!(StatusCode::Ok, status);
+            assert!(body.is_err());
+            assert_eq!(1, metrics.oversized.count());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Per-service retry budgets.
+//!
+//! Retrying each failed request up to the attempts limit multiplies the load exactly when
+//! services are struggling the most. Each request deposits a fraction of a token into the budget
+//! of its service and each retry withdraws a whole one, so retries make up at most that fraction
+//! of recent requests. A small reserve refilled over time keeps rarely called services retriable.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use crate::config::RetryBudgetConfig;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    timestamp: Instant,
+}
+
+impl Bucket {
+    fn new(cfg: &RetryBudgetConfig, now: Instant) -> Self {
+        Self {
+            tokens: cfg.burst(),
+            timestamp: now,
+        }
+    }
+
+    fn deposit(&mut self, cfg: &RetryBudgetConfig) {
+        self.tokens = (self.tokens + cfg.ratio()).min(cfg.burst());
+    }
+
+    fn withdraw(&mut self, cfg: &RetryBudgetConfig, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.timestamp);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        self.tokens = (self.tokens + elapsed * cfg.min_rate()).min(cfg.burst());
+        self.timestamp = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Retry budgets of all services, shared between all worker threads.
+#[derive(Debug)]
+pub struct RetryBudget {
+    cfg: RetryBudgetConfig,
+    buckets: RwLock<HashMap<String, Arc<Mutex<Bucket>>>>,
+}
+
+impl RetryBudget {
+    pub fn new(cfg: RetryBudgetConfig) -> Self {
+        Self {
+            cfg: cfg,
+            buckets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn bucket(&self, service: &str) -> Arc<Mutex<Bucket>> {
+        if let Some(bucket) = self.buckets.read().unwrap().get(service) {
+            return bucket.clone();
+        }
+
+        self.buckets.write().unwrap()
+            .entry(service.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(&self.cfg, Instant::now()))))
+            .clone()
+    }
+
+    /// Accounts a new request to the given service.
+    pub fn deposit(&self, service: &str) {
+        self.bucket(service).lock().unwrap().deposit(&self.cfg);
+    }
+
+    /// Tries to spend the budget of the given service on a retry.
+    ///
+    /// Returns `false` if the budget is exhausted, meaning that the retry must be suppressed.
+    pub fn withdraw(&self, service: &str) -> bool {
+        self.bucket(service).lock().unwrap().withdraw(&self.cfg, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use serde_yaml;
+
+    use super::Bucket;
+    use crate::config::RetryBudgetConfig;
+
+    #[test]
+    fn test_bucket_ratio() {
+        let cfg: RetryBudgetConfig = serde_yaml::from_str("{ratio: 0.2, min_rate: 0.0, burst: 2.0}").unwrap();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&cfg, now);
+
+        assert!(bucket.withdraw(&cfg, now));
+        assert!(bucket.withdraw(&cfg, now));
+        assert!(!bucket.withdraw(&cfg, now));
+
+        // Five requests earn a single retry.
+        for _ in 0..4 {
+            bucket.deposit(&cfg);
+        }
+        assert!(!bucket.withdraw(&cfg, now));
+        bucket.deposit(&cfg);
+        assert!(bucket.withdraw(&cfg, now));
+    }
+
+    #[test]
+    fn test_bucket_reserve() {
+        let cfg: RetryBudgetConfig = serde_yaml::from_str("{ratio: 0.2, min_rate: 2.0, burst: 1.0}").unwrap();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(&cfg, now);
+
+        assert!(bucket.withdraw(&cfg, now));
+        assert!(!bucket.withdraw(&cfg, now));
+        assert!(bucket.withdraw(&cfg, now + Duration::from_millis(500)));
+    }
+}
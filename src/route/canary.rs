@@ -0,0 +1,334 @@
+//! Scheduled canary ramps.
+//!
+//! A ramp routes a growing share of requests addressed to a service into its canary version,
+//! following the configured steps. The canary must bake at each step for the given time and serve
+//! enough requests before moving on, and its error ratio is continuously compared with the one of
+//! the baseline service. Once the canary fails noticeably more often, the ramp is rolled back and
+//! all requests return to the baseline until the proxy is restarted or reconfigured.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::config::{CanaryConfig, CanaryStepConfig};
+use crate::random;
+
+/// Current weights of canaries and counters of their transitions.
+#[derive(Debug, Default)]
+pub struct CanaryStats {
+    weights: Mutex<BTreeMap<String, f64>>,
+    promoted: AtomicUsize,
+    rolled_back: AtomicUsize,
+}
+
+impl CanaryStats {
+    /// Returns the percentage of requests routed into the canary of each service.
+    pub fn weights(&self) -> Vec<(String, f64)> {
+        self.weights.lock().unwrap()
+            .iter()
+            .map(|(service, &weight)| (service.clone(), weight))
+            .collect()
+    }
+
+    /// Returns the number of times canaries have moved to the next ramp step.
+    pub fn promoted(&self) -> usize {
+        self.promoted.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of times canary ramps have been rolled back.
+    pub fn rolled_back(&self) -> usize {
+        self.rolled_back.load(Ordering::SeqCst)
+    }
+
+    fn set_weight(&self, service: &str, weight: f64) {
+        self.weights.lock().unwrap().insert(service.to_owned(), weight);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Outcomes {
+    requests: u64,
+    failures: u64,
+}
+
+impl Outcomes {
+    fn add(&mut self, success: bool) {
+        self.requests += 1;
+        if !success {
+            self.failures += 1;
+        }
+    }
+
+    fn ratio(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.failures as f64 / self.requests as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    /// Index of the current step, `None` after the rollback.
+    step: Option<usize>,
+    since: Instant,
+    baseline: Outcomes,
+    canary: Outcomes,
+}
+
+/// What happened to a ramp during the update.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// Moved to the step with the given weight.
+    Promoted(f64),
+    /// Rolled back, because the canary error ratio exceeded the baseline one.
+    RolledBack {
+        canary: f64,
+        baseline: f64,
+    },
+}
+
+/// A ramp that has changed its state since the last update.
+#[derive(Debug, PartialEq)]
+pub struct Transition<'a> {
+    pub service: &'a str,
+    pub target: &'a str,
+    pub change: Change,
+}
+
+#[derive(Debug)]
+struct Ramp {
+    service: String,
+    target: String,
+    steps: Vec<CanaryStepConfig>,
+    max_error_ratio: f64,
+    min_requests: u64,
+    state: Mutex<State>,
+}
+
+impl Ramp {
+    fn new(service: String, cfg: &CanaryConfig, now: Instant) -> Self {
+        Self {
+            service: service,
+            target: cfg.target().to_owned(),
+            steps: cfg.steps().to_vec(),
+            max_error_ratio: cfg.max_error_ratio(),
+            min_requests: cfg.min_requests(),
+            state: Mutex::new(State {
+                step: Some(0),
+                since: now,
+                baseline: Outcomes::default(),
+                canary: Outcomes::default(),
+            }),
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        match self.state.lock().unwrap().step {
+            Some(step) => self.steps[step].weight(),
+            None => 0.0,
+        }
+    }
+
+    fn report(&self, canary: bool, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if canary {
+            state.canary.add(success);
+        } else {
+            state.baseline.add(success);
+        }
+    }
+
+    fn update(&self, now: Instant) -> Option<Change> {
+        let mut state = self.state.lock().unwrap();
+        let step = match state.step {
+            // The last step is final, there is nothing left to compare the canary with.
+            Some(step) if step + 1 < self.steps.len() => step,
+            Some(..) | None => return None,
+        };
+
+        if state.canary.requests < self.min_requests {
+            return None;
+        }
+
+        let canary = state.canary.ratio();
+        let baseline = state.baseline.ratio();
+        if canary > baseline + self.max_error_ratio {
+            state.step = None;
+            return Some(Change::RolledBack { canary: canary, baseline: baseline });
+        }
+
+        if now.duration_since(state.since) < self.steps[step].bake() {
+            return None;
+        }
+
+        state.step = Some(step + 1);
+        state.since = now;
+        state.baseline = Outcomes::default();
+        state.canary = Outcomes::default();
+        Some(Change::Promoted(self.steps[step + 1].weight()))
+    }
+}
+
+/// A decision where to route a single request addressed to a service with the canary ramp.
+#[derive(Debug)]
+pub struct Sample {
+    ramp: Arc<Ramp>,
+    canary: bool,
+}
+
+impl Sample {
+    /// Returns the canary service name if the request has been routed into it.
+    pub fn target(&self) -> Option<&str> {
+        if self.canary {
+            Some(&self.ramp.target)
+        } else {
+            None
+        }
+    }
+
+    /// Accounts the outcome of the request, comparing canary and baseline error ratios.
+    pub fn report(&self, success: bool) {
+        self.ramp.report(self.canary, success);
+    }
+}
+
+/// Canary ramps of all services, shared between clones.
+#[derive(Clone, Debug, Default)]
+pub struct Canaries {
+    ramps: HashMap<String, Arc<Ramp>>,
+    stats: Arc<CanaryStats>,
+}
+
+impl Canaries {
+    /// Constructs ramps, which start with their first step right away.
+    pub fn new(cfg: &HashMap<String, CanaryConfig>, stats: Arc<CanaryStats>) -> Self {
+        let now = Instant::now();
+        let ramps = cfg.iter()
+            .map(|(service, cfg)| (service.clone(), Arc::new(Ramp::new(service.clone(), cfg, now))))
+            .collect::<HashMap<_, _>>();
+
+        for ramp in ramps.values() {
+            stats.set_weight(&ramp.service, ramp.weight());
+        }
+
+        Self {
+            ramps: ramps,
+            stats: stats,
+        }
+    }
+
+    /// Re-evaluates ramps at the given time, returning ones that have moved to the next step or
+    /// have been rolled back since the last update.
+    pub fn update(&self, now: Instant) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        for ramp in self.ramps.values() {
+            if let Some(change) = ramp.update(now) {
+                match change {
+                    Change::Promoted(..) => self.stats.promoted.fetch_add(1, Ordering::SeqCst),
+                    Change::RolledBack { .. } => self.stats.rolled_back.fetch_add(1, Ordering::SeqCst),
+                };
+                self.stats.set_weight(&ramp.service, ramp.weight());
+
+                transitions.push(Transition {
+                    service: &ramp.service,
+                    target: &ramp.target,
+                    change: change,
+                });
+            }
+        }
+
+        transitions
+    }
+
+    /// Randomly decides whether a request addressed to the given service goes into its canary,
+    /// according to the current weight, returning `None` if the service has no ramp.
+    pub fn select(&self, service: &str) -> Option<Sample> {
+        self.ramps.get(service).map(|ramp| {
+            Sample {
+                ramp: ramp.clone(),
+                canary: random::gen::<f64>() * 100.0 < ramp.weight(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use serde_yaml;
+
+    use super::{Canaries, CanaryStats, Change};
+
+    fn canaries(now: Instant) -> Canaries {
+        let cfg = serde_yaml::from_str(r#"
+            app:
+              target: app-canary
+              steps: [{weight: 5, bake: 60}, {weight: 100, bake: 0}]
+              max_error_ratio: 0.1
+              min_requests: 10
+        "#).unwrap();
+        let mut canaries = Canaries::new(&cfg, Arc::new(CanaryStats::default()));
+        for ramp in canaries.ramps.values_mut() {
+            Arc::get_mut(ramp).unwrap().state.get_mut().unwrap().since = now;
+        }
+        canaries
+    }
+
+    fn report(canaries: &Canaries, canary: bool, requests: u64, failures: u64) {
+        let ramp = &canaries.ramps["app"];
+        for id in 0..requests {
+            ramp.report(canary, id >= failures);
+        }
+    }
+
+    #[test]
+    fn test_promote_after_bake() {
+        let now = Instant::now();
+        let canaries = canaries(now);
+        report(&canaries, true, 10, 0);
+
+        assert!(canaries.update(now + Duration::from_secs(30)).is_empty());
+
+        let transitions = canaries.update(now + Duration::from_secs(60));
+        assert_eq!(1, transitions.len());
+        assert_eq!(Change::Promoted(100.0), transitions[0].change);
+        assert_eq!(vec![("app".to_owned(), 100.0)], canaries.stats.weights());
+        assert_eq!(1, canaries.stats.promoted());
+    }
+
+    #[test]
+    fn test_wait_for_min_requests() {
+        let now = Instant::now();
+        let canaries = canaries(now);
+        report(&canaries, true, 9, 0);
+
+        assert!(canaries.update(now + Duration::from_secs(120)).is_empty());
+    }
+
+    #[test]
+    fn test_rollback_on_regression() {
+        let now = Instant::now();
+        let canaries = canaries(now);
+        report(&canaries, false, 100, 5);
+        report(&canaries, true, 10, 2);
+
+        let transitions = canaries.update(now);
+        assert_eq!(1, transitions.len());
+        assert_eq!(Change::RolledBack { canary: 0.2, baseline: 0.05 }, transitions[0].change);
+        assert_eq!(vec![("app".to_owned(), 0.0)], canaries.stats.weights());
+        assert!(canaries.select("app").unwrap().target().is_none());
+        assert!(canaries.update(now + Duration::from_secs(120)).is_empty());
+    }
+
+    #[test]
+    fn test_select_unknown_service() {
+        let canaries = Canaries::new(&HashMap::new(), Arc::new(CanaryStats::default()));
+        assert!(canaries.select("app").is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! Per-service encodings of HTTP bodies exchanged with applications.
+//!
+//! Only body bytes are affected, while meta and headers frames are always sent the same way, so
+//! a service may switch its codec independently of the protocol version.
+
+use std::borrow::Cow;
+
+use serde::{Serialize, Serializer};
+
+use cocaine;
+
+use crate::config::BodyCodec;
+
+use super::digest::base64;
+
+/// Body bytes, which are serialized according to the codec.
+pub(crate) struct Encoded<'a> {
+    codec: BodyCodec,
+    body: &'a [u8],
+}
+
+impl<'a> Encoded<'a> {
+    pub(crate) fn new(codec: BodyCodec, body: &'a [u8]) -> Self {
+        Self {
+            codec: codec,
+            body: body,
+        }
+    }
+}
+
+impl<'a> Serialize for Encoded<'a> {
+    fn serialize<S>(&self, se: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match self.codec {
+            BodyCodec::Identity => se.serialize_str(unsafe { ::std::str::from_utf8_unchecked(self.body) }),
+            BodyCodec::MsgpackBinary => se.serialize_bytes(self.body),
+            BodyCodec::Base64 => se.serialize_str(&base64(self.body)),
+        }
+    }
+}
+
+/// Wraps the given body bytes into a chunk frame.
+pub(crate) fn make_chunk(codec: BodyCodec, body: &[u8]) -> cocaine::Request {
+    cocaine::Request::new(0, &[Encoded::new(codec, body)]).unwrap()
+}
+
+/// Decodes body bytes received in a chunk frame.
+pub(crate) fn decode(codec: BodyCodec, data: &[u8]) -> Result<Cow<[u8]>, String> {
+    match codec {
+        BodyCodec::Identity | BodyCodec::MsgpackBinary => Ok(Cow::Borrowed(data)),
+        BodyCodec::Base64 => unbase64(data).map(Cow::Owned),
+    }
+}
+
+fn sextet(byte: u8) -> Option<u32> {
+    match byte {
+        b'A'..=b'Z' => Some((byte - b'A') as u32),
+        b'a'..=b'z' => Some((byte - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((byte - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes padded standard base64.
+fn unbase64(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() % 4 != 0 {
+        return Err(format!("base64 length {} is not a multiple of 4", data.len()));
+    }
+
+    let mut result = Vec::with_capacity(data.len() / 4 * 3);
+    for (id, chunk) in data.chunks(4).enumerate() {
+        let last = (id + 1) * 4 == data.len();
+        let padding = chunk.iter().rev().take_while(|&&byte| byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("misplaced base64 padding".into());
+        }
+
+        let mut n = 0;
+        for &byte in &chunk[..4 - padding] {
+            let value = sextet(byte).ok_or_else(|| format!("invalid base64 byte 0x{:02x}", byte))?;
+            n = n << 6 | value;
+        }
+        n <<= 6 * padding as u32;
+
+        result.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - padding]);
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::BodyCodec;
+    use crate::route::digest::base64;
+    use crate::route::serialize;
+
+    use super::{decode, unbase64, Encoded};
+
+    #[test]
+    fn test_unbase64() {
+        for body in &[&b""[..], b"f", b"fo", b"foo", b"foobar", b"\x00\xff\x10"] {
+            assert_eq!(body.to_vec(), unbase64(base64(body).as_bytes()).unwrap());
+        }
+
+        assert!(unbase64(b"Zm9").is_err());
+        assert!(unbase64(b"Zg==Zg==").is_err());
+        assert!(unbase64(b"Z!==").is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(&b"Zm9v"[..], &*decode(BodyCodec::Identity, b"Zm9v").unwrap());
+        assert_eq!(&b"foo"[..], &*decode(BodyCodec::Base64, b"Zm9v").unwrap());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(vec![0xa3, b'f', b'o', b'o'], serialize::to_vec(&Encoded::new(BodyCodec::Identity, b"foo")).unwrap());
+        assert_eq!(vec![0xc4, 3, b'f', b'o', b'o'], serialize::to_vec(&Encoded::new(BodyCodec::MsgpackBinary, b"foo")).unwrap());
+        assert_eq!(vec![0xa4, b'Z', b'm', b'9', b'v'], serialize::to_vec(&Encoded::new(BodyCodec::Base64, b"foo")).unwrap());
+    }
+}
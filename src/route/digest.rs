@@ -0,0 +1,130 @@
+//! Body digests for end-to-end integrity checks.
+//!
+//! Incoming request bodies may be verified against `Content-MD5` (RFC 1864) and `Digest`
+//! (RFC 3230) headers, while buffered responses may be given a `Digest` header, accompanied by
+//! `Content-MD5` for the MD5 algorithm. Digests with unknown algorithms are ignored, since the
+//! proxy can't tell whether they match.
+
+use md5::Md5;
+use sha2::{Digest as _, Sha256};
+
+use crate::config::DigestAlgorithm;
+
+pub const CONTENT_MD5_HEADER: &str = "Content-MD5";
+pub const DIGEST_HEADER: &str = "Digest";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Returns the base64-encoded digest of the given body.
+pub fn digest(algorithm: DigestAlgorithm, body: &[u8]) -> String {
+    match algorithm {
+        DigestAlgorithm::Md5 => base64(&Md5::digest(body)),
+        DigestAlgorithm::Sha256 => base64(&Sha256::digest(body)),
+    }
+}
+
+/// Checks the body against digest headers, returning the name of the first mismatching one.
+pub fn verify(headers: &[(String, String)], body: &[u8]) -> Result<(), &'static str> {
+    for &(ref name, ref value) in headers {
+        if name.eq_ignore_ascii_case(CONTENT_MD5_HEADER) {
+            if value.trim() != digest(DigestAlgorithm::Md5, body) {
+                return Err(CONTENT_MD5_HEADER);
+            }
+        } else if name.eq_ignore_ascii_case(DIGEST_HEADER) {
+            for (algorithm, expected) in parse(value) {
+                if expected != digest(algorithm, body) {
+                    return Err(DIGEST_HEADER);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns headers carrying the digest of the given body.
+pub fn headers(algorithm: DigestAlgorithm, body: &[u8]) -> Vec<(&'static str, String)> {
+    let value = digest(algorithm, body);
+
+    match algorithm {
+        DigestAlgorithm::Md5 => vec![(DIGEST_HEADER, format!("md5={}", value)), (CONTENT_MD5_HEADER, value)],
+        DigestAlgorithm::Sha256 => vec![(DIGEST_HEADER, format!("sha-256={}", value))],
+    }
+}
+
+/// Parses `Digest` header value into digests with known algorithms.
+fn parse(value: &str) -> Vec<(DigestAlgorithm, &str)> {
+    value.split(',')
+        .filter_map(|item| {
+            // Base64 values may end with `=`, so only the first one separates the algorithm.
+            let mut parts = item.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(algorithm), Some(value)) if algorithm.eq_ignore_ascii_case("md5") => {
+                    Some((DigestAlgorithm::Md5, value))
+                }
+                (Some(algorithm), Some(value)) if algorithm.eq_ignore_ascii_case("sha-256") => {
+                    Some((DigestAlgorithm::Sha256, value))
+                }
+                (..) => None,
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[n >> (18 - 6 * i) & 0x3f] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::DigestAlgorithm;
+
+    use super::{base64, digest, headers, verify};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+    }
+
+    #[test]
+    fn test_digest() {
+        assert_eq!("XUFAKrxLKna5cZ2REBfFkg==", digest(DigestAlgorithm::Md5, b"hello"));
+        assert_eq!("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=", digest(DigestAlgorithm::Sha256, b"hello"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let headers = vec![
+            ("content-md5".to_owned(), "XUFAKrxLKna5cZ2REBfFkg==".to_owned()),
+            ("Digest".to_owned(), "unixsum=30637, SHA-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=".to_owned()),
+        ];
+        assert_eq!(Ok(()), verify(&headers, b"hello"));
+        assert_eq!(Err("Content-MD5"), verify(&headers, b"hell0"));
+        assert_eq!(Err("Digest"), verify(&headers[1..], b"hell0"));
+        assert_eq!(Ok(()), verify(&[], b"hello"));
+    }
+
+    #[test]
+    fn test_headers() {
+        assert_eq!(vec![("Digest", "md5=XUFAKrxLKna5cZ2REBfFkg==".to_owned()),
+            ("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==".to_owned())], headers(DigestAlgorithm::Md5, b"hello"));
+    }
+}
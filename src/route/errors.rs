@@ -0,0 +1,72 @@
+//! Mapping of Cocaine errors into HTTP responses.
+
+use hyper::StatusCode;
+
+use cocaine;
+
+use crate::config::ErrorStatusRule;
+
+/// Rules mapping Cocaine errors into HTTP statuses and bodies, where the first matching one wins.
+#[derive(Debug)]
+pub struct ErrorStatuses {
+    rules: Vec<ErrorStatusRule>,
+}
+
+impl Default for ErrorStatuses {
+    fn default() -> Self {
+        ErrorStatuses::new(vec![ErrorStatusRule::unresolved()])
+    }
+}
+
+impl ErrorStatuses {
+    pub fn new(rules: Vec<ErrorStatusRule>) -> Self {
+        Self { rules: rules }
+    }
+
+    /// Returns the status and, if the matched rule has a template, the body of the response for
+    /// the given error.
+    ///
+    /// Only service errors carry categories, so other ones are never matched.
+    pub fn map(&self, err: &cocaine::Error) -> Option<(StatusCode, Option<String>)> {
+        match *err {
+            cocaine::Error::Service(ref service) => self.lookup(service.category(), service.code(), &err.to_string()),
+            _ => None,
+        }
+    }
+
+    fn lookup(&self, category: u64, code: u64, message: &str) -> Option<(StatusCode, Option<String>)> {
+        self.rules.iter()
+            .find(|rule| rule.matches(category, code))
+            .map(|rule| {
+                let status = StatusCode::try_from(rule.status()).unwrap_or(StatusCode::InternalServerError);
+                let body = rule.body().map(|body| {
+                    body.replace("{category}", &category.to_string())
+                        .replace("{code}", &code.to_string())
+                        .replace("{message}", message)
+                });
+                (status, body)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::StatusCode;
+    use serde_yaml;
+
+    use super::ErrorStatuses;
+
+    #[test]
+    fn test_map() {
+        let rules = serde_yaml::from_str(r#"
+            - {category: 42, codes: [1, 9], status: 429, body: "{category}/{code}: {message}"}
+            - {category: 42, status: 502}
+        "#).unwrap();
+        let statuses = ErrorStatuses::new(rules);
+
+        let expected = Some((StatusCode::TooManyRequests, Some("42/5: slow down".to_owned())));
+        assert_eq!(expected, statuses.lookup(42, 5, "slow down"));
+        assert_eq!(Some((StatusCode::BadGateway, None)), statuses.lookup(42, 10, "broken"));
+        assert_eq!(None, statuses.lookup(43, 5, "other"));
+    }
+}
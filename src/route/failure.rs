@@ -0,0 +1,73 @@
+//! Taxonomy of upstream failures.
+//!
+//! Failed requests look alike in status metrics, while they may be caused by a locator outage,
+//! broken connections, overloaded workers or applications themselves. Each request failed upstream
+//! is classified into one of a fixed set of kinds, which is exposed both as a metrics label and as
+//! an access log field.
+
+use std::fmt::{self, Display, Formatter};
+
+use cocaine;
+
+/// Error category of the locator, which fails to resolve services.
+const LOCATOR_CATEGORY: u64 = 10;
+/// Error category and code the runtime responds with when the application queue is full.
+const QUEUE_FULL: (u64, u64) = (0x52ff, 1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// The service can't be resolved.
+    Resolve,
+    /// The connection has failed before the invocation was sent.
+    Connect,
+    /// The connection has broken while the invocation was in flight.
+    ChannelReset,
+    /// The application queue is full.
+    QueueFull,
+    /// The worker has not responded in time.
+    Timeout,
+    /// The worker has responded with malformed or rejected meta information.
+    Protocol,
+    /// The worker has responded with an error of the given category.
+    Worker(u64),
+}
+
+impl Failure {
+    /// Classifies the error received either before or after the invocation was sent.
+    pub fn classify(err: &cocaine::Error, sent: bool) -> Self {
+        match *err {
+            cocaine::Error::Service(ref err) => match (err.category(), err.code()) {
+                (LOCATOR_CATEGORY, ..) => Failure::Resolve,
+                QUEUE_FULL => Failure::QueueFull,
+                (category, ..) => Failure::Worker(category),
+            },
+            _ if sent => Failure::ChannelReset,
+            _ => Failure::Connect,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Failure::Resolve => fmt.write_str("resolve"),
+            Failure::Connect => fmt.write_str("connect"),
+            Failure::ChannelReset => fmt.write_str("channel_reset"),
+            Failure::QueueFull => fmt.write_str("queue_full"),
+            Failure::Timeout => fmt.write_str("timeout"),
+            Failure::Protocol => fmt.write_str("protocol"),
+            Failure::Worker(category) => write!(fmt, "worker_{}", category),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Failure;
+
+    #[test]
+    fn test_display() {
+        assert_eq!("channel_reset", Failure::ChannelReset.to_string());
+        assert_eq!("worker_42", Failure::Worker(42).to_string());
+    }
+}
@@ -0,0 +1,131 @@
+//! Response body filters.
+//!
+//! Filters rewrite buffered response bodies of a service before they are sent to the client,
+//! which allows to mask sensitive data without waiting for applications to be fixed. Streamed
+//! responses are passed as is, since their bodies are never seen as a whole. Filters that can't
+//! parse the body, like the JSON redaction of a malformed document, leave it unchanged.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use regex::{self, bytes::Regex};
+use serde_json;
+
+use crate::config::BodyFilterConfig;
+use crate::logging::redact_json;
+
+/// A compiled body filter.
+#[derive(Clone, Debug)]
+pub enum BodyFilter {
+    /// Replaces all matches of the pattern, expanding `$name` groups in the replacement.
+    Replace(Regex, Vec<u8>),
+    /// Inserts the markup just after the opening `<body>` tag of HTML documents.
+    Banner(Vec<u8>),
+    /// Masks values of the named fields at any depth of JSON documents.
+    Redact(HashSet<String>),
+}
+
+impl BodyFilter {
+    pub fn new(cfg: &BodyFilterConfig) -> Result<Self, regex::Error> {
+        let filter = match *cfg {
+            BodyFilterConfig::Replace { ref pattern, ref replacement } => {
+                BodyFilter::Replace(Regex::new(pattern)?, replacement.clone().into_bytes())
+            }
+            BodyFilterConfig::Banner(ref html) => BodyFilter::Banner(html.clone().into_bytes()),
+            BodyFilterConfig::Redact(ref fields) => BodyFilter::Redact(fields.iter().cloned().collect()),
+        };
+
+        Ok(filter)
+    }
+
+    fn apply(&self, content_type: &str, body: Vec<u8>) -> Vec<u8> {
+        match *self {
+            BodyFilter::Replace(ref regex, ref replacement) => {
+                match regex.replace_all(&body, &replacement[..]) {
+                    Cow::Borrowed(..) => body,
+                    Cow::Owned(body) => body,
+                }
+            }
+            BodyFilter::Banner(ref html) if content_type.contains("text/html") => insert_banner(body, html),
+            BodyFilter::Redact(ref fields) if content_type.contains("json") => {
+                match serde_json::from_slice(&body) {
+                    Ok(mut value) => {
+                        redact_json(&mut value, fields);
+                        serde_json::to_vec(&value).unwrap_or(body)
+                    }
+                    Err(..) => body,
+                }
+            }
+            BodyFilter::Banner(..) | BodyFilter::Redact(..) => body,
+        }
+    }
+}
+
+/// Applies filters in order to the body with the given content type.
+pub fn apply(filters: &[BodyFilter], content_type: Option<&str>, body: Vec<u8>) -> Vec<u8> {
+    let content_type = content_type.unwrap_or("").to_ascii_lowercase();
+
+    filters.iter().fold(body, |body, filter| filter.apply(&content_type, body))
+}
+
+/// Returns the offset just after the opening `<body>` tag, or the beginning of the document if
+/// there is no such tag.
+fn body_offset(body: &[u8]) -> usize {
+    let lowercase = body.to_ascii_lowercase();
+
+    lowercase.windows(5)
+        .position(|window| window == b"<body")
+        .and_then(|pos| body[pos..].iter().position(|&byte| byte == b'>').map(|end| pos + end + 1))
+        .unwrap_or(0)
+}
+
+fn insert_banner(body: Vec<u8>, html: &[u8]) -> Vec<u8> {
+    let offset = body_offset(&body);
+
+    let mut result = Vec::with_capacity(body.len() + html.len());
+    result.extend_from_slice(&body[..offset]);
+    result.extend_from_slice(html);
+    result.extend_from_slice(&body[offset..]);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use serde_yaml;
+
+    use super::{apply, BodyFilter};
+
+    fn filters(cfg: &str) -> Vec<BodyFilter> {
+        let cfg: Vec<_> = serde_yaml::from_str(cfg).unwrap();
+        cfg.iter().map(|cfg| BodyFilter::new(cfg).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_replace() {
+        let filters = filters(r#"[{replace: {pattern: '\d{12}(\d{4})', replacement: '************$1'}}]"#);
+
+        assert_eq!(&b"card: ************1234"[..], &apply(&filters, None, b"card: 4000000000001234".to_vec())[..]);
+        assert_eq!(&b"no cards"[..], &apply(&filters, None, b"no cards".to_vec())[..]);
+    }
+
+    #[test]
+    fn test_banner() {
+        let filters = filters("[{banner: <p>beta</p>}]");
+
+        let body = b"<html><BODY class=\"x\"><h1>hi</h1></BODY></html>".to_vec();
+        assert_eq!(&b"<html><BODY class=\"x\"><p>beta</p><h1>hi</h1></BODY></html>"[..],
+            &apply(&filters, Some("text/html; charset=utf-8"), body)[..]);
+        assert_eq!(&b"<p>beta</p>hi"[..], &apply(&filters, Some("text/html"), b"hi".to_vec())[..]);
+        assert_eq!(&b"hi"[..], &apply(&filters, Some("text/plain"), b"hi".to_vec())[..]);
+    }
+
+    #[test]
+    fn test_redact() {
+        let filters = filters("[{redact: [password, token]}]");
+
+        let body = br#"{"user":"me","password":"secret","sessions":[{"token":"abc","id":1}]}"#.to_vec();
+        assert_eq!(&br#"{"password":"***","sessions":[{"id":1,"token":"***"}],"user":"me"}"#[..],
+            &apply(&filters, Some("application/json"), body)[..]);
+        assert_eq!(&b"{broken"[..], &apply(&filters, Some("application/json"), b"{broken".to_vec())[..]);
+    }
+}
@@ -29,6 +29,7 @@
 use std::collections::HashMap;
 use std::io::{self, ErrorKind};
 use std::mem;
+use std::sync::Arc;
 
 use futures::{future, stream, Future, Stream};
 use futures::sync::oneshot;
@@ -46,7 +47,7 @@ use cocaine::{self, Dispatch, EventGraph, GraphNode, Service};
 use cocaine::logging::{Severity, Log};
 
 use crate::pool::{Event, EventDispatch, Settings};
-use crate::route::{Match, Route};
+use crate::route::{Match, Route, Tenants};
 
 header! { (XJsonRpc, "X-Cocaine-JSON-RPC") => [i64] }
 
@@ -77,6 +78,7 @@ impl Into<Error> for ServerErrorCode {
 
 pub struct JsonRpc<L> {
     dispatcher: EventDispatch,
+    tenants: Arc<Tenants>,
     log: L,
 }
 
@@ -84,9 +86,16 @@ impl<L: Log> JsonRpc<L> {
     pub fn new(dispatcher: EventDispatch, log: L) -> Self {
         Self {
             dispatcher: dispatcher,
+            tenants: Arc::new(Tenants::new()),
             log: log,
         }
     }
+
+    /// Sets tenants, which requests were resolved into by the router.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
 }
 
 fn parse_method(method: &str) -> Result<(String, String), Error> {
@@ -374,7 +383,7 @@ impl<L: Log + Clone + Send + Sync + 'static> Route for JsonRpc<L> {
     fn process(&self, req: HttpRequest) -> Match<Self::Future> {
         if req.headers().has::<XJsonRpc>() {
             // TODO: Send 406 back if there are no "application/json or json-rpc Accept.
-            let d = self.dispatcher.clone();
+            let d = self.tenants.dispatcher(req.headers(), &self.dispatcher).clone();
             let log = self.log.clone();
 
             let future = req.body().concat2().and_then(move |data| {
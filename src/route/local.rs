@@ -0,0 +1,136 @@
+//! Local HTTP upstreams serving applications outside of the Cocaine.
+//!
+//! Services may be mapped onto plain HTTP servers listening on TCP or Unix sockets, which allows
+//! to migrate applications onto or off the Cocaine without changing anything at the edge. Requests
+//! to such services are routed, logged and measured exactly as others, but instead of being
+//! invoked via the Cocaine, they are forwarded as is, with their bodies streamed both ways.
+//!
+//! Upstream connections are kept alive and pooled per worker thread, which must be bound to its
+//! event loop before serving requests.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+
+use futures::{future, Future};
+use hyper::{self, Body, HttpVersion, Uri};
+use hyper::client::{Client, Request as ClientRequest};
+use hyper::header::{Connection, ContentLength};
+use hyper::server::Response;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use tokio_uds::UnixStream;
+
+use crate::net::Endpoint;
+use crate::route::app::RequestMeta;
+use crate::server::Io;
+
+thread_local! {
+    static HANDLE: RefCell<Option<Handle>> = RefCell::new(None);
+    static CLIENTS: RefCell<HashMap<String, Client<Connector>>> = RefCell::new(HashMap::new());
+}
+
+/// Binds the current worker thread to its event loop, on which upstream connections are made.
+pub fn bind(handle: &Handle) {
+    HANDLE.with(|current| *current.borrow_mut() = Some(handle.clone()));
+}
+
+/// Connects to the upstream endpoint regardless of the request URI.
+#[derive(Clone)]
+struct Connector {
+    endpoint: Endpoint,
+    handle: Handle,
+}
+
+impl Service for Connector {
+    type Request = Uri;
+    type Response = Box<dyn Io>;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Box<dyn Io>, Error = io::Error>>;
+
+    fn call(&self, _uri: Uri) -> Self::Future {
+        match self.endpoint {
+            Endpoint::Tcp(ref addr) => {
+                Box::new(TcpStream::connect(addr, &self.handle).map(|sock| Box::new(sock) as Box<dyn Io>))
+            }
+            Endpoint::Unix(ref path) => {
+                let sock = UnixStream::connect(path, &self.handle).map(|sock| Box::new(sock) as Box<dyn Io>);
+                Box::new(future::result(sock))
+            }
+        }
+    }
+}
+
+/// A plain HTTP server, into which requests are forwarded.
+#[derive(Clone, Debug)]
+pub struct LocalUpstream {
+    endpoint: Endpoint,
+}
+
+impl LocalUpstream {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self { endpoint: endpoint }
+    }
+
+    /// Returns the client connected to this upstream from the current worker thread.
+    fn client(&self) -> Result<Client<Connector>, hyper::Error> {
+        let handle = HANDLE.with(|handle| handle.borrow().clone()).ok_or_else(|| {
+            hyper::Error::Io(io::Error::new(io::ErrorKind::Other, "worker thread is not bound to an event loop"))
+        })?;
+
+        let client = CLIENTS.with(|clients| {
+            clients.borrow_mut()
+                .entry(self.endpoint.to_string())
+                .or_insert_with(|| {
+                    let connector = Connector {
+                        endpoint: self.endpoint.clone(),
+                        handle: handle.clone(),
+                    };
+                    Client::configure().connector(connector).build(&handle)
+                })
+                .clone()
+        });
+
+        Ok(client)
+    }
+
+    /// Forwards the request described by the meta frame with the given body, resolving with the
+    /// response and its size in bytes, if known in advance.
+    pub fn forward(&self, frame: &RequestMeta, body: Body) -> Box<dyn Future<Item = (Response, u64), Error = hyper::Error>> {
+        let client = match self.client() {
+            Ok(client) => client,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        // The authority is only a key of the connection pool, since the connector ignores it.
+        let uri = match format!("http://upstream{}", frame.uri).parse() {
+            Ok(uri) => uri,
+            Err(err) => return Box::new(future::err(hyper::Error::Uri(err))),
+        };
+
+        let mut req = ClientRequest::new(frame.method.clone(), uri);
+        req.set_version(HttpVersion::Http11);
+        for &(ref name, ref value) in &frame.headers {
+            req.headers_mut().append_raw(name.clone(), value.clone());
+        }
+        req.headers_mut().remove::<Connection>();
+        req.set_body(body);
+
+        let future = client.request(req).map(|resp| {
+            let size = resp.headers().get::<ContentLength>().map(|&ContentLength(len)| len).unwrap_or(0);
+
+            let mut headers = resp.headers().clone();
+            headers.remove::<Connection>();
+
+            let resp = Response::new()
+                .with_status(resp.status())
+                .with_headers(headers)
+                .with_body(resp.body());
+
+            (resp, size)
+        });
+
+        Box::new(future)
+    }
+}
@@ -6,14 +6,57 @@ use futures::{future, Future};
 use hyper::{self, StatusCode};
 use hyper::server::{Response, Request};
 
-pub use self::app::AppRoute;
+use crate::common::{XCocaineEvent, XCocaineService};
+
+pub use self::app::{AppRoute, CLIENT_CLOSED_REQUEST};
+pub use self::budget::RetryBudget;
+pub use self::canary::{Canaries, CanaryStats};
+pub use self::errors::ErrorStatuses;
+pub use self::failure::Failure;
+pub use self::filter::BodyFilter;
 pub use self::jsonrpc::JsonRpc;
-pub use self::perf::PerfRoute;
+pub use self::local::LocalUpstream;
+pub(crate) use self::local::bind as bind_local_upstreams;
+pub use self::peer::Peers;
+pub use self::perf::{PerfRoute, Sweep, SweepReport, run_sweep};
+pub use self::priority::Priorities;
+pub use self::quota::Quota;
+pub use self::rules::Rules;
+pub use self::signing::HeaderSigner;
+pub use self::sse::SseRoute;
+pub use self::table::RoutingTable;
+pub use self::tenant::{Tenant, Tenants};
+pub use self::timeout::drop_timed_out;
+pub use self::standby::{Standby, StandbyRoute};
+pub use self::vhost::VirtualHosts;
+pub use self::via::Via;
+pub use self::websocket::WebSocketRoute;
 
 mod app;
+mod budget;
+mod canary;
+mod codec;
+mod digest;
+mod errors;
+mod failure;
+mod filter;
 mod jsonrpc;
+mod local;
+mod peer;
 mod perf;
+mod priority;
+mod quota;
+mod rules;
 mod serialize;
+mod signing;
+mod sse;
+mod standby;
+mod table;
+mod tenant;
+mod timeout;
+mod vhost;
+mod via;
+mod websocket;
 
 /// Request matching.
 ///
@@ -53,6 +96,26 @@ impl<F> Match<F> {
     }
 }
 
+/// Extracts the service and the event of a long-lived connection from `X-Cocaine-Service` and
+/// `X-Cocaine-Event` headers, falling back to the `/<service>/<event>` path.
+pub(crate) fn target(req: &Request) -> Option<(String, String)> {
+    let service = req.headers().get::<XCocaineService>();
+    let event = req.headers().get::<XCocaineEvent>();
+
+    match (service, event) {
+        (Some(service), Some(event)) => Some((service.0.clone(), event.0.clone())),
+        (..) => {
+            let mut segments = req.path().trim_start_matches('/').splitn(3, '/');
+            match (segments.next(), segments.next()) {
+                (Some(service), Some(event)) if !service.is_empty() && !event.is_empty() => {
+                    Some((service.into(), event.into()))
+                }
+                (..) => None,
+            }
+        }
+    }
+}
+
 pub trait Route: Send + Sync {
     type Future: Future<Item = Response, Error = hyper::Error>;
 
@@ -72,11 +135,21 @@ pub type HyperRoute = Arc<dyn Route<Future = Box<dyn Future<Item = Response, Err
 #[derive(Clone)]
 pub struct Router {
     routes: Vec<HyperRoute>,
+    tenants: Arc<Tenants>,
 }
 
 impl Router {
     pub fn new() -> Self {
-        Self { routes: Vec::new() }
+        Self {
+            routes: Vec::new(),
+            tenants: Arc::new(Tenants::new()),
+        }
+    }
+
+    /// Sets tenants, which each request is resolved into before being passed to routes.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
     }
 
     /// Adds a route to the `Router`.
@@ -87,6 +160,10 @@ impl Router {
     /// Tries to process the request, returning a future on first route match. If none of them
     /// match, returns a ready future with `NotFound` HTTP status.
     pub fn process(&self, mut req: Request) -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+        if !self.tenants.is_empty() {
+            self.tenants.resolve(&mut req);
+        }
+
         for route in &self.routes {
             match route.process(req) {
                 Match::Some(future) => return future,
@@ -0,0 +1,93 @@
+//! Forwarding into peer proxies.
+//!
+//! During partial zone outages the local pool of a service may have no healthy endpoints, while
+//! proxies of other zones still reach the service just fine. Instead of answering 503, requests
+//! rejected by the open circuit breaker are forwarded as is into one of the configured peers. A
+//! forwarded request is marked with a special header and is never forwarded again, so peers with
+//! broken pools answer 503 themselves rather than bouncing requests between each other.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::Future;
+use hyper;
+use hyper::header::Headers;
+use hyper::server::{Request, Response};
+
+use crate::net::Endpoint;
+use crate::route::app::RequestMeta;
+use crate::route::local::LocalUpstream;
+use crate::route::signing;
+
+/// Header marking requests forwarded from another proxy.
+pub const FORWARDED_HEADER: &str = "X-Cocaine-Peer-Forwarded";
+
+/// Peer proxies, tried in turn.
+#[derive(Debug)]
+pub struct Peers {
+    upstreams: Vec<LocalUpstream>,
+    next: AtomicUsize,
+}
+
+impl Peers {
+    pub fn new(endpoints: &[Endpoint]) -> Self {
+        Self {
+            upstreams: endpoints.iter().cloned().map(LocalUpstream::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `true` if the request has already been forwarded by another proxy.
+    pub fn is_forwarded(headers: &Headers) -> bool {
+        headers.get_raw(FORWARDED_HEADER).is_some()
+    }
+
+    /// Returns the index of the peer the next request goes into.
+    fn pick(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len()
+    }
+
+    /// Forwards the request with its original URI into the next peer, resolving with the response
+    /// and its size in bytes, if known in advance.
+    ///
+    /// Extra headers, like `Via`, are appended to the request ones.
+    pub fn forward(&self, req: Request, headers: &[(String, String)])
+        -> Box<dyn Future<Item = (Response, u64), Error = hyper::Error>>
+    {
+        let mut frame = RequestMeta::new(&req, req.uri().to_string());
+        for &(ref name, ref value) in headers {
+            signing::set_header(&mut frame.headers, name, value.clone());
+        }
+        signing::set_header(&mut frame.headers, FORWARDED_HEADER, "1".into());
+
+        self.upstreams[self.pick()].forward(&frame, req.body())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::Headers;
+
+    use crate::net::Endpoint;
+
+    use super::{Peers, FORWARDED_HEADER};
+
+    #[test]
+    fn test_pick_rotates() {
+        let endpoints = vec![
+            Endpoint::Tcp("10.0.0.1:8080".parse().unwrap()),
+            Endpoint::Tcp("10.0.0.2:8080".parse().unwrap()),
+        ];
+        let peers = Peers::new(&endpoints);
+
+        assert_eq!(vec![0, 1, 0], vec![peers.pick(), peers.pick(), peers.pick()]);
+    }
+
+    #[test]
+    fn test_is_forwarded() {
+        let mut headers = Headers::new();
+        assert!(!Peers::is_forwarded(&headers));
+
+        headers.set_raw(FORWARDED_HEADER, "1");
+        assert!(Peers::is_forwarded(&headers));
+    }
+}
@@ -1,10 +1,14 @@
 //! Contain a route that is used primarily for performance measuring.
 //!
 //! Currently all requests are transformed into a Geobase requests.
+//!
+//! Also contains a concurrency sweep, which measures latency and throughput of a service at
+//! increasing concurrency levels through the same pools the real traffic uses.
 
 use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{future, stream, Future, Stream};
 use futures::sync::oneshot;
 
 use hyper::{self, StatusCode};
@@ -13,7 +17,9 @@ use hyper::server::{Response, Request};
 
 use cocaine::{self, Dispatch, Error, Service};
 use cocaine::logging::Logger;
-use cocaine::protocol::{Primitive, Flatten};
+use cocaine::protocol::{self, Primitive, Flatten};
+
+use rmps;
 
 use crate::logging::AccessLogger;
 use crate::pool::{Event, EventDispatch, Settings};
@@ -99,3 +105,216 @@ impl Dispatch for SingleChunkReadDispatch {
         drop(self.tx.send((res, body_len)));
     }
 }
+
+/// Concurrency sweep parameters.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    /// Target service name.
+    pub service: String,
+    /// Event invoked on each request.
+    pub event: String,
+    /// Optional single chunk sent before closing each request.
+    pub body: Option<String>,
+    /// Concurrency levels measured one after another.
+    pub levels: Vec<usize>,
+    /// Number of requests made at each level.
+    pub requests: usize,
+}
+
+/// Latency distribution in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct LatencyReport {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Measurements of a single concurrency level.
+#[derive(Clone, Debug, Serialize)]
+pub struct LevelReport {
+    pub concurrency: usize,
+    pub requests: usize,
+    pub errors: usize,
+    /// Wall time of the whole level in seconds.
+    pub duration: f64,
+    /// Completed requests per second.
+    pub throughput: f64,
+    pub latency: LatencyReport,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SweepReport {
+    pub service: String,
+    pub event: String,
+    pub levels: Vec<LevelReport>,
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    (duration.as_secs() * 1000000000 + duration.subsec_nanos() as u64) as f64 / 1e6
+}
+
+impl LevelReport {
+    /// Builds the report from per-request latencies and their outcomes.
+    fn new(concurrency: usize, elapsed: Duration, samples: Vec<(Duration, bool)>) -> Self {
+        let errors = samples.iter().filter(|&&(_, ok)| !ok).count();
+        let mut latencies = samples.into_iter()
+            .map(|(duration, _)| as_millis(duration))
+            .collect::<Vec<_>>();
+        latencies.sort_by(|a, b| a.partial_cmp(b).expect("latencies must not be NaN"));
+
+        let percentile = |p: f64| -> f64 {
+            if latencies.is_empty() {
+                return 0.0;
+            }
+            let id = ((latencies.len() as f64 * p).ceil() as usize).max(1) - 1;
+            latencies[id.min(latencies.len() - 1)]
+        };
+
+        let latency = LatencyReport {
+            min: latencies.first().cloned().unwrap_or_default(),
+            mean: if latencies.is_empty() { 0.0 } else { latencies.iter().sum::<f64>() / latencies.len() as f64 },
+            p50: percentile(0.50),
+            p90: percentile(0.90),
+            p99: percentile(0.99),
+            max: latencies.last().cloned().unwrap_or_default(),
+        };
+
+        let duration = as_millis(elapsed) / 1000.0;
+        let throughput = if duration > 0.0 { latencies.len() as f64 / duration } else { 0.0 };
+
+        Self {
+            concurrency: concurrency,
+            requests: latencies.len(),
+            errors: errors,
+            duration: duration,
+            throughput: throughput,
+            latency: latency,
+        }
+    }
+}
+
+/// Waits for the whole response stream, reporting whether it was closed without errors.
+struct SweepDispatch {
+    tx: oneshot::Sender<bool>,
+}
+
+impl Dispatch for SweepDispatch {
+    fn process(self: Box<Self>, response: &cocaine::Response) -> Option<Box<dyn Dispatch>> {
+        match response.deserialize::<protocol::Streaming<rmps::RawRef>>().flatten() {
+            Ok(Some(..)) => Some(self),
+            Ok(None) => {
+                drop(self.tx.send(true));
+                None
+            }
+            Err(..) => {
+                drop(self.tx.send(false));
+                None
+            }
+        }
+    }
+
+    fn discard(self: Box<Self>, _err: &Error) {
+        drop(self.tx.send(false));
+    }
+}
+
+/// Makes a single request, resolving with its latency and outcome.
+fn invoke(dispatcher: &EventDispatch, sweep: &Sweep) -> Box<dyn Future<Item = (Duration, bool), Error = ()>> {
+    let (tx, rx) = oneshot::channel();
+    let event = sweep.event.clone();
+    let body = sweep.body.clone();
+
+    let ev = Event::Service {
+        name: sweep.service.clone(),
+        func: Box::new(move |service: &Service, _settings: Settings| {
+            let body = body.clone();
+            let future = service.call(cocaine::Request::new(0, &[event.clone()]).unwrap(), SweepDispatch { tx: tx })
+                .and_then(move |tx| {
+                    if let Some(body) = body {
+                        tx.send(cocaine::Request::new(0, &[body]).unwrap());
+                    }
+                    tx.send(cocaine::Request::new(2, &[0; 0]).unwrap());
+                    Ok(())
+                })
+                .then(|_| Ok(()));
+
+            Box::new(future) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }),
+    };
+
+    let birth = Instant::now();
+    dispatcher.send(ev);
+
+    let future = rx.then(move |result| Ok::<_, ()>((birth.elapsed(), result.unwrap_or(false))));
+    Box::new(future)
+}
+
+/// Runs the sweep, measuring each concurrency level one after another.
+///
+/// Requests are scheduled into worker pools exactly like the real traffic, so the report reflects
+/// the production configuration, including pool limits and timeouts.
+pub fn run_sweep(dispatcher: EventDispatch, sweep: Sweep) -> Box<dyn Future<Item = SweepReport, Error = ()>> {
+    let levels = sweep.levels.clone();
+    let report = SweepReport {
+        service: sweep.service.clone(),
+        event: sweep.event.clone(),
+        levels: Vec::with_capacity(levels.len()),
+    };
+
+    let future = stream::iter_ok::<_, ()>(levels)
+        .and_then(move |concurrency| {
+            let dispatcher = dispatcher.clone();
+            let sweep = sweep.clone();
+
+            future::lazy(move || {
+                let birth = Instant::now();
+                stream::iter_ok::<_, ()>(0..sweep.requests)
+                    .map(move |_| invoke(&dispatcher, &sweep))
+                    .buffer_unordered(concurrency)
+                    .collect()
+                    .map(move |samples| LevelReport::new(concurrency, birth.elapsed(), samples))
+            })
+        })
+        .fold(report, |mut report, level| {
+            report.levels.push(level);
+            Ok::<_, ()>(report)
+        });
+
+    Box::new(future)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::LevelReport;
+
+    #[test]
+    fn test_level_report() {
+        let samples = (1..101)
+            .map(|ms| (Duration::from_millis(ms), ms % 10 != 0))
+            .collect();
+
+        let report = LevelReport::new(4, Duration::from_secs(2), samples);
+        assert_eq!(100, report.requests);
+        assert_eq!(10, report.errors);
+        assert_eq!(50.0, report.throughput);
+        assert_eq!(1.0, report.latency.min);
+        assert_eq!(50.0, report.latency.p50);
+        assert_eq!(90.0, report.latency.p90);
+        assert_eq!(99.0, report.latency.p99);
+        assert_eq!(100.0, report.latency.max);
+        assert_eq!(50.5, report.latency.mean);
+    }
+
+    #[test]
+    fn test_empty_level_report() {
+        let report = LevelReport::new(1, Duration::from_secs(0), Vec::new());
+        assert_eq!(0, report.requests);
+        assert_eq!(0.0, report.throughput);
+        assert_eq!(0.0, report.latency.p99);
+    }
+}
@@ -0,0 +1,109 @@
+//! Priority classes of requests.
+//!
+//! Requests are classified by their service, tenant or headers, and services pools process queued
+//! events of higher classes first while saturated. Each class may have its own backlog threshold,
+//! at which its requests are shed with 503 instead of being queued, so that background traffic is
+//! dropped long before the critical one.
+
+use std::collections::HashSet;
+
+use hyper::header::Headers;
+
+use crate::config::{PrioritiesConfig, PriorityClassConfig};
+
+/// Name of the implicit lowest priority class of requests matching no configured one.
+pub const DEFAULT_CLASS: &str = "default";
+
+#[derive(Clone, Debug)]
+struct PriorityClass {
+    name: String,
+    services: HashSet<String>,
+    tenants: HashSet<String>,
+    headers: Vec<(String, Option<String>)>,
+}
+
+impl PriorityClass {
+    fn matches(&self, service: &str, tenant: Option<&str>, headers: &Headers) -> bool {
+        self.services.contains(service) ||
+            tenant.map(|tenant| self.tenants.contains(tenant)).unwrap_or(false) ||
+            self.headers.iter().any(|&(ref name, ref expected)| {
+                match (headers.get_raw(name).and_then(|raw| raw.one()), expected) {
+                    (Some(actual), &Some(ref expected)) => actual == expected.as_bytes(),
+                    (Some(..), &None) => true,
+                    (None, ..) => false,
+                }
+            })
+    }
+}
+
+impl<'a> From<&'a PriorityClassConfig> for PriorityClass {
+    fn from(cfg: &'a PriorityClassConfig) -> Self {
+        Self {
+            name: cfg.name().to_owned(),
+            services: cfg.services().iter().cloned().collect(),
+            tenants: cfg.tenants().iter().cloned().collect(),
+            headers: cfg.headers().iter()
+                .map(|header| (header.name().to_owned(), header.value().map(|v| v.to_owned())))
+                .collect(),
+        }
+    }
+}
+
+/// Priority classes ordered from the highest one.
+#[derive(Clone, Debug)]
+pub struct Priorities {
+    classes: Vec<PriorityClass>,
+}
+
+impl Priorities {
+    /// Returns the index and the name of the first class matching the request, falling back to
+    /// the implicit lowest one.
+    pub fn classify(&self, service: &str, tenant: Option<&str>, headers: &Headers) -> (usize, &str) {
+        self.classes.iter()
+            .enumerate()
+            .find(|&(.., class)| class.matches(service, tenant, headers))
+            .map(|(id, class)| (id, class.name.as_str()))
+            .unwrap_or((self.classes.len(), DEFAULT_CLASS))
+    }
+}
+
+impl<'a> From<&'a PrioritiesConfig> for Priorities {
+    fn from(cfg: &'a PrioritiesConfig) -> Self {
+        Self {
+            classes: cfg.classes().iter().map(PriorityClass::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::Headers;
+    use serde_yaml;
+
+    use crate::config::PrioritiesConfig;
+
+    use super::Priorities;
+
+    #[test]
+    fn test_classify() {
+        let cfg: PrioritiesConfig = serde_yaml::from_str(r#"
+            classes:
+              - name: critical
+                services: [payments]
+                headers: [{name: X-Priority, value: high}]
+              - name: background
+                tenants: [batch]
+        "#).unwrap();
+        let priorities = Priorities::from(&cfg);
+
+        let mut headers = Headers::new();
+        assert_eq!((0, "critical"), priorities.classify("payments", Some("batch"), &headers));
+        assert_eq!((1, "background"), priorities.classify("app", Some("batch"), &headers));
+        assert_eq!((2, "default"), priorities.classify("app", None, &headers));
+
+        headers.set_raw("X-Priority", "high");
+        assert_eq!((0, "critical"), priorities.classify("app", None, &headers));
+        headers.set_raw("X-Priority", "low");
+        assert_eq!((2, "default"), priorities.classify("app", None, &headers));
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::config::QuotaConfig;
+
+/// Token bucket, refilled continuously with the given rate.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    timestamp: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        // Allow bursts of one second worth of requests, but at least a single request.
+        let burst = rate.max(1.0);
+
+        Self {
+            rate: rate,
+            burst: burst,
+            tokens: burst,
+            timestamp: Instant::now(),
+        }
+    }
+
+    fn acquire(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.timestamp);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.timestamp = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Request-rate and concurrency limits shared between all worker threads.
+#[derive(Debug)]
+pub struct Quota {
+    bucket: Option<Mutex<Bucket>>,
+    concurrency: Option<usize>,
+    active: AtomicUsize,
+}
+
+impl Quota {
+    pub fn new(rate: Option<f64>, concurrency: Option<usize>) -> Self {
+        Self {
+            bucket: rate.map(|rate| Mutex::new(Bucket::new(rate))),
+            concurrency: concurrency,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Tries to admit a new request, returning a permit that must be kept alive until the request
+    /// is finished.
+    ///
+    /// Returns `None` if either of limits is exceeded.
+    pub fn acquire(quota: &Arc<Quota>) -> Option<QuotaPermit> {
+        let active = quota.active.fetch_add(1, Ordering::SeqCst);
+        let permit = QuotaPermit { quota: quota.clone() };
+
+        if let Some(concurrency) = quota.concurrency {
+            if active >= concurrency {
+                return None;
+            }
+        }
+
+        if let Some(ref bucket) = quota.bucket {
+            if !bucket.lock().unwrap().acquire(Instant::now()) {
+                return None;
+            }
+        }
+
+        Some(permit)
+    }
+
+    /// Returns the number of requests being processed now.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+impl<'a> From<&'a QuotaConfig> for Quota {
+    fn from(cfg: &'a QuotaConfig) -> Self {
+        Quota::new(cfg.rate(), cfg.concurrency())
+    }
+}
+
+/// Releases a concurrency slot on drop.
+#[derive(Debug)]
+pub struct QuotaPermit {
+    quota: Arc<Quota>,
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        self.quota.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{Bucket, Quota};
+
+    #[test]
+    fn test_bucket_refill() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2.0);
+        bucket.timestamp = now;
+
+        assert!(bucket.acquire(now));
+        assert!(bucket.acquire(now));
+        assert!(!bucket.acquire(now));
+
+        assert!(bucket.acquire(now + Duration::from_millis(500)));
+        assert!(!bucket.acquire(now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let quota = Arc::new(Quota::new(None, Some(2)));
+
+        let p1 = Quota::acquire(&quota).unwrap();
+        let p2 = Quota::acquire(&quota).unwrap();
+        assert!(Quota::acquire(&quota).is_none());
+        assert_eq!(2, quota.active());
+
+        drop(p1);
+        let p3 = Quota::acquire(&quota).unwrap();
+        assert_eq!(2, quota.active());
+
+        drop(p2);
+        drop(p3);
+        assert_eq!(0, quota.active());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let quota = Arc::new(Quota::new(None, None));
+        let permits = (0..1000).map(|_| Quota::acquire(&quota).unwrap()).collect::<Vec<_>>();
+        assert_eq!(1000, permits.len());
+    }
+}
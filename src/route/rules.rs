@@ -0,0 +1,312 @@
+//! Routing rules that redirect requests addressed to a service into another destination service
+//! when all of their predicates match.
+//!
+//! This is mainly used to pin users to experiments, for example by routing requests that carry
+//! a special cookie into a separate application version. Rules may also be limited to daily time
+//! windows, like routing into a degraded version of an application during nightly maintenance.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{Cookie, Headers};
+
+use crate::config::{CookiePredicateConfig, RuleConfig, TimeWindowConfig, UtcOffset};
+
+const SECONDS_IN_DAY: i64 = 86400;
+
+/// A single condition evaluated against an HTTP request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    /// Matches when the cookie with the given name is present and, if specified, its value equals
+    /// to the given one.
+    Cookie {
+        name: String,
+        value: Option<String>,
+    },
+}
+
+impl Predicate {
+    /// Returns `true` if this predicate matches the given request headers.
+    pub fn matches(&self, headers: &Headers) -> bool {
+        match *self {
+            Predicate::Cookie { ref name, ref value } => {
+                let cookie = headers.get::<Cookie>().and_then(|cookie| cookie.get(name));
+
+                match (cookie, value) {
+                    (Some(actual), &Some(ref expected)) => actual == expected,
+                    (Some(..), &None) => true,
+                    (None, ..) => false,
+                }
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a CookiePredicateConfig> for Predicate {
+    fn from(cfg: &'a CookiePredicateConfig) -> Self {
+        Predicate::Cookie {
+            name: cfg.name().to_owned(),
+            value: cfg.value().map(|v| v.to_owned()),
+        }
+    }
+}
+
+/// A daily time window in local time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeWindow {
+    /// Minutes since midnight, inclusive.
+    from: u32,
+    /// Minutes since midnight, exclusive.
+    to: u32,
+    /// Days the window starts at, numbered from Sunday. Empty means every day.
+    days: Vec<u32>,
+}
+
+impl TimeWindow {
+    fn starts_at(&self, day: u32) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Returns `true` if the given week day and minute of the day fall into this window.
+    pub fn contains(&self, day: u32, minute: u32) -> bool {
+        if self.from <= self.to {
+            self.starts_at(day) && minute >= self.from && minute < self.to
+        } else {
+            // Spans midnight, so the tail belongs to the window started the day before.
+            (self.starts_at(day) && minute >= self.from) || (self.starts_at((day + 6) % 7) && minute < self.to)
+        }
+    }
+}
+
+impl<'a> From<&'a TimeWindowConfig> for TimeWindow {
+    fn from(cfg: &'a TimeWindowConfig) -> Self {
+        Self {
+            from: cfg.from().minutes(),
+            to: cfg.to().minutes(),
+            days: cfg.days().iter().map(|&day| day as u32).collect(),
+        }
+    }
+}
+
+/// Returns the week day, numbered from Sunday, and the minute of the day of the given time in the
+/// given timezone.
+fn local_time(now: SystemTime, timezone: UtcOffset) -> (u32, u32) {
+    let secs = match now.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    };
+    let secs = secs + timezone.seconds() as i64;
+
+    let days = secs.div_euclid(SECONDS_IN_DAY);
+    let minute = secs.rem_euclid(SECONDS_IN_DAY) / 60;
+
+    // The epoch is Thursday.
+    ((days + 4).rem_euclid(7) as u32, minute as u32)
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    service: String,
+    target: String,
+    predicates: Vec<Predicate>,
+    windows: Vec<TimeWindow>,
+    /// Whether the current time falls into one of windows as of the last update, shared between
+    /// clones.
+    active: Arc<AtomicBool>,
+}
+
+impl Rule {
+    fn is_active(&self) -> bool {
+        self.windows.is_empty() || self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// A rule, whose time window has been activated or deactivated.
+#[derive(Debug, PartialEq)]
+pub struct Transition<'a> {
+    pub service: &'a str,
+    pub target: &'a str,
+    pub active: bool,
+}
+
+/// An ordered list of routing rules.
+///
+/// Rules are evaluated in order, the first one matching wins. Rules limited to time windows are
+/// skipped unless one of their windows was active as of the last update.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    timezone: UtcOffset,
+}
+
+impl Rules {
+    /// Constructs rules, whose time windows are evaluated in the given timezone.
+    pub fn new(cfg: &[RuleConfig], timezone: UtcOffset) -> Self {
+        let mut rules = Self::from(cfg);
+        rules.timezone = timezone;
+        rules
+    }
+
+    /// Re-evaluates time windows at the given time, returning rules that have been activated or
+    /// deactivated since the last update.
+    pub fn update(&self, now: SystemTime) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        if self.rules.iter().all(|rule| rule.windows.is_empty()) {
+            return transitions;
+        }
+
+        let (day, minute) = local_time(now, self.timezone);
+        for rule in self.rules.iter().filter(|rule| !rule.windows.is_empty()) {
+            let active = rule.windows.iter().any(|window| window.contains(day, minute));
+            if rule.active.swap(active, Ordering::Relaxed) != active {
+                transitions.push(Transition {
+                    service: &rule.service,
+                    target: &rule.target,
+                    active: active,
+                });
+            }
+        }
+
+        transitions
+    }
+
+    /// Selects a destination service for a request addressed to the given service, returning
+    /// `None` if no rule matches.
+    pub fn select(&self, service: &str, headers: &Headers) -> Option<&str> {
+        self.rules.iter()
+            .filter(|rule| rule.service == service && rule.is_active())
+            .find(|rule| rule.predicates.iter().all(|predicate| predicate.matches(headers)))
+            .map(|rule| rule.target.as_str())
+    }
+}
+
+impl<'a> From<&'a [RuleConfig]> for Rules {
+    fn from(cfg: &'a [RuleConfig]) -> Self {
+        let rules = cfg.iter()
+            .map(|rule| {
+                Rule {
+                    service: rule.service().to_owned(),
+                    target: rule.target().to_owned(),
+                    predicates: rule.cookies().iter().map(Predicate::from).collect(),
+                    windows: rule.windows().iter().map(TimeWindow::from).collect(),
+                    active: Arc::new(AtomicBool::new(false)),
+                }
+            })
+            .collect();
+
+        Self {
+            rules: rules,
+            timezone: UtcOffset::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use hyper::header::{Cookie, Headers};
+    use serde_yaml;
+
+    use crate::config::RuleConfig;
+
+    use super::{Predicate, Rule, Rules, TimeWindow, Transition};
+
+    fn cookie(name: &str, value: Option<&str>) -> Predicate {
+        Predicate::Cookie {
+            name: name.into(),
+            value: value.map(|v| v.into()),
+        }
+    }
+
+    fn headers(cookies: &[(&'static str, &'static str)]) -> Headers {
+        let mut cookie = Cookie::new();
+        for &(name, value) in cookies {
+            cookie.append(name, value);
+        }
+
+        let mut headers = Headers::new();
+        headers.set(cookie);
+        headers
+    }
+
+    #[test]
+    fn test_cookie_presence() {
+        assert!(cookie("exp", None).matches(&headers(&[("exp", "1")])));
+        assert!(!cookie("exp", None).matches(&headers(&[("session", "1")])));
+        assert!(!cookie("exp", None).matches(&Headers::new()));
+    }
+
+    #[test]
+    fn test_cookie_value() {
+        assert!(cookie("exp", Some("42")).matches(&headers(&[("sid", "1"), ("exp", "42")])));
+        assert!(!cookie("exp", Some("42")).matches(&headers(&[("exp", "420")])));
+    }
+
+    #[test]
+    fn test_cookie_name_is_not_substring_matched() {
+        assert!(!cookie("exp", None).matches(&headers(&[("my-exp", "1")])));
+    }
+
+    #[test]
+    fn test_rules_select() {
+        let rules = Rules {
+            rules: vec![
+                Rule {
+                    service: "app".into(),
+                    target: "app-exp".into(),
+                    predicates: vec![cookie("exp", Some("1"))],
+                    windows: Vec::new(),
+                    active: Default::default(),
+                },
+                Rule {
+                    service: "app".into(),
+                    target: "app-beta".into(),
+                    predicates: vec![cookie("beta", None)],
+                    windows: Vec::new(),
+                    active: Default::default(),
+                },
+            ],
+            timezone: Default::default(),
+        };
+
+        assert_eq!(Some("app-exp"), rules.select("app", &headers(&[("exp", "1"), ("beta", "1")])));
+        assert_eq!(Some("app-beta"), rules.select("app", &headers(&[("beta", "1")])));
+        assert_eq!(None, rules.select("app", &headers(&[("exp", "2")])));
+        assert_eq!(None, rules.select("other", &headers(&[("exp", "1")])));
+    }
+
+    #[test]
+    fn test_time_window_spanning_midnight() {
+        // From Friday 23:00 till Saturday 02:00.
+        let window = TimeWindow { from: 23 * 60, to: 2 * 60, days: vec![5] };
+
+        assert!(window.contains(5, 23 * 60 + 30));
+        assert!(window.contains(6, 60));
+        assert!(!window.contains(6, 2 * 60));
+        assert!(!window.contains(6, 23 * 60 + 30));
+        assert!(!window.contains(5, 60));
+    }
+
+    #[test]
+    fn test_rules_update() {
+        let cfg: Vec<RuleConfig> = serde_yaml::from_str(r#"
+            - service: app
+              target: app-batchoff
+              windows: [{from: "01:00", to: "05:00"}]
+        "#).unwrap();
+        let rules = Rules::new(&cfg, "+03:00".parse().unwrap());
+
+        // 1500000000 is Friday 02:40 UTC, i.e. 05:40 in the configured timezone.
+        let now = UNIX_EPOCH + Duration::from_secs(1500000000);
+        assert!(rules.update(now).is_empty());
+        assert_eq!(None, rules.select("app", &Headers::new()));
+
+        let now = now - Duration::from_secs(3600);
+        let activated = Transition { service: "app", target: "app-batchoff", active: true };
+        assert_eq!(vec![activated], rules.update(now));
+        assert_eq!(Some("app-batchoff"), rules.select("app", &Headers::new()));
+        assert!(rules.update(now).is_empty());
+    }
+}
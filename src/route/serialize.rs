@@ -107,8 +107,12 @@ where
     }
 
     #[inline]
-    fn serialize_bytes(self, _value: &[u8]) -> Result<Self::Ok, Self::Error> {
-        unreachable!()
+    fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Self::Error> {
+        // There were no binaries in MessagePack v4 either, they are written only for applications
+        // configured to expect them.
+        rmp::encode::write_bin_len(&mut self.wr, value.len() as u32)?;
+        self.wr.write_all(value).map_err(ValueWriteError::InvalidDataWrite)?;
+        Ok(())
     }
 
     #[inline]
@@ -0,0 +1,192 @@
+//! Signing of headers the proxy vouches for.
+//!
+//! Applications behind the proxy may be called directly by internal callers, which can set any
+//! headers they like. To let applications tell genuine values from spoofed ones, the proxy signs
+//! selected headers with HMAC-SHA256 using a key shared with applications.
+//!
+//! The signature is carried in a separate header in `t=<timestamp>,v1=<hex>` format, where the
+//! timestamp is in milliseconds since UNIX epoch. The signed message consists of the timestamp
+//! followed by `<lowercase name>:<value>` lines for each value of each signed header in the
+//! configured order, each terminated with `\n`. Absent headers are signed as `<lowercase name>`
+//! lines without a colon, which distinguishes them from headers with empty values.
+//!
+//! Values supplied by clients are never signed: the route strips all signed headers from the
+//! request before setting the ones the proxy derives itself.
+
+use std::fmt::{self, Debug, Formatter};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::SigningConfig;
+
+/// Header with the client address as seen by the proxy.
+pub const REAL_IP_HEADER: &str = "X-Real-IP";
+/// Header with the name of the tenant the request was routed into.
+pub const TENANT_HEADER: &str = "X-Cocaine-Tenant";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct HeaderSigner {
+    key: Vec<u8>,
+    header: String,
+    headers: Vec<String>,
+}
+
+impl HeaderSigner {
+    pub fn new(key: Vec<u8>, header: String, headers: Vec<String>) -> Self {
+        Self {
+            key: key,
+            header: header,
+            headers: headers,
+        }
+    }
+
+    /// Removes the signature header and all signed headers, so that values supplied by the
+    /// client can't be vouched for.
+    pub fn strip(&self, headers: &mut Vec<(String, String)>) {
+        headers.retain(|&(ref name, ..)| {
+            !name.eq_ignore_ascii_case(&self.header) &&
+                !self.headers.iter().any(|signed| name.eq_ignore_ascii_case(signed))
+        });
+    }
+
+    /// Signs the given headers, replacing any existing signature header.
+    pub fn sign(&self, headers: &mut Vec<(String, String)>, timestamp: u64) {
+        headers.retain(|&(ref name, ..)| !name.eq_ignore_ascii_case(&self.header));
+
+        let signature = self.signature(headers, timestamp);
+        headers.push((self.header.clone(), format!("t={},v1={}", timestamp, signature)));
+    }
+
+    fn signature(&self, headers: &[(String, String)], timestamp: u64) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}\n", timestamp).as_bytes());
+
+        for name in &self.headers {
+            let name = name.to_lowercase();
+            let mut values = headers.iter()
+                .filter(|&&(ref header, ..)| header.eq_ignore_ascii_case(&name))
+                .map(|&(.., ref value)| value.as_str())
+                .peekable();
+
+            if values.peek().is_none() {
+                mac.update(format!("{}\n", name).as_bytes());
+            }
+            for value in values {
+                mac.update(format!("{}:{}\n", name, value).as_bytes());
+            }
+        }
+
+        hex(&mac.finalize().into_bytes())
+    }
+}
+
+impl Debug for HeaderSigner {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("HeaderSigner")
+            .field("key", &"<...>")
+            .field("header", &self.header)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl<'a> From<&'a SigningConfig> for HeaderSigner {
+    fn from(cfg: &'a SigningConfig) -> Self {
+        HeaderSigner::new(cfg.key().as_bytes().to_vec(), cfg.header().to_owned(), cfg.headers().to_vec())
+    }
+}
+
+/// Replaces all headers with the given name with a single one.
+pub fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: String) {
+    headers.retain(|&(ref header, ..)| !header.eq_ignore_ascii_case(name));
+    headers.push((name.to_owned(), value));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HeaderSigner, hex, set_header};
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            hex(&mac.finalize().into_bytes()));
+    }
+
+    #[test]
+    fn test_sign() {
+        let signer = HeaderSigner::new(b"secret".to_vec(), "X-Signature".into(),
+            vec!["X-Real-IP".into(), "X-User".into()]);
+
+        let mut headers = vec![
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+            ("x-real-ip".to_owned(), "10.0.0.1".to_owned()),
+            ("x-signature".to_owned(), "spoofed".to_owned()),
+        ];
+        signer.sign(&mut headers, 1500000000000);
+
+        let signatures = headers.iter().filter(|&&(ref name, ..)| name.eq_ignore_ascii_case("X-Signature")).collect::<Vec<_>>();
+        assert_eq!(1, signatures.len());
+        assert!(signatures[0].1.starts_with("t=1500000000000,v1="));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(b"1500000000000\nx-real-ip:10.0.0.1\nx-user\n");
+        assert_eq!(format!("t=1500000000000,v1={}", hex(&mac.finalize().into_bytes())), signatures[0].1);
+    }
+
+    #[test]
+    fn test_sign_distinguishes_absent_from_empty() {
+        let signer = HeaderSigner::new(b"secret".to_vec(), "X-Signature".into(), vec!["X-User".into()]);
+
+        let mut absent = Vec::new();
+        signer.sign(&mut absent, 1500000000000);
+        let mut empty = vec![("X-User".to_owned(), "".to_owned())];
+        signer.sign(&mut empty, 1500000000000);
+
+        assert_ne!(absent.last(), empty.last());
+    }
+
+    #[test]
+    fn test_sign_covers_duplicates() {
+        let signer = HeaderSigner::new(b"secret".to_vec(), "X-Signature".into(), vec!["X-User".into()]);
+
+        let mut single = vec![("X-User".to_owned(), "alice".to_owned())];
+        signer.sign(&mut single, 1500000000000);
+        let mut duplicated = vec![("X-User".to_owned(), "alice".to_owned()), ("x-user".to_owned(), "eve".to_owned())];
+        signer.sign(&mut duplicated, 1500000000000);
+
+        assert_ne!(single.last(), duplicated.last());
+    }
+
+    #[test]
+    fn test_strip() {
+        let signer = HeaderSigner::new(b"secret".to_vec(), "X-Signature".into(), vec!["X-User".into()]);
+
+        let mut headers = vec![
+            ("Content-Type".to_owned(), "text/plain".to_owned()),
+            ("x-user".to_owned(), "spoofed".to_owned()),
+            ("X-User".to_owned(), "spoofed".to_owned()),
+            ("x-signature".to_owned(), "spoofed".to_owned()),
+        ];
+        signer.strip(&mut headers);
+        assert_eq!(vec![("Content-Type".to_owned(), "text/plain".to_owned())], headers);
+    }
+
+    #[test]
+    fn test_set_header_replaces_spoofed() {
+        let mut headers = vec![("x-real-ip".to_owned(), "1.1.1.1".to_owned())];
+        set_header(&mut headers, "X-Real-IP", "10.0.0.1".into());
+        assert_eq!(vec![("X-Real-IP".to_owned(), "10.0.0.1".to_owned())], headers);
+    }
+}
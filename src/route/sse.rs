@@ -0,0 +1,185 @@
+//! Server-Sent Events bridging from Cocaine streaming applications.
+//!
+//! Requests accepting `text/event-stream` are routed by `X-Cocaine-Service` and `X-Cocaine-Event`
+//! headers, falling back to the `/<service>/<event>` path. The application receives the request
+//! meta frame as the only chunk, while each chunk written by the application is sent to the client
+//! as a single event. The connection is kept open until the application closes its channel.
+//!
+//! Application errors are sent as a final `error` event, because the response status has already
+//! been sent by then.
+
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future, Sink, Stream};
+use futures::sync::mpsc;
+
+use hyper::{self, Body, Chunk, Method, StatusCode};
+use hyper::server::{Request, Response};
+
+use cocaine::{self, Dispatch, Service};
+use cocaine::hpack::{self, Header as CocaineHeader};
+use cocaine::logging::{Log, Severity};
+use cocaine::protocol::{self, Flatten};
+
+use rmps;
+
+use crate::common::XRequestId;
+use crate::config::SseConfig;
+use crate::pool::{Event, EventDispatch, Settings};
+use crate::random;
+use crate::route::{self, serialize, Match, Route, Tenants};
+use crate::route::app::{self, RequestMeta};
+
+const CONTENT_TYPE: &str = "text/event-stream";
+
+/// Encodes the chunk as a single event, splitting it into `data` lines.
+fn encode(event: Option<&str>, data: &[u8]) -> Vec<u8> {
+    let data = String::from_utf8_lossy(data);
+
+    let mut buf = String::with_capacity(data.len() + 8);
+    if let Some(event) = event {
+        buf.push_str("event: ");
+        buf.push_str(event);
+        buf.push('\n');
+    }
+    for line in data.split('\n') {
+        buf.push_str("data: ");
+        buf.push_str(line.trim_end_matches('\r'));
+        buf.push('\n');
+    }
+    buf.push('\n');
+
+    buf.into_bytes()
+}
+
+/// Streams application chunks as events, finishing the body once the channel is closed.
+struct SseDispatch {
+    tx: mpsc::UnboundedSender<Chunk>,
+}
+
+impl Dispatch for SseDispatch {
+    fn process(self: Box<Self>, response: &cocaine::Response) -> Option<Box<dyn Dispatch>> {
+        match response.deserialize::<protocol::Streaming<rmps::RawRef>>().flatten() {
+            Ok(Some(data)) => {
+                // The body is dropped only when the client has gone away.
+                if self.tx.unbounded_send(encode(None, data.as_bytes()).into()).is_err() {
+                    return None;
+                }
+                Some(self)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                drop(self.tx.unbounded_send(encode(Some("error"), err.to_string().as_bytes()).into()));
+                None
+            }
+        }
+    }
+
+    fn discard(self: Box<Self>, err: &cocaine::Error) {
+        drop(self.tx.unbounded_send(encode(Some("error"), err.to_string().as_bytes()).into()));
+    }
+}
+
+/// A route bridging event streams.
+pub struct SseRoute<L> {
+    dispatcher: EventDispatch,
+    tenants: Arc<Tenants>,
+    config: SseConfig,
+    log: L,
+}
+
+impl<L: Log + Clone + Send + Sync + 'static> SseRoute<L> {
+    pub fn new(dispatcher: EventDispatch, config: SseConfig, log: L) -> Self {
+        Self {
+            dispatcher: dispatcher,
+            tenants: Arc::new(Tenants::new()),
+            config: config,
+            log: log,
+        }
+    }
+
+    /// Sets tenants, which requests were resolved into by the router.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+}
+
+impl<L: Log + Clone + Send + Sync + 'static> Route for SseRoute<L> {
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn process(&self, req: Request) -> Match<Self::Future> {
+        let accepted = req.headers().get_raw("Accept")
+            .map(|raw| raw.iter().any(|line| String::from_utf8_lossy(line).contains(CONTENT_TYPE)))
+            .unwrap_or(false);
+
+        let (service, event) = match route::target(&req) {
+            Some(target) if accepted && *req.method() == Method::Get => target,
+            Some(..) | None => return Match::None(req),
+        };
+
+        let trace = random::gen::<u64>();
+        let meta = serialize::to_vec(&RequestMeta::new(&req, req.uri().to_string())).unwrap();
+
+        cocaine_log!(self.log, Severity::Info, "accepted event stream"; {
+            service: service,
+            event: event,
+            trace: trace,
+        });
+
+        let (body_tx, body) = Body::pair();
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(retry) = self.config.retry() {
+            let millis = retry.as_secs() * 1000 + retry.subsec_millis() as u64;
+            drop(tx.unbounded_send(format!("retry: {}\n\n", millis).into()));
+        }
+
+        // Failures here mean that the client has gone away, which is noticed by the dispatch.
+        let forward = rx.map(Ok).forward(body_tx.sink_map_err(drop)).then(|_| Ok::<(), ()>(()));
+
+        let parts = Arc::new(Mutex::new(Some((tx, forward))));
+        let ev = Event::Service {
+            name: service,
+            func: Box::new(move |service: &Service, _settings: Settings| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                let (tx, forward) = match parts.lock().unwrap().take() {
+                    Some(parts) => parts,
+                    None => return Box::new(future::ok(())),
+                };
+                let meta = meta.clone();
+                let headers = vec![hpack::TraceId(trace).into_raw(), hpack::SpanId(trace).into_raw()];
+                let req = cocaine::Request::new(0, &[event.clone()]).unwrap()
+                    .add_headers(headers);
+
+                let future = service.call(req, SseDispatch { tx: tx }).and_then(move |tx| {
+                    tx.send(app::make_chunk(&meta));
+                    tx.send(cocaine::Request::new(2, &[0; 0]).unwrap());
+                    Ok(())
+                }).then(|_| Ok::<(), ()>(()));
+
+                Box::new(future.join(forward).map(drop))
+            }),
+        };
+        self.tenants.dispatcher(req.headers(), &self.dispatcher).send(ev);
+
+        let mut resp = Response::new()
+            .with_status(StatusCode::Ok)
+            .with_header(XRequestId(trace))
+            .with_body(body);
+        resp.headers_mut().set_raw("Content-Type", CONTENT_TYPE);
+        resp.headers_mut().set_raw("Cache-Control", "no-cache");
+
+        Match::Some(Box::new(future::ok(resp)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::encode;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(&b"data: hello\n\n"[..], &encode(None, b"hello")[..]);
+        assert_eq!(&b"data: a\ndata: b\ndata: \n\n"[..], &encode(None, b"a\r\nb\n")[..]);
+        assert_eq!(&b"event: error\ndata: failed\n\n"[..], &encode(Some("error"), b"failed")[..]);
+    }
+}
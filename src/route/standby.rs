@@ -0,0 +1,84 @@
+//! Warm standby mode.
+//!
+//! A standby proxy initializes completely, i.e. connects to the locator and warms pools of the
+//! configured services, but answers 503 to all traffic until it's promoted either through the
+//! monitoring server or a Unicorn flag. This allows failover instances to take over without
+//! paying cold-start costs. Promotion is one-way, returning into standby requires a restart.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::{future, Future};
+
+use hyper::{self, StatusCode};
+use hyper::server::{Request, Response};
+
+use crate::common::XErrorGeneratedBy;
+use crate::route::{Match, Route};
+
+/// A switch shared between the standby route and the means of promotion.
+#[derive(Clone, Debug)]
+pub struct Standby {
+    active: Arc<AtomicBool>,
+}
+
+impl Standby {
+    /// Constructs the switch, which is already active unless the proxy starts in standby.
+    pub fn new(active: bool) -> Self {
+        Self { active: Arc::new(AtomicBool::new(active)) }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Promotes the proxy into serving traffic, returning `true` if it was in standby.
+    pub fn activate(&self) -> bool {
+        !self.active.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// A route answering 503 to all requests while the proxy is in standby.
+#[derive(Debug)]
+pub struct StandbyRoute {
+    standby: Standby,
+}
+
+impl StandbyRoute {
+    pub fn new(standby: Standby) -> Self {
+        Self { standby: standby }
+    }
+}
+
+impl Route for StandbyRoute {
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn process(&self, req: Request) -> Match<Self::Future> {
+        if self.standby.is_active() {
+            return Match::None(req);
+        }
+
+        let resp = Response::new()
+            .with_status(StatusCode::ServiceUnavailable)
+            .with_header(XErrorGeneratedBy::proxy())
+            .with_body("the proxy is in warm standby");
+
+        Match::Some(Box::new(future::ok(resp)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Standby;
+
+    #[test]
+    fn test_activate() {
+        let standby = Standby::new(false);
+        let clone = standby.clone();
+        assert!(!clone.is_active());
+
+        assert!(standby.activate());
+        assert!(clone.is_active());
+        assert!(!clone.activate());
+    }
+}
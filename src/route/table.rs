@@ -0,0 +1,184 @@
+//! Routing table mapping arbitrary request paths into services.
+//!
+//! By default the service and the event are taken from the first two path segments. Entries of
+//! the table are checked before that in order, the first matching one wins, so public URL layouts
+//! can be served without making applications aware of them.
+
+use regex::{self, Regex};
+
+use crate::config::PathRouteConfig;
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Prefix(String),
+    Regex(Regex),
+}
+
+#[derive(Clone, Debug)]
+struct PathRoute {
+    matcher: Matcher,
+    service: String,
+    event: String,
+    rewrite: Option<String>,
+}
+
+/// Prepends a slash to the URI unless it already starts with one.
+fn absolute(uri: String) -> String {
+    if uri.starts_with('/') {
+        uri
+    } else {
+        format!("/{}", uri)
+    }
+}
+
+impl PathRoute {
+    fn new(cfg: &PathRouteConfig) -> Result<Self, regex::Error> {
+        let matcher = match (cfg.prefix(), cfg.regex()) {
+            (Some(prefix), ..) => Matcher::Prefix(prefix.trim_end_matches('/').to_owned()),
+            (None, Some(pattern)) => Matcher::Regex(Regex::new(pattern)?),
+            (None, None) => Matcher::Prefix(String::new()),
+        };
+
+        let route = Self {
+            matcher: matcher,
+            service: cfg.service().to_owned(),
+            event: cfg.event().to_owned(),
+            rewrite: cfg.rewrite().map(|v| v.to_owned()),
+        };
+
+        Ok(route)
+    }
+
+    /// Returns the service, the event and the URI for the given path without the query.
+    fn matches(&self, path: &str) -> Option<(String, String, String)> {
+        match self.matcher {
+            Matcher::Prefix(ref prefix) => {
+                if !path.starts_with(prefix.as_str()) {
+                    return None;
+                }
+
+                let rest = &path[prefix.len()..];
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+
+                let uri = match self.rewrite {
+                    Some(ref rewrite) => format!("{}{}", rewrite.trim_end_matches('/'), rest),
+                    None => rest.to_owned(),
+                };
+
+                Some((self.service.clone(), self.event.clone(), absolute(uri)))
+            }
+            Matcher::Regex(ref regex) => {
+                let caps = regex.captures(path)?;
+                let expand = |template: &str| {
+                    let mut result = String::new();
+                    caps.expand(template, &mut result);
+                    result
+                };
+
+                let uri = match self.rewrite {
+                    Some(ref rewrite) => expand(rewrite),
+                    None => path.to_owned(),
+                };
+
+                Some((expand(&self.service), expand(&self.event), absolute(uri)))
+            }
+        }
+    }
+}
+
+/// An ordered list of path routes.
+#[derive(Clone, Debug, Default)]
+pub struct RoutingTable {
+    routes: Vec<PathRoute>,
+}
+
+impl RoutingTable {
+    pub fn new(cfg: &[PathRouteConfig]) -> Result<Self, regex::Error> {
+        let routes = cfg.iter()
+            .map(PathRoute::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { routes: routes })
+    }
+
+    /// Returns `true` if the table has no routes.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Maps the path with the query into the service, the event and the URI passed to the
+    /// application, keeping the query, using the first matching route.
+    ///
+    /// Routes expanding pattern groups into an empty service or event name are skipped.
+    pub fn route(&self, path: &str) -> Option<(String, String, String)> {
+        let (path, query) = match path.find('?') {
+            Some(pos) => (&path[..pos], &path[pos..]),
+            None => (path, ""),
+        };
+
+        self.routes.iter()
+            .filter_map(|route| route.matches(path))
+            .find(|&(ref service, ref event, ..)| !service.is_empty() && !event.is_empty())
+            .map(|(service, event, uri)| (service, event, format!("{}{}", uri, query)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_yaml;
+
+    use super::RoutingTable;
+
+    fn table(yaml: &str) -> RoutingTable {
+        RoutingTable::new(&serde_yaml::from_str::<Vec<_>>(yaml).unwrap()).unwrap()
+    }
+
+    fn route(service: &str, event: &str, uri: &str) -> Option<(String, String, String)> {
+        Some((service.into(), event.into(), uri.into()))
+    }
+
+    #[test]
+    fn test_prefix() {
+        let table = table("[{prefix: /api/users/, service: users, event: http}]");
+
+        assert_eq!(route("users", "http", "/42?full=1"), table.route("/api/users/42?full=1"));
+        assert_eq!(route("users", "http", "/"), table.route("/api/users"));
+        assert_eq!(None, table.route("/api/usersx"));
+        assert_eq!(None, table.route("/api"));
+    }
+
+    #[test]
+    fn test_prefix_rewrite() {
+        let table = table("[{prefix: /v2, service: api, event: http, rewrite: /internal/v2}]");
+
+        assert_eq!(route("api", "http", "/internal/v2/items?id=1"), table.route("/v2/items?id=1"));
+        assert_eq!(route("api", "http", "/internal/v2"), table.route("/v2"));
+    }
+
+    #[test]
+    fn test_regex() {
+        let table = table(r#"
+            - regex: ^/static/(?P<app>[a-z]+)/(.*)$
+              service: $app-static
+              event: get
+              rewrite: /$2
+            - prefix: /
+              service: frontend
+              event: http
+        "#);
+
+        assert_eq!(route("docs-static", "get", "/css/main.css?v=3"), table.route("/static/docs/css/main.css?v=3"));
+        assert_eq!(route("frontend", "http", "/static/42/file"), table.route("/static/42/file"));
+        assert_eq!(route("frontend", "http", "/"), table.route("/"));
+    }
+
+    #[test]
+    fn test_empty_expansion_is_skipped() {
+        let table = table("[{regex: '^/(x)?/', service: '$1', event: http}]");
+
+        assert_eq!(route("x", "http", "/x/y"), table.route("/x/y"));
+        assert_eq!(None, table.route("//y"));
+    }
+}
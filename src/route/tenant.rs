@@ -0,0 +1,180 @@
+//! Multi-tenant mode.
+//!
+//! Requests are routed into tenants by their `Host` header. The tenant is resolved once by the
+//! router before any route sees the request, so that all routes, including ones added by
+//! embedders, serve the same host from the same cluster. The resolved tenant name is passed to
+//! routes in `X-Cocaine-Tenant` header, which replaces whatever the client has sent, while requests
+//! of the default cluster have no such header.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use hyper::header::{Headers, Host};
+use hyper::server::Request;
+
+use regex::Regex;
+
+use crate::pool::EventDispatch;
+use crate::route::Quota;
+use crate::route::signing::TENANT_HEADER;
+
+/// An isolated Cocaine installation, into which requests are routed by their `Host` header.
+pub struct Tenant {
+    name: String,
+    hosts: Regex,
+    dispatcher: EventDispatch,
+    headers: HashMap<String, String>,
+    quota: Option<Arc<Quota>>,
+}
+
+impl Tenant {
+    pub fn new(name: String, hosts: Regex, dispatcher: EventDispatch) -> Self {
+        Self {
+            name: name,
+            hosts: hosts,
+            dispatcher: dispatcher,
+            headers: HashMap::new(),
+            quota: None,
+        }
+    }
+
+    pub fn with_headers_mapping(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Limits requests this tenant may process, preventing its overload from starving others.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(Arc::new(quota));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dispatcher(&self) -> &EventDispatch {
+        &self.dispatcher
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn quota(&self) -> Option<&Arc<Quota>> {
+        self.quota.as_ref()
+    }
+
+    /// Returns `true` if the request with the given headers belongs to this tenant.
+    fn matches(&self, headers: &Headers) -> bool {
+        match headers.get::<Host>() {
+            Some(host) => self.hosts.is_match(host.hostname()),
+            None => false,
+        }
+    }
+}
+
+/// Tenants shared between the router and routes.
+#[derive(Default)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tenant. Requests, whose `Host` header matches none of tenants, are served by the
+    /// default cluster.
+    pub fn add(&mut self, tenant: Tenant) {
+        self.tenants.push(tenant);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    /// Routes the request into the first tenant matching its `Host` header by marking it with the
+    /// tenant header.
+    ///
+    /// The header sent by the client is always dropped, so it can't select a tenant on its own.
+    pub fn resolve(&self, req: &mut Request) {
+        let name = self.tenants.iter()
+            .find(|tenant| tenant.matches(req.headers()))
+            .map(|tenant| tenant.name.clone());
+
+        let headers = req.headers_mut();
+        headers.remove_raw(TENANT_HEADER);
+        if let Some(name) = name {
+            headers.set_raw(TENANT_HEADER, name);
+        }
+    }
+
+    /// Returns the tenant the request with the given headers was resolved into, if any.
+    pub fn get(&self, headers: &Headers) -> Option<&Tenant> {
+        let name = headers.get_raw(TENANT_HEADER).and_then(|raw| raw.one())?;
+        self.tenants.iter().find(|tenant| tenant.name.as_bytes() == name)
+    }
+
+    /// Returns the dispatcher of the tenant the request was resolved into, falling back to the
+    /// given default one.
+    pub fn dispatcher<'a>(&'a self, headers: &Headers, default: &'a EventDispatch) -> &'a EventDispatch {
+        self.get(headers).map(Tenant::dispatcher).unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Method;
+    use hyper::header::{Headers, Host};
+    use hyper::server::Request;
+
+    use regex::Regex;
+
+    use crate::pool::EventDispatch;
+    use crate::route::signing::TENANT_HEADER;
+
+    use super::{Tenant, Tenants};
+
+    fn tenant(name: &str, hosts: &str) -> Tenant {
+        Tenant::new(name.into(), Regex::new(hosts).unwrap(), EventDispatch::new(Vec::new()))
+    }
+
+    #[test]
+    fn test_tenant_matches_host() {
+        let tenant = tenant("search", r"^(.+\.)?search\.local$");
+
+        let mut headers = Headers::new();
+        assert!(!tenant.matches(&headers));
+
+        headers.set(Host::new("search.local", Some(8080)));
+        assert!(tenant.matches(&headers));
+
+        headers.set(Host::new("api.search.local", None));
+        assert!(tenant.matches(&headers));
+
+        headers.set(Host::new("search.local.evil", None));
+        assert!(!tenant.matches(&headers));
+    }
+
+    #[test]
+    fn test_resolve_overrides_client_header() {
+        let mut tenants = Tenants::new();
+        tenants.add(tenant("search", r"^search\.local$"));
+        tenants.add(tenant("maps", r"^maps\.local$"));
+
+        let mut req = Request::new(Method::Get, "/app/event".parse().unwrap());
+        req.headers_mut().set(Host::new("maps.local", None));
+        req.headers_mut().set_raw(TENANT_HEADER, "search");
+        tenants.resolve(&mut req);
+        assert_eq!(Some("maps"), tenants.get(req.headers()).map(Tenant::name));
+
+        req.headers_mut().set(Host::new("example.com", None));
+        req.headers_mut().set_raw(TENANT_HEADER, "search");
+        tenants.resolve(&mut req);
+        assert!(req.headers().get_raw(TENANT_HEADER).is_none());
+        assert!(tenants.get(req.headers()).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+//! Signalling of the proxy timeout to futures of requests, which are dropped because of it.
+//!
+//! The timeout middleware abandons the whole response future at once, so routes can't tell
+//! a timed out request from the one, whose client has gone away, by anything but this flag,
+//! which is set only while the future is being dropped.
+
+use std::cell::Cell;
+
+thread_local! {
+    static TIMED_OUT: Cell<bool> = Cell::new(false);
+}
+
+/// Resets the flag even if dropping the future panics.
+struct Reset;
+
+impl Drop for Reset {
+    fn drop(&mut self) {
+        TIMED_OUT.with(|flag| flag.set(false));
+    }
+}
+
+/// Drops the future of the request, which has run out of the proxy timeout.
+pub fn drop_timed_out<T>(future: T) {
+    TIMED_OUT.with(|flag| flag.set(true));
+    let _reset = Reset;
+    drop(future);
+}
+
+/// Returns `true` if called while the future of the request is being dropped because of the
+/// proxy timeout.
+pub fn is_timed_out() -> bool {
+    TIMED_OUT.with(Cell::get)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::{drop_timed_out, is_timed_out};
+
+    /// Remembers whether it has been dropped because of the timeout.
+    struct Probe(Arc<Mutex<Option<bool>>>);
+
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = Some(is_timed_out());
+        }
+    }
+
+    #[test]
+    fn test_drop_timed_out() {
+        let outcome = Arc::new(Mutex::new(None));
+
+        drop_timed_out(Probe(outcome.clone()));
+        assert_eq!(Some(true), *outcome.lock().unwrap());
+        assert!(!is_timed_out());
+
+        drop(Probe(outcome.clone()));
+        assert_eq!(Some(false), *outcome.lock().unwrap());
+    }
+}
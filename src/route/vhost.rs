@@ -0,0 +1,94 @@
+//! Routing by the `Host` header.
+//!
+//! Lets a single proxy front many applications under their own domains, like `app1.example.com`,
+//! without clients specifying the service either in headers or in the path, which is passed to
+//! the application as is.
+
+use regex::{self, Regex};
+
+use crate::config::VirtualHostConfig;
+
+#[derive(Clone, Debug)]
+struct VirtualHost {
+    hosts: Regex,
+    service: String,
+    event: String,
+}
+
+impl VirtualHost {
+    fn new(cfg: &VirtualHostConfig) -> Result<Self, regex::Error> {
+        let vhost = Self {
+            hosts: Regex::new(cfg.hosts())?,
+            service: cfg.service().to_owned(),
+            event: cfg.event().to_owned(),
+        };
+
+        Ok(vhost)
+    }
+
+    fn matches(&self, hostname: &str) -> Option<(String, String)> {
+        let caps = self.hosts.captures(hostname)?;
+
+        let mut service = String::new();
+        caps.expand(&self.service, &mut service);
+        if service.is_empty() {
+            return None;
+        }
+
+        Some((service, self.event.clone()))
+    }
+}
+
+/// An ordered list of virtual hosts, the first matching one wins.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualHosts {
+    vhosts: Vec<VirtualHost>,
+}
+
+impl VirtualHosts {
+    pub fn new(cfg: &[VirtualHostConfig]) -> Result<Self, regex::Error> {
+        let vhosts = cfg.iter()
+            .map(VirtualHost::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { vhosts: vhosts })
+    }
+
+    /// Returns the service and the event for the given host name without the port.
+    ///
+    /// Host names are case-insensitive, so they are matched in lowercase.
+    pub fn route(&self, hostname: &str) -> Option<(String, String)> {
+        if self.vhosts.is_empty() {
+            return None;
+        }
+
+        let hostname = hostname.trim_end_matches('.').to_lowercase();
+        self.vhosts.iter()
+            .filter_map(|vhost| vhost.matches(&hostname))
+            .next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_yaml;
+
+    use super::VirtualHosts;
+
+    #[test]
+    fn test_route() {
+        let vhosts = VirtualHosts::new(&serde_yaml::from_str::<Vec<_>>(r#"
+            - hosts: ^api\.example\.com$
+              service: gateway
+              event: api
+            - hosts: ^(?P<app>[a-z0-9-]+)\.example\.com$
+              service: $app
+              event: http
+        "#).unwrap()).unwrap();
+
+        assert_eq!(Some(("gateway".into(), "api".into())), vhosts.route("api.example.com"));
+        assert_eq!(Some(("app1".into(), "http".into())), vhosts.route("App1.Example.com."));
+        assert_eq!(None, vhosts.route("a.b.example.com"));
+        assert_eq!(None, vhosts.route("example.org"));
+    }
+}
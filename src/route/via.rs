@@ -0,0 +1,131 @@
+//! Proxy identity in `Via` header chains.
+//!
+//! Each proxy appends `<protocol> <pseudonym> (<product>)` entry to the `Via` header of both the
+//! request passed further and the response returned back, so multi-hop deployments can tell which
+//! proxies a message went through. A request, whose incoming chain already contains this instance
+//! pseudonym, is looping and must be rejected.
+
+use hyper::HttpVersion;
+use hyper::header::Headers;
+
+use crate::config::ViaConfig;
+
+/// Name of the header.
+pub const VIA_HEADER: &str = "Via";
+
+#[derive(Clone, Debug)]
+pub struct Via {
+    pseudonym: String,
+    product: String,
+    strip: bool,
+}
+
+impl Via {
+    pub fn new(pseudonym: String, strip: bool) -> Self {
+        Self {
+            pseudonym: pseudonym,
+            product: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            strip: strip,
+        }
+    }
+
+    /// Returns this instance entry for a message received with the given protocol version.
+    pub fn entry(&self, version: &HttpVersion) -> String {
+        let protocol = match *version {
+            HttpVersion::Http09 => "0.9",
+            HttpVersion::Http10 => "1.0",
+            _ => "1.1",
+        };
+
+        format!("{} {} ({})", protocol, self.pseudonym, self.product)
+    }
+
+    /// Checks whether the incoming chain has already passed through this instance.
+    ///
+    /// Malformed entries are skipped, since they can't match a well-formed pseudonym anyway.
+    pub fn is_looped(&self, chain: &str) -> bool {
+        split(chain).into_iter().any(|entry| {
+            let mut parts = entry.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(..), Some(received_by)) => received_by == self.pseudonym,
+                (..) => false,
+            }
+        })
+    }
+
+    /// Appends this instance entry to the given chain, which is dropped entirely if stripping is
+    /// enabled, hiding the upstream topology from applications.
+    pub fn append(&self, chain: Option<&str>, version: &HttpVersion) -> String {
+        match chain {
+            Some(chain) if !self.strip && !chain.trim().is_empty() => {
+                format!("{}, {}", chain.trim(), self.entry(version))
+            }
+            Some(..) | None => self.entry(version),
+        }
+    }
+}
+
+impl<'a> From<&'a ViaConfig> for Via {
+    fn from(cfg: &'a ViaConfig) -> Self {
+        Via::new(cfg.pseudonym().to_owned(), cfg.strip())
+    }
+}
+
+/// Returns the chain from all `Via` headers, joined into a single line.
+pub fn chain(headers: &Headers) -> Option<String> {
+    headers.get_raw(VIA_HEADER).map(|raw| {
+        raw.iter()
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+/// Splits the chain into entries, respecting commas inside comments.
+fn split(chain: &str) -> Vec<&str> {
+    let mut depth = 0;
+    chain.split(move |ch| {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => return true,
+            _ => {}
+        }
+        false
+    }).map(str::trim).filter(|entry| !entry.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::HttpVersion;
+
+    use super::Via;
+
+    #[test]
+    fn test_entry() {
+        let via = Via::new("proxy-1".into(), false);
+        assert!(via.entry(&HttpVersion::Http11).starts_with("1.1 proxy-1 (cocaine-http-proxy/"));
+        assert!(via.entry(&HttpVersion::Http10).starts_with("1.0 proxy-1 "));
+    }
+
+    #[test]
+    fn test_is_looped() {
+        let via = Via::new("proxy-1".into(), false);
+        assert!(!via.is_looped(""));
+        assert!(!via.is_looped("1.1 nginx, 1.0 proxy-2 (cocaine-http-proxy/0.1, proxy-1)"));
+        assert!(via.is_looped("1.1 nginx, 1.1 proxy-1 (cocaine-http-proxy/0.1)"));
+        assert!(via.is_looped("HTTP/1.1 proxy-1"));
+        assert!(!via.is_looped("1.1 proxy-10, garbage"));
+    }
+
+    #[test]
+    fn test_append() {
+        let via = Via::new("proxy-1".into(), false);
+        let entry = via.entry(&HttpVersion::Http11);
+        assert_eq!(entry, via.append(None, &HttpVersion::Http11));
+        assert_eq!(format!("1.1 nginx, {}", entry), via.append(Some("1.1 nginx "), &HttpVersion::Http11));
+
+        let via = Via::new("proxy-1".into(), true);
+        assert_eq!(entry, via.append(Some("1.1 nginx"), &HttpVersion::Http11));
+    }
+}
@@ -0,0 +1,550 @@
+//! WebSocket proxying into Cocaine streaming applications.
+//!
+//! Upgrade requests are routed by `X-Cocaine-Service` and `X-Cocaine-Event` headers, falling back
+//! to the `/<service>/<event>` path. Once the handshake is accepted, the application receives the
+//! upgrade request meta frame as the first chunk, followed by a chunk per client message. Each
+//! chunk written by the application is sent to the client as a single message, which is a text
+//! one if the chunk is valid UTF-8 and a binary one otherwise.
+//!
+//! Closing either side closes the other one, while application errors close the connection with
+//! 1011 status. Pings are answered by the proxy itself and never reach applications.
+
+use std::io::{self, Read, Write};
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, future};
+use futures::sync::mpsc;
+use sha1::{Digest, Sha1};
+
+use hyper::{self, Method, StatusCode};
+use hyper::header::{Protocol, ProtocolName, Upgrade};
+use hyper::server::{Request, Response};
+
+use cocaine::{self, Dispatch, Service};
+use cocaine::hpack::{self, Header as CocaineHeader};
+use cocaine::logging::{Log, Severity};
+use cocaine::protocol::{self, Flatten};
+
+use rmps;
+
+use crate::common::{XErrorGeneratedBy, XRequestId};
+use crate::config::{BodyCodec, WebSocketConfig};
+use crate::pool::{Event, EventDispatch, Settings};
+use crate::random;
+use crate::route::{self, serialize, Match, Route, Tenants};
+use crate::route::app::{self, BodyReceiver, RequestMeta, Upstream};
+use crate::route::digest::base64;
+use crate::server::{self, Io, Tunnel};
+
+/// Appended to the client key before hashing it into the accept key, see RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const VERSION: &str = "13";
+
+/// Number of client messages buffered before the application.
+const MESSAGE_BUFFER: usize = 4;
+
+const READ_CHUNK: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Returns the accept key for the given client key.
+fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.trim().as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    base64(&sha.finalize())
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Decodes a client frame, consuming it from the buffer.
+///
+/// Fails with a close status if the frame violates the protocol or exceeds the given size limit.
+fn decode(buf: &mut BytesMut, limit: usize) -> Result<Option<Frame>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    // No extensions are negotiated, so reserved bits must be clear. Clients must mask frames.
+    if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let (len, offset) = match buf[1] & 0x7f {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (BigEndian::read_u16(&buf[2..4]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (BigEndian::read_u64(&buf[2..10]), 10),
+        len => (len as u64, 2),
+    };
+
+    // Control frames must not be fragmented and their payload must fit in a short length.
+    if opcode & 0x8 != 0 && (!fin || len > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    if len > limit as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+
+    let len = len as usize;
+    if buf.len() < offset + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = buf.split_to(offset + 4).split_off(offset);
+    let mut payload = buf.split_to(len).to_vec();
+    for (id, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[id % 4];
+    }
+
+    let frame = Frame {
+        fin: fin,
+        opcode: opcode,
+        payload: payload,
+    };
+
+    Ok(Some(frame))
+}
+
+/// Encodes a single unmasked server frame.
+fn encode(opcode: u8, payload: &[u8], buf: &mut Vec<u8>) {
+    buf.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => buf.push(len as u8),
+        len if len <= 0xffff => {
+            buf.push(126);
+            buf.write_u16::<BigEndian>(len as u16).unwrap();
+        }
+        len => {
+            buf.push(127);
+            buf.write_u64::<BigEndian>(len as u64).unwrap();
+        }
+    }
+    buf.extend_from_slice(payload);
+}
+
+/// Events received from the application.
+#[derive(Debug)]
+enum Outbound {
+    Message(Vec<u8>),
+    Close(u16),
+}
+
+struct WebSocketDispatch {
+    tx: mpsc::UnboundedSender<Outbound>,
+}
+
+impl Dispatch for WebSocketDispatch {
+    fn process(self: Box<Self>, response: &cocaine::Response) -> Option<Box<dyn Dispatch>> {
+        match response.deserialize::<protocol::Streaming<rmps::RawRef>>().flatten() {
+            Ok(Some(data)) => {
+                drop(self.tx.unbounded_send(Outbound::Message(data.as_bytes().to_vec())));
+                Some(self)
+            }
+            Ok(None) => {
+                drop(self.tx.unbounded_send(Outbound::Close(CLOSE_NORMAL)));
+                None
+            }
+            Err(..) => {
+                drop(self.tx.unbounded_send(Outbound::Close(CLOSE_INTERNAL_ERROR)));
+                None
+            }
+        }
+    }
+
+    fn discard(self: Box<Self>, _err: &cocaine::Error) {
+        drop(self.tx.unbounded_send(Outbound::Close(CLOSE_INTERNAL_ERROR)));
+    }
+}
+
+/// Bridges frames of the upgraded connection with the application channel.
+struct Bridge<L> {
+    io: Box<dyn Io>,
+    rbuf: BytesMut,
+    wbuf: Vec<u8>,
+    limit: usize,
+    /// A fragmented message being assembled.
+    message: Option<Vec<u8>>,
+    /// Client messages into the application, where `None` closes the channel.
+    tx: Option<mpsc::Sender<Option<Vec<u8>>>>,
+    /// A client message waiting for the application channel capacity.
+    pending: Option<Option<Vec<u8>>>,
+    rx: mpsc::UnboundedReceiver<Outbound>,
+    /// Whether the close frame has been queued, after which nothing is read or sent anymore.
+    closing: bool,
+    trace: u64,
+    log: L,
+}
+
+impl<L: Log> Bridge<L> {
+    fn send_message(&mut self, data: &[u8]) {
+        let opcode = match str::from_utf8(data) {
+            Ok(..) => OPCODE_TEXT,
+            Err(..) => OPCODE_BINARY,
+        };
+        encode(opcode, data, &mut self.wbuf);
+    }
+
+    /// Queues the close frame, closing the application channel as well.
+    fn close(&mut self, code: u16) {
+        if self.closing {
+            return;
+        }
+
+        cocaine_log!(self.log, Severity::Info, "closing WebSocket connection with {} status", code; {
+            trace: self.trace,
+        });
+
+        let mut payload = Vec::with_capacity(2);
+        payload.write_u16::<BigEndian>(code).unwrap();
+        encode(OPCODE_CLOSE, &payload, &mut self.wbuf);
+        self.closing = true;
+
+        if self.pending.is_none() {
+            self.pending = Some(None);
+        }
+    }
+
+    fn on_frame(&mut self, frame: Frame) {
+        match frame.opcode {
+            OPCODE_PING => encode(OPCODE_PONG, &frame.payload, &mut self.wbuf),
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                let code = if frame.payload.len() >= 2 {
+                    BigEndian::read_u16(&frame.payload[..2])
+                } else {
+                    CLOSE_NORMAL
+                };
+                self.close(code);
+            }
+            OPCODE_TEXT | OPCODE_BINARY if self.message.is_none() => {
+                if frame.fin {
+                    self.pending = Some(Some(frame.payload));
+                } else {
+                    self.message = Some(frame.payload);
+                }
+            }
+            OPCODE_CONTINUATION if self.message.is_some() => {
+                let mut message = self.message.take().unwrap();
+                if message.len() + frame.payload.len() > self.limit {
+                    self.close(CLOSE_TOO_BIG);
+                    return;
+                }
+
+                message.extend(frame.payload);
+                if frame.fin {
+                    self.pending = Some(Some(message));
+                } else {
+                    self.message = Some(message);
+                }
+            }
+            _ => self.close(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+
+    fn poll_bridge(&mut self) -> Poll<(), io::Error> {
+        loop {
+            while !self.closing {
+                match self.rx.poll().expect("unbounded receiver never fails") {
+                    Async::Ready(Some(Outbound::Message(data))) => self.send_message(&data),
+                    Async::Ready(Some(Outbound::Close(code))) => self.close(code),
+                    Async::Ready(None) => self.close(CLOSE_INTERNAL_ERROR),
+                    Async::NotReady => break,
+                }
+            }
+
+            if let Some(item) = self.pending.take() {
+                if let Some(ref mut tx) = self.tx {
+                    match tx.start_send(item) {
+                        Ok(AsyncSink::Ready) => {}
+                        Ok(AsyncSink::NotReady(item)) => self.pending = Some(item),
+                        // The application has gone, which is reported through its dispatch.
+                        Err(..) => {}
+                    }
+                }
+            }
+
+            while !self.wbuf.is_empty() {
+                match self.io.write(&self.wbuf) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(size) => {
+                        self.wbuf.drain(..size);
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if self.wbuf.is_empty() {
+                match self.io.flush() {
+                    Ok(()) if self.closing => return Ok(Async::Ready(())),
+                    Ok(()) => {}
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            // Client messages are not read until the application accepts the previous one.
+            if self.pending.is_some() || self.closing {
+                return Ok(Async::NotReady);
+            }
+
+            match decode(&mut self.rbuf, self.limit) {
+                Ok(Some(frame)) => {
+                    self.on_frame(frame);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(code) => {
+                    self.close(code);
+                    continue;
+                }
+            }
+
+            let mut buf = [0; READ_CHUNK];
+            match self.io.read(&mut buf) {
+                // The client has gone away without closing, which aborts the application channel.
+                Ok(0) => return Ok(Async::Ready(())),
+                Ok(size) => self.rbuf.extend_from_slice(&buf[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<L: Log> Future for Bridge<L> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_bridge().map_err(|err| {
+            cocaine_log!(self.log, Severity::Info, "WebSocket connection failed: {}", err; {
+                trace: self.trace,
+            });
+        })
+    }
+}
+
+/// A route accepting WebSocket upgrades.
+pub struct WebSocketRoute<L> {
+    dispatcher: EventDispatch,
+    tenants: Arc<Tenants>,
+    config: WebSocketConfig,
+    log: L,
+}
+
+impl<L: Log + Clone + Send + Sync + 'static> WebSocketRoute<L> {
+    pub fn new(dispatcher: EventDispatch, config: WebSocketConfig, log: L) -> Self {
+        Self {
+            dispatcher: dispatcher,
+            tenants: Arc::new(Tenants::new()),
+            config: config,
+            log: log,
+        }
+    }
+
+    /// Sets tenants, which requests were resolved into by the router.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Checks the opening handshake, returning the accept key on success.
+    fn handshake(req: &Request) -> Result<String, Response> {
+        if *req.method() != Method::Get {
+            return Err(Self::reject(StatusCode::BadRequest, "WebSocket handshake requires GET method"));
+        }
+
+        let version = req.headers().get_raw("Sec-WebSocket-Version").and_then(|raw| raw.one());
+        if version != Some(VERSION.as_bytes()) {
+            let mut resp = Self::reject(StatusCode::UpgradeRequired, "unsupported WebSocket version");
+            resp.headers_mut().set_raw("Sec-WebSocket-Version", VERSION);
+            return Err(resp);
+        }
+
+        let key = req.headers().get_raw("Sec-WebSocket-Key")
+            .and_then(|raw| raw.one())
+            .and_then(|key| str::from_utf8(key).ok());
+        match key {
+            Some(key) if !key.trim().is_empty() => Ok(accept_key(key)),
+            Some(..) | None => Err(Self::reject(StatusCode::BadRequest, "missing WebSocket key")),
+        }
+    }
+
+    fn reject(status: StatusCode, reason: &'static str) -> Response {
+        Response::new()
+            .with_status(status)
+            .with_header(XErrorGeneratedBy::proxy())
+            .with_body(reason)
+    }
+
+    /// Creates the tunnel, which invokes the application once the handshake is sent.
+    fn tunnel(&self, dispatcher: EventDispatch, service: String, event: String, meta: Vec<u8>, trace: u64) -> Tunnel {
+        let limit = self.config.max_message_size();
+        let log = self.log.clone();
+
+        Box::new(move |io: Box<dyn Io>, read_buf: Bytes| {
+            let (tx, stream) = mpsc::channel(MESSAGE_BUFFER);
+            let (events, rx) = mpsc::unbounded();
+
+            let stream: Arc<Mutex<Option<BodyReceiver>>> = Arc::new(Mutex::new(Some(Box::new(stream.map(|message| (message, None))))));
+            let ev = Event::Service {
+                name: service,
+                func: Box::new(move |service: &Service, _settings: Settings| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                    let stream = match stream.lock().unwrap().take() {
+                        Some(stream) => stream,
+                        None => return Box::new(future::ok(())),
+                    };
+                    let meta = meta.clone();
+                    let headers = vec![hpack::TraceId(trace).into_raw(), hpack::SpanId(trace).into_raw()];
+                    let req = cocaine::Request::new(0, &[event.clone()]).unwrap()
+                        .add_headers(headers);
+                    let dispatch = WebSocketDispatch { tx: events.clone() };
+
+                    let future = service.call(req, dispatch).and_then(move |tx| {
+                        let upstream = Upstream::new();
+                        upstream.attach(tx);
+                        upstream.send(app::make_chunk(&meta));
+                        app::send_stream(upstream, stream, BodyCodec::Identity).then(|_| Ok::<(), cocaine::Error>(()))
+                    }).then(|_| Ok(()));
+
+                    Box::new(future)
+                }),
+            };
+            dispatcher.send(ev);
+
+            let bridge = Bridge {
+                io: io,
+                rbuf: BytesMut::from(&read_buf[..]),
+                wbuf: Vec::new(),
+                limit: limit,
+                message: None,
+                tx: Some(tx),
+                pending: None,
+                rx: rx,
+                closing: false,
+                trace: trace,
+                log: log,
+            };
+
+            Box::new(bridge) as Box<dyn Future<Item = (), Error = ()>>
+        })
+    }
+}
+
+impl<L: Log + Clone + Send + Sync + 'static> Route for WebSocketRoute<L> {
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn process(&self, req: Request) -> Match<Self::Future> {
+        let upgrade = req.headers().get::<Upgrade>()
+            .map(|upgrade| upgrade.0.iter().any(|protocol| protocol.name == ProtocolName::WebSocket))
+            .unwrap_or(false);
+
+        let (service, event) = match route::target(&req) {
+            Some(target) if upgrade => target,
+            Some(..) | None => return Match::None(req),
+        };
+
+        let accept = match Self::handshake(&req) {
+            Ok(accept) => accept,
+            Err(resp) => return Match::Some(Box::new(future::ok(resp))),
+        };
+
+        let trace = random::gen::<u64>();
+        let meta = serialize::to_vec(&RequestMeta::new(&req, req.uri().to_string())).unwrap();
+        let dispatcher = self.tenants.dispatcher(req.headers(), &self.dispatcher).clone();
+
+        cocaine_log!(self.log, Severity::Info, "accepted WebSocket connection"; {
+            service: service,
+            event: event,
+            trace: trace,
+        });
+
+        let mut resp = Response::new()
+            .with_status(StatusCode::SwitchingProtocols)
+            .with_header(Upgrade(vec![Protocol::new(ProtocolName::WebSocket, None)]))
+            .with_header(XRequestId(trace));
+        resp.headers_mut().set_raw("Connection", "Upgrade");
+        resp.headers_mut().set_raw("Sec-WebSocket-Accept", accept);
+        server::register_upgrade(&mut resp, self.tunnel(dispatcher, service, event, meta, trace));
+
+        Match::Some(Box::new(future::ok(resp)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::{Frame, accept_key, decode, encode, CLOSE_PROTOCOL_ERROR, CLOSE_TOO_BIG, OPCODE_TEXT};
+
+    /// Masks the payload as clients do.
+    fn masked(opcode: u8, payload: &[u8]) -> BytesMut {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut buf = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(id, byte)| byte ^ mask[id % 4]));
+        BytesMut::from(&buf[..])
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455.
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut buf = masked(OPCODE_TEXT, b"Hello");
+        buf.extend_from_slice(&[0x81]);
+
+        let expected = Frame { fin: true, opcode: OPCODE_TEXT, payload: b"Hello".to_vec() };
+        assert_eq!(Ok(Some(expected)), decode(&mut buf, 1024));
+        assert_eq!(Ok(None), decode(&mut buf, 1024));
+        assert_eq!(1, buf.len());
+    }
+
+    #[test]
+    fn test_decode_rejects_unmasked_and_oversized_frames() {
+        let mut buf = BytesMut::from(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o'][..]);
+        assert_eq!(Err(CLOSE_PROTOCOL_ERROR), decode(&mut buf, 1024));
+
+        let mut buf = masked(OPCODE_TEXT, b"Hello");
+        assert_eq!(Err(CLOSE_TOO_BIG), decode(&mut buf, 4));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        encode(OPCODE_TEXT, b"Hello", &mut buf);
+        assert_eq!(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'], buf);
+
+        let mut buf = Vec::new();
+        encode(OPCODE_TEXT, &[0; 256], &mut buf);
+        assert_eq!(&[0x81, 126, 0x01, 0x00], &buf[..4]);
+        assert_eq!(260, buf.len());
+    }
+}
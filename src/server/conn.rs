@@ -0,0 +1,128 @@
+//! Forwarding of connection attributes to applications.
+//!
+//! Attributes of established connections in the configured allowlist are forwarded to
+//! applications in request headers. Headers of allowed attributes are always replaced, so clients
+//! can't supply them on their own, while absent attributes, like TLS ones of a plain TCP
+//! connection or a certificate of a client that hasn't presented one, leave no header at all.
+
+use hyper::header::Headers;
+use hyper::server::Request;
+
+use tokio_service::Service;
+
+use crate::config::TlsAttribute;
+
+/// Returns the name of the request header the given attribute is forwarded in.
+pub fn header_name(attribute: TlsAttribute) -> &'static str {
+    match attribute {
+        TlsAttribute::Protocol => "X-TLS-Protocol",
+        TlsAttribute::Cipher => "X-TLS-Cipher",
+        TlsAttribute::Sni => "X-TLS-SNI",
+        TlsAttribute::ClientCert => "X-TLS-Client-Cert-Fingerprint",
+    }
+}
+
+/// Allowed attributes of an established connection along with their values, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    attributes: Vec<(TlsAttribute, Option<String>)>,
+}
+
+impl ConnectionInfo {
+    /// Collects values of the given attributes.
+    pub fn new<F>(forward: &[TlsAttribute], value: F) -> Self
+        where F: Fn(TlsAttribute) -> Option<String>
+    {
+        let attributes = forward.iter()
+            .map(|&attribute| (attribute, value(attribute)))
+            .collect();
+
+        Self { attributes: attributes }
+    }
+
+    /// Constructs the info of a plain TCP connection, which has none of the attributes.
+    pub fn plain(forward: &[TlsAttribute]) -> Self {
+        Self::new(forward, |..| None)
+    }
+
+    /// Replaces headers of allowed attributes with the ones of the connection.
+    pub fn apply(&self, headers: &mut Headers) {
+        for &(attribute, ref value) in &self.attributes {
+            let name = header_name(attribute);
+            headers.remove_raw(name);
+            if let Some(ref value) = *value {
+                headers.set_raw(name, value.clone());
+            }
+        }
+    }
+}
+
+/// Passes attributes of the connection to requests sent over it.
+pub struct ConnectionService<S> {
+    inner: S,
+    info: ConnectionInfo,
+}
+
+impl<S> ConnectionService<S> {
+    pub fn new(inner: S, info: ConnectionInfo) -> Self {
+        Self {
+            inner: inner,
+            info: info,
+        }
+    }
+}
+
+impl<S> Service for ConnectionService<S>
+    where S: Service<Request = Request>
+{
+    type Request  = S::Request;
+    type Response = S::Response;
+    type Error    = S::Error;
+    type Future   = S::Future;
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        self.info.apply(req.headers_mut());
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::Headers;
+
+    use crate::config::TlsAttribute;
+
+    use super::{ConnectionInfo, header_name};
+
+    #[test]
+    fn test_apply_replaces_client_headers() {
+        let info = ConnectionInfo::new(&[TlsAttribute::Protocol, TlsAttribute::ClientCert], |attribute| {
+            match attribute {
+                TlsAttribute::Protocol => Some("TLSv1_3".into()),
+                _ => None,
+            }
+        });
+
+        let mut headers = Headers::new();
+        headers.set_raw(header_name(TlsAttribute::Protocol), "SSLv3");
+        headers.set_raw(header_name(TlsAttribute::ClientCert), "spoofed");
+        headers.set_raw(header_name(TlsAttribute::Cipher), "untouched");
+        info.apply(&mut headers);
+
+        assert_eq!(Some(&b"TLSv1_3"[..]), headers.get_raw("X-TLS-Protocol").and_then(|raw| raw.one()));
+        assert!(headers.get_raw("X-TLS-Client-Cert-Fingerprint").is_none());
+        // Attributes out of the allowlist are not the proxy's business.
+        assert_eq!(Some(&b"untouched"[..]), headers.get_raw("X-TLS-Cipher").and_then(|raw| raw.one()));
+    }
+
+    #[test]
+    fn test_plain_connection_drops_client_headers() {
+        let info = ConnectionInfo::plain(&[TlsAttribute::Sni]);
+
+        let mut headers = Headers::new();
+        headers.set_raw(header_name(TlsAttribute::Sni), "internal.local");
+        info.apply(&mut headers);
+
+        assert!(headers.get_raw("X-TLS-SNI").is_none());
+    }
+}
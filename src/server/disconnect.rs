@@ -0,0 +1,131 @@
+//! Detection of clients going away while their requests are processed.
+//!
+//! Once a request is read, hyper does not touch the socket until the response is ready, so a
+//! client closing the connection stays unnoticed while the proxy keeps waiting for the worker.
+//! The probe peeks into the socket periodically while a request is in flight and drops its future
+//! once the peer has closed or reset the connection, which cancels all upstream work behind it.
+//! Peeking does not consume pipelined requests, which are left for hyper.
+//!
+//! Clients half-closing connections after sending requests look exactly like gone ones, which is
+//! why the probe is disabled by default.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use futures::future::{self, Either};
+use libc;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+/// Returns `true` if the peer has closed or reset the connection.
+fn is_closed(fd: RawFd) -> bool {
+    let mut buf = [0u8; 1];
+    let rc = unsafe {
+        libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_PEEK | libc::MSG_DONTWAIT)
+    };
+
+    match rc {
+        0 => true,
+        rc if rc > 0 => false,
+        _ => match io::Error::last_os_error().kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => false,
+            _ => true,
+        },
+    }
+}
+
+/// A future that resolves once the peer of the given socket has gone.
+struct Closed {
+    fd: RawFd,
+    interval: Duration,
+    handle: Handle,
+    timeout: Timeout,
+}
+
+impl Future for Closed {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Async::NotReady = self.timeout.poll()? {
+                return Ok(Async::NotReady);
+            }
+
+            if is_closed(self.fd) {
+                return Ok(Async::Ready(()));
+            }
+
+            self.timeout = Timeout::new(self.interval, &self.handle)?;
+        }
+    }
+}
+
+/// Drops request futures of the connection once its client has gone.
+///
+/// Without an interval requests are passed through as is.
+pub struct DisconnectService<S> {
+    inner: S,
+    fd: RawFd,
+    interval: Option<Duration>,
+    handle: Handle,
+}
+
+impl<S> DisconnectService<S> {
+    pub fn new(inner: S, fd: RawFd, interval: Option<Duration>, handle: Handle) -> Self {
+        Self {
+            inner: inner,
+            fd: fd,
+            interval: interval,
+            handle: handle,
+        }
+    }
+}
+
+impl<S> Service for DisconnectService<S>
+    where S: Service,
+          S::Error: From<io::Error> + 'static,
+          S::Response: 'static,
+          S::Future: 'static
+{
+    type Request  = S::Request;
+    type Response = S::Response;
+    type Error    = S::Error;
+    type Future   = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let future = self.inner.call(req);
+
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Box::new(future),
+        };
+
+        let timeout = match Timeout::new(interval, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(..) => return Box::new(future),
+        };
+
+        let closed = Closed {
+            fd: self.fd,
+            interval: interval,
+            handle: self.handle.clone(),
+            timeout: timeout,
+        };
+
+        let future = future.select2(closed).then(|result| match result {
+            Ok(Either::A((resp, ..))) => Either::A(future::ok(resp)),
+            Err(Either::A((err, ..))) => Either::A(future::err(err)),
+            Ok(Either::B(((), ..))) => {
+                let err = io::Error::new(io::ErrorKind::ConnectionAborted, "client has closed the connection");
+                Either::A(future::err(err.into()))
+            }
+            // The probe is broken, so the request is processed as if there were none.
+            Err(Either::B((.., future))) => Either::B(future),
+        });
+
+        Box::new(future)
+    }
+}
@@ -1,13 +1,17 @@
 //! HTTP Server
 
 use std::cell::RefCell;
+use std::fs;
 use std::io;
 use std::net::{self, SocketAddr};
+use std::path::Path;
 use std::rc::{Rc, Weak};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
 
 use cocaine::logging::{Logger, Severity};
 
@@ -25,11 +29,29 @@ use net2::unix::UnixTcpBuilderExt;
 
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_service::Service;
+use tokio_uds::{UnixListener, UnixStream};
 
 use net::Incoming;
+use crate::config::TlsAttribute;
+use crate::memory::MemoryBudget;
+use crate::net::Endpoint;
 use crate::service::{ServiceFactory, ServiceFactorySpawn};
 
+use self::conn::{ConnectionInfo, ConnectionService};
+use self::disconnect::DisconnectService;
+use self::upgrade::UpgradeConnection;
+pub use self::conn::header_name as tls_header_name;
+pub use self::tls::Certificates;
+pub use self::upgrade::{register_upgrade, Io, Tunnel};
+
+mod conn;
+mod disconnect;
+mod proxy_protocol;
+mod tls;
+mod upgrade;
+
 const DEFAULT_NUM_THREADS: usize = 1;
 const DEFAULT_BACKLOG: i32 = 1024;
 
@@ -110,51 +132,196 @@ impl Future for WaitUntilZero {
     }
 }
 
-struct HttpService<T> {
-    rx: mpsc::UnboundedReceiver<(net::TcpStream, SocketAddr)>,
+/// A connection accepted by the main thread, which is passed to one of worker threads.
+#[derive(Debug)]
+enum Accepted {
+    Tcp(net::TcpStream, SocketAddr),
+    Unix(StdUnixStream),
+}
+
+impl Accepted {
+    fn peer(&self) -> String {
+        match *self {
+            Accepted::Tcp(_, addr) => addr.to_string(),
+            Accepted::Unix(..) => "Unix socket".into(),
+        }
+    }
+}
+
+/// Serves HTTP over the TCP connection, which may be wrapped into TLS.
+fn serve_tcp<I, S>(protocol: &Http, handle: &Handle, io: I, addr: SocketAddr, service: S, upgrades: bool, log: &Logger)
+    where I: AsyncRead + AsyncWrite + 'static,
+          S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          S::Future: 'static
+{
+    if upgrades {
+        // Connections, which may be upgraded, lose their peer address, because hyper binds it
+        // only into connections it owns entirely.
+        let log = log.clone();
+        let conn = UpgradeConnection::new(protocol, io, service, handle.clone())
+            .map_err(move |err| {
+                cocaine_log!(log, Severity::Debug, "failed to serve connection from {}: {}", addr, err);
+            });
+        handle.spawn(conn);
+    } else {
+        protocol.bind_connection(handle, io, addr, service);
+    }
+}
+
+/// Worker state required to start serving accepted connections, shared with connections, which
+/// are still being set up.
+struct Acceptor<T> {
     handle: Handle,
     protocol: Http,
-    factory: T,
+    factory: RefCell<T>,
+    forward: Vec<TlsAttribute>,
+    upgrades: bool,
+    tls: Option<Arc<Certificates>>,
+    /// Interval of checking whether clients with requests in flight are still there.
+    disconnect_probe: Option<Duration>,
     log: Logger,
 }
 
+/// Starts serving the TCP connection from the given client address.
+fn accept_tcp<T, I>(acceptor: &Rc<Acceptor<T>>, io: I, addr: SocketAddr, fd: RawFd)
+    where T: ServiceFactory<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static,
+          I: AsyncRead + AsyncWrite + 'static
+{
+    let service = match acceptor.factory.borrow_mut().create_service(Some(addr)) {
+        Ok(service) => DisconnectService::new(service, fd, acceptor.disconnect_probe, acceptor.handle.clone()),
+        Err(err) => {
+            cocaine_log!(acceptor.log, Severity::Error, "failed to create HTTP handler: {}", err);
+            return;
+        }
+    };
+
+    match acceptor.tls {
+        Some(ref certs) => {
+            let this = acceptor.clone();
+            let handshake = certs.acceptor(&acceptor.log).accept(io).then(move |result| {
+                match result {
+                    Ok(io) => {
+                        let service = ConnectionService::new(service, tls::info(io.get_ref().1, &this.forward));
+                        serve_tcp(&this.protocol, &this.handle, io, addr, service, this.upgrades, &this.log)
+                    }
+                    Err(err) => {
+                        cocaine_log!(this.log, Severity::Debug, "failed TLS handshake with {}: {}", addr, err);
+                    }
+                }
+                Ok(())
+            });
+            acceptor.handle.spawn(handshake);
+        }
+        None => {
+            // Plain connections have no attributes, but clients must not supply them.
+            let service = ConnectionService::new(service, ConnectionInfo::plain(&acceptor.forward));
+            serve_tcp(&acceptor.protocol, &acceptor.handle, io, addr, service, acceptor.upgrades, &acceptor.log)
+        }
+    }
+}
+
+struct HttpService<T> {
+    rx: mpsc::UnboundedReceiver<Accepted>,
+    acceptor: Rc<Acceptor<T>>,
+    proxy_protocol: bool,
+}
+
 impl<T> HttpService<T> {
-    fn new(rx: mpsc::UnboundedReceiver<(net::TcpStream, SocketAddr)>, handle: Handle, factory: T, log: Logger) -> Self {
-        Self {
-            rx: rx,
+    fn new(rx: mpsc::UnboundedReceiver<Accepted>, handle: Handle, factory: T, forward: Vec<TlsAttribute>, upgrades: bool,
+        tls: Option<Arc<Certificates>>, proxy_protocol: bool, disconnect_probe: Option<Duration>, log: Logger) -> Self
+    {
+        let acceptor = Acceptor {
             handle: handle,
             protocol: Http::new(),
-            factory: factory,
+            factory: RefCell::new(factory),
+            forward: forward,
+            upgrades: upgrades,
+            tls: tls,
+            disconnect_probe: disconnect_probe,
             log: log,
+        };
+
+        Self {
+            rx: rx,
+            acceptor: Rc::new(acceptor),
+            proxy_protocol: proxy_protocol,
         }
     }
 }
 
-impl<T: ServiceFactory<Request=Request, Response=Response, Error=hyper::Error>> Future for HttpService<T>
-    where T::Instance: 'static
+impl<T: ServiceFactory<Request=Request, Response=Response, Error=hyper::Error> + 'static> Future for HttpService<T>
+    where T::Instance: 'static,
+          <T::Instance as Service>::Future: 'static
 {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let acceptor = &self.acceptor;
+
         loop {
             match self.rx.poll() {
-                Ok(Async::Ready(Some((sock, addr)))) => {
-                    let sock = match TcpStream::from_stream(sock, &self.handle) {
+                Ok(Async::Ready(Some(Accepted::Tcp(sock, addr)))) => {
+                    let sock = match TcpStream::from_stream(sock, &acceptor.handle) {
                         Ok(sock) => sock,
                         Err(err) => {
-                            cocaine_log!(self.log, Severity::Error, "failed to create socket: {}", err);
+                            cocaine_log!(acceptor.log, Severity::Error, "failed to create socket: {}", err);
                             break;
                         }
                     };
-                    let service = match self.factory.create_service(Some(addr)) {
+
+                    let fd = sock.as_raw_fd();
+                    if self.proxy_protocol {
+                        // The real client address is known only after the header is read.
+                        let this = acceptor.clone();
+                        let future = proxy_protocol::read_header(sock).then(move |result| {
+                            match result {
+                                Ok((sock, source)) => accept_tcp(&this, sock, source.unwrap_or(addr), fd),
+                                Err(err) => {
+                                    cocaine_log!(this.log, Severity::Debug, "failed to read PROXY protocol header from {}: {}", addr, err);
+                                }
+                            }
+                            Ok(())
+                        });
+                        acceptor.handle.spawn(future);
+                    } else {
+                        accept_tcp(acceptor, sock, addr, fd);
+                    }
+                }
+                Ok(Async::Ready(Some(Accepted::Unix(sock)))) => {
+                    let sock = match UnixStream::from_stream(sock, &acceptor.handle) {
                         Ok(sock) => sock,
                         Err(err) => {
-                            cocaine_log!(self.log, Severity::Error, "failed to create HTTP handler: {}", err);
+                            cocaine_log!(acceptor.log, Severity::Error, "failed to create Unix socket: {}", err);
+                            break;
+                        }
+                    };
+                    let fd = sock.as_raw_fd();
+                    let service = match acceptor.factory.borrow_mut().create_service(None) {
+                        Ok(service) => DisconnectService::new(service, fd, acceptor.disconnect_probe, acceptor.handle.clone()),
+                        Err(err) => {
+                            cocaine_log!(acceptor.log, Severity::Error, "failed to create HTTP handler: {}", err);
                             break;
                         }
                     };
-                    self.protocol.bind_connection(&self.handle, sock, addr, service);
+                    let service = ConnectionService::new(service, ConnectionInfo::plain(&acceptor.forward));
+
+                    // Unix sockets have no peer address, hence no deprecated binding.
+                    let log = acceptor.log.clone();
+                    if acceptor.upgrades {
+                        let conn = UpgradeConnection::new(&acceptor.protocol, sock, service, acceptor.handle.clone())
+                            .map_err(move |err| {
+                                cocaine_log!(log, Severity::Debug, "failed to serve Unix socket connection: {}", err);
+                            });
+                        acceptor.handle.spawn(conn);
+                    } else {
+                        let conn = acceptor.protocol.serve_connection(sock, service).map_err(move |err| {
+                            cocaine_log!(log, Severity::Debug, "failed to serve Unix socket connection: {}", err);
+                        });
+                        acceptor.handle.spawn(conn);
+                    }
                 }
                 Ok(Async::NotReady) => {
                     break;
@@ -194,21 +361,97 @@ impl<F> GodFather for F
     }
 }
 
+/// A stream of accepted connections, which is paused while the memory budget is exceeded.
+///
+/// Instead of rejecting requests under memory pressure, new connections are left in the listen
+/// backlog for a short time slice, after which the budget is checked again. This smooths out
+/// short bursts without clients noticing anything but a slightly increased latency.
+struct Throttle<S> {
+    inner: S,
+    budget: Arc<MemoryBudget>,
+    slice: Duration,
+    timeout: Option<Timeout>,
+    paused: bool,
+    handle: Handle,
+    log: Logger,
+}
+
+impl<S> Throttle<S> {
+    fn new(inner: S, budget: Arc<MemoryBudget>, slice: Duration, handle: Handle, log: Logger) -> Self {
+        Self {
+            inner: inner,
+            budget: budget,
+            slice: slice,
+            timeout: None,
+            paused: false,
+            handle: handle,
+            log: log,
+        }
+    }
+}
+
+impl<S: Stream<Error = io::Error>> Stream for Throttle<S> {
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(mut timeout) = self.timeout.take() {
+                if let Async::NotReady = timeout.poll()? {
+                    self.timeout = Some(timeout);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            if self.budget.is_exceeded() {
+                if !self.paused {
+                    self.paused = true;
+                    self.budget.mark_paused();
+                    cocaine_log!(self.log, Severity::Warn, "paused accepting new connections: {} bytes of buffered bodies exceed the soft limit",
+                        self.budget.used());
+                }
+
+                self.timeout = Some(Timeout::new(self.slice, &self.handle)?);
+                continue;
+            }
+
+            if self.paused {
+                self.paused = false;
+                cocaine_log!(self.log, Severity::Info, "resumed accepting new connections");
+            }
+
+            return self.inner.poll();
+        }
+    }
+}
+
 /// Chained server configuration.
 pub struct ServerConfig<G> {
-    addr: SocketAddr,
+    addr: Endpoint,
     backlog: i32,
     godfather: G,
     num_threads: usize,
+    budget: Option<(Arc<MemoryBudget>, Duration)>,
+    forward: Vec<TlsAttribute>,
+    upgrades: bool,
+    tls: Option<Arc<Certificates>>,
+    proxy_protocol: bool,
+    disconnect_probe: Option<Duration>,
 }
 
 impl ServerConfig<DefaultGodFather> {
-    pub fn new(addr: SocketAddr) -> Self {
+    pub fn new<A: Into<Endpoint>>(addr: A) -> Self {
         Self {
-            addr: addr,
+            addr: addr.into(),
             backlog: DEFAULT_BACKLOG,
             godfather: DefaultGodFather,
             num_threads: DEFAULT_NUM_THREADS,
+            budget: None,
+            forward: Vec::new(),
+            upgrades: false,
+            tls: None,
+            proxy_protocol: false,
+            disconnect_probe: None,
         }
     }
 }
@@ -225,6 +468,12 @@ impl<G> ServerConfig<G> {
             backlog: self.backlog,
             godfather: godfather,
             num_threads: self.num_threads,
+            budget: self.budget,
+            forward: self.forward,
+            upgrades: self.upgrades,
+            tls: self.tls,
+            proxy_protocol: self.proxy_protocol,
+            disconnect_probe: self.disconnect_probe,
         }
     }
 
@@ -232,6 +481,49 @@ impl<G> ServerConfig<G> {
         self.num_threads = num_threads;
         self
     }
+
+    /// Pauses accepting new connections for the given time slice while the memory budget is
+    /// exceeded.
+    pub fn memory_budget(mut self, budget: Arc<MemoryBudget>, slice: Duration) -> Self {
+        self.budget = Some((budget, slice));
+        self
+    }
+
+    /// Forwards the given connection attributes to applications in request headers.
+    pub fn forward(mut self, forward: Vec<TlsAttribute>) -> Self {
+        self.forward = forward;
+        self
+    }
+
+    /// Allows routes to take connections over with HTTP upgrades.
+    ///
+    /// Note that TCP connections are served without peer addresses in this mode.
+    pub fn upgrades(mut self, enabled: bool) -> Self {
+        self.upgrades = enabled;
+        self
+    }
+
+    /// Terminates TLS on TCP connections with the given certificates.
+    ///
+    /// Unix sockets are always served in plain text.
+    pub fn tls(mut self, certs: Arc<Certificates>) -> Self {
+        self.tls = Some(certs);
+        self
+    }
+
+    /// Requires TCP connections to start with a PROXY protocol header, which replaces the peer
+    /// address with the one of the real client.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Checks sockets with requests in flight every given interval, dropping requests of clients,
+    /// which have closed their connections.
+    pub fn disconnect_probe(mut self, interval: Duration) -> Self {
+        self.disconnect_probe = Some(interval);
+        self
+    }
 }
 
 fn bind(addr: SocketAddr, backlog: i32, handle: &Handle) -> Result<TcpListener, io::Error> {
@@ -248,10 +540,44 @@ fn bind(addr: SocketAddr, backlog: i32, handle: &Handle) -> Result<TcpListener,
     TcpListener::from_listener(listener, &addr, handle)
 }
 
+fn bind_unix(path: &Path, backlog: i32, handle: &Handle) -> Result<UnixListener, io::Error> {
+    // A socket file left by the previous run prevents binding, while other files are not ours to
+    // remove.
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path, handle)?;
+
+    // Listening again on the listening socket just updates its backlog.
+    if unsafe { libc::listen(listener.as_raw_fd(), backlog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+fn dup(fd: RawFd) -> Result<RawFd, io::Error> {
+    let fd = unsafe { libc::dup(fd) };
+    if fd >= 0 {
+        Ok(fd)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
 #[derive(Debug)]
 pub struct ServerGroup {
     core: Core,
-    servers: Vec<(TcpListener, Vec<mpsc::UnboundedSender<(net::TcpStream, SocketAddr)>>)>,
+    servers: Vec<(Listener, Option<(Arc<MemoryBudget>, Duration)>, Vec<mpsc::UnboundedSender<Accepted>>)>,
     threads: Vec<JoinHandle<Result<(), io::Error>>>,
     log: Logger,
 }
@@ -274,9 +600,13 @@ impl ServerGroup {
     pub fn expose<F, T, G>(mut self, cfg: ServerConfig<G>, factory: F) -> Result<Self, io::Error>
         where F: ServiceFactorySpawn<Factory = T> + 'static,
               T: ServiceFactory<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+              <T::Instance as Service>::Future: 'static,
               G: GodFather
     {
-        let listener = bind(cfg.addr, cfg.backlog, &self.core.handle())?;
+        let listener = match cfg.addr {
+            Endpoint::Tcp(addr) => Listener::Tcp(bind(addr, cfg.backlog, &self.core.handle())?),
+            Endpoint::Unix(ref path) => Listener::Unix(bind_unix(path, cfg.backlog, &self.core.handle())?),
+        };
 
         let mut dispatchers = Vec::new();
         let factory = Arc::new(factory);
@@ -284,6 +614,11 @@ impl ServerGroup {
         for id in 0..cfg.num_threads {
             let (tx, rx) = mpsc::unbounded();
             let factory = factory.clone();
+            let forward = cfg.forward.clone();
+            let upgrades = cfg.upgrades;
+            let tls = cfg.tls.clone();
+            let proxy_protocol = cfg.proxy_protocol;
+            let disconnect_probe = cfg.disconnect_probe;
             let log = self.log.clone();
             let thread = thread::Builder::new().name(cfg.godfather.name(id)).spawn(move || {
                 let mut core = Core::new()?;
@@ -301,7 +636,7 @@ impl ServerGroup {
 
                 // This will stop just after listener is stopped, because it polls the connection
                 // receiver.
-                core.run(HttpService::new(rx, handle.clone(), factory, log))?;
+                core.run(HttpService::new(rx, handle.clone(), factory, forward, upgrades, tls, proxy_protocol, disconnect_probe, log))?;
 
                 let monitor = WaitUntilZero { info: info };
                 let timeout = Timeout::new(Duration::new(5, 0), &handle)?;
@@ -324,7 +659,7 @@ impl ServerGroup {
             self.threads.push(thread);
         }
 
-        self.servers.push((listener, dispatchers));
+        self.servers.push((listener, cfg.budget, dispatchers));
 
         Ok(self)
     }
@@ -341,21 +676,43 @@ impl ServerGroup {
         where F: Future<Item = (), Error = ()>
     {
         let log = self.log.clone();
-        let listeners = self.servers.into_iter().map(|(listener, dispatchers)| {
+        let handle = self.core.handle();
+        let listeners = self.servers.into_iter().map(|(listener, budget, dispatchers)| {
             let log = log.clone();
             let mut iter = dispatchers.into_iter().cycle();
-            
-            listener.incoming().for_each(move |accept| {
-                let (sock, addr) = accept;
-                let fd = unsafe { libc::dup(sock.as_raw_fd()) };
-                if fd >= 0 {
-                    let cloned = unsafe { net::TcpStream::from_raw_fd(fd) };
-                    if let Err(..) = iter.next().expect("iterator is infinite").unbounded_send((cloned, addr)) {
-                        cocaine_log!(log, Severity::Error, "failed to schedule incoming TCP connection from {}", addr);
+
+            // Accepted sockets are registered in this thread's event loop, so workers receive
+            // their duplicates to register in their own ones.
+            let incoming: Box<dyn Stream<Item = Result<Accepted, io::Error>, Error = io::Error>> = match listener {
+                Listener::Tcp(listener) => {
+                    Box::new(listener.incoming().map(|(sock, addr)| {
+                        dup(sock.as_raw_fd()).map(|fd| Accepted::Tcp(unsafe { net::TcpStream::from_raw_fd(fd) }, addr))
+                    }))
+                }
+                Listener::Unix(listener) => {
+                    Box::new(listener.incoming().map(|(sock, ..)| {
+                        dup(sock.as_raw_fd()).map(|fd| Accepted::Unix(unsafe { StdUnixStream::from_raw_fd(fd) }))
+                    }))
+                }
+            };
+
+            let incoming: Box<dyn Stream<Item = _, Error = io::Error>> = match budget {
+                Some((budget, slice)) => {
+                    Box::new(Throttle::new(incoming, budget, slice, handle.clone(), log.clone()))
+                }
+                None => incoming,
+            };
+
+            incoming.for_each(move |accepted| {
+                match accepted {
+                    Ok(accepted) => {
+                        if let Err(err) = iter.next().expect("iterator is infinite").unbounded_send(accepted) {
+                            cocaine_log!(log, Severity::Error, "failed to schedule incoming connection from {}", err.into_inner().peer());
+                        }
+                    }
+                    Err(err) => {
+                        cocaine_log!(log, Severity::Error, "failed to dup file descriptor: {}", err);
                     }
-                } else {
-                    let err = io::Error::last_os_error();
-                    cocaine_log!(log, Severity::Error, "failed to dup file descriptor: {}", err);
                 }
 
                 Ok(())
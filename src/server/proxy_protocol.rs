@@ -0,0 +1,242 @@
+//! PROXY protocol, with which L4 balancers pass the real client address ahead of the connection
+//! data.
+//!
+//! Both the text (v1) and the binary (v2) versions are accepted, see
+//! <https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt>. Headers without a usable source
+//! address, like v2 `LOCAL` health checks of the balancer itself, leave the peer address intact.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::{Async, Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Maximum length of the v1 header line including CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Headers may carry TLVs after addresses, but nothing that large is expected.
+const MAX_LEN: usize = 4096;
+
+const READ_CHUNK: usize = 512;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    /// Length of the header, after which the connection data starts.
+    len: usize,
+    source: Option<SocketAddr>,
+}
+
+fn field<T: FromStr>(value: Option<&str>) -> Result<T, io::Error> {
+    value.and_then(|value| value.parse().ok()).ok_or_else(|| invalid("malformed PROXY protocol v1 header"))
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, io::Error> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(..) => return Err(invalid("PROXY protocol v1 header is too long")),
+        None if buf.len() >= V1_MAX_LEN => return Err(invalid("PROXY protocol v1 header is too long")),
+        None => return Ok(None),
+    };
+
+    let line = str::from_utf8(&buf[..end]).map_err(|_| invalid("malformed PROXY protocol v1 header"))?;
+    let mut parts = line.split(' ').skip(1);
+    let source = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some("TCP4") | Some("TCP6") => {
+            let addr: IpAddr = field(parts.next())?;
+            let _: IpAddr = field(parts.next())?;
+            let port: u16 = field(parts.next())?;
+            let _: u16 = field(parts.next())?;
+            Some(SocketAddr::new(addr, port))
+        }
+        Some(..) | None => return Err(invalid("unsupported PROXY protocol v1 address family")),
+    };
+
+    Ok(Some(Header { len: end + 2, source: source }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, io::Error> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+
+    if buf[12] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let len = V2_HEADER_LEN + BigEndian::read_u16(&buf[14..16]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let addrs = &buf[V2_HEADER_LEN..len];
+    let source = match (buf[12] & 0x0f, buf[13]) {
+        // Connections made by the balancer itself, for example health checks.
+        (0x0, ..) => None,
+        (0x1, 0x11) if addrs.len() >= 12 => {
+            let addr = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Some(SocketAddr::new(addr.into(), BigEndian::read_u16(&addrs[8..10])))
+        }
+        (0x1, 0x21) if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), BigEndian::read_u16(&addrs[32..34])))
+        }
+        (0x1, 0x11) | (0x1, 0x21) => return Err(invalid("truncated PROXY protocol v2 addresses")),
+        // Unspecified and Unix socket families.
+        (0x1, ..) => None,
+        (..) => return Err(invalid("unsupported PROXY protocol v2 command")),
+    };
+
+    Ok(Some(Header { len: len, source: source }))
+}
+
+/// Parses the header at the beginning of the buffer, returning `None` if more data is required.
+fn parse(buf: &[u8]) -> Result<Option<Header>, io::Error> {
+    let len = cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..len] == V2_SIGNATURE[..len] {
+        return parse_v2(buf);
+    }
+
+    let len = cmp::min(buf.len(), V1_PREFIX.len());
+    if buf[..len] == V1_PREFIX[..len] {
+        return parse_v1(buf);
+    }
+
+    Err(invalid("missing PROXY protocol header"))
+}
+
+/// A connection, which yields bytes read ahead of the header before reading from the socket.
+pub struct Rewind<I> {
+    pre: Vec<u8>,
+    io: I,
+}
+
+impl<I: Read> Read for Rewind<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pre.is_empty() {
+            return self.io.read(buf);
+        }
+
+        let size = cmp::min(buf.len(), self.pre.len());
+        buf[..size].copy_from_slice(&self.pre[..size]);
+        self.pre.drain(..size);
+        Ok(size)
+    }
+}
+
+impl<I: Write> Write for Rewind<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for Rewind<I> {}
+
+impl<I: AsyncWrite> AsyncWrite for Rewind<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+/// Reads the header, resolving with the connection and the client address it carries.
+pub struct ReadHeader<I> {
+    io: Option<I>,
+    buf: Vec<u8>,
+}
+
+pub fn read_header<I: AsyncRead>(io: I) -> ReadHeader<I> {
+    ReadHeader {
+        io: Some(io),
+        buf: Vec::new(),
+    }
+}
+
+impl<I: AsyncRead> Future for ReadHeader<I> {
+    type Item = (Rewind<I>, Option<SocketAddr>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(header) = parse(&self.buf)? {
+                let io = self.io.take().expect("future must not be polled after completion");
+                let pre = self.buf.split_off(header.len);
+                return Ok(Async::Ready((Rewind { pre: pre, io: io }, header.source)));
+            }
+
+            if self.buf.len() >= MAX_LEN {
+                return Err(invalid("PROXY protocol header is too long"));
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let io = self.io.as_mut().expect("future must not be polled after completion");
+            match io.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{Header, parse};
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        assert_eq!(Some(Header { len: 45, source: Some(source) }), parse(buf).unwrap());
+
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let source: SocketAddr = "[2001:db8::1]:56324".parse().unwrap();
+        assert_eq!(Some(source), parse(buf).unwrap().unwrap().source);
+
+        assert_eq!(Some(Header { len: 15, source: None }), parse(b"PROXY UNKNOWN\r\n").unwrap());
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        assert_eq!(None, parse(b"").unwrap());
+        assert_eq!(None, parse(b"PRO").unwrap());
+        assert_eq!(None, parse(b"PROXY TCP4 192.0.2.1").unwrap());
+        assert_eq!(None, parse(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c").unwrap());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        buf.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        buf.extend_from_slice(b"GET");
+
+        let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        assert_eq!(Some(Header { len: 28, source: Some(source) }), parse(&buf).unwrap());
+
+        // Local connections keep the peer address.
+        let buf = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(Some(Header { len: 16, source: None }), parse(buf).unwrap());
+    }
+
+    #[test]
+    fn test_parse_rejects_missing_header() {
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(b"PROXY SCTP 192.0.2.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! TLS termination with certificates reloaded on change.
+//!
+//! Certificate and key files are checked for modifications at most once per the reload interval,
+//! lazily on accepting connections, so rotated certificates are picked up without restarting the
+//! proxy. Files that fail to load are reported and otherwise ignored, leaving the previous
+//! certificate in use.
+//!
+//! Connection attributes forwarded to applications are taken from the negotiated session.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use cocaine::logging::{Logger, Severity};
+
+use rustls::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, ServerSession, Session};
+use rustls::internal::pemfile;
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsAttribute;
+
+use super::conn::ConnectionInfo;
+
+/// Protocols advertised with ALPN, since the listener speaks HTTP/1.x only.
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"http/1.1"];
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Loads the server config from PEM-encoded certificate chain and private key files.
+///
+/// With certificates of authorities clients are asked for their certificates, which must be issued
+/// by one of them if presented.
+fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerConfig, io::Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|()| invalid(format!("failed to parse certificates from {}", cert.display())))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", cert.display())));
+    }
+
+    // Both PKCS #8 and traditional RSA keys are accepted.
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|()| invalid(format!("failed to parse private key from {}", key.display())))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|()| invalid(format!("failed to parse private key from {}", key.display())))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(invalid(format!("no private key found in {}", key.display()))),
+    };
+
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, ..) = roots.add_pem_file(&mut BufReader::new(File::open(path)?))
+                .map_err(|()| invalid(format!("failed to parse client CA certificates from {}", path.display())))?;
+            if added == 0 {
+                return Err(invalid(format!("no client CA certificates found in {}", path.display())));
+            }
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config.set_single_cert(certs, key).map_err(|err| invalid(err.to_string()))?;
+    config.set_protocols(&ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_vec()).collect::<Vec<_>>());
+
+    Ok(config)
+}
+
+struct State {
+    acceptor: TlsAcceptor,
+    /// Modification times of the certificate and the key files at the moment of loading.
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    checked: Instant,
+}
+
+/// TLS certificates shared between all workers of the listener.
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    interval: Duration,
+    state: Mutex<State>,
+}
+
+impl Certificates {
+    /// Loads certificates, failing if they are not valid.
+    pub fn load<P: Into<PathBuf>>(cert: P, key: P, client_ca: Option<P>, interval: Duration) -> Result<Self, io::Error> {
+        let cert = cert.into();
+        let key = key.into();
+        let client_ca = client_ca.map(Into::into);
+
+        let modified = (modified(&cert), modified(&key));
+        let config = load(&cert, &key, client_ca.as_ref().map(PathBuf::as_path))?;
+        let state = State {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            modified: modified,
+            checked: Instant::now(),
+        };
+
+        let result = Self {
+            cert: cert,
+            key: key,
+            client_ca: client_ca,
+            interval: interval,
+            state: Mutex::new(state),
+        };
+
+        Ok(result)
+    }
+
+    /// Returns the acceptor with the most recent certificates, reloading them if files have
+    /// changed since the last check.
+    pub fn acceptor(&self, log: &Logger) -> TlsAcceptor {
+        let mut state = self.state.lock().unwrap();
+
+        if state.checked.elapsed() >= self.interval {
+            state.checked = Instant::now();
+
+            let modified = (modified(&self.cert), modified(&self.key));
+            if modified != state.modified {
+                // Files are remembered even on failure, so that a broken certificate is reported
+                // once instead of on every check until it's fixed.
+                state.modified = modified;
+
+                match load(&self.cert, &self.key, self.client_ca.as_ref().map(PathBuf::as_path)) {
+                    Ok(config) => {
+                        state.acceptor = TlsAcceptor::from(Arc::new(config));
+                        cocaine_log!(log, Severity::Info, "reloaded TLS certificates from {}", self.cert.display());
+                    }
+                    Err(err) => {
+                        cocaine_log!(log, Severity::Error, "failed to reload TLS certificates, keeping previous ones: {}", err);
+                    }
+                }
+            }
+        }
+
+        state.acceptor.clone()
+    }
+}
+
+/// Extracts the given attributes of the negotiated session.
+pub fn info(session: &ServerSession, forward: &[TlsAttribute]) -> ConnectionInfo {
+    ConnectionInfo::new(forward, |attribute| {
+        match attribute {
+            TlsAttribute::Protocol => session.get_protocol_version().map(|version| format!("{:?}", version)),
+            TlsAttribute::Cipher => session.get_negotiated_ciphersuite().map(|suite| format!("{:?}", suite.suite)),
+            TlsAttribute::Sni => session.get_sni_hostname().map(str::to_owned),
+            TlsAttribute::ClientCert => {
+                session.get_peer_certificates()
+                    .and_then(|certs| certs.into_iter().next())
+                    .map(|cert| fingerprint(&cert.0))
+            }
+        }
+    })
+}
+
+/// Returns SHA-256 fingerprint of the DER-encoded certificate as lowercase hex.
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::fingerprint;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", fingerprint(b""));
+    }
+}
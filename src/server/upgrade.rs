@@ -0,0 +1,179 @@
+//! HTTP upgrades, handing connections over from HTTP to other protocols.
+//!
+//! A route accepting an upgrade registers a tunnel in the per-worker registry and marks its
+//! `101 Switching Protocols` response with the issued token. The connection service strips the
+//! token, while the connection driver stops serving HTTP once the response is written and passes
+//! the raw socket together with any bytes read ahead into the tunnel.
+//!
+//! Routes run on the same worker thread as connections they serve, so the registry is
+//! thread-local and tunnels are registered only when their response is ready, which leaves no
+//! chance for them to leak.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll};
+use hyper::{self, StatusCode};
+use hyper::server::{Connection, Request, Response};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_service::Service;
+
+/// Private header carrying the tunnel token from the route to the connection service.
+const TOKEN_HEADER: &str = "X-Cocaine-Upgrade-Token";
+
+/// A raw connection taken over from HTTP.
+pub trait Io: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> Io for T {}
+
+/// Serves the upgraded connection, given bytes the client has sent after the upgrade request.
+pub type Tunnel = Box<dyn FnOnce(Box<dyn Io>, Bytes) -> Box<dyn Future<Item = (), Error = ()>>>;
+
+thread_local! {
+    static PENDING: RefCell<HashMap<u64, Tunnel>> = RefCell::new(HashMap::new());
+    static NEXT_TOKEN: Cell<u64> = Cell::new(0);
+}
+
+/// Registers the tunnel and marks the response with its token.
+///
+/// Must be called on the worker thread serving the connection, just before returning the
+/// response.
+pub fn register_upgrade(resp: &mut Response, tunnel: Tunnel) {
+    let token = NEXT_TOKEN.with(|next| {
+        let token = next.get();
+        next.set(token.wrapping_add(1));
+        token
+    });
+
+    PENDING.with(|pending| pending.borrow_mut().insert(token, tunnel));
+    resp.headers_mut().set_raw(TOKEN_HEADER, token.to_string());
+}
+
+/// Takes the tunnel registered for the response, removing its token.
+fn take(resp: &mut Response) -> Option<Tunnel> {
+    let token = resp.headers().get_raw(TOKEN_HEADER)
+        .and_then(|raw| raw.one())
+        .and_then(|value| String::from_utf8_lossy(value).parse::<u64>().ok());
+    resp.headers_mut().remove_raw(TOKEN_HEADER);
+
+    match token {
+        Some(token) if resp.status() == StatusCode::SwitchingProtocols => {
+            PENDING.with(|pending| pending.borrow_mut().remove(&token))
+        }
+        Some(token) => {
+            PENDING.with(|pending| pending.borrow_mut().remove(&token));
+            None
+        }
+        None => None,
+    }
+}
+
+/// The tunnel of the upgraded connection, shared between its service and driver.
+type Slot = Rc<RefCell<Option<Tunnel>>>;
+
+/// Connection service, which catches upgrade responses.
+pub struct UpgradeService<S> {
+    inner: S,
+    slot: Slot,
+}
+
+impl<S> Service for UpgradeService<S>
+    where S: Service<Request = Request, Response = Response, Error = hyper::Error>,
+          S::Future: 'static
+{
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Response, Error = hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let slot = self.slot.clone();
+
+        let future = self.inner.call(req).map(move |mut resp| {
+            if let Some(tunnel) = take(&mut resp) {
+                *slot.borrow_mut() = Some(tunnel);
+            }
+            resp
+        });
+
+        Box::new(future)
+    }
+}
+
+/// Drives an HTTP connection, which may be upgraded.
+pub struct UpgradeConnection<I, S>
+    where S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          I: AsyncRead + AsyncWrite + 'static
+{
+    conn: Option<Connection<I, UpgradeService<S>>>,
+    slot: Slot,
+    handle: Handle,
+    closing: bool,
+}
+
+impl<I, S> UpgradeConnection<I, S>
+    where S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          S::Future: 'static,
+          I: AsyncRead + AsyncWrite + 'static
+{
+    pub fn new(protocol: &hyper::server::Http, io: I, service: S, handle: Handle) -> Self {
+        let slot = Rc::new(RefCell::new(None));
+        let service = UpgradeService {
+            inner: service,
+            slot: slot.clone(),
+        };
+
+        Self {
+            conn: Some(protocol.serve_connection(io, service)),
+            slot: slot,
+            handle: handle,
+            closing: false,
+        }
+    }
+}
+
+impl<I, S> Future for UpgradeConnection<I, S>
+    where S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+          S::Future: 'static,
+          I: AsyncRead + AsyncWrite + 'static
+{
+    type Item = ();
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<(), hyper::Error> {
+        loop {
+            let conn = self.conn.as_mut().expect("connection must not be polled after completion");
+
+            // Once the upgrade response is caught, no more requests are read, so the connection
+            // is done right after the response is flushed.
+            if !self.closing && self.slot.borrow().is_some() {
+                self.closing = true;
+                conn.disable_keep_alive();
+            }
+
+            match conn.poll_without_shutdown()? {
+                Async::Ready(()) => break,
+                Async::NotReady if !self.closing && self.slot.borrow().is_some() => continue,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+
+        let conn = self.conn.take().expect("connection must not be polled after completion");
+        if let Some(tunnel) = self.slot.borrow_mut().take() {
+            let parts = conn.into_parts();
+            // The service lives as long as the tunnel, so that the connection is still accounted
+            // as active.
+            let service = parts.service;
+            let future = tunnel(Box::new(parts.io), parts.read_buf).then(move |result| {
+                drop(service);
+                result
+            });
+            self.handle.spawn(future);
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
@@ -1,50 +1,47 @@
 use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::{future, Future};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio_core::reactor::{Handle, Timeout};
 use tokio_service::Service;
 
-use hyper::{self, StatusCode};
+use hyper::{self, HttpVersion, Method, StatusCode};
+use hyper::header::{Connection, ConnectionOption, ContentLength, TransferEncoding};
 use hyper::server::{Request, Response};
 
 use cocaine::{Resolver, ServiceBuilder};
 use cocaine::service::Locator;
-use cocaine::logging::{Severity, Logger};
+use cocaine::logging::Logger;
 
 use crate::{Metrics, DEFAULT_LOCATOR_NAME};
 use crate::config::Config;
+use crate::logging::ConnectionLog;
 use crate::metrics::{Meter, Count};
-use crate::pool::{Event, PoolTask};
-use crate::route::Router;
+use crate::pool::{Event, Negotiator, PoolTask, SettingsRegistry};
+use crate::route::{self, Router, CLIENT_CLOSED_REQUEST};
 use crate::service::{ServiceFactory, ServiceFactorySpawn};
 
 pub struct ProxyService {
     addr: Option<SocketAddr>,
     router: Router,
     metrics: Arc<Metrics>,
-    log: Logger,
+    connections: Arc<ConnectionLog>,
 }
 
 impl ProxyService {
-    fn new(addr: Option<SocketAddr>, router: Router, metrics: Arc<Metrics>, log: Logger) -> Self {
+    fn new(addr: Option<SocketAddr>, router: Router, metrics: Arc<Metrics>, connections: Arc<ConnectionLog>) -> Self {
         metrics.connections.active.add(1);
         metrics.connections.accepted.add(1);
-
-        if let Some(addr) = addr {
-            cocaine_log!(log, Severity::Info, "accepted connection from {}", addr);
-        } else {
-            cocaine_log!(log, Severity::Info, "accepted connection from Unix socket");
-        }
+        connections.on_open(addr);
 
         Self {
             addr: addr,
             router: router,
             metrics: metrics,
-            log: log,
+            connections: connections,
         }
     }
 }
@@ -58,10 +55,28 @@ impl Service for ProxyService {
     fn call(&self, req: Request) -> Self::Future {
         let metrics = self.metrics.clone();
 
+        let http10 = match req.version() {
+            HttpVersion::Http10 | HttpVersion::Http09 => {
+                let keep_alive = req.headers().get::<Connection>()
+                    .map(|conn| conn.contains(&ConnectionOption::KeepAlive))
+                    .unwrap_or(false);
+                Some((req.method().clone(), keep_alive))
+            }
+            _ => None,
+        };
+
+        let birth = Instant::now();
         metrics.requests.mark(1);
-        Box::new(self.router.process(req).and_then(move |resp| {
-            if resp.status().is_server_error() {
-                metrics.responses.c5xx.mark(1);
+        Box::new(self.router.process(req).and_then(move |mut resp| {
+            // Client aborts are reported with non-standard 499 status, which is accounted
+            // separately by routes, since nobody receives such responses.
+            if resp.status() != CLIENT_CLOSED_REQUEST {
+                metrics.responses.mark(resp.status());
+                metrics.observe_latency(birth.elapsed());
+            }
+
+            if let Some((method, keep_alive)) = http10 {
+                adapt_http10(&mut resp, &method, keep_alive);
             }
 
             Ok(resp)
@@ -69,14 +84,29 @@ impl Service for ProxyService {
     }
 }
 
+/// Adapts the response for HTTP/1.0 clients, which can't decode chunked bodies.
+///
+/// Bodies of unknown length are delimited by closing the connection instead. The connection is
+/// closed by default and is kept alive only if the client asked for it explicitly and the end of
+/// the response can be detected without closing.
+fn adapt_http10(resp: &mut Response, method: &Method, keep_alive: bool) {
+    resp.headers_mut().remove::<TransferEncoding>();
+
+    let delimited = *method == Method::Head || resp.headers().has::<ContentLength>() || match resp.status() {
+        StatusCode::NoContent | StatusCode::NotModified => true,
+        _ => false,
+    };
+
+    if keep_alive && delimited {
+        resp.headers_mut().set(Connection::keep_alive());
+    } else {
+        resp.headers_mut().set(Connection::close());
+    }
+}
+
 impl Drop for ProxyService {
     fn drop(&mut self) {
-        if let Some(addr) = self.addr.take() {
-            cocaine_log!(self.log, Severity::Info, "closed connection from {}", addr);
-        } else {
-            cocaine_log!(self.log, Severity::Info, "closed connection from Unix socket");
-        }
-
+        self.connections.on_close(self.addr.take());
         self.metrics.connections.active.add(-1);
     }
 }
@@ -125,12 +155,22 @@ impl<T> Service for TimeoutMiddleware<T>
     fn call(&self, req: Self::Request) -> Self::Future {
         let timeout = future::result(Timeout::new(self.timeout, &self.handle))
             .flatten()
-            .map(|()| Self::Response::from(TimedOut))
+            .map(|()| None)
             .map_err(From::from);
 
         let future = self.upstream.call(req)
+            .map(Some)
             .select(timeout)
-            .map(|v| v.0)
+            .map(|(resp, other)| {
+                match resp {
+                    Some(resp) => resp,
+                    None => {
+                        // Routes account the timeout while their futures are being dropped.
+                        route::drop_timed_out(other);
+                        Self::Response::from(TimedOut)
+                    }
+                }
+            })
             .map_err(|e| e.0);
 
         Box::new(future)
@@ -143,7 +183,7 @@ pub struct ProxyServiceFactory {
     timeout: Duration,
     handle: Handle,
     metrics: Arc<Metrics>,
-    log: Logger,
+    connections: Arc<ConnectionLog>,
 }
 
 impl ServiceFactory for ProxyServiceFactory {
@@ -153,7 +193,7 @@ impl ServiceFactory for ProxyServiceFactory {
     type Error    = hyper::Error;
 
     fn create_service(&mut self, addr: Option<SocketAddr>) -> Result<Self::Instance, io::Error> {
-        let service = ProxyService::new(addr, self.router.clone(), self.metrics.clone(), self.log.clone());
+        let service = ProxyService::new(addr, self.router.clone(), self.metrics.clone(), self.connections.clone());
         let wrapped = TimeoutMiddleware::new(service, self.timeout, self.handle.clone());
 
         Ok(wrapped)
@@ -164,17 +204,19 @@ pub struct ProxyServiceFactoryFactory<I> {
     channels: Mutex<I>,
     cfg: Config,
     router: Router,
+    settings: Arc<SettingsRegistry>,
     metrics: Arc<Metrics>,
     log: Logger,
 }
 
 impl<I> ProxyServiceFactoryFactory<I>
 where
-    I: Iterator<Item = (UnboundedSender<Event>, UnboundedReceiver<Event>)> + Send
+    I: Iterator<Item = Vec<(Config, UnboundedSender<Event>, UnboundedReceiver<Event>)>> + Send
 {
     pub fn new(channels: I,
                cfg: Config,
                router: Router,
+               settings: Arc<SettingsRegistry>,
                metrics: Arc<Metrics>,
                log: Logger) -> Self
     {
@@ -182,6 +224,7 @@ where
             channels: Mutex::new(channels),
             cfg: cfg,
             router: router,
+            settings: settings,
             metrics: metrics,
             log: log,
         }
@@ -190,33 +233,89 @@ where
 
 impl<I> ServiceFactorySpawn for ProxyServiceFactoryFactory<I>
 where
-    I: Iterator<Item = (UnboundedSender<Event>, UnboundedReceiver<Event>)> + Send
+    I: Iterator<Item = Vec<(Config, UnboundedSender<Event>, UnboundedReceiver<Event>)>> + Send
 {
     type Factory = ProxyServiceFactory;
 
     fn create_factory(&self, handle: &Handle) -> Self::Factory {
-        let (tx, rx) = self.channels.lock().unwrap().next()
+        let channels = self.channels.lock().unwrap().next()
             .expect("number of event channels must be exactly the same as the number of threads");
 
-        let locator_addrs = self.cfg.locators().iter()
-            .map(|&(addr, port)| SocketAddr::new(addr, port))
-            .collect::<Vec<SocketAddr>>();
-        let locator = ServiceBuilder::new(DEFAULT_LOCATOR_NAME)
-            .locator_addrs(locator_addrs)
-            .build(handle);
-        let locator = Locator::new(locator);
-        let resolver = Resolver::new(locator);
+        // There is a separate pool for each cluster, i.e. for the default one and for each tenant.
+        for (cfg, tx, rx) in channels {
+            let locator_addrs = cfg.locators().iter()
+                .map(|&(addr, port)| SocketAddr::new(addr, port))
+                .collect::<Vec<SocketAddr>>();
+            let locator = ServiceBuilder::new(DEFAULT_LOCATOR_NAME)
+                .locator_addrs(locator_addrs)
+                .build(handle);
+            let negotiator = Negotiator::new(locator.clone());
+            let resolver = Resolver::new(Locator::new(locator));
+
+            // This will stop after all associated connections are closed.
+            let pool = PoolTask::new(handle.clone(), resolver, self.log.clone(), tx, rx, cfg, self.settings.clone())
+                .with_negotiator(negotiator)
+                .with_stats(self.metrics.pools.clone());
+
+            handle.spawn(pool);
+        }
+
+        crate::route::bind_local_upstreams(handle);
 
-        // This will stop after all associated connections are closed.
-        let pool = PoolTask::new(handle.clone(), resolver, self.log.clone(), tx, rx, self.cfg.clone());
+        let connections = Arc::new(ConnectionLog::new(self.log.clone(), self.cfg.logging().connections()));
+        handle.spawn(connections.clone().run(handle));
 
-        handle.spawn(pool);
         ProxyServiceFactory {
             router: self.router.clone(),
             timeout: self.cfg.timeout(),
             handle: handle.clone(),
             metrics: self.metrics.clone(),
-            log: self.log.clone(),
+            connections: connections,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::{Method, StatusCode};
+    use hyper::header::{Connection, ContentLength, TransferEncoding};
+    use hyper::server::Response;
+
+    use super::adapt_http10;
+
+    #[test]
+    fn test_http10_closes_by_default() {
+        let mut resp = Response::new()
+            .with_header(ContentLength(2))
+            .with_body("ok");
+        adapt_http10(&mut resp, &Method::Get, false);
+
+        assert_eq!(Some(&Connection::close()), resp.headers().get::<Connection>());
+    }
+
+    #[test]
+    fn test_http10_keep_alive_with_known_length() {
+        let mut resp = Response::new()
+            .with_header(ContentLength(2))
+            .with_body("ok");
+        adapt_http10(&mut resp, &Method::Get, true);
+
+        assert_eq!(Some(&Connection::keep_alive()), resp.headers().get::<Connection>());
+    }
+
+    #[test]
+    fn test_http10_unknown_length_is_close_delimited() {
+        let mut resp = Response::new()
+            .with_header(TransferEncoding::chunked())
+            .with_body("ok");
+        adapt_http10(&mut resp, &Method::Get, true);
+
+        assert!(!resp.headers().has::<TransferEncoding>());
+        assert_eq!(Some(&Connection::close()), resp.headers().get::<Connection>());
+
+        let mut resp = Response::new().with_status(StatusCode::NoContent);
+        adapt_http10(&mut resp, &Method::Get, true);
+
+        assert_eq!(Some(&Connection::keep_alive()), resp.headers().get::<Connection>());
+    }
+}
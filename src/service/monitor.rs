@@ -1,29 +1,50 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use cocaine::logging::Filter;
+use cocaine::logging::{Filter, Severity};
 
-use futures::future;
+use futures::{future, Future};
 
 use hyper::{self, Method, StatusCode};
-use hyper::header::{ContentLength, ContentType};
+use hyper::header::{Accept, Authorization, Bearer, ContentLength, ContentType};
 use hyper::server::{Request, Response};
 
 use regex::Regex;
 
 use serde::Serialize;
 use serde_json;
+use serde_yaml;
 
-use tokio_core::reactor::Handle;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_service::Service;
 
-use crate::Metrics;
-use crate::config::Config;
-use crate::logging::Loggers;
+use crate::{Metrics, VersionInfo};
+use crate::config::{AdminRole, Config};
+use crate::logging::{AuditLog, Loggers};
+use crate::metrics::{prometheus, status};
+use crate::pool::EventDispatch;
+#[cfg(feature = "chaos")]
+use crate::pool::Fault;
+use crate::route::{Standby, Sweep, run_sweep};
 use crate::service::{ServiceFactory, ServiceFactorySpawn};
 
+/// Maximum concurrency level allowed for performance sweeps.
+const SWEEP_MAX_CONCURRENCY: usize = 1024;
+/// Maximum number of requests per level allowed for performance sweeps.
+const SWEEP_MAX_REQUESTS: usize = 100000;
+/// Number of recent errors returned unless specified otherwise.
+const RECENT_ERRORS_LIMIT: usize = 100;
+/// Path of cached resolve results, optionally followed by a service name.
+const RESOLVE_CACHE_PATH: &str = "/v1/resolve-cache";
+/// Path prefix of simulated faults of a service.
+#[cfg(feature = "chaos")]
+const CHAOS_PREFIX: &str = "/v1/chaos/";
+
 fn response_json<T: Serialize>(value: &T) -> Response {
     match serde_json::to_string(value) {
         Ok(body) => {
@@ -41,30 +62,289 @@ fn response_json<T: Serialize>(value: &T) -> Response {
     }
 }
 
+/// Responds with the value in YAML, which is useful for emitting configs that can be written back
+/// into a file.
+fn response_yaml<T: Serialize>(value: &T) -> Response {
+    match serde_yaml::to_string(value) {
+        Ok(body) => {
+            Response::new()
+                .with_status(StatusCode::Ok)
+                .with_header(ContentType("application/x-yaml".parse().unwrap()))
+                .with_header(ContentLength(body.len() as u64))
+                .with_body(body)
+        }
+        Err(err) => {
+            Response::new()
+                .with_status(StatusCode::InternalServerError)
+                .with_body(format!("{}", err))
+        }
+    }
+}
+
+/// Checks whether metrics are requested in Prometheus text format rather than JSON, either
+/// explicitly with `format=prometheus` query parameter or by a scraper accepting text.
+fn wants_prometheus(req: &Request) -> bool {
+    if let Some(format) = parse_query(req.query()).get("format") {
+        return *format == "prometheus";
+    }
+
+    req.headers().get::<Accept>()
+        .map(|accept| {
+            accept.iter().any(|item| {
+                let item = item.item.to_string();
+                item.starts_with("text/plain") || item.starts_with("application/openmetrics-text")
+            })
+        })
+        .unwrap_or(false)
+}
+
+fn response_prometheus(metrics: &Metrics) -> Response {
+    let body = prometheus::render(metrics);
+
+    Response::new()
+        .with_status(StatusCode::Ok)
+        .with_header(ContentType(prometheus::CONTENT_TYPE.parse().unwrap()))
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+/// Outcome of the readiness probe.
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    standby: bool,
+    /// Whether any locator has accepted a connection in time.
+    locator: bool,
+    /// Whether each required service has been resolved into at least one endpoint.
+    services: BTreeMap<String, bool>,
+}
+
+/// Checks whether any of the given locators accepts a connection within the timeout.
+fn probe_locators(addrs: Vec<SocketAddr>, timeout: Duration, handle: &Handle) -> Box<dyn Future<Item = bool, Error = ()>> {
+    if addrs.is_empty() {
+        return Box::new(future::ok(false));
+    }
+
+    let connects = addrs.iter()
+        .map(|addr| TcpStream::connect(addr, handle))
+        .collect::<Vec<_>>();
+    let connected = future::select_ok(connects)
+        .then(|result| Ok::<_, io::Error>(result.is_ok()));
+
+    let timeout = future::result(Timeout::new(timeout, handle))
+        .flatten()
+        .then(|_| Ok(false));
+
+    let future = connected.select(timeout)
+        .map(|(reachable, ..)| reachable)
+        .map_err(|_| ());
+
+    Box::new(future)
+}
+
 #[derive(Debug)]
 pub struct MonitorService {
+    addr: Option<SocketAddr>,
+    handle: Handle,
     config: Arc<Config>,
+    dispatcher: EventDispatch,
+    pools: EventDispatch,
     metrics: Arc<Metrics>,
     loggers: Arc<Loggers>,
+    audit: Option<Arc<AuditLog>>,
+    standby: Standby,
     regex: Regex,
+    breaker: Regex,
 }
 
 impl MonitorService {
-    pub fn new(config: Arc<Config>, loggers: Arc<Loggers>, metrics: Arc<Metrics>) -> Self {
+    pub fn new(addr: Option<SocketAddr>, handle: Handle, config: Arc<Config>, dispatcher: EventDispatch, pools: EventDispatch,
+               loggers: Arc<Loggers>, metrics: Arc<Metrics>, audit: Option<Arc<AuditLog>>, standby: Standby) -> Self
+    {
         Self {
+            addr: addr,
+            handle: handle,
             config: config,
+            dispatcher: dispatcher,
+            pools: pools,
             metrics: metrics,
             loggers: loggers,
-            regex: Regex::new("/v1/severity/(?P<logger>[^/]*)/(?P<severity>\\d)")
+            audit: audit,
+            standby: standby,
+            regex: Regex::new("^/v1/severity/(?P<logger>[^/]*)/(?P<severity>[^/]+)$")
                 .expect("invalid URI regex in monitoring"),
+            breaker: Regex::new("^/v1/breakers/(?P<service>[^/]+)/(?P<action>trip|reset)$")
+                .expect("invalid URI regex in monitoring"),
+        }
+    }
+
+    /// Returns the peer address of the caller.
+    fn peer(&self) -> String {
+        match self.addr {
+            Some(addr) => addr.to_string(),
+            None => "unix".into(),
+        }
+    }
+
+    /// Checks that the request is allowed to be performed, returning the caller identity on
+    /// success.
+    ///
+    /// Read-only requests require at least `read` role, while all others require `operate` one.
+    /// Ping and health requests are always allowed, because they are used by health checkers.
+    fn authorize(&self, req: &Request) -> Result<String, Response> {
+        let allow = self.config.monitoring().allow();
+        if !allow.is_empty() {
+            let allowed = match self.addr {
+                Some(addr) => allow.iter().any(|net| net.contains(addr.ip())),
+                // Unix sockets are protected by file permissions.
+                None => true,
+            };
+
+            if !allowed {
+                return Err(Response::new().with_status(StatusCode::Forbidden));
+            }
+        }
+
+        let tokens = self.config.monitoring().auth();
+        if tokens.is_empty() || req.path() == "/ping" || req.path().starts_with("/health/") {
+            return Ok(self.peer());
         }
+
+        let required = match *req.method() {
+            Method::Get | Method::Head => AdminRole::Read,
+            _ => AdminRole::Operate,
+        };
+
+        let token = req.headers().get::<Authorization<Bearer>>()
+            .and_then(|auth| tokens.iter().find(|cfg| constant_time_eq(cfg.token(), &auth.token)));
+
+        match token {
+            Some(cfg) if cfg.role() >= required => Ok(format!("{}@{}", cfg.name(), self.peer())),
+            Some(..) => Err(Response::new().with_status(StatusCode::Forbidden)),
+            None => Err(Response::new().with_status(StatusCode::Unauthorized)),
+        }
+    }
+
+    /// Checks whether the proxy is ready to serve traffic, i.e. it's not in warm standby, the
+    /// locator is reachable and all services warmed up at start have been resolved.
+    fn readiness(&self) -> Box<dyn Future<Item = Readiness, Error = ()>> {
+        let standby = !self.standby.is_active();
+        let required = self.config.warmup();
+        let addrs = self.config.locators()
+            .iter()
+            .map(|&(addr, port)| SocketAddr::new(addr, port))
+            .collect();
+
+        let future = probe_locators(addrs, self.config.monitoring().readiness().timeout(), &self.handle)
+            .join(self.pools.inspect())
+            .map(move |(locator, snapshot)| {
+                let services = required.into_iter()
+                    .map(|name| {
+                        let resolved = snapshot.services.get(&name).map(|s| !s.endpoints.is_empty()).unwrap_or(false);
+                        (name, resolved)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+
+                Readiness {
+                    ready: !standby && locator && services.values().all(|&resolved| resolved),
+                    standby: standby,
+                    locator: locator,
+                    services: services,
+                }
+            });
+
+        Box::new(future)
+    }
+
+    /// Writes the successfully performed mutation into the audit log, if enabled.
+    fn audit(&self, caller: &str, action: &str, params: &[(&str, &str)]) {
+        if let Some(ref audit) = self.audit {
+            if let Err(err) = audit.record(caller, action, params) {
+                cocaine_log!(self.loggers.common().logger(), Severity::Error, "failed to write audit record: {}", err; {
+                    action: action,
+                });
+            }
+        }
+    }
+}
+
+/// Parses query parameters, without percent-decoding, because all of them are plain names and
+/// numbers.
+fn parse_query(query: Option<&str>) -> HashMap<&str, &str> {
+    query.unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            (kv.next().unwrap_or(""), kv.next().unwrap_or(""))
+        })
+        .collect()
+}
+
+/// Extracts performance sweep parameters from the query.
+fn parse_sweep(query: Option<&str>) -> Result<Sweep, String> {
+    let query = parse_query(query);
+
+    let service = match query.get("service") {
+        Some(service) if !service.is_empty() => service.to_string(),
+        _ => return Err("`service` parameter is required".into()),
+    };
+
+    let event = match query.get("event") {
+        Some(event) if !event.is_empty() => event.to_string(),
+        _ => return Err("`event` parameter is required".into()),
+    };
+
+    let levels = query.get("levels").cloned().unwrap_or("1,2,4,8,16")
+        .split(',')
+        .map(|level| match usize::from_str(level) {
+            Ok(level) if level > 0 && level <= SWEEP_MAX_CONCURRENCY => Ok(level),
+            _ => Err(format!("concurrency levels must be integers in [1; {}] range", SWEEP_MAX_CONCURRENCY)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let requests = match query.get("requests") {
+        Some(requests) => match usize::from_str(requests) {
+            Ok(requests) if requests > 0 && requests <= SWEEP_MAX_REQUESTS => requests,
+            _ => return Err(format!("number of requests must be an integer in [1; {}] range", SWEEP_MAX_REQUESTS)),
+        },
+        None => 100,
+    };
+
+    let sweep = Sweep {
+        service: service,
+        event: event,
+        body: query.get("body").map(|body| body.to_string()),
+        levels: levels,
+        requests: requests,
+    };
+
+    Ok(sweep)
+}
+
+/// Compares two strings in time that depends only on their lengths, preventing timing attacks on
+/// tokens.
+fn constant_time_eq(lhs: &str, rhs: &str) -> bool {
+    if lhs.len() != rhs.len() {
+        return false;
     }
+
+    lhs.bytes().zip(rhs.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
 }
 
 fn match_severity(sev: isize) -> bool {
     0 <= sev && sev <= 3
 }
 
+/// Parses the severity either from its numeric value or from its name, like `debug` or `warn`.
+fn parse_severity(value: &str) -> Result<isize, Error> {
+    match isize::from_str(value) {
+        Ok(sev) if match_severity(sev) => Ok(sev),
+        Ok(sev) => Err(Error::SeverityNotInRange(sev)),
+        Err(..) => Severity::from_str(value).map(Into::into).map_err(|_| Error::InvalidSeverity),
+    }
+}
+
 enum Error<'a> {
     LoggerNotFound(&'a str),
     SeverityNotInRange(isize),
@@ -76,7 +356,7 @@ impl<'a> Into<Response> for Error<'a> {
         let description = match self {
             Error::LoggerNotFound(logger) => format!("Logger `{}` not found", logger),
             Error::SeverityNotInRange(..) => format!("Severity value must be in [0; 3] range"),
-            Error::InvalidSeverity => format!("Severity value must be an integer"),
+            Error::InvalidSeverity => format!("Severity value must be an integer or one of severity names"),
         };
 
         Response::new()
@@ -99,13 +379,199 @@ impl Service for MonitorService {
     type Request  = Request;
     type Response = Response;
     type Error    = hyper::Error;
-    type Future   = future::FutureResult<Self::Response, Self::Error>;
+    type Future   = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
+        let caller = match self.authorize(&req) {
+            Ok(caller) => caller,
+            Err(res) => return Box::new(future::ok(res)),
+        };
+
+        // Sweeps are asynchronous, since they take a while, so they are handled apart from
+        // everything else.
+        if let (&Method::Post, "/v1/perf/sweep") = (req.method(), req.path()) {
+            if !self.config.is_load_testing_enabled() {
+                return Box::new(future::ok(Response::new().with_status(StatusCode::NotFound)));
+            }
+
+            let sweep = match parse_sweep(req.query()) {
+                Ok(sweep) => sweep,
+                Err(err) => {
+                    let res = Response::new()
+                        .with_status(StatusCode::BadRequest)
+                        .with_header(ContentType::plaintext())
+                        .with_header(ContentLength(err.len() as u64))
+                        .with_body(err);
+                    return Box::new(future::ok(res));
+                }
+            };
+
+            let levels = sweep.levels.iter().map(|level| level.to_string()).collect::<Vec<_>>().join(",");
+            let requests = sweep.requests.to_string();
+            self.audit(&caller, "perf.sweep", &[
+                ("service", &sweep.service),
+                ("event", &sweep.event),
+                ("levels", &levels),
+                ("requests", &requests),
+            ]);
+
+            let future = run_sweep(self.dispatcher.clone(), sweep)
+                .then(|report| {
+                    match report {
+                        Ok(report) => Ok(response_json(&report)),
+                        Err(()) => Ok(Response::new().with_status(StatusCode::InternalServerError)),
+                    }
+                });
+            return Box::new(future);
+        }
+
+        if let (&Method::Get, "/status") = (req.method(), req.path()) {
+            let metrics = self.metrics.clone();
+            let breakers = self.pools.breakers().map(|breakers| breakers.states()).unwrap_or_default();
+            let future = self.pools.inspect()
+                .then(move |snapshot| {
+                    let body = status::render(&metrics, &snapshot.unwrap_or_default(), &breakers);
+                    let res = Response::new()
+                        .with_status(StatusCode::Ok)
+                        .with_header(ContentType(status::CONTENT_TYPE.parse().unwrap()))
+                        .with_header(ContentLength(body.len() as u64))
+                        .with_body(body);
+                    Ok(res)
+                });
+            return Box::new(future);
+        }
+
+        if let (&Method::Get, "/health/ready") = (req.method(), req.path()) {
+            let future = self.readiness()
+                .then(|readiness| {
+                    match readiness {
+                        Ok(readiness) => {
+                            let mut res = response_json(&readiness);
+                            if !readiness.ready {
+                                res.set_status(StatusCode::ServiceUnavailable);
+                            }
+                            Ok(res)
+                        }
+                        Err(()) => Ok(Response::new().with_status(StatusCode::ServiceUnavailable)),
+                    }
+                });
+            return Box::new(future);
+        }
+
+        if *req.method() == Method::Delete && req.path().starts_with(RESOLVE_CACHE_PATH) {
+            let service = match &req.path()[RESOLVE_CACHE_PATH.len()..] {
+                "" => None,
+                path if path.len() > 1 && path.starts_with('/') => Some(&path[1..]),
+                _ => return Box::new(future::ok(Response::new().with_status(StatusCode::NotFound))),
+            };
+
+            cocaine_log!(self.loggers.common().logger(), Severity::Info, "flushing cached resolve results of {} by {}",
+                service.map(|v| format!("`{}` service", v)).unwrap_or_else(|| "all services".into()), caller);
+            self.audit(&caller, "resolve_cache.flush", &[("service", service.unwrap_or("*"))]);
+
+            let future = self.pools.flush_resolves(service)
+                .then(|count| {
+                    match count {
+                        Ok(count) => {
+                            let mut body = HashMap::new();
+                            body.insert("flushed", count);
+                            Ok(response_json(&body))
+                        }
+                        Err(()) => Ok(Response::new().with_status(StatusCode::InternalServerError)),
+                    }
+                });
+            return Box::new(future);
+        }
+
+        if let (&Method::Get, "/v1/pools") = (req.method(), req.path()) {
+            let future = self.pools.inspect()
+                .then(|snapshot| {
+                    match snapshot {
+                        Ok(snapshot) => Ok(response_json(&snapshot)),
+                        Err(()) => Ok(Response::new().with_status(StatusCode::InternalServerError)),
+                    }
+                });
+            return Box::new(future);
+        }
+
         let res = match (req.method(), req.path()) {
             (&Method::Get, "/ping") => Response::new().with_status(StatusCode::Ok),
+            (&Method::Get, "/health/alive") => Response::new().with_status(StatusCode::Ok),
+            (&Method::Get, "/version") => response_json(&VersionInfo::new()),
             (&Method::Get, "/config") => response_json(&*self.config),
+            (&Method::Get, "/config/migrated") => response_yaml(&*self.config),
+            (&Method::Get, "/metrics") if wants_prometheus(&req) => response_prometheus(&self.metrics),
             (&Method::Get, "/metrics") => response_json(&*self.metrics),
+            (&Method::Get, "/v1/slo") => response_json(&self.metrics.slo_summaries()),
+            (&Method::Get, "/v1/errors") => {
+                let limit = parse_query(req.query()).get("limit")
+                    .and_then(|limit| usize::from_str(limit).ok())
+                    .unwrap_or(RECENT_ERRORS_LIMIT);
+                response_json(&self.metrics.recent_errors.snapshot(limit))
+            }
+            (&Method::Get, "/v1/standby") => {
+                let mut state = HashMap::new();
+                state.insert("active", self.standby.is_active());
+                response_json(&state)
+            }
+            (&Method::Post, "/v1/standby/activate") => {
+                if self.standby.activate() {
+                    cocaine_log!(self.loggers.common().logger(), Severity::Info, "promoted out of warm standby by {}", caller);
+                    self.audit(&caller, "standby.activate", &[]);
+                }
+                Response::new().with_status(StatusCode::Ok)
+            }
+            (&Method::Get, "/v1/breakers") => {
+                match self.pools.breakers() {
+                    Some(breakers) => response_json(&breakers.states()),
+                    None => Response::new().with_status(StatusCode::NotFound),
+                }
+            }
+            (&Method::Post, path) if self.breaker.is_match(path) => {
+                match (self.pools.breakers(), self.breaker.captures(path)) {
+                    (Some(breakers), Some(captures)) => {
+                        let service = &captures["service"];
+                        match &captures["action"] {
+                            "trip" => breakers.trip(service),
+                            _ => breakers.reset(service),
+                        }
+                        cocaine_log!(self.loggers.common().logger(), Severity::Info, "circuit breaker of `{}` service: {} by {}",
+                            service, &captures["action"], caller);
+                        self.audit(&caller, &format!("breaker.{}", &captures["action"]), &[("service", service)]);
+                        Response::new().with_status(StatusCode::Ok)
+                    }
+                    (..) => Response::new().with_status(StatusCode::NotFound),
+                }
+            }
+            #[cfg(feature = "chaos")]
+            (&Method::Put, path) if path.len() > CHAOS_PREFIX.len() && path.starts_with(CHAOS_PREFIX) => {
+                let service = &path[CHAOS_PREFIX.len()..];
+                match Fault::from_query(&parse_query(req.query())) {
+                    Ok(fault) => {
+                        self.pools.set_fault(service, Some(fault));
+                        cocaine_log!(self.loggers.common().logger(), Severity::Warn, "enabled simulated faults of `{}` service: {} by {}",
+                            service, fault, caller);
+                        self.audit(&caller, "chaos.set", &[("service", service), ("fault", &fault.to_string())]);
+                        Response::new().with_status(StatusCode::Ok)
+                    }
+                    Err(err) => {
+                        Response::new()
+                            .with_status(StatusCode::BadRequest)
+                            .with_header(ContentType::plaintext())
+                            .with_header(ContentLength(err.len() as u64))
+                            .with_body(err)
+                    }
+                }
+            }
+            #[cfg(feature = "chaos")]
+            (&Method::Delete, path) if path.len() > CHAOS_PREFIX.len() && path.starts_with(CHAOS_PREFIX) => {
+                let service = &path[CHAOS_PREFIX.len()..];
+                self.pools.set_fault(service, None);
+                cocaine_log!(self.loggers.common().logger(), Severity::Info, "disabled simulated faults of `{}` service by {}",
+                    service, caller);
+                self.audit(&caller, "chaos.clear", &[("service", service)]);
+                Response::new().with_status(StatusCode::Ok)
+            }
             (&Method::Get, "/v1/severity/common") => {
                 response_json(&self.loggers.common().filter().get())
             }
@@ -117,14 +583,19 @@ impl Service for MonitorService {
                     Some(captures) => {
                         match extract_filter(&self.loggers, &captures["logger"]) {
                             Ok(filter) => {
-                                match FromStr::from_str(&captures["severity"]) {
-                                    Ok(sev) if match_severity(sev) => {
+                                match parse_severity(&captures["severity"]) {
+                                    Ok(sev) => {
                                         filter.set(sev);
+                                        cocaine_log!(self.loggers.common().logger(), Severity::Info, "changed severity of `{}` logger to {} by {}",
+                                            &captures["logger"], &captures["severity"], caller);
+                                        self.audit(&caller, "severity.set", &[
+                                            ("logger", &captures["logger"]),
+                                            ("severity", &captures["severity"]),
+                                        ]);
                                         Response::new()
                                             .with_status(StatusCode::Ok)
                                     }
-                                    Ok(sev) => Error::SeverityNotInRange(sev).into(),
-                                    Err(..) => Error::InvalidSeverity.into(),
+                                    Err(err) => err.into(),
                                 }
                             }
                             Err(err) => err.into(),
@@ -136,15 +607,20 @@ impl Service for MonitorService {
             (..) => Response::new().with_status(StatusCode::NotFound),
         };
 
-        future::ok(res)
+        Box::new(future::ok(res))
     }
 }
 
 #[derive(Debug)]
 pub struct MonitorServiceFactory {
+    handle: Handle,
     config: Arc<Config>,
+    dispatcher: EventDispatch,
+    pools: EventDispatch,
     metrics: Arc<Metrics>,
     loggers: Arc<Loggers>,
+    audit: Option<Arc<AuditLog>>,
+    standby: Standby,
 }
 
 impl ServiceFactory for MonitorServiceFactory {
@@ -153,24 +629,35 @@ impl ServiceFactory for MonitorServiceFactory {
     type Instance = MonitorService;
     type Error    = hyper::Error;
 
-    fn create_service(&mut self, _addr: Option<SocketAddr>) -> Result<Self::Instance, io::Error> {
-        Ok(MonitorService::new(self.config.clone(), self.loggers.clone(), self.metrics.clone()))
+    fn create_service(&mut self, addr: Option<SocketAddr>) -> Result<Self::Instance, io::Error> {
+        Ok(MonitorService::new(addr, self.handle.clone(), self.config.clone(), self.dispatcher.clone(), self.pools.clone(),
+            self.loggers.clone(), self.metrics.clone(), self.audit.clone(), self.standby.clone()))
     }
 }
 
 #[derive(Debug)]
 pub struct MonitorServiceFactoryFactory {
     config: Arc<Config>,
+    dispatcher: EventDispatch,
+    pools: EventDispatch,
     metrics: Arc<Metrics>,
     loggers: Arc<Loggers>,
+    audit: Option<Arc<AuditLog>>,
+    standby: Standby,
 }
 
 impl MonitorServiceFactoryFactory {
-    pub fn new(config: Arc<Config>, loggers: Arc<Loggers>, metrics: Arc<Metrics>) -> Self {
+    pub fn new(config: Arc<Config>, dispatcher: EventDispatch, pools: EventDispatch, loggers: Arc<Loggers>,
+               metrics: Arc<Metrics>, audit: Option<Arc<AuditLog>>, standby: Standby) -> Self
+    {
         Self {
             config: config,
+            dispatcher: dispatcher,
+            pools: pools,
             metrics: metrics,
             loggers: loggers.clone(),
+            audit: audit,
+            standby: standby,
         }
     }
 }
@@ -178,11 +665,80 @@ impl MonitorServiceFactoryFactory {
 impl ServiceFactorySpawn for MonitorServiceFactoryFactory {
     type Factory = MonitorServiceFactory;
 
-    fn create_factory(&self, _handle: &Handle) -> Self::Factory {
+    fn create_factory(&self, handle: &Handle) -> Self::Factory {
         MonitorServiceFactory {
+            handle: handle.clone(),
             config: self.config.clone(),
+            dispatcher: self.dispatcher.clone(),
+            pools: self.pools.clone(),
             metrics: self.metrics.clone(),
             loggers: self.loggers.clone(),
+            audit: self.audit.clone(),
+            standby: self.standby.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::Method;
+    use hyper::header::{Accept, Header};
+    use hyper::server::Request;
+
+    use super::{constant_time_eq, parse_severity, parse_sweep, wants_prometheus};
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("", ""));
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secrets"));
+    }
+
+    #[test]
+    fn test_parse_severity() {
+        assert_eq!(Some(0), parse_severity("0").ok());
+        assert_eq!(Some(3), parse_severity("3").ok());
+        assert_eq!(Some(0), parse_severity("debug").ok());
+        assert!(parse_severity("4").is_err());
+        assert!(parse_severity("-1").is_err());
+        assert!(parse_severity("verbose").is_err());
+    }
+
+    #[test]
+    fn test_wants_prometheus() {
+        let req = Request::new(Method::Get, "/metrics".parse().unwrap());
+        assert!(!wants_prometheus(&req));
+
+        let req = Request::new(Method::Get, "/metrics?format=prometheus".parse().unwrap());
+        assert!(wants_prometheus(&req));
+
+        let mut req = Request::new(Method::Get, "/metrics".parse().unwrap());
+        req.headers_mut().set_raw(Accept::header_name(), "text/plain;version=0.0.4;q=0.5,*/*;q=0.1");
+        assert!(wants_prometheus(&req));
+
+        let mut req = Request::new(Method::Get, "/metrics?format=json".parse().unwrap());
+        req.headers_mut().set_raw(Accept::header_name(), "text/plain");
+        assert!(!wants_prometheus(&req));
+    }
+
+    #[test]
+    fn test_parse_sweep() {
+        let sweep = parse_sweep(Some("service=echo&event=ping&levels=1,10,100&requests=500")).unwrap();
+        assert_eq!("echo", sweep.service);
+        assert_eq!("ping", sweep.event);
+        assert_eq!(vec![1, 10, 100], sweep.levels);
+        assert_eq!(500, sweep.requests);
+        assert_eq!(None, sweep.body);
+
+        let sweep = parse_sweep(Some("service=echo&event=ping")).unwrap();
+        assert_eq!(vec![1, 2, 4, 8, 16], sweep.levels);
+        assert_eq!(100, sweep.requests);
+
+        assert!(parse_sweep(None).is_err());
+        assert!(parse_sweep(Some("service=echo")).is_err());
+        assert!(parse_sweep(Some("service=echo&event=ping&levels=0")).is_err());
+        assert!(parse_sweep(Some("service=echo&event=ping&levels=1,x")).is_err());
+        assert!(parse_sweep(Some("service=echo&event=ping&requests=1000000")).is_err());
+    }
+}